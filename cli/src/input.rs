@@ -0,0 +1,186 @@
+use cli_helpers::prelude::*;
+use notify::Watcher;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+/// Number of lines that can be read ahead of the downloads.
+const CHANNEL_SIZE: usize = 1024;
+
+/// Time after which a followed file is checked again even if no change has been reported (for file
+/// systems that don't report changes).
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("File watching error")]
+    Notify(#[from] notify::Error),
+    #[error("Invalid checkpoint")]
+    InvalidCheckpoint(#[from] serde_json::Error),
+    #[error("Input doesn't match checkpoint at line {line}: {found}")]
+    CheckpointMismatch { line: u64, found: String },
+    #[error("Input ended before checkpoint line {0}")]
+    CheckpointNotReached(u64),
+}
+
+/// A record of how much of the input has been processed, so that an interrupted run can be resumed.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Checkpoint {
+    /// The input file (or none for standard input)
+    pub input: Option<PathBuf>,
+    /// Number of lines that have been processed
+    pub line: u64,
+    /// The last processed line (used to check that the input is the same when resuming)
+    pub last_line: Option<String>,
+}
+
+impl Checkpoint {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// Write the checkpoint to a temporary file that is then moved into place.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let temp_path = path.as_ref().with_extension("tmp");
+
+        std::fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(temp_path, path)?;
+
+        Ok(())
+    }
+
+    /// Check whether a line (numbered from one) was already processed, failing if the line that was
+    /// processed last is different.
+    pub fn skips(&self, line: u64, value: &str) -> Result<bool, Error> {
+        if line == self.line
+            && self
+                .last_line
+                .as_deref()
+                .is_some_and(|last_line| last_line != value)
+        {
+            Err(Error::CheckpointMismatch {
+                line,
+                found: value.to_string(),
+            })
+        } else {
+            Ok(line <= self.line)
+        }
+    }
+}
+
+type Lines = Receiver<Result<String, std::io::Error>>;
+
+/// Read lines from standard input (on a separate thread).
+pub fn stdin() -> Lines {
+    let (sender, receiver) = tokio::sync::mpsc::channel(CHANNEL_SIZE);
+
+    std::thread::spawn(move || send_lines(std::io::stdin().lock(), &sender));
+
+    receiver
+}
+
+/// Read lines from a file as they are appended to it (on a separate thread).
+///
+/// The file is read from the start, and then the thread waits to be woken by change notifications
+/// (using inotify on Linux). A file that becomes shorter than the amount already read is assumed to
+/// have been truncated, and is read again from the start. Named pipes are read until the process
+/// exits, so writers can connect and disconnect at any time.
+pub fn follow<P: AsRef<Path>>(path: P) -> Result<Lines, Error> {
+    let path = path.as_ref();
+    let (sender, receiver) = tokio::sync::mpsc::channel(CHANNEL_SIZE);
+
+    if is_fifo(path)? {
+        // Keeping a write end open means that reads wait for data instead of reaching the end of
+        // the file when no other writers are connected.
+        let file = File::options().read(true).write(true).open(path)?;
+
+        std::thread::spawn(move || send_lines(BufReader::new(file), &sender));
+    } else {
+        let (event_sender, events) = std::sync::mpsc::channel();
+        let mut watcher = notify::recommended_watcher(event_sender)?;
+        watcher.watch(path, notify::RecursiveMode::NonRecursive)?;
+
+        let file = File::open(path)?;
+
+        std::thread::spawn(move || {
+            // The watcher stops sending events when it is dropped.
+            let _watcher = watcher;
+
+            if let Err(error) = follow_file(file, &events, &sender) {
+                let _ = sender.blocking_send(Err(error));
+            }
+        });
+    }
+
+    Ok(receiver)
+}
+
+/// Send every line until the end of the input (or until the receiver is closed).
+fn send_lines<R: BufRead>(reader: R, sender: &Sender<Result<String, std::io::Error>>) {
+    for line in reader.lines() {
+        if sender.blocking_send(line).is_err() {
+            break;
+        }
+    }
+}
+
+fn follow_file(
+    file: File,
+    events: &std::sync::mpsc::Receiver<notify::Result<notify::Event>>,
+    sender: &Sender<Result<String, std::io::Error>>,
+) -> Result<(), std::io::Error> {
+    let mut reader = BufReader::new(file);
+    let mut position = 0;
+    let mut line = String::new();
+
+    loop {
+        let len = reader.read_line(&mut line)?;
+
+        if len == 0 {
+            match events.recv_timeout(POLL_INTERVAL) {
+                Ok(_) | Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+
+            // A single read handles any number of changes.
+            while events.try_recv().is_ok() {}
+
+            if reader.get_ref().metadata()?.len() < position {
+                log::warn!("Followed file was truncated, reading from the start");
+
+                reader.seek(SeekFrom::Start(0))?;
+                position = 0;
+                line.clear();
+            }
+        } else {
+            position += len as u64;
+
+            // An incomplete line is finished when the rest of it is appended.
+            if line.ends_with('\n') {
+                let complete = line.trim_end_matches(['\n', '\r']).to_string();
+                line.clear();
+
+                if sender.blocking_send(Ok(complete)).is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> Result<bool, std::io::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    Ok(std::fs::metadata(path)?.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> Result<bool, std::io::Error> {
+    Ok(false)
+}
@@ -1,13 +1,108 @@
 use chrono::{DateTime, Utc};
+use image_scraper::digest::Digest;
+use image_scraper::history::FailureKind;
 use image_scraper::image_type::ImageType;
+use image_scraper_index::{Entry, db::Database};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("CSV error")]
+    Csv(#[from] csv::Error),
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
+    #[error("Invalid log record: {0}")]
+    InvalidRecord(String),
+    #[error("Log record without a timestamp: {0}")]
+    MissingTimestamp(String),
+}
+
+/// The format of a download log.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Positional CSV fields (the status code, then fields that depend on the status)
+    #[default]
+    Csv,
+    /// One JSON object per line, with named fields (see [`DownloadEvent`])
+    Jsonl,
+}
+
+impl LogFormat {
+    /// Determine the format of a log file.
+    ///
+    /// Files ending in `.jsonl` or `.csv` (optionally followed by `.gz`) use those formats, and
+    /// otherwise the format is JSON Lines if the first non-blank character is `{`.
+    pub fn detect(path: &Path) -> Result<Self, std::io::Error> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let name = name.strip_suffix(".gz").unwrap_or(&name);
+
+        if name.ends_with(".jsonl") {
+            Ok(Self::Jsonl)
+        } else if name.ends_with(".csv") {
+            Ok(Self::Csv)
+        } else {
+            let mut reader = BufReader::new(open_reader(Some(path))?);
+            let mut first = None;
+
+            while first.is_none() {
+                let buffer = reader.fill_buf()?;
+
+                if buffer.is_empty() {
+                    break;
+                }
+
+                first = buffer
+                    .iter()
+                    .find(|byte| !byte.is_ascii_whitespace())
+                    .copied();
+
+                let len = buffer.len();
+                reader.consume(len);
+            }
+
+            Ok(if first == Some(b'{') {
+                Self::Jsonl
+            } else {
+                Self::Csv
+            })
+        }
+    }
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Csv => "csv",
+            Self::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "jsonl" => Ok(Self::Jsonl),
+            other => Err(format!("Invalid log format: {other}")),
+        }
+    }
+}
 
 #[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct DownloadLogEntry {
     pub status: DownloadStatus,
     #[serde(with = "chrono::serde::ts_seconds")]
     pub timestamp: DateTime<Utc>,
-    #[serde(with = "serde_hex::SerHex::<serde_hex::config::Strict>")]
-    pub digest: [u8; 16],
+    pub digest: Digest,
     pub image_type: ImageType,
     pub url: String,
 }
@@ -18,4 +113,499 @@ pub enum DownloadStatus {
     Added,
     #[serde(rename = "F")]
     Found,
+    /// The URL was already in the index, so it wasn't downloaded
+    #[serde(rename = "S")]
+    Skipped,
+}
+
+/// An event in a JSON Lines download log.
+///
+/// Events are tagged with an `event` field. Fields that aren't known are ignored when reading
+/// logs, and so are unknown events, so logs can be extended without breaking imports.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DownloadEvent {
+    /// The image was downloaded and added to the store
+    Added {
+        timestamp: DateTime<Utc>,
+        url: String,
+        digest: Digest,
+        image_type: ImageType,
+    },
+    /// The image was downloaded, but was already in the store
+    Found {
+        timestamp: DateTime<Utc>,
+        url: String,
+        digest: Digest,
+        image_type: ImageType,
+    },
+    /// The URL was already in the index, so it wasn't downloaded
+    Skipped {
+        timestamp: DateTime<Utc>,
+        url: String,
+        digest: Digest,
+        image_type: ImageType,
+    },
+    /// The download wasn't an image, and was saved to the quarantine directory
+    Quarantined {
+        timestamp: DateTime<Utc>,
+        url: String,
+        digest: Digest,
+    },
+    /// The download wasn't an image, and was discarded
+    Rejected {
+        timestamp: DateTime<Utc>,
+        url: String,
+        digest: Digest,
+    },
+    /// The URL isn't allowed by the URL patterns, so it wasn't downloaded
+    Excluded {
+        timestamp: DateTime<Utc>,
+        url: String,
+        reason: String,
+    },
+    /// The download failed (with the HTTP status code, if one was received)
+    Failed {
+        timestamp: DateTime<Utc>,
+        url: String,
+        status: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        kind: Option<FailureKind>,
+        /// A description of the error (for failures that didn't receive a response)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    #[serde(other)]
+    Unknown,
+}
+
+impl DownloadEvent {
+    /// Convert the event to a log entry, if it is one that is imported.
+    fn into_log_entry(self) -> Option<DownloadLogEntry> {
+        let (status, timestamp, url, digest, image_type) = match self {
+            Self::Added {
+                timestamp,
+                url,
+                digest,
+                image_type,
+            } => (DownloadStatus::Added, timestamp, url, digest, image_type),
+            Self::Found {
+                timestamp,
+                url,
+                digest,
+                image_type,
+            } => (DownloadStatus::Found, timestamp, url, digest, image_type),
+            Self::Skipped {
+                timestamp,
+                url,
+                digest,
+                image_type,
+            } => (DownloadStatus::Skipped, timestamp, url, digest, image_type),
+            Self::Quarantined { .. }
+            | Self::Rejected { .. }
+            | Self::Excluded { .. }
+            | Self::Failed { .. }
+            | Self::Unknown => return None,
+        };
+
+        Some(DownloadLogEntry {
+            status,
+            timestamp,
+            digest,
+            image_type,
+            url,
+        })
+    }
+
+    /// The positional fields used for the event in CSV logs.
+    ///
+    /// CSV logs don't include timestamps, and failures only include the status code and the kind of
+    /// failure.
+    fn csv_record(&self) -> [String; 4] {
+        match self {
+            Self::Added {
+                url,
+                digest,
+                image_type,
+                ..
+            } => [
+                "A".to_string(),
+                digest.to_string(),
+                image_type.to_string(),
+                url.clone(),
+            ],
+            Self::Found {
+                url,
+                digest,
+                image_type,
+                ..
+            } => [
+                "F".to_string(),
+                digest.to_string(),
+                image_type.to_string(),
+                url.clone(),
+            ],
+            Self::Skipped {
+                url,
+                digest,
+                image_type,
+                ..
+            } => [
+                "S".to_string(),
+                digest.to_string(),
+                image_type.to_string(),
+                url.clone(),
+            ],
+            Self::Quarantined { url, digest, .. } => [
+                "Q".to_string(),
+                digest.to_string(),
+                ImageType::empty().to_string(),
+                url.clone(),
+            ],
+            Self::Rejected { url, digest, .. } => [
+                "R".to_string(),
+                digest.to_string(),
+                String::new(),
+                url.clone(),
+            ],
+            Self::Excluded { url, .. } => {
+                ["X".to_string(), String::new(), String::new(), url.clone()]
+            }
+            Self::Failed { status, kind, .. } => [
+                "E".to_string(),
+                status.map(|status| status.to_string()).unwrap_or_default(),
+                kind.map(|kind| kind.to_string()).unwrap_or_default(),
+                String::new(),
+            ],
+            Self::Unknown => [String::new(), String::new(), String::new(), String::new()],
+        }
+    }
+}
+
+/// Writes download events to standard output in either format.
+pub enum Writer {
+    Csv(Box<csv::Writer<std::io::Stdout>>),
+    Jsonl(std::io::Stdout),
+}
+
+impl Writer {
+    #[must_use]
+    pub fn new(format: LogFormat) -> Self {
+        match format {
+            LogFormat::Csv => Self::Csv(Box::new(
+                csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(std::io::stdout()),
+            )),
+            LogFormat::Jsonl => Self::Jsonl(std::io::stdout()),
+        }
+    }
+
+    pub fn write(&mut self, event: &DownloadEvent) -> Result<(), Error> {
+        match self {
+            Self::Csv(writer) => Ok(writer.write_record(event.csv_record())?),
+            Self::Jsonl(writer) => {
+                let mut line = serde_json::to_vec(event)?;
+                line.push(b'\n');
+
+                Ok(writer.write_all(&line)?)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        match self {
+            Self::Csv(writer) => Ok(writer.flush()?),
+            Self::Jsonl(writer) => Ok(writer.flush()?),
+        }
+    }
+}
+
+/// Number of log lines that are parsed (and imported) together.
+pub const BATCH_SIZE: usize = 10_000;
+
+/// Open a file (or standard input if no path is given), decompressing it if it ends in `.gz`.
+fn open_reader(path: Option<&Path>) -> Result<Box<dyn Read>, std::io::Error> {
+    Ok(match path {
+        Some(path) if path.extension().is_some_and(|extension| extension == "gz") => Box::new(
+            flate2::read::MultiGzDecoder::new(BufReader::new(File::open(path)?)),
+        ),
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(std::io::stdin()),
+    })
+}
+
+/// Open a download log (or standard input if no path is given), returning its entries in batches.
+///
+/// Files with a `.gz` extension are decompressed. CSV lines without timestamps are given the
+/// file's modification time.
+pub fn open(path: Option<&Path>, format: LogFormat) -> Result<Batches, std::io::Error> {
+    let reader = open_reader(path)?;
+
+    Ok(match format {
+        LogFormat::Csv => Batches::Csv {
+            reader: csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(reader),
+            default_timestamp: path
+                .map(|path| std::fs::metadata(path)?.modified())
+                .transpose()?
+                .map(DateTime::<Utc>::from),
+        },
+        LogFormat::Jsonl => Batches::Jsonl(BufReader::new(reader).lines()),
+    })
+}
+
+/// The entries parsed from a batch of log lines.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Batch {
+    pub entries: Vec<DownloadLogEntry>,
+    /// Number of lines that weren't entries (failures, rejected downloads, unknown events, etc.)
+    pub ignored: usize,
+}
+
+impl FromIterator<Option<DownloadLogEntry>> for Batch {
+    fn from_iter<I: IntoIterator<Item = Option<DownloadLogEntry>>>(iter: I) -> Self {
+        let mut batch = Self::default();
+
+        for entry in iter {
+            match entry {
+                Some(entry) => batch.entries.push(entry),
+                None => batch.ignored += 1,
+            }
+        }
+
+        batch
+    }
+}
+
+/// Batches of log entries, with the lines in each batch parsed in parallel.
+pub enum Batches {
+    Csv {
+        reader: csv::Reader<Box<dyn Read>>,
+        default_timestamp: Option<DateTime<Utc>>,
+    },
+    Jsonl(std::io::Lines<BufReader<Box<dyn Read>>>),
+}
+
+impl Iterator for Batches {
+    type Item = Result<Batch, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Self::Csv {
+                reader,
+                default_timestamp,
+            } => {
+                let mut records = Vec::with_capacity(BATCH_SIZE);
+
+                while records.len() < BATCH_SIZE {
+                    let mut record = csv::StringRecord::new();
+
+                    match reader.read_record(&mut record) {
+                        Ok(true) => records.push(record),
+                        Ok(false) => break,
+                        Err(error) => return Some(Err(Error::from(error))),
+                    }
+                }
+
+                (!records.is_empty()).then(|| {
+                    let entries = records
+                        .par_iter()
+                        .map(|record| parse_csv_record(record, *default_timestamp))
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    Ok(entries.into_iter().collect())
+                })
+            }
+            Self::Jsonl(lines) => {
+                let mut records = Vec::with_capacity(BATCH_SIZE);
+
+                while records.len() < BATCH_SIZE {
+                    match lines.next() {
+                        Some(Ok(line)) if line.trim().is_empty() => {}
+                        Some(Ok(line)) => records.push(line),
+                        Some(Err(error)) => return Some(Err(Error::from(error))),
+                        None => break,
+                    }
+                }
+
+                (!records.is_empty()).then(|| {
+                    let entries = records
+                        .par_iter()
+                        .map(|line| {
+                            serde_json::from_str::<DownloadEvent>(line)
+                                .map(DownloadEvent::into_log_entry)
+                                .map_err(Error::from)
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    Ok(entries.into_iter().collect())
+                })
+            }
+        }
+    }
+}
+
+/// Parse a CSV log line, returning `None` for lines that aren't entries.
+///
+/// Lines start with a status code. Entry lines written by `download-all` have the digest, image
+/// type, and URL, and older logs also have a timestamp (in epoch seconds) after the status code.
+fn parse_csv_record(
+    record: &csv::StringRecord,
+    default_timestamp: Option<DateTime<Utc>>,
+) -> Result<Option<DownloadLogEntry>, Error> {
+    let invalid = || Error::InvalidRecord(record.iter().collect::<Vec<_>>().join(","));
+
+    let status = match record.get(0) {
+        Some("A") => DownloadStatus::Added,
+        Some("F") => DownloadStatus::Found,
+        Some("S") => DownloadStatus::Skipped,
+        _ => return Ok(None),
+    };
+
+    match record.len() {
+        5 => Ok(Some(record.deserialize(None)?)),
+        4 => Ok(Some(DownloadLogEntry {
+            status,
+            timestamp: default_timestamp.ok_or_else(|| {
+                Error::MissingTimestamp(record.iter().collect::<Vec<_>>().join(","))
+            })?,
+            digest: record[1].parse().map_err(|_| invalid())?,
+            image_type: record[2].parse().map_err(|_| invalid())?,
+            url: record[3].to_string(),
+        })),
+        _ => Err(invalid()),
+    }
+}
+
+/// Counts for the lines imported from a download log.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ImportCounts {
+    /// Entries added to the index
+    pub added: usize,
+    /// Found entries for images whose types weren't known yet (see [`Importer::finish`])
+    pub deferred: usize,
+    /// Lines that don't correspond to new index entries
+    pub ignored: usize,
+}
+
+/// Adds the entries from download logs to an index.
+///
+/// Index records are identified by URL and timestamp, so importing the same log more than once has
+/// no further effect.
+pub struct Importer<'a> {
+    index: &'a Database,
+    image_types: HashMap<Digest, ImageType>,
+    deferred: Vec<DownloadLogEntry>,
+}
+
+impl<'a> Importer<'a> {
+    pub fn new(index: &'a Database) -> Self {
+        Self {
+            index,
+            image_types: HashMap::new(),
+            deferred: vec![],
+        }
+    }
+
+    /// Import a log, calling the given function with the counts so far after each batch.
+    pub fn import<F: FnMut(&ImportCounts)>(
+        &mut self,
+        batches: Batches,
+        mut on_batch: F,
+    ) -> Result<ImportCounts, Error> {
+        let mut counts = ImportCounts::default();
+
+        for batch in batches {
+            let batch = batch?;
+            let mut records = Vec::with_capacity(batch.entries.len());
+
+            counts.ignored += batch.ignored;
+
+            for log_entry in batch.entries {
+                match log_entry.status {
+                    DownloadStatus::Added => {
+                        if let Some(image_type) = log_entry.image_type.value() {
+                            self.image_types
+                                .insert(log_entry.digest, log_entry.image_type);
+                            let entry = to_entry(&log_entry, image_type);
+                            records.push((log_entry.url, entry));
+                        } else {
+                            counts.ignored += 1;
+                        }
+                    }
+                    DownloadStatus::Found => {
+                        // Found lines may not include the image type.
+                        match log_entry.image_type.value().or_else(|| {
+                            self.image_types
+                                .get(&log_entry.digest)
+                                .and_then(|image_type| image_type.value())
+                        }) {
+                            Some(image_type) => {
+                                let entry = to_entry(&log_entry, image_type);
+                                records.push((log_entry.url, entry));
+                            }
+                            None => {
+                                self.deferred.push(log_entry);
+                                counts.deferred += 1;
+                            }
+                        }
+                    }
+                    // These URLs already have index entries.
+                    DownloadStatus::Skipped => {
+                        counts.ignored += 1;
+                    }
+                }
+            }
+
+            counts.added += self
+                .index
+                .add_all(records.iter().map(|(url, entry)| (url.as_str(), *entry)))?;
+
+            on_batch(&counts);
+        }
+
+        Ok(counts)
+    }
+
+    /// Add the deferred found entries whose image types are now known.
+    ///
+    /// Returns the number of entries added and the number whose image types are still unknown.
+    pub fn finish(self) -> Result<(usize, usize), Error> {
+        let mut records = vec![];
+        let mut leftovers = 0;
+
+        for log_entry in &self.deferred {
+            match self
+                .image_types
+                .get(&log_entry.digest)
+                .and_then(|image_type| image_type.value())
+            {
+                Some(image_type) => {
+                    records.push((log_entry.url.as_str(), to_entry(log_entry, image_type)));
+                }
+                None => {
+                    leftovers += 1;
+                }
+            }
+        }
+
+        let mut added = 0;
+
+        for batch in records.chunks(BATCH_SIZE) {
+            added += self.index.add_all(batch.iter().copied())?;
+        }
+
+        Ok((added, leftovers))
+    }
+}
+
+fn to_entry(log_entry: &DownloadLogEntry, image_type: imghdr::Type) -> Entry {
+    Entry {
+        timestamp: log_entry.timestamp,
+        digest: log_entry.digest,
+        image_type,
+    }
 }
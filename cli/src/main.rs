@@ -1,13 +1,38 @@
 use cli_helpers::prelude::*;
 use image_scraper::{
-    client::Client,
-    store::{PrefixPartLengths, Store},
+    archive::Format as ArchiveFormat,
+    client::{ChunkedDownloads, Client, ConnectionOptions, HttpVersion, IpVersion, RemoteHead},
+    digest::{Digest, DigestKind},
+    header_template::{HeaderTemplate, HeaderTemplates},
+    history::FailureKind,
+    image_type::ImageType,
+    quarantine::Quarantine,
+    s3::{Credentials, S3Store},
+    store::{
+        Cursor, MergeMode, NonImagePolicy, PrefixPartLengths, PrefixRange, RepairMode, Store,
+        ValidationReport, ValidationResult,
+    },
+    url_norm::Normalizer,
+    url_policy::{UrlPattern, UrlPolicy},
+};
+use image_scraper_index::{
+    Entry, Missing,
+    db::{Database, KeyScheme},
 };
-use image_scraper_index::{Entry, db::Database};
+use rayon::iter::ParallelIterator;
 use std::collections::{BTreeMap, BTreeSet};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+mod input;
 mod logs;
+mod report;
+mod validation;
+
+/// Index records for every URL or for a single domain.
+type IndexRecords<'a> = Box<
+    dyn Iterator<Item = Result<(String, Result<Entry, Missing>), image_scraper_index::db::Error>>
+        + 'a,
+>;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -15,180 +40,1259 @@ async fn main() -> Result<(), Error> {
     opts.verbose.init_logging()?;
 
     match opts.command {
-        Command::DownloadAll {
+        Command::DownloadAll(opts) => {
+            let DownloadAllOpts {
+                store,
+                prefix,
+                delay_ms,
+                strip_params,
+                non_images,
+                metadata,
+                header_templates,
+                allowed_url_patterns,
+                denied_url_patterns,
+                pool_max_idle_per_host,
+                pool_idle_timeout,
+                http_version,
+                ip_version,
+                connect_timeout,
+                read_timeout,
+                user_agent,
+                proxy,
+                max_redirects,
+                chunk_size,
+                chunk_concurrency,
+                index,
+                failure_backoff,
+                log_format,
+                watch,
+                checkpoint,
+                resume_from,
+            } = *opts;
+
+            let store = load_store(&store, prefix)?.with_metadata(metadata);
+            store.record_layout()?;
+
+            let url_policy = (!allowed_url_patterns.is_empty() || !denied_url_patterns.is_empty())
+                .then(|| {
+                    UrlPolicy::new()
+                        .with_allowed_patterns(allowed_url_patterns)
+                        .with_denied_patterns(denied_url_patterns)
+                });
+
+            let client = Client::builder(store)
+                .with_connection_options(ConnectionOptions {
+                    pool_max_idle_per_host,
+                    pool_idle_timeout: pool_idle_timeout.map(std::time::Duration::from_secs),
+                    http_version,
+                    ip_version,
+                    connect_timeout: connect_timeout.map(std::time::Duration::from_secs),
+                    read_timeout: read_timeout.map(std::time::Duration::from_secs),
+                    user_agent,
+                    proxy,
+                    max_redirects,
+                    url_policy: url_policy.map(std::sync::Arc::new),
+                })
+                .build()?
+                .with_normalizer(Normalizer::new().with_stripped_params(strip_params))
+                .with_non_image_policy(non_images)
+                .with_header_templates(HeaderTemplates::new(header_templates));
+
+            let client = match chunk_size {
+                Some(chunk_size) => client.with_chunked_downloads(ChunkedDownloads {
+                    chunk_size,
+                    concurrency: chunk_concurrency,
+                }),
+                None => client,
+            };
+
+            let client = match index {
+                Some(index) => {
+                    let index = Database::open(index)?.with_normalizer(client.normalizer().clone());
+
+                    client.with_history(
+                        std::sync::Arc::new(index),
+                        std::time::Duration::from_secs(failure_backoff),
+                    )
+                }
+                None => client,
+            };
+
+            let mut writer = logs::Writer::new(log_format);
+
+            let resume_from = resume_from.map(input::Checkpoint::read).transpose()?;
+
+            if let Some(checkpoint) = &resume_from
+                && checkpoint.input != watch
+            {
+                log::warn!("Checkpoint was written for a different input");
+            }
+
+            let mut lines = match &watch {
+                Some(path) => input::follow(path)?,
+                None => input::stdin(),
+            };
+
+            let mut line_number = 0;
+            let mut last_line = None;
+            let mut interrupted = false;
+
+            // The signal is only checked between downloads, so the current download is finished.
+            let interrupt = tokio::signal::ctrl_c();
+            tokio::pin!(interrupt);
+
+            loop {
+                let line = tokio::select! {
+                    biased;
+                    result = &mut interrupt => {
+                        result?;
+                        interrupted = true;
+                        break;
+                    }
+                    line = lines.recv() => match line {
+                        Some(line) => line?,
+                        None => break,
+                    },
+                };
+
+                line_number += 1;
+
+                let skip = resume_from
+                    .as_ref()
+                    .map(|checkpoint| checkpoint.skips(line_number, &line))
+                    .transpose()?
+                    .unwrap_or(false);
+
+                last_line = Some(line.clone());
+
+                if skip {
+                    continue;
+                }
+
+                let url = client.normalizer().normalize_or_keep(&line).into_owned();
+                let excluded = client
+                    .url_policy()
+                    .and_then(|url_policy| url_policy.check(&url).err());
+
+                let event = if let Some(error) = excluded {
+                    logs::DownloadEvent::Excluded {
+                        timestamp: chrono::Utc::now(),
+                        url,
+                        reason: error.to_string(),
+                    }
+                } else {
+                    let result = client.download(&url).await;
+                    let timestamp = chrono::Utc::now();
+
+                    match result {
+                        Ok(Ok((_, action))) => {
+                            let digest = action.entry.digest;
+                            let image_type = action.image_type;
+
+                            if action.skipped {
+                                logs::DownloadEvent::Skipped {
+                                    timestamp,
+                                    url,
+                                    digest,
+                                    image_type,
+                                }
+                            } else if action.quarantined {
+                                logs::DownloadEvent::Quarantined {
+                                    timestamp,
+                                    url,
+                                    digest,
+                                }
+                            } else if action.added {
+                                logs::DownloadEvent::Added {
+                                    timestamp,
+                                    url,
+                                    digest,
+                                    image_type,
+                                }
+                            } else {
+                                logs::DownloadEvent::Found {
+                                    timestamp,
+                                    url,
+                                    digest,
+                                    image_type,
+                                }
+                            }
+                        }
+                        Ok(Err(status_code)) => logs::DownloadEvent::Failed {
+                            timestamp,
+                            url,
+                            status: Some(status_code.as_u16()),
+                            kind: Some(FailureKind::Status),
+                            error: None,
+                        },
+                        Err(image_scraper::client::Error::RecentlyFailed { status }) => {
+                            logs::DownloadEvent::Failed {
+                                timestamp,
+                                url,
+                                status,
+                                kind: None,
+                                error: None,
+                            }
+                        }
+                        // Request failures are logged, so that one unreachable host doesn't stop the run.
+                        Err(image_scraper::client::Error::Http(error)) => {
+                            let description = error.to_string();
+                            let kind = image_scraper::client::Error::Http(error).failure_kind();
+
+                            log::warn!("Request failed ({url}): {description}");
+
+                            logs::DownloadEvent::Failed {
+                                timestamp,
+                                url,
+                                status: None,
+                                kind: Some(kind),
+                                error: Some(description),
+                            }
+                        }
+                        Err(image_scraper::client::Error::Store(
+                            image_scraper::store::Error::NotAnImage(digest),
+                        )) => logs::DownloadEvent::Rejected {
+                            timestamp,
+                            url,
+                            digest,
+                        },
+                        Err(error) => {
+                            writer.flush()?;
+                            return Err(Error::from(error));
+                        }
+                    }
+                };
+
+                writer.write(&event)?;
+
+                // Events are written as they happen when following a file, since there is no end.
+                if watch.is_some() {
+                    writer.flush()?;
+                }
+
+                if let Some(delay_ms) = delay_ms {
+                    tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+                }
+            }
+
+            writer.flush()?;
+
+            if let Some(checkpoint) = &resume_from
+                && !interrupted
+                && line_number < checkpoint.line
+            {
+                return Err(Error::from(input::Error::CheckpointNotReached(
+                    checkpoint.line,
+                )));
+            }
+
+            if interrupted {
+                log::warn!("Interrupted after line {line_number}");
+
+                if let Some(path) = checkpoint {
+                    input::Checkpoint {
+                        input: watch,
+                        line: line_number,
+                        last_line,
+                    }
+                    .write(path)?;
+                }
+            }
+        }
+        Command::List {
             store,
             prefix,
-            delay_ms,
+            validate,
+            report_json,
+            range,
+            checkpoint,
         } => {
-            let inferred_prefix_part_length = Store::infer_prefix_part_lengths(&store)?;
+            let store = load_store(&store, prefix)?;
+
+            if report_json {
+                let log_progress = |report: &ValidationReport| {
+                    if report.files().is_multiple_of(validation::LOG_INTERVAL) {
+                        log::info!(
+                            "Validated {} files ({} bytes) in {:.1}s",
+                            report.files(),
+                            report.bytes_read,
+                            report.elapsed.as_secs_f64()
+                        );
+                    }
+                };
+
+                let report = match range {
+                    Some(range) => ValidationReport::from_par_entries(
+                        store.par_entries_in_range(range.start(), range.end())?,
+                        log_progress,
+                    ),
+                    None => ValidationReport::from_par_entries(store.par_entries(), log_progress),
+                };
+
+                serde_json::to_writer_pretty(std::io::stdout().lock(), &report)?;
+                println!();
+            } else if validate {
+                let print_valid = |entry: Result<_, image_scraper::store::IterationError>| {
+                    let entry = ValidationResult::for_entry(entry?)?.result()?;
+
+                    println!("{}", entry.path.as_os_str().to_string_lossy());
+
+                    Ok::<_, Error>(())
+                };
+
+                // Files are read and checked in parallel, so they aren't listed in order.
+                match range {
+                    Some(range) => store
+                        .par_entries_in_range(range.start(), range.end())?
+                        .try_for_each(print_valid)?,
+                    None => store.par_entries().try_for_each(print_valid)?,
+                }
+            } else {
+                let mut entries = entries_in_range(&store, range);
+
+                if let Some(cursor) = checkpoint
+                    .as_deref()
+                    .map(read_cursor)
+                    .transpose()?
+                    .flatten()
+                {
+                    entries = entries.resume(cursor);
+                }
+
+                let mut count = 0;
+
+                while let Some(entry) = entries.next() {
+                    let entry = match entry {
+                        Ok(entry) => entry,
+                        Err(error) => {
+                            // The position is saved so that the next run continues from here.
+                            if let Some(checkpoint) = &checkpoint
+                                && let Some(cursor) = entries.cursor()
+                            {
+                                write_cursor(checkpoint, cursor)?;
+                            }
+
+                            return Err(error.into());
+                        }
+                    };
+
+                    println!("{}", entry.path.as_os_str().to_string_lossy());
+                    count += 1;
 
-            let prefix_part_lengths = check_prefix_part_lengths(
-                inferred_prefix_part_length,
-                prefix.map(|prefix_part_lengths| prefix_part_lengths.0),
+                    if let Some(checkpoint) = &checkpoint
+                        && count % validation::WRITE_INTERVAL == 0
+                        && let Some(cursor) = entries.cursor()
+                    {
+                        write_cursor(checkpoint, cursor)?;
+                    }
+                }
+
+                // The next run starts from the beginning.
+                if let Some(checkpoint) = &checkpoint
+                    && checkpoint.exists()
+                {
+                    std::fs::remove_file(checkpoint)?;
+                }
+            }
+        }
+        Command::Validate {
+            store,
+            prefix,
+            checkpoint,
+            limit,
+            max_duration,
+            range,
+        } => {
+            let store = load_store(&store, prefix)?;
+
+            let mut progress = checkpoint
+                .as_ref()
+                .map(validation::Checkpoint::read_or_new)
+                .transpose()?
+                .unwrap_or_default();
+
+            let deadline = max_duration.map(|max_duration| {
+                std::time::Instant::now() + std::time::Duration::from_secs(max_duration)
+            });
+            let mut count = 0;
+            let mut finished = true;
+
+            let entries = entries_in_range(&store, range);
+            let entries = match progress.last_digest {
+                Some(last_digest) => entries.resume(Cursor::after(last_digest)),
+                None => entries,
+            };
+
+            for entry in entries {
+                let entry = entry?;
+
+                if limit.is_some_and(|limit| count >= limit)
+                    || deadline.is_some_and(|deadline| std::time::Instant::now() >= deadline)
+                {
+                    finished = false;
+                    break;
+                }
+
+                match entry.validate()? {
+                    Ok(()) => {
+                        progress.valid += 1;
+                    }
+                    Err(actual) => {
+                        progress.invalid += 1;
+
+                        println!(
+                            "{},{},{}",
+                            entry.digest,
+                            actual,
+                            entry.path.as_os_str().to_string_lossy()
+                        );
+                    }
+                }
+
+                progress.last_digest = Some(entry.digest);
+                count += 1;
+
+                // Progress is saved regularly, so that little is lost if the run is killed.
+                if let Some(checkpoint) = &checkpoint
+                    && count % validation::WRITE_INTERVAL == 0
+                {
+                    progress.write(checkpoint)?;
+                }
+            }
+
+            if finished {
+                log::info!(
+                    "Validation pass started at {} completed: {} valid, {} invalid",
+                    progress.started_at,
+                    progress.valid,
+                    progress.invalid
+                );
+
+                // The next run starts a new pass.
+                if let Some(checkpoint) = &checkpoint
+                    && checkpoint.exists()
+                {
+                    std::fs::remove_file(checkpoint)?;
+                }
+            } else {
+                log::info!(
+                    "Validated {count} files, stopping at {}",
+                    progress
+                        .last_digest
+                        .map(|digest| digest.to_string())
+                        .unwrap_or_default()
+                );
+
+                if let Some(checkpoint) = &checkpoint {
+                    progress.write(checkpoint)?;
+                }
+            }
+        }
+        Command::Export {
+            store,
+            prefix,
+            dest,
+            image_types,
+            index,
+            since,
+            until,
+            digests,
+        } => {
+            let store = load_store(&store, prefix)?;
+
+            // The date an image was first indexed is used for date filtering.
+            let first_seen = match index {
+                Some(index) => {
+                    let index = Database::open(&index)?;
+                    let mut first_seen = BTreeMap::new();
+
+                    for result in index.iter() {
+                        if let (_, Ok(entry)) = result? {
+                            let date = entry.timestamp.date_naive();
+
+                            first_seen
+                                .entry(entry.digest)
+                                .and_modify(|first: &mut chrono::NaiveDate| {
+                                    *first = (*first).min(date);
+                                })
+                                .or_insert(date);
+                        }
+                    }
+
+                    Some(first_seen)
+                }
+                None => None,
+            };
+
+            let digests = digests
+                .map(|digests| read_digests(&digests, store.digest_kind()))
+                .transpose()?;
+
+            let count = store.export_linked(&dest, |entry| {
+                digests
+                    .as_ref()
+                    .is_none_or(|digests| digests.contains(&entry.digest))
+                    && first_seen.as_ref().is_none_or(|first_seen| {
+                        first_seen.get(&entry.digest).is_some_and(|date| {
+                            since.is_none_or(|since| *date >= since)
+                                && until.is_none_or(|until| *date <= until)
+                        })
+                    })
+                    && (image_types.is_empty()
+                        || match store.image_type(entry) {
+                            Ok(image_type) => image_types.contains(&image_type),
+                            Err(error) => {
+                                log::warn!(
+                                    "Unable to read {}: {error}",
+                                    entry.path.as_os_str().to_string_lossy()
+                                );
+
+                                false
+                            }
+                        })
+            })?;
+
+            log::info!("Exported {count} files");
+        }
+        Command::MergeStores {
+            into,
+            into_prefix,
+            from,
+            from_prefix,
+            move_files,
+            index,
+            from_index,
+        } => {
+            let from = load_store(&from, from_prefix)?;
+
+            // An empty destination uses the same layout as the source by default.
+            let into_prefix_part_lengths = match check_prefix_part_lengths(
+                Store::infer_prefix_part_lengths(&into)?,
+                into_prefix.map(|prefix_part_lengths| prefix_part_lengths.0),
+            ) {
+                Err(Error::MissingPrefixPartLengths) => from.prefix_part_lengths.clone(),
+                result => result?,
+            };
+
+            let into = Store::load(&into)?
+                .with_prefix_part_lengths(into_prefix_part_lengths)?
+                .with_digest_kind(from.digest_kind())?;
+            into.record_layout()?;
+
+            let counts = into.merge_from(
+                &from,
+                if move_files {
+                    MergeMode::Move
+                } else {
+                    MergeMode::Link
+                },
             )?;
 
-            let store = Store::new(&store).with_prefix_part_lengths(prefix_part_lengths)?;
-            let client = Client::new(store);
+            for entry in &counts.invalid {
+                log::warn!(
+                    "Contents don't match digest: {}",
+                    entry.path.as_os_str().to_string_lossy()
+                );
+            }
+
+            log::info!(
+                "Added {} files ({} already present, {} invalid)",
+                counts.added,
+                counts.existing,
+                counts.invalid.len()
+            );
+
+            if let Some((index, from_index)) = index.zip(from_index) {
+                let index = Database::open(index)?;
+                let from_index = Database::open(from_index)?;
+                let mut records = Vec::with_capacity(logs::BATCH_SIZE);
+                let mut count = 0;
+
+                // Only successful downloads are added, since failures and deletions in the source
+                // index could hide images that are available in the destination.
+                for result in from_index.iter() {
+                    if let (url, Ok(entry)) = result? {
+                        records.push((url, entry));
+
+                        if records.len() == logs::BATCH_SIZE {
+                            count += index.add_all(
+                                records.iter().map(|(url, entry)| (url.as_str(), *entry)),
+                            )?;
+                            records.clear();
+                        }
+                    }
+                }
+
+                count +=
+                    index.add_all(records.iter().map(|(url, entry)| (url.as_str(), *entry)))?;
+
+                log::info!("Added {count} index entries");
+            }
+        }
+        Command::StoreSync {
+            from,
+            from_prefix,
+            to,
+            to_prefix,
+            to_bucket,
+            s3_endpoint,
+            s3_region,
+            dry_run,
+        } => {
+            let from = load_store(&from, from_prefix)?;
+
+            let counts = if let Some((bucket, endpoint)) = to_bucket.zip(s3_endpoint) {
+                // The bucket's layout can't be inferred, so it's the source's by default.
+                let to = s3_store(&endpoint, &bucket, s3_region.as_deref())?
+                    .with_prefix_part_lengths(to_prefix.map_or_else(
+                        || from.prefix_part_lengths.clone(),
+                        |prefix_part_lengths| prefix_part_lengths.0,
+                    ))?;
+
+                image_scraper::sync::sync(&from, &to, dry_run)?
+            } else {
+                let to = to.ok_or(Error::MissingDestination)?;
+
+                // An empty destination uses the same layout as the source by default.
+                let to_prefix_part_lengths = match check_prefix_part_lengths(
+                    Store::infer_prefix_part_lengths(&to)?,
+                    to_prefix.map(|prefix_part_lengths| prefix_part_lengths.0),
+                ) {
+                    Err(Error::MissingPrefixPartLengths) => from.prefix_part_lengths.clone(),
+                    result => result?,
+                };
+
+                let to = Store::load(&to)?
+                    .with_prefix_part_lengths(to_prefix_part_lengths)?
+                    .with_digest_kind(from.digest_kind())?;
+
+                if !dry_run {
+                    to.record_layout()?;
+                }
+
+                image_scraper::sync::sync(&from, &to, dry_run)?
+            };
+
+            for digest in &counts.invalid {
+                log::warn!("Contents don't match digest in source: {digest:x}");
+            }
+
+            for digest in &counts.failed {
+                log::error!("Copy doesn't match digest: {digest:x}");
+            }
+
+            log::info!(
+                "{} {} files ({} already present, {} invalid, {} failed)",
+                if dry_run { "Would copy" } else { "Copied" },
+                counts.copied,
+                counts.existing,
+                counts.invalid.len(),
+                counts.failed.len()
+            );
+        }
+        Command::RebuildManifest { store, prefix } => {
+            let store = load_store(&store, prefix)?;
+            let count = store.rebuild_manifest()?;
+
+            log::info!("Wrote {count} entries to the manifest");
+        }
+        Command::StoreDelete {
+            store,
+            prefix,
+            index,
+            digests,
+        } => {
+            let store = load_store(&store, prefix)?;
+            let index = index.map(|index| Database::open(&index)).transpose()?;
+            let timestamp = chrono::Utc::now();
+
+            for digest in digests {
+                let digest = Digest::from_hex(store.digest_kind(), &digest)?;
+                let removed = store.delete(digest)?;
+
+                let tombstoned = match &index {
+                    Some(index) => index.tombstone(digest, timestamp)?.len(),
+                    None => 0,
+                };
+
+                println!("{digest:x},{removed},{tombstoned}");
+            }
+        }
+        Command::StoreRepair {
+            store,
+            prefix,
+            quarantine,
+        } => {
+            let store = load_store(&store, prefix)?;
+            let mode = quarantine.map_or(RepairMode::Rename, RepairMode::Quarantine);
+            let report = store.repair(&mode)?;
+            let empty_digest = store.digest_kind().compute([]);
+
+            for file in &report.mismatched {
+                println!(
+                    "{},{:x},{}",
+                    file.path.as_os_str().to_string_lossy(),
+                    file.actual,
+                    file.destination
+                        .as_ref()
+                        .map(|destination| destination.as_os_str().to_string_lossy())
+                        .unwrap_or_default()
+                );
+            }
+
+            for path in &report.removed_empty {
+                println!("{},{empty_digest:x},", path.as_os_str().to_string_lossy());
+            }
+
+            log::info!(
+                "{} valid files, {} mismatched, {} empty removed",
+                report.valid,
+                report.mismatched.len(),
+                report.removed_empty.len()
+            );
+        }
+        Command::StoreMigrate { store, to, dry_run } => {
+            let store = Store::load(&store)?;
+            let counts = store.migrate_to(to.0, dry_run)?;
+
+            log::info!(
+                "{} {} files ({} unchanged)",
+                if dry_run { "Would move" } else { "Moved" },
+                counts.moved,
+                counts.unchanged
+            );
+        }
+        Command::StoreMetadata {
+            store,
+            prefix,
+            digests,
+        } => {
+            let store = load_store(&store, prefix)?;
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(std::io::stdout());
+
+            for digest in digests {
+                let digest = Digest::from_hex(store.digest_kind(), &digest)?;
+
+                if let Some(metadata) = store.metadata(digest)? {
+                    writer.write_record([
+                        format!("{digest:x}").as_str(),
+                        &metadata.url,
+                        &chrono::DateTime::<chrono::Utc>::from(metadata.fetched_at).to_rfc3339(),
+                        metadata.content_type.as_deref().unwrap_or_default(),
+                        &metadata
+                            .content_length
+                            .map(|content_length| content_length.to_string())
+                            .unwrap_or_default(),
+                    ])?;
+                }
+            }
+
+            writer.flush()?;
+        }
+        Command::StoreGc { store, prefix } => {
+            let store = load_store(&store, prefix)?;
+            let removed = store.gc_empty_dirs()?;
+
+            log::info!("Removed {removed} empty directories");
+        }
+        Command::StoreExport {
+            store,
+            prefix,
+            output,
+            format,
+            digests,
+        } => {
+            let store = load_store(&store, prefix)?;
+            let format = archive_format(&output, format)?;
+            let digests = digests
+                .map(|digests| read_digests(&digests, store.digest_kind()))
+                .transpose()?;
+
+            let writer = std::io::BufWriter::new(std::fs::File::create(&output)?);
+            let count = store.export_archive(writer, format, |entry| {
+                digests
+                    .as_ref()
+                    .is_none_or(|digests| digests.contains(&entry.digest))
+            })?;
+
+            log::info!("Exported {count} files");
+        }
+        Command::StoreImport {
+            store,
+            prefix,
+            input,
+            format,
+            digest_kind,
+        } => {
+            let store = load_store(&store, prefix)?;
+            let store = match digest_kind {
+                Some(digest_kind) => store.with_digest_kind(digest_kind)?,
+                None => store,
+            };
+            store.record_layout()?;
+
+            let format = archive_format(&input, format)?;
+            let reader = std::io::BufReader::new(std::fs::File::open(&input)?);
+            let counts = store.import_archive(reader, format)?;
+
+            for name in &counts.invalid {
+                log::warn!("Contents don't match digest: {name}");
+            }
 
+            log::info!(
+                "Added {} files ({} already present, {} invalid)",
+                counts.added,
+                counts.existing,
+                counts.invalid.len()
+            );
+        }
+        Command::StoreImportDir { store, prefix, dir } => {
+            let store = load_store(&store, prefix)?;
+            store.record_layout()?;
+
+            let actions = store.import_dir(&dir)?;
             let mut writer = csv::WriterBuilder::new()
                 .has_headers(false)
                 .from_writer(std::io::stdout());
 
-            for line in std::io::stdin().lines() {
-                let line = line?;
+            for (path, action) in &actions {
+                writer.write_record([
+                    path.as_os_str().to_string_lossy().as_ref(),
+                    &format!("{:x}", action.entry.digest),
+                    if action.added { "added" } else { "found" },
+                ])?;
+            }
+
+            writer.flush()?;
+
+            log::info!(
+                "Added {} files ({} already present)",
+                actions.iter().filter(|(_, action)| action.added).count(),
+                actions.iter().filter(|(_, action)| !action.added).count()
+            );
+        }
+        Command::Stats {
+            store,
+            prefix,
+            count_only,
+        } => {
+            let store = load_store(&store, prefix)?;
+
+            if count_only {
+                let counts = store.count_entries()?;
+
+                println!("files,{}", counts.total);
+
+                for (prefix, count) in counts.prefixes {
+                    println!("prefix:{prefix},{count}");
+                }
+
+                return Ok(());
+            }
+
+            let stats = store.stats()?;
+
+            println!("files,{}", stats.files);
+            println!("bytes,{}", stats.bytes);
+
+            for (image_type, count) in stats.image_types {
+                println!("type:{image_type},{count}");
+            }
+        }
+        Command::IndexImport {
+            index,
+            strip_params,
+            log_format,
+            files,
+        } => {
+            let index = Database::open(&index)?
+                .with_normalizer(Normalizer::new().with_stripped_params(strip_params));
+
+            let paths = if files.is_empty() {
+                vec![None]
+            } else {
+                files.iter().map(|path| Some(path.as_path())).collect()
+            };
+
+            let mut importer = logs::Importer::new(&index);
+            let mut count = 0;
+
+            for path in paths {
+                if let Some(path) = path {
+                    log::info!("Importing {}", path.display());
+                }
+
+                let counts = importer.import(logs::open(path, log_format)?, |counts| {
+                    log::info!("Added {} entries", count + counts.added);
+                })?;
+
+                count += counts.added;
+            }
+
+            let (added, leftovers) = importer.finish()?;
+
+            log::info!("Added {} entries", count + added);
+            log::warn!("{leftovers} leftover found entries");
+        }
+        Command::LogsReplay {
+            index,
+            strip_params,
+            files,
+        } => {
+            let index = Database::open(&index)?
+                .with_normalizer(Normalizer::new().with_stripped_params(strip_params));
+
+            let mut importer = logs::Importer::new(&index);
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(std::io::stdout());
+
+            for path in &files {
+                let log_format = logs::LogFormat::detect(path)?;
+
+                log::info!("Replaying {} ({})", path.display(), log_format.as_str());
+
+                let counts = importer.import(logs::open(Some(path), log_format)?, |_| {})?;
+
+                writer.write_record([
+                    path.as_os_str().to_string_lossy().as_ref(),
+                    log_format.as_str(),
+                    &counts.added.to_string(),
+                    &counts.deferred.to_string(),
+                    &counts.ignored.to_string(),
+                ])?;
+                writer.flush()?;
+            }
+
+            let (added, leftovers) = importer.finish()?;
+
+            log::info!("Added {added} deferred entries ({leftovers} with unknown image types)");
+        }
+        Command::IndexDump { index, domain } => {
+            let index = Database::open(&index)?;
+
+            let records: IndexRecords<'_> = match &domain {
+                Some(domain) => Box::new(index.iter_domain(domain)),
+                None => Box::new(index.iter()),
+            };
+
+            for result in records {
+                let (url, result) = result?;
+
+                match result {
+                    Ok(entry) => {
+                        println!(
+                            "S,{},{},{},{:x}",
+                            url,
+                            entry.timestamp.timestamp(),
+                            image_scraper::image_type::ImageType::from(entry.image_type),
+                            entry.digest
+                        );
+                    }
+                    Err(Missing::Failed {
+                        timestamp,
+                        status,
+                        kind,
+                    }) => {
+                        println!(
+                            "E,{},{},{},{}",
+                            url,
+                            timestamp.timestamp(),
+                            status.map(|status| status.to_string()).unwrap_or_default(),
+                            kind.map(|kind| kind.to_string()).unwrap_or_default()
+                        );
+                    }
+                    Err(Missing::Deleted { timestamp, digest }) => {
+                        println!("D,{},{},,{:x}", url, timestamp.timestamp(), digest);
+                    }
+                }
+            }
+        }
+        Command::IndexReportDomains {
+            index,
+            store,
+            prefix,
+            format,
+            limit,
+        } => {
+            let store = match store {
+                Some(store) => Some(load_store(&store, prefix)?),
+                None => None,
+            };
+
+            let index = Database::open(&index)?;
+            let mut domain_report = image_scraper_index::report::DomainReport::new();
+
+            for result in index.iter() {
+                let (url, record) = result?;
+
+                domain_report.add(&url, &record);
+            }
+
+            let mut rows = domain_report.rows(|digest| {
+                store
+                    .as_ref()
+                    .and_then(|store| store.lookup(digest))
+                    .and_then(|entry| entry.size().ok())
+            });
+
+            if let Some(limit) = limit {
+                rows.truncate(limit);
+            }
+
+            report::write_domains(std::io::stdout().lock(), &rows, format)?;
+        }
+        Command::IndexRemoveDomain { index, domain } => {
+            let index = Database::open(&index)?;
+            let removed = index.remove_domain(&domain)?;
+
+            log::info!("Removed {removed} records");
+        }
+        Command::IndexRekey {
+            index,
+            output,
+            key_scheme,
+        } => {
+            if output.exists() {
+                return Err(Error::ExistingIndex(output));
+            }
+
+            let index = Database::open(&index)?;
+            let output = Database::open_with_key_scheme(&output, key_scheme)?;
+            let count = index.copy_into(&output)?;
+
+            log::info!("Copied {count} records");
+        }
+        Command::IndexStats { index, exact } => {
+            let index = Database::open(&index)?;
+
+            if exact {
+                println!("records,{}", index.count(|_, _| true)?);
+                println!(
+                    "failed,{}",
+                    index.count(|_, record| matches!(record, Err(Missing::Failed { .. })))?
+                );
+                println!(
+                    "deleted,{}",
+                    index.count(|_, record| matches!(record, Err(Missing::Deleted { .. })))?
+                );
+            } else {
+                println!("estimated_records,{}", index.estimate_count()?);
+            }
+        }
+        Command::IndexPruneHistory {
+            index,
+            keep,
+            unchanged_only,
+            url_prefix,
+        } => {
+            let index = Database::open(&index)?;
+
+            let removed = index.prune_history(
+                |url| {
+                    url_prefix
+                        .as_ref()
+                        .is_none_or(|url_prefix| url.starts_with(url_prefix))
+                },
+                keep,
+                unchanged_only,
+            )?;
+
+            log::info!("Removed {removed} records");
+        }
+        Command::IndexPrune {
+            index,
+            before,
+            failed_only,
+        } => {
+            let index = Database::open(&index)?;
 
-                match client.download(&line).await {
-                    Ok(Ok((_, action))) => {
-                        writer.write_record([
-                            if action.added { "A" } else { "F" },
-                            &format!("{:x?}", action.entry.digest),
-                            &action.image_type.to_string(),
-                            &line,
-                        ])?;
+            let removed = index.prune_before(before, failed_only, |removed| {
+                log::info!("Removed {removed} records");
+            })?;
 
-                        Ok(())
-                    }
-                    Ok(Err(status_code)) => {
-                        writer.write_record(["E", &status_code.as_u16().to_string(), "", ""])?;
+            log::info!("Finished: removed {removed} records");
+        }
+        Command::IndexFailed {
+            index,
+            since,
+            details,
+        } => {
+            let index = Database::open(&index)?;
+            let mut latest: Option<(String, Result<Entry, Missing>)> = None;
 
-                        Ok(())
-                    }
-                    Err(error) => {
-                        writer.flush()?;
-                        Err(error)
-                    }
-                }?;
+            // Index records for a URL are contiguous and sorted by ascending timestamp, so the last
+            // record for a URL is its latest.
+            for result in index.iter() {
+                let (url, record) = result?;
 
-                if let Some(delay_ms) = delay_ms {
-                    tokio::time::sleep(std::time::Duration::from_millis(delay_ms)).await;
+                if let Some((previous_url, previous_record)) =
+                    latest.take_if(|(previous_url, _)| *previous_url != url)
+                {
+                    print_failure(&previous_url, &previous_record, since, details);
                 }
+
+                latest = Some((url, record));
+            }
+
+            if let Some((url, record)) = latest {
+                print_failure(&url, &record, since, details);
             }
         }
-        Command::List {
+        Command::VerifyRemote {
+            index,
             store,
-            prefix,
-            validate,
+            host,
+            since,
+            before,
+            delay_ms,
+            header_templates,
+            mark_dead,
         } => {
-            let inferred_prefix_part_length = Store::infer_prefix_part_lengths(&store)?;
+            let index = Database::open(&index)?;
+            let store = store.map(|store| load_store(&store, None)).transpose()?;
 
-            let prefix_part_lengths = check_prefix_part_lengths(
-                inferred_prefix_part_length,
-                prefix.map(|prefix_part_lengths| prefix_part_lengths.0),
-            )?;
+            let is_selected = |url: &str, record: &Result<Entry, Missing>| match record {
+                Ok(entry) => {
+                    since.is_none_or(|since| entry.timestamp >= since)
+                        && before.is_none_or(|before| entry.timestamp < before)
+                        && host.as_ref().is_none_or(|host| {
+                            reqwest::Url::parse(url).is_ok_and(|url| {
+                                url.host_str().is_some_and(|value| host.matches(value))
+                            })
+                        })
+                }
+                Err(_) => false,
+            };
 
-            let store = Store::new(&store).with_prefix_part_lengths(prefix_part_lengths)?;
+            // Only URLs whose latest record is a successful download are checked.
+            let mut selected = vec![];
+            let mut latest: Option<(String, Result<Entry, Missing>)> = None;
 
-            if validate {
-                for entry in store.entries() {
-                    let entry = entry?;
+            for result in index.iter() {
+                let (url, record) = result?;
 
-                    println!("{}", entry.path.as_os_str().to_string_lossy());
+                if let Some((previous_url, Ok(previous_entry))) = latest
+                    .take_if(|(previous_url, _)| *previous_url != url)
+                    .filter(|(previous_url, previous_record)| {
+                        is_selected(previous_url.as_str(), previous_record)
+                    })
+                {
+                    selected.push((previous_url, previous_entry));
                 }
-            } else {
-                for entry in store.entries().validate_fail_fast() {
-                    let entry = entry?;
 
-                    println!("{}", entry.path.as_os_str().to_string_lossy());
-                }
+                latest = Some((url, record));
             }
-        }
-        Command::IndexImport { index } => {
-            let index = Database::open(&index)?;
 
-            let mut reader = csv::ReaderBuilder::new()
-                .has_headers(false)
-                .from_reader(std::io::stdin());
+            if let Some((url, Ok(entry))) =
+                latest.filter(|(url, record)| is_selected(url.as_str(), record))
+            {
+                selected.push((url, entry));
+            }
 
-            let mut count = 0;
-            let mut image_type_map = BTreeMap::new();
-            let mut found_leftovers = vec![];
-
-            for result in reader.deserialize::<logs::DownloadLogEntry>() {
-                let log_entry = result?;
-
-                match log_entry.status {
-                    logs::DownloadStatus::Added => {
-                        if let Some(image_type) = log_entry.image_type.value() {
-                            image_type_map.insert(log_entry.digest, image_type);
-
-                            index.add(
-                                &log_entry.url,
-                                Entry {
-                                    timestamp: log_entry.timestamp,
-                                    digest: md5::Digest(log_entry.digest),
-                                    image_type,
-                                },
-                            )?;
+            log::info!("Checking {} URLs", selected.len());
 
-                            count += 1;
-                        }
-                    }
-                    logs::DownloadStatus::Found => match image_type_map.get(&log_entry.digest) {
-                        Some(image_type) => {
-                            index.add(
-                                &log_entry.url,
-                                Entry {
-                                    timestamp: log_entry.timestamp,
-                                    digest: md5::Digest(log_entry.digest),
-                                    image_type: *image_type,
-                                },
-                            )?;
+            // Nothing is saved, so the client's store is never used.
+            let client = Client::new(store.clone().unwrap_or_else(|| Store::new(".")))
+                .with_header_templates(HeaderTemplates::new(header_templates));
+            let mut limiter =
+                image_scraper::client::HostLimiter::new(std::time::Duration::from_millis(delay_ms));
 
-                            count += 1;
-                        }
-                        None => {
-                            found_leftovers.push(log_entry);
-                        }
-                    },
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(std::io::stdout());
+
+            for (url, entry) in selected {
+                let wait_time = limiter.wait_time(&url, std::time::Instant::now());
+
+                if !wait_time.is_zero() {
+                    tokio::time::sleep(wait_time).await;
                 }
-            }
 
-            let mut final_leftovers = vec![];
+                limiter.record(&url, std::time::Instant::now());
 
-            for log_entry in found_leftovers {
-                match image_type_map.get(&log_entry.digest) {
-                    Some(image_type) => {
-                        index.add(
-                            &log_entry.url,
-                            Entry {
-                                timestamp: log_entry.timestamp,
-                                digest: md5::Digest(log_entry.digest),
-                                image_type: *image_type,
-                            },
-                        )?;
+                let expected_size = store
+                    .as_ref()
+                    .and_then(|store| store.lookup(entry.digest))
+                    .map(|stored| stored.size())
+                    .transpose()?;
 
-                        count += 1;
-                    }
-                    None => {
-                        final_leftovers.push(log_entry);
+                let (liveness, head) = match client.head(&url).await {
+                    Ok(head) => (Liveness::classify(&head, expected_size), Some(head)),
+                    Err(error) => {
+                        log::warn!("Request failed ({url}): {error}");
+
+                        (Liveness::Error, None)
                     }
+                };
+
+                if mark_dead
+                    && liveness == Liveness::Dead
+                    && let Some(head) = &head
+                {
+                    index.add_failed(
+                        &url,
+                        chrono::Utc::now(),
+                        Some(head.status.as_u16()),
+                        Some(FailureKind::Status),
+                    )?;
                 }
-            }
 
-            log::info!("Added {} entries", count);
-            log::warn!("{} leftover found entries", final_leftovers.len())
+                writer.write_record([
+                    url.as_str(),
+                    liveness.as_str(),
+                    &head
+                        .as_ref()
+                        .map(|head| head.status.as_u16().to_string())
+                        .unwrap_or_default(),
+                    &entry.digest.to_string(),
+                    &expected_size
+                        .map(|size| size.to_string())
+                        .unwrap_or_default(),
+                    &head
+                        .as_ref()
+                        .and_then(|head| head.content_length)
+                        .map(|content_length| content_length.to_string())
+                        .unwrap_or_default(),
+                    head.as_ref()
+                        .and_then(|head| head.etag.as_deref())
+                        .unwrap_or_default(),
+                ])?;
+                writer.flush()?;
+            }
         }
-        Command::IndexDump { index } => {
-            let index = Database::open(&index)?;
+        Command::Quarantine {
+            directory,
+            purge,
+            older_than,
+            digest_kind,
+        } => {
+            let quarantine = Quarantine::new(directory).with_digest_kind(digest_kind);
 
-            for result in index.iter() {
-                let (url, result) = result?;
+            let files = if purge {
+                quarantine.purge(older_than.map(std::time::Duration::from_secs))?
+            } else {
+                quarantine.files()?
+            };
 
-                match result {
-                    Ok(entry) => {
-                        println!(
-                            "S,{},{},{},{:x}",
-                            url,
-                            entry.timestamp.timestamp(),
-                            image_scraper::image_type::ImageType::from(entry.image_type),
-                            entry.digest
-                        );
-                    }
-                    Err(timestamp) => {
-                        println!("E,{},{},,", url, timestamp.timestamp());
-                    }
-                }
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(std::io::stdout());
+
+            for file in files {
+                writer.write_record([
+                    file.digest.to_string(),
+                    file.size.to_string(),
+                    file.kind.to_string(),
+                    chrono::DateTime::<chrono::Utc>::from(file.modified)
+                        .timestamp()
+                        .to_string(),
+                ])?;
             }
+
+            writer.flush()?;
         }
         Command::ListUnindexed {
             index,
@@ -200,24 +1304,17 @@ async fn main() -> Result<(), Error> {
                 .iter()
                 .filter_map(|result| {
                     result
-                        .map(|(_, entry)| entry.ok().map(|entry| entry.digest.0))
+                        .map(|(_, entry)| entry.ok().map(|entry| entry.digest))
                         .map_or_else(|error| Some(Err(Error::from(error))), |value| value.map(Ok))
                 })
                 .collect::<Result<BTreeSet<_>, Error>>()?;
 
-            let inferred_prefix_part_length = Store::infer_prefix_part_lengths(&store)?;
-
-            let prefix_part_lengths = check_prefix_part_lengths(
-                inferred_prefix_part_length,
-                prefix.map(|prefix_part_lengths| prefix_part_lengths.0),
-            )?;
-
-            let store = Store::new(&store).with_prefix_part_lengths(prefix_part_lengths)?;
+            let store = load_store(&store, prefix)?;
 
             for entry in store.entries() {
                 let entry = entry?;
 
-                if !digests.contains(&entry.digest.0) {
+                if !digests.contains(&entry.digest) {
                     println!("{}", entry.path.as_os_str().to_string_lossy());
                 }
             }
@@ -235,6 +1332,8 @@ pub enum Error {
     Args(#[from] cli_helpers::Error),
     #[error("CSV error")]
     Csv(#[from] csv::Error),
+    #[error("JSON error")]
+    Json(#[from] serde_json::Error),
     #[error("Client error")]
     Client(#[from] image_scraper::client::Error),
     #[error("Store error")]
@@ -245,6 +1344,26 @@ pub enum Error {
     StoreIteration(#[from] image_scraper::store::IterationError),
     #[error("Index database error")]
     IndexDatabase(#[from] image_scraper_index::db::Error),
+    #[error("Download log error")]
+    Log(#[from] logs::Error),
+    #[error("Input error")]
+    Input(#[from] input::Error),
+    #[error("Validation checkpoint error")]
+    Validation(#[from] validation::Error),
+    #[error("Report error")]
+    Report(#[from] report::Error),
+    #[error("Invalid digest")]
+    InvalidDigest(#[from] image_scraper::digest::ParseError),
+    #[error("Index already exists: {0}")]
+    ExistingIndex(PathBuf),
+    #[error("Unknown archive format: {0}")]
+    UnknownArchiveFormat(PathBuf),
+    #[error("S3 error")]
+    S3(#[from] image_scraper::s3::Error),
+    #[error("Missing S3 credentials (set AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY)")]
+    MissingS3Credentials,
+    #[error("Missing destination")]
+    MissingDestination,
     #[error("Missing prefix part lengths")]
     MissingPrefixPartLengths,
     #[error("Prefix part lengths mismatch")]
@@ -266,15 +1385,33 @@ struct Opts {
 #[derive(Debug, Parser)]
 enum Command {
     /// Download a list of URLs provided on standard input
-    DownloadAll {
+    DownloadAll(Box<DownloadAllOpts>),
+    /// Validate the files in an image store, printing the digest, actual digest, and path of any
+    /// whose contents don't match
+    Validate {
         #[clap(long)]
         store: PathBuf,
         #[clap(long)]
         prefix: Option<PrefixPartLengths>,
+        /// File recording the progress of the validation pass, so that later runs can continue it
+        #[clap(long)]
+        checkpoint: Option<PathBuf>,
+        /// Maximum number of files validated in this run
+        #[clap(long)]
+        limit: Option<usize>,
+        /// Stop validating after this many seconds
+        #[clap(long)]
+        max_duration: Option<u64>,
+        /// Only validate files whose digests are in this range of hexadecimal prefixes (e.g. 00-3f)
+        ///
+        /// Each range should have its own checkpoint file.
         #[clap(long)]
-        delay_ms: Option<u64>,
+        range: Option<PrefixRange>,
     },
     /// List the contents of an image store, optionally validating
+    ///
+    /// Files are validated in parallel, so they are listed in an unspecified order with
+    /// --validate.
     List {
         #[clap(long)]
         store: PathBuf,
@@ -282,14 +1419,394 @@ enum Command {
         prefix: Option<PrefixPartLengths>,
         #[clap(long)]
         validate: bool,
+        /// Print a JSON summary of the validation (including invalid and unreadable files) instead
+        /// of listing valid files
+        #[clap(long, requires = "validate")]
+        report_json: bool,
+        /// Only list files whose digests are in this range of hexadecimal prefixes (e.g. 00-3f)
+        #[clap(long)]
+        range: Option<PrefixRange>,
+        /// File recording the position of the listing, so that an interrupted run can be continued
+        ///
+        /// The file is removed once the listing is complete.
+        #[clap(long, conflicts_with = "validate")]
+        checkpoint: Option<PathBuf>,
+    },
+    /// Create a store containing a filtered subset of images using hard links
+    Export {
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+        /// Directory for the new store (which must be on the same file system)
+        #[clap(long)]
+        dest: PathBuf,
+        /// Only include images of this type (e.g. png)
+        #[clap(long = "type")]
+        image_types: Vec<ImageType>,
+        /// Index used for date filtering (only indexed images are included if provided)
+        #[clap(long)]
+        index: Option<PathBuf>,
+        /// Only include images first indexed on or after this date (YYYY-MM-DD)
+        #[clap(long, requires = "index")]
+        since: Option<chrono::NaiveDate>,
+        /// Only include images first indexed on or before this date (YYYY-MM-DD)
+        #[clap(long, requires = "index")]
+        until: Option<chrono::NaiveDate>,
+        /// File containing the digests of the images to include (one per line)
+        #[clap(long)]
+        digests: Option<PathBuf>,
+    },
+    /// Add every image from one store to another (skipping any that are already present)
+    ///
+    /// Images are checked against their digests before they are added, and any that don't match
+    /// are reported and left in the source store.
+    MergeStores {
+        /// Store that images are added to (which is created if it doesn't exist)
+        #[clap(long)]
+        into: PathBuf,
+        /// Prefix part lengths for the destination (by default those of the source if it is empty)
+        #[clap(long)]
+        into_prefix: Option<PrefixPartLengths>,
+        #[clap(long)]
+        from: PathBuf,
+        #[clap(long)]
+        from_prefix: Option<PrefixPartLengths>,
+        /// Move files instead of hard-linking them (removing them from the source store)
+        #[clap(long = "move")]
+        move_files: bool,
+        /// Index for the destination store, to which the source index's entries are added
+        #[clap(long, requires = "from_index")]
+        index: Option<PathBuf>,
+        /// Index for the source store
+        #[clap(long, requires = "index")]
+        from_index: Option<PathBuf>,
+    },
+    /// Copy the images in one store that are missing from another (e.g. to keep a mirror up to date)
+    ///
+    /// Both stores are iterated in order of digest, so unchanged images aren't copied or checked
+    /// individually. Images are checked against their digests before they are copied, and each
+    /// copy is read back and checked again.
+    StoreSync {
+        #[clap(long)]
+        from: PathBuf,
+        #[clap(long)]
+        from_prefix: Option<PrefixPartLengths>,
+        /// Store that images are copied to (which is created if it doesn't exist)
+        #[clap(long, required_unless_present = "to_bucket")]
+        to: Option<PathBuf>,
+        /// S3-compatible bucket that images are copied to instead of a store directory
+        ///
+        /// Credentials are read from the AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY environment
+        /// variables.
+        #[clap(long, conflicts_with = "to", requires = "s3_endpoint")]
+        to_bucket: Option<String>,
+        /// Endpoint for the bucket (e.g. http://localhost:9000)
+        #[clap(long, requires = "to_bucket")]
+        s3_endpoint: Option<String>,
+        /// Region used to sign requests to the bucket (us-east-1 by default)
+        #[clap(long, requires = "to_bucket")]
+        s3_region: Option<String>,
+        /// Prefix part lengths for the destination (by default those of the source if it is empty)
+        #[clap(long)]
+        to_prefix: Option<PrefixPartLengths>,
+        /// Only count the images that would be copied
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Create or replace the store's manifest, which is then used for listing
+    RebuildManifest {
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+    },
+    /// Print the number of files and bytes in a store, and the number of files of each type
+    Stats {
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+        /// Only count files (in total and for each top-level prefix), without reading them
+        #[clap(long)]
+        count_only: bool,
+    },
+    /// Remove images from a store (e.g. for takedown requests)
+    ///
+    /// Each line of the output has the digest, whether the image was removed, and the number of
+    /// URLs that were tombstoned in the index (if one is given), so that they aren't downloaded
+    /// again.
+    StoreDelete {
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+        #[clap(long)]
+        index: Option<PathBuf>,
+        /// Digests of the images to remove
+        #[clap(required = true)]
+        digests: Vec<String>,
+    },
+    /// Check every file in a store against its name, moving files that don't match and removing
+    /// zero-byte leftovers
+    ///
+    /// Each line of the output has the path of a file that was moved or removed, the digest of its
+    /// contents, and its new path (empty if it was removed because the store already had a file
+    /// with the same contents, or because it was empty).
+    StoreRepair {
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+        /// Move mismatched files to this directory instead of renaming them within the store
+        #[clap(long)]
+        quarantine: Option<PathBuf>,
+    },
+    /// Move every file in a store into a new prefix layout (e.g. to re-shard a flat store)
+    ///
+    /// Files are found wherever they are in the directory tree, so an interrupted migration can be
+    /// resumed by running the command again.
+    StoreMigrate {
+        #[clap(long)]
+        store: PathBuf,
+        /// Prefix part lengths of the new layout
+        #[clap(long)]
+        to: PrefixPartLengths,
+        /// Only count the files that would be moved
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Print the recorded metadata for images (see `download-all --metadata`)
+    ///
+    /// Each line of the output has the digest, URL, time fetched, content type, and content length
+    /// (images without metadata are skipped).
+    StoreMetadata {
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+        /// Digests of the images to look up
+        #[clap(required = true)]
+        digests: Vec<String>,
+    },
+    /// Clean up a store (currently by removing empty prefix directories, which slow down iteration)
+    StoreGc {
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+    },
+    /// Write the images in a store to a tar or zip archive (e.g. for backups), preserving the
+    /// prefix layout
+    StoreExport {
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+        /// Archive file to create
+        #[clap(long)]
+        output: PathBuf,
+        /// Archive format (tar or zip, by default determined by the output file's extension)
+        #[clap(long)]
+        format: Option<ArchiveFormat>,
+        /// File containing the digests of the images to include (one per line)
+        #[clap(long)]
+        digests: Option<PathBuf>,
+    },
+    /// Add the images in a tar or zip archive to a store (skipping any that are already present)
+    ///
+    /// Images are checked against their digests before they are added, and any that don't match
+    /// are reported and skipped. The archive's prefix layout doesn't need to match the store's.
+    StoreImport {
+        /// Store that images are added to (which is created if it doesn't exist)
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+        /// Archive file to read
+        #[clap(long)]
+        input: PathBuf,
+        /// Archive format (tar or zip, by default determined by the input file's extension)
+        #[clap(long)]
+        format: Option<ArchiveFormat>,
+        /// Digest kind for a new store (an existing store must already use this kind)
+        #[clap(long)]
+        digest_kind: Option<DigestKind>,
+    },
+    /// Add the images in an arbitrary directory (and its subdirectories) to a store, hard-linking
+    /// them where possible
+    ///
+    /// Each line of the output has the path of a file, its digest, and whether it was added or
+    /// found (i.e. was already present).
+    StoreImportDir {
+        /// Store that images are added to (which is created if it doesn't exist)
+        #[clap(long)]
+        store: PathBuf,
+        #[clap(long)]
+        prefix: Option<PrefixPartLengths>,
+        /// Directory to import
+        #[clap(long)]
+        dir: PathBuf,
     },
     IndexImport {
         #[clap(long)]
         index: PathBuf,
+        /// Query parameter removed from URLs (a trailing * matches any suffix)
+        #[clap(long = "strip-param")]
+        strip_params: Vec<String>,
+        /// Format of the download logs (csv or jsonl)
+        #[clap(long, default_value = "csv")]
+        log_format: logs::LogFormat,
+        /// Download logs to import (gzipped if they end in .gz), or standard input if none
+        files: Vec<PathBuf>,
+    },
+    /// Re-apply archived download logs (in any supported format) to an index
+    ///
+    /// The format of each file is detected from its name or contents, and importing a log again
+    /// has no further effect. Each line of the report has the file, its format, and the number of
+    /// entries added, deferred (found images whose types aren't known yet), and ignored.
+    LogsReplay {
+        #[clap(long)]
+        index: PathBuf,
+        /// Query parameter removed from URLs (a trailing * matches any suffix)
+        #[clap(long = "strip-param")]
+        strip_params: Vec<String>,
+        /// Download logs to replay (gzipped if they end in .gz)
+        #[clap(required = true)]
+        files: Vec<PathBuf>,
     },
     IndexDump {
         #[clap(long)]
         index: PathBuf,
+        /// Only include URLs for this domain and its subdomains
+        #[clap(long)]
+        domain: Option<String>,
+    },
+    /// Summarize the index by registrable domain, with the most frequent domains first
+    ///
+    /// Each row has the number of successful and failed download records, the number of distinct
+    /// images, and their total size (if a store is provided).
+    IndexReportDomains {
+        #[clap(long)]
+        index: PathBuf,
+        /// Store used to look up image sizes
+        #[clap(long)]
+        store: Option<PathBuf>,
+        #[clap(long, requires = "store")]
+        prefix: Option<PrefixPartLengths>,
+        /// Output format (text or csv)
+        #[clap(long, default_value = "text")]
+        format: report::ReportFormat,
+        /// Only include this many domains
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+    /// Remove all records for URLs on a domain and its subdomains
+    IndexRemoveDomain {
+        #[clap(long)]
+        index: PathBuf,
+        #[clap(long)]
+        domain: String,
+    },
+    /// Copy an index into a new index that uses the given key scheme (url or reversed-host)
+    ///
+    /// With the reversed-host scheme the records for a domain and its subdomains are stored
+    /// together, so reading or removing them with --domain doesn't require a full scan.
+    IndexRekey {
+        #[clap(long)]
+        index: PathBuf,
+        /// Directory for the new index (which must not exist)
+        #[clap(long)]
+        output: PathBuf,
+        #[clap(long)]
+        key_scheme: KeyScheme,
+    },
+    /// Print the (estimated) number of records in the index
+    IndexStats {
+        #[clap(long)]
+        index: PathBuf,
+        /// Count the records exactly (which requires reading the whole index)
+        #[clap(long)]
+        exact: bool,
+    },
+    /// Remove all but the most recent records for each URL
+    IndexPruneHistory {
+        #[clap(long)]
+        index: PathBuf,
+        /// Number of records to keep for each URL
+        #[clap(long)]
+        keep: std::num::NonZeroUsize,
+        /// Only remove older entries for the same image as the latest kept entry
+        #[clap(long)]
+        unchanged_only: bool,
+        /// Only prune URLs that start with this prefix
+        #[clap(long)]
+        url_prefix: Option<String>,
+    },
+    /// Remove records added before a given time
+    IndexPrune {
+        #[clap(long)]
+        index: PathBuf,
+        /// Remove records added before this time (e.g. 2024-01-01T00:00:00Z)
+        #[clap(long)]
+        before: chrono::DateTime<chrono::Utc>,
+        /// Only remove failed downloads
+        #[clap(long)]
+        failed_only: bool,
+    },
+    /// Print URLs whose latest record is a failure (one per line, for retrying with download-all)
+    IndexFailed {
+        #[clap(long)]
+        index: PathBuf,
+        /// Only include failures at or after this time (e.g. 2024-01-01T00:00:00Z)
+        #[clap(long)]
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        /// Also print the time of the failure and the HTTP status code (if known) after each URL
+        #[clap(long)]
+        details: bool,
+    },
+    /// Check that the source URLs of indexed images are still available (with HEAD requests)
+    ///
+    /// Each line of the report has the URL, the result (live, changed, dead, or error), the HTTP
+    /// status code, the digest, the stored size, the remote content length, and the remote ETag.
+    VerifyRemote {
+        #[clap(long)]
+        index: PathBuf,
+        /// Store used to compare the remote content length with the size of the stored image
+        #[clap(long)]
+        store: Option<PathBuf>,
+        /// Only check URLs with this host (e.g. "example.com" or "*.example.com")
+        #[clap(long)]
+        host: Option<image_scraper::header_template::HostPattern>,
+        /// Only check images downloaded at or after this time (e.g. 2024-01-01T00:00:00Z)
+        #[clap(long)]
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        /// Only check images downloaded before this time (e.g. 2024-01-01T00:00:00Z)
+        #[clap(long)]
+        before: Option<chrono::DateTime<chrono::Utc>>,
+        /// Time to wait between requests to the same host in milliseconds
+        #[clap(long, default_value = "500")]
+        delay_ms: u64,
+        /// Header sent with requests to matching hosts (e.g. "*.example.com=Referer: https://example.com/")
+        #[clap(long = "header-template")]
+        header_templates: Vec<HeaderTemplate>,
+        /// Record a failed download in the index for URLs that no longer exist (404 or 410)
+        #[clap(long)]
+        mark_dead: bool,
+    },
+    /// List quarantined (non-image) downloads, optionally removing them
+    Quarantine {
+        #[clap(long)]
+        directory: PathBuf,
+        /// Remove the files (the removed files are listed)
+        #[clap(long)]
+        purge: bool,
+        /// Only remove files older than this many seconds
+        #[clap(long, requires = "purge")]
+        older_than: Option<u64>,
+        /// Digest kind used by the store the files were quarantined from
+        #[clap(long, default_value = "md5")]
+        digest_kind: DigestKind,
     },
     ListUnindexed {
         #[clap(long)]
@@ -301,6 +1818,228 @@ enum Command {
     },
 }
 
+/// Options for the download command.
+#[derive(Debug, Parser)]
+struct DownloadAllOpts {
+    #[clap(long)]
+    store: PathBuf,
+    #[clap(long)]
+    prefix: Option<PrefixPartLengths>,
+    #[clap(long)]
+    delay_ms: Option<u64>,
+    /// Query parameter removed from URLs (a trailing * matches any suffix)
+    #[clap(long = "strip-param")]
+    strip_params: Vec<String>,
+    /// What to do with downloads that aren't images (store, reject, or quarantine=DIR)
+    #[clap(long, default_value = "store")]
+    non_images: NonImagePolicy,
+    /// Record the URL, time, content type, and content length of each new image in the store
+    #[clap(long)]
+    metadata: bool,
+    /// Header sent with downloads from matching hosts (e.g. "*.example.com=Referer: https://example.com/")
+    #[clap(long = "header-template")]
+    header_templates: Vec<HeaderTemplate>,
+    /// Only download URLs whose host and path match a glob pattern (e.g. "*.fbcdn.net/v/**")
+    #[clap(long = "allow-url")]
+    allowed_url_patterns: Vec<UrlPattern>,
+    /// Never download URLs whose host and path match a glob pattern
+    #[clap(long = "deny-url")]
+    denied_url_patterns: Vec<UrlPattern>,
+    /// Maximum number of idle connections kept open for each host
+    #[clap(long)]
+    pool_max_idle_per_host: Option<usize>,
+    /// Time in seconds after which idle connections are closed
+    #[clap(long)]
+    pool_idle_timeout: Option<u64>,
+    /// HTTP versions used for downloads (negotiate, http1, or http2)
+    #[clap(long, default_value = "negotiate")]
+    http_version: HttpVersion,
+    /// IP versions used for downloads (any, ipv4, ipv6, prefer-ipv4, or prefer-ipv6)
+    #[clap(long, default_value = "any")]
+    ip_version: IpVersion,
+    /// Time in seconds allowed for connecting to a host (divided between its addresses)
+    #[clap(long)]
+    connect_timeout: Option<u64>,
+    /// Time in seconds allowed for each read from a connection (so that stalled downloads fail)
+    #[clap(long)]
+    read_timeout: Option<u64>,
+    /// User-Agent header sent with each request
+    #[clap(long)]
+    user_agent: Option<String>,
+    /// Proxy URL used for all requests (by default proxies are taken from environment variables
+    /// such as HTTPS_PROXY)
+    #[clap(long)]
+    proxy: Option<String>,
+    /// Maximum number of redirects followed for each request (10 by default)
+    #[clap(long)]
+    max_redirects: Option<usize>,
+    /// Download large images in ranges of this many bytes (from servers that support ranges)
+    #[clap(long)]
+    chunk_size: Option<std::num::NonZeroU64>,
+    /// Maximum number of concurrent range requests for each chunked download
+    #[clap(long, default_value = "4", requires = "chunk_size")]
+    chunk_concurrency: std::num::NonZeroUsize,
+    /// Index used to skip URLs that have already been downloaded (or failed recently)
+    #[clap(long)]
+    index: Option<PathBuf>,
+    /// Time in seconds after a failed download before the URL is tried again
+    #[clap(long, default_value = "3600", requires = "index")]
+    failure_backoff: u64,
+    /// Format of the log written to standard output (csv or jsonl)
+    #[clap(long, default_value = "csv")]
+    log_format: logs::LogFormat,
+    /// Read URLs from this file (or named pipe) as they are appended, instead of standard input
+    #[clap(long)]
+    watch: Option<PathBuf>,
+    /// File that records the last processed input line if the command is interrupted (Ctrl-C)
+    #[clap(long)]
+    checkpoint: Option<PathBuf>,
+    /// Skip the input lines recorded as processed in this checkpoint file
+    #[clap(long)]
+    resume_from: Option<PathBuf>,
+}
+
+/// Print a URL if its latest record is a failure that happened at or after the given time.
+fn print_failure(
+    url: &str,
+    record: &Result<Entry, Missing>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    details: bool,
+) {
+    if let Err(Missing::Failed {
+        timestamp,
+        status,
+        kind,
+    }) = record
+        && since.is_none_or(|since| *timestamp >= since)
+    {
+        if details {
+            println!(
+                "{},{},{},{}",
+                url,
+                timestamp.timestamp(),
+                status.map(|status| status.to_string()).unwrap_or_default(),
+                kind.map(|kind| kind.to_string()).unwrap_or_default()
+            );
+        } else {
+            println!("{url}");
+        }
+    }
+}
+
+/// The result of checking whether an indexed image's source URL is still available.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Liveness {
+    Live,
+    /// The remote content length differs from the size of the stored image
+    Changed,
+    /// The URL no longer exists (404 or 410)
+    Dead,
+    /// The request failed or had some other unsuccessful status
+    Error,
+}
+
+impl Liveness {
+    fn classify(head: &RemoteHead, expected_size: Option<u64>) -> Self {
+        if head.status.is_success() {
+            match (head.content_length, expected_size) {
+                (Some(content_length), Some(expected_size)) if content_length != expected_size => {
+                    Self::Changed
+                }
+                _ => Self::Live,
+            }
+        } else if head.status == reqwest::StatusCode::NOT_FOUND
+            || head.status == reqwest::StatusCode::GONE
+        {
+            Self::Dead
+        } else {
+            Self::Error
+        }
+    }
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Live => "live",
+            Self::Changed => "changed",
+            Self::Dead => "dead",
+            Self::Error => "error",
+        }
+    }
+}
+
+fn entries_in_range(
+    store: &Store,
+    range: Option<PrefixRange>,
+) -> image_scraper::store::Entries<'_> {
+    match range {
+        Some(range) => store.entries().in_range(range),
+        None => store.entries(),
+    }
+}
+
+/// Read the position saved by an earlier run (if there was one).
+fn read_cursor(path: &Path) -> Result<Option<Cursor>, Error> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => Ok(Some(contents.parse()?)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(Error::from(error)),
+    }
+}
+
+/// Write a position to a temporary file that is then moved into place.
+fn write_cursor(path: &Path, cursor: Cursor) -> Result<(), Error> {
+    let temp_path = path.with_extension("tmp");
+
+    std::fs::write(&temp_path, format!("{cursor}\n"))?;
+    std::fs::rename(temp_path, path)?;
+
+    Ok(())
+}
+
+/// An S3 store with credentials from the environment.
+fn s3_store(endpoint: &str, bucket: &str, region: Option<&str>) -> Result<S3Store, Error> {
+    let credentials = std::env::var("AWS_ACCESS_KEY_ID")
+        .ok()
+        .zip(std::env::var("AWS_SECRET_ACCESS_KEY").ok())
+        .map(|(access_key_id, secret_access_key)| {
+            Credentials::new(access_key_id, secret_access_key)
+        })
+        .ok_or(Error::MissingS3Credentials)?;
+
+    let store = S3Store::new(endpoint, bucket, credentials)?;
+
+    Ok(match region {
+        Some(region) => store.with_region(region),
+        None => store,
+    })
+}
+
+/// Open a store, checking the given prefix part lengths against its recorded (or inferred) layout.
+fn load_store(base: &Path, prefix: Option<PrefixPartLengths>) -> Result<Store, Error> {
+    let prefix_part_lengths = check_prefix_part_lengths(
+        Store::infer_prefix_part_lengths(base)?,
+        prefix.map(|prefix_part_lengths| prefix_part_lengths.0),
+    )?;
+
+    Ok(Store::load(base)?.with_prefix_part_lengths(prefix_part_lengths)?)
+}
+
+/// Read a file of digests (one per line, ignoring blank lines).
+fn read_digests(path: &Path, digest_kind: DigestKind) -> Result<BTreeSet<Digest>, Error> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| Digest::from_hex(digest_kind, line.trim()).map_err(Error::from))
+        .collect()
+}
+
+/// Use the given archive format, or determine it from the archive's file name.
+fn archive_format(path: &Path, format: Option<ArchiveFormat>) -> Result<ArchiveFormat, Error> {
+    format
+        .or_else(|| ArchiveFormat::from_path(path))
+        .ok_or_else(|| Error::UnknownArchiveFormat(path.to_path_buf()))
+}
+
 fn check_prefix_part_lengths(
     inferred: Option<Vec<usize>>,
     provided: Option<Vec<usize>>,
@@ -318,3 +2057,41 @@ fn check_prefix_part_lengths(
         (None, None) => Err(Error::MissingPrefixPartLengths),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Opts;
+    use cli_helpers::prelude::clap::{CommandFactory, Parser};
+
+    #[test]
+    fn test_opts() {
+        Opts::command().debug_assert();
+    }
+
+    #[test]
+    fn test_store_sync_destination() {
+        let parse = |args: &[&str]| {
+            Opts::try_parse_from(
+                ["image-scraper", "store-sync", "--from", "store"]
+                    .iter()
+                    .chain(args),
+            )
+        };
+
+        assert!(parse(&["--to", "mirror"]).is_ok());
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["--to-bucket", "images"]).is_err());
+        assert!(
+            parse(&[
+                "--to-bucket",
+                "images",
+                "--s3-endpoint",
+                "http://localhost:9000",
+                "--s3-region",
+                "eu-west-1"
+            ])
+            .is_ok()
+        );
+        assert!(parse(&["--to", "mirror", "--s3-region", "eu-west-1"]).is_err());
+    }
+}
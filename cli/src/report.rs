@@ -0,0 +1,108 @@
+use image_scraper_index::report::DomainRow;
+use std::io::Write;
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("CSV error")]
+    Csv(#[from] csv::Error),
+}
+
+/// The output format of a report.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum ReportFormat {
+    /// Aligned columns for reading in a terminal
+    #[default]
+    Text,
+    /// CSV with a header row
+    Csv,
+}
+
+impl std::str::FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "csv" => Ok(Self::Csv),
+            other => Err(format!("Invalid report format: {other}")),
+        }
+    }
+}
+
+const DOMAIN_HEADERS: [&str; 6] = [
+    "domain",
+    "entries",
+    "digests",
+    "bytes",
+    "failures",
+    "failure_rate",
+];
+
+/// Write a domain report (bytes are left empty when no sizes are known).
+pub fn write_domains<W: Write>(
+    writer: W,
+    rows: &[DomainRow],
+    format: ReportFormat,
+) -> Result<(), Error> {
+    match format {
+        ReportFormat::Text => write_domains_text(writer, rows)?,
+        ReportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+
+            writer.write_record(DOMAIN_HEADERS)?;
+
+            for row in rows {
+                writer.write_record([
+                    row.domain.clone(),
+                    row.entries.to_string(),
+                    row.digests.to_string(),
+                    row.bytes.map(|bytes| bytes.to_string()).unwrap_or_default(),
+                    row.failures.to_string(),
+                    format!("{:.4}", row.failure_rate()),
+                ])?;
+            }
+
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn write_domains_text<W: Write>(mut writer: W, rows: &[DomainRow]) -> Result<(), std::io::Error> {
+    let domain_width = rows
+        .iter()
+        .map(|row| row.domain.len())
+        .max()
+        .unwrap_or_default()
+        .max(DOMAIN_HEADERS[0].len());
+
+    writeln!(
+        writer,
+        "{:<domain_width$} {:>10} {:>10} {:>14} {:>10} {:>12}",
+        DOMAIN_HEADERS[0],
+        DOMAIN_HEADERS[1],
+        DOMAIN_HEADERS[2],
+        DOMAIN_HEADERS[3],
+        DOMAIN_HEADERS[4],
+        DOMAIN_HEADERS[5]
+    )?;
+
+    for row in rows {
+        writeln!(
+            writer,
+            "{:<domain_width$} {:>10} {:>10} {:>14} {:>10} {:>11.1}%",
+            row.domain,
+            row.entries,
+            row.digests,
+            row.bytes
+                .map_or_else(|| "-".to_string(), |bytes| bytes.to_string()),
+            row.failures,
+            row.failure_rate() * 100.0
+        )?;
+    }
+
+    writer.flush()
+}
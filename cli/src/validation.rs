@@ -0,0 +1,68 @@
+use chrono::{DateTime, Utc};
+use image_scraper::digest::Digest;
+use std::path::Path;
+
+/// Number of files validated between checkpoint writes.
+pub const WRITE_INTERVAL: usize = 1000;
+
+/// Number of files validated between progress messages for validation reports.
+pub const LOG_INTERVAL: u64 = 10_000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid checkpoint")]
+    Json(#[from] serde_json::Error),
+}
+
+/// The progress of a validation pass over a store that is spread across several runs.
+///
+/// Files are validated in order of digest, so a pass can be continued after the last validated
+/// digest.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Checkpoint {
+    /// When the first run of the pass started
+    pub started_at: DateTime<Utc>,
+    /// The last digest that was validated
+    pub last_digest: Option<Digest>,
+    pub valid: usize,
+    pub invalid: usize,
+}
+
+impl Checkpoint {
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            started_at: Utc::now(),
+            last_digest: None,
+            valid: 0,
+            invalid: 0,
+        }
+    }
+
+    /// Read a checkpoint, starting a new pass if the file doesn't exist.
+    pub fn read_or_new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(error) => Err(Error::from(error)),
+        }
+    }
+
+    /// Write the checkpoint to a temporary file that is then moved into place.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let temp_path = path.as_ref().with_extension("tmp");
+
+        std::fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(temp_path, path)?;
+
+        Ok(())
+    }
+}
+
+impl Default for Checkpoint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
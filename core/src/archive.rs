@@ -0,0 +1,190 @@
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Zip error")]
+    Zip(#[from] zip::result::ZipError),
+}
+
+/// Archive formats that stores can be exported to and imported from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Tar,
+    Zip,
+}
+
+impl Format {
+    /// Determine the format from a file name's extension (`.tar` or `.zip`).
+    #[must_use]
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "tar" => Some(Self::Tar),
+            "zip" => Some(Self::Zip),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tar" => Ok(Self::Tar),
+            "zip" => Ok(Self::Zip),
+            other => Err(format!("Invalid archive format: {other}")),
+        }
+    }
+}
+
+/// Writes files to an archive without reading them into memory.
+pub enum Writer<W: Write + Seek> {
+    Tar(tar::Builder<W>),
+    Zip(Box<zip::ZipWriter<W>>),
+}
+
+impl<W: Write + Seek> Writer<W> {
+    #[must_use]
+    pub fn new(writer: W, format: Format) -> Self {
+        match format {
+            Format::Tar => Self::Tar(tar::Builder::new(writer)),
+            Format::Zip => Self::Zip(Box::new(zip::ZipWriter::new(writer))),
+        }
+    }
+
+    /// Add a file with the given name (using `/` as the separator), size, and contents.
+    pub fn append<R: Read>(
+        &mut self,
+        name: &str,
+        size: u64,
+        modified: SystemTime,
+        mut contents: R,
+    ) -> Result<(), Error> {
+        match self {
+            Self::Tar(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(size);
+                header.set_mode(0o644);
+                header.set_mtime(
+                    modified
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .map_or(0, |duration| duration.as_secs()),
+                );
+
+                builder.append_data(&mut header, name, contents)?;
+            }
+            Self::Zip(writer) => {
+                // Images are generally already compressed.
+                let options = zip::write::SimpleFileOptions::default()
+                    .compression_method(zip::CompressionMethod::Stored)
+                    .large_file(size >= u64::from(u32::MAX));
+
+                writer.start_file(name, options)?;
+                std::io::copy(&mut contents, writer.as_mut())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the end of the archive, returning the underlying writer.
+    pub fn finish(self) -> Result<W, Error> {
+        match self {
+            Self::Tar(builder) => Ok(builder.into_inner()?),
+            Self::Zip(writer) => {
+                let writer = *writer;
+
+                Ok(writer.finish()?)
+            }
+        }
+    }
+}
+
+/// Call a function with the name and contents of each file in an archive (directories and other
+/// special entries are skipped).
+pub fn for_each_file<R, E, F>(reader: R, format: Format, mut f: F) -> Result<(), E>
+where
+    R: Read + Seek,
+    E: From<Error>,
+    F: FnMut(&str, &mut dyn Read) -> Result<(), E>,
+{
+    match format {
+        Format::Tar => {
+            let mut archive = tar::Archive::new(reader);
+
+            for entry in archive.entries().map_err(Error::from)? {
+                let mut entry = entry.map_err(Error::from)?;
+
+                if entry.header().entry_type().is_file() {
+                    let name = entry
+                        .path()
+                        .map_err(Error::from)?
+                        .to_string_lossy()
+                        .into_owned();
+
+                    f(&name, &mut entry)?;
+                }
+            }
+        }
+        Format::Zip => {
+            let mut archive = zip::ZipArchive::new(reader).map_err(Error::from)?;
+
+            for index in 0..archive.len() {
+                let mut file = archive.by_index(index).map_err(Error::from)?;
+
+                if file.is_file() {
+                    let name = file.name().to_string();
+
+                    f(&name, &mut file)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Format, Writer};
+    use std::io::Cursor;
+    use std::time::SystemTime;
+
+    #[test]
+    fn test_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        for format in [Format::Tar, Format::Zip] {
+            let mut writer = Writer::new(Cursor::new(vec![]), format);
+            writer.append("ab/abcd", 3, SystemTime::now(), &b"foo"[..])?;
+            writer.append(".store.toml", 0, SystemTime::UNIX_EPOCH, &b""[..])?;
+
+            let bytes = writer.finish()?.into_inner();
+            let mut files = vec![];
+
+            super::for_each_file(Cursor::new(bytes), format, |name, contents| {
+                let mut buffer = vec![];
+                contents.read_to_end(&mut buffer)?;
+                files.push((name.to_string(), buffer));
+
+                Ok::<_, Error>(())
+            })?;
+
+            assert_eq!(
+                files,
+                vec![
+                    ("ab/abcd".to_string(), b"foo".to_vec()),
+                    (".store.toml".to_string(), vec![])
+                ]
+            );
+        }
+
+        assert_eq!(Format::from_path("backup.zip"), Some(Format::Zip));
+        assert_eq!(Format::from_path("backup.tar.gz"), None);
+
+        Ok(())
+    }
+}
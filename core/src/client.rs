@@ -1,4 +1,22 @@
-use crate::store::{Action, Store};
+use crate::header_template::HeaderTemplates;
+use crate::history::{DownloadHistory, FailureKind, LastDownload, Validators};
+use crate::hook::Hooks;
+use crate::metadata::Metadata;
+use crate::refresh::RefreshPolicy;
+use crate::store::{Action, BackgroundWriter, NonImagePolicy, Store, StoreBackend};
+use crate::url_norm::Normalizer;
+use crate::url_policy::UrlPolicy;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{Span, field::Empty};
+
+/// Default maximum number of redirects followed for a request (the same as the HTTP client's
+/// default).
+const MAX_REDIRECTS: usize = 10;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -6,37 +24,1606 @@ pub enum Error {
     Http(#[from] reqwest::Error),
     #[error("Store error")]
     Store(#[from] crate::store::Error),
+    #[error("Download history error")]
+    History(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("Download failed recently (status {status:?})")]
+    RecentlyFailed { status: Option<u16> },
+    #[error("Invalid response to range request (status {status}): {range}")]
+    InvalidRange {
+        range: String,
+        status: reqwest::StatusCode,
+    },
+    #[error("URL blocked by policy")]
+    Blocked(#[from] crate::url_policy::Error),
+}
+
+impl Error {
+    /// Classify the error (for recording why a download failed).
+    #[must_use]
+    pub fn failure_kind(&self) -> FailureKind {
+        match self {
+            Self::Blocked(_) => FailureKind::Blocked,
+            // Blocked addresses and redirects are reported by the HTTP client.
+            Self::Http(error) if is_blocked(error) => FailureKind::Blocked,
+            Self::Http(error) if error.is_timeout() => FailureKind::Timeout,
+            Self::Http(error) if error.is_redirect() => FailureKind::Redirect,
+            Self::Http(error) if error.is_connect() => {
+                // Resolution and TLS failures are only distinguished by their messages.
+                let mut messages = String::new();
+                let mut source: Option<&dyn std::error::Error> = Some(error);
+
+                while let Some(error) = source {
+                    messages.push_str(&error.to_string().to_ascii_lowercase());
+                    messages.push('\n');
+                    source = error.source();
+                }
+
+                if messages.contains("dns error") || messages.contains("failed to lookup address") {
+                    FailureKind::Dns
+                } else if messages.contains("certificate")
+                    || messages.contains("tls")
+                    || messages.contains("ssl")
+                {
+                    FailureKind::Tls
+                } else {
+                    FailureKind::Connect
+                }
+            }
+            Self::Http(error) if error.is_body() || error.is_decode() => FailureKind::Body,
+            Self::Http(error) if error.is_status() => FailureKind::Status,
+            Self::Store(crate::store::Error::NotAnImage(_)) => FailureKind::NotAnImage,
+            Self::Store(crate::store::Error::Transform(crate::transform::Error::Corrupt(_))) => {
+                FailureKind::Corrupt
+            }
+            _ => FailureKind::Other,
+        }
+    }
+}
+
+/// Check whether an HTTP client error was caused by the URL policy.
+fn is_blocked(error: &reqwest::Error) -> bool {
+    let mut source: Option<&(dyn std::error::Error + 'static)> = Some(error);
+
+    while let Some(error) = source {
+        if error.is::<crate::url_policy::Error>() {
+            return true;
+        }
+
+        source = error.source();
+    }
+
+    false
+}
+
+/// The HTTP versions used for requests.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum HttpVersion {
+    /// HTTP/2 for servers that support it (negotiated during the TLS handshake), and otherwise HTTP/1.1
+    #[default]
+    Negotiate,
+    /// Only HTTP/1.1
+    Http1,
+    /// Only HTTP/2, without negotiation (for servers that are known to support it)
+    Http2,
+}
+
+impl std::str::FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "negotiate" => Ok(Self::Negotiate),
+            "http1" => Ok(Self::Http1),
+            "http2" => Ok(Self::Http2),
+            other => Err(format!("Invalid HTTP version: {other}")),
+        }
+    }
+}
+
+/// The IP versions used to connect to hosts.
+///
+/// When both versions are used, connections are attempted to addresses of the first version
+/// returned by the resolver (or the preferred version), and connections to the other version are
+/// started if these are slow to succeed ("happy eyeballs").
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum IpVersion {
+    /// Both versions, in the order given by the resolver
+    #[default]
+    Any,
+    /// Only IPv4 (hosts that only have IPv6 addresses can't be reached)
+    V4,
+    /// Only IPv6 (hosts that only have IPv4 addresses can't be reached)
+    V6,
+    /// Both versions, trying IPv4 addresses first
+    PreferV4,
+    /// Both versions, trying IPv6 addresses first
+    PreferV6,
+}
+
+impl IpVersion {
+    /// The local address that restricts connections to a single version, if there is one.
+    const fn local_address(self) -> Option<IpAddr> {
+        match self {
+            Self::V4 => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            Self::V6 => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+            Self::Any | Self::PreferV4 | Self::PreferV6 => None,
+        }
+    }
+}
+
+impl std::str::FromStr for IpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Self::Any),
+            "ipv4" => Ok(Self::V4),
+            "ipv6" => Ok(Self::V6),
+            "prefer-ipv4" => Ok(Self::PreferV4),
+            "prefer-ipv6" => Ok(Self::PreferV6),
+            other => Err(format!("Invalid IP version: {other}")),
+        }
+    }
+}
+
+/// Resolves host names with the system resolver, optionally ordering the addresses so that those of
+/// one IP version come first, and removing addresses that aren't allowed by a URL policy.
+#[derive(Clone, Debug)]
+struct Resolver {
+    prefer_v6: Option<bool>,
+    url_policy: Option<Arc<UrlPolicy>>,
+}
+
+impl Resolver {
+    /// Move the preferred version's addresses to the front (otherwise keeping the resolver's order).
+    fn sort(&self, addrs: &mut [SocketAddr]) {
+        if let Some(prefer_v6) = self.prefer_v6 {
+            addrs.sort_by_key(|addr| addr.is_ipv6() != prefer_v6);
+        }
+    }
+
+    /// Remove addresses that aren't allowed, failing if none are.
+    fn filter(&self, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, crate::url_policy::Error> {
+        let Some(url_policy) = &self.url_policy else {
+            return Ok(addrs);
+        };
+
+        let mut first_error = None;
+        let allowed = addrs
+            .into_iter()
+            .filter(|addr| match url_policy.check_address(addr.ip()) {
+                Ok(()) => true,
+                Err(error) => {
+                    first_error.get_or_insert(error);
+                    false
+                }
+            })
+            .collect::<Vec<_>>();
+
+        match first_error {
+            Some(error) if allowed.is_empty() => Err(error),
+            _ => Ok(allowed),
+        }
+    }
+}
+
+impl reqwest::dns::Resolve for Resolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let resolver = self.clone();
+
+        Box::pin(async move {
+            // The port is replaced by the connector.
+            let addrs = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .collect::<Vec<_>>();
+
+            let mut addrs = resolver.filter(addrs)?;
+            resolver.sort(&mut addrs);
+
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Connection pool and protocol settings for the underlying HTTP client.
+///
+/// Unset values use the HTTP client's defaults.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectionOptions {
+    /// Maximum number of idle connections kept open for each host
+    pub pool_max_idle_per_host: Option<usize>,
+    /// Time after which idle connections are closed
+    pub pool_idle_timeout: Option<Duration>,
+    pub http_version: HttpVersion,
+    pub ip_version: IpVersion,
+    /// Time allowed for establishing a connection (divided between a host's addresses)
+    pub connect_timeout: Option<Duration>,
+    /// Time allowed for each read from a connection (so that stalled downloads fail)
+    pub read_timeout: Option<Duration>,
+    /// Value of the `User-Agent` header sent with each request (none by default)
+    pub user_agent: Option<String>,
+    /// Proxy URL used for all requests (by default, proxies are configured by environment
+    /// variables such as `HTTPS_PROXY`, unless there's a URL policy)
+    ///
+    /// Hosts are resolved by the proxy, so when there's a URL policy only the URLs (and not the
+    /// addresses they resolve to) are checked, and a warning is logged if the policy denies any
+    /// addresses.
+    pub proxy: Option<String>,
+    /// Maximum number of redirects followed for a request (10 by default)
+    pub max_redirects: Option<usize>,
+    /// Policy that resolved addresses and redirects are checked against
+    pub url_policy: Option<Arc<UrlPolicy>>,
+}
+
+impl ConnectionOptions {
+    /// Build an HTTP client with these settings.
+    ///
+    /// The client can be shared by several [`Client`] instances (with [`Client::with_http_client`])
+    /// so that they use the same connection pool.
+    pub fn build(&self) -> Result<reqwest::Client, reqwest::Error> {
+        let mut builder = reqwest::Client::builder();
+
+        if let Some(pool_max_idle_per_host) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+
+        if let Some(pool_idle_timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(pool_idle_timeout);
+        }
+
+        builder = match self.http_version {
+            HttpVersion::Negotiate => builder.http2_adaptive_window(true),
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge().http2_adaptive_window(true),
+        };
+
+        if let Some(local_address) = self.ip_version.local_address() {
+            builder = builder.local_address(local_address);
+        }
+
+        let prefer_v6 = match self.ip_version {
+            IpVersion::PreferV4 => Some(false),
+            IpVersion::PreferV6 => Some(true),
+            IpVersion::Any | IpVersion::V4 | IpVersion::V6 => None,
+        };
+
+        if prefer_v6.is_some() || self.url_policy.is_some() {
+            builder = builder.dns_resolver(Arc::new(Resolver {
+                prefer_v6,
+                url_policy: self.url_policy.clone(),
+            }));
+        }
+
+        let max_redirects = self.max_redirects.unwrap_or(MAX_REDIRECTS);
+
+        if let Some(url_policy) = self.url_policy.clone() {
+            builder = builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                // The previous URLs include the original one (as in the default policy).
+                if attempt.previous().len() > max_redirects {
+                    attempt.error("too many redirects")
+                } else if let Err(error) = url_policy.check(attempt.url().as_str()) {
+                    attempt.error(error)
+                } else {
+                    attempt.follow()
+                }
+            }));
+        } else if self.max_redirects.is_some() {
+            builder = builder.redirect(reqwest::redirect::Policy::limited(max_redirects));
+        }
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+
+        if let Some(read_timeout) = self.read_timeout {
+            builder = builder.read_timeout(read_timeout);
+        }
+
+        if let Some(user_agent) = &self.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+
+        if let Some(proxy) = &self.proxy {
+            if self
+                .url_policy
+                .as_ref()
+                .is_some_and(|url_policy| url_policy.checks_addresses())
+            {
+                log::warn!(
+                    "Resolved addresses are not checked against the URL policy when a proxy is used"
+                );
+            }
+
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        } else if self.url_policy.is_some() {
+            // A proxy from the environment would resolve hosts without checking the addresses.
+            builder = builder.no_proxy();
+        }
+
+        builder.build()
+    }
+}
+
+/// Settings for downloading large files with several concurrent range requests.
+///
+/// The first chunk of each file is requested as a range, and if the server responds with the full
+/// file instead (because it doesn't support ranges), the file is downloaded as usual. Chunks are
+/// written to the store (and hashed) in order as they arrive.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChunkedDownloads {
+    /// Size in bytes of each range request
+    pub chunk_size: NonZeroU64,
+    /// Maximum number of range requests made at the same time
+    pub concurrency: NonZeroUsize,
+}
+
+/// The response to a `HEAD` request for an image URL.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RemoteHead {
+    pub status: http::StatusCode,
+    pub content_length: Option<u64>,
+    pub etag: Option<String>,
+}
+
+/// The result of revalidating a previously downloaded URL.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Revalidation {
+    /// The server confirmed that the image hasn't changed
+    NotModified { validators: Validators },
+    /// The server sent the image again (which may or may not have changed), and it was saved
+    Downloaded {
+        action: Action,
+        validators: Validators,
+    },
+}
+
+/// Builds a [`Client`] with custom connection settings (see [`ConnectionOptions`]).
+///
+/// Unset values use the HTTP client's defaults. Other settings (such as a download history) can be
+/// added to the built client.
+#[derive(Clone, Debug)]
+pub struct ClientBuilder<B = Store> {
+    store: B,
+    options: ConnectionOptions,
+}
+
+impl<B: StoreBackend> ClientBuilder<B> {
+    /// Replace all of the connection settings.
+    #[must_use]
+    pub fn with_connection_options(self, options: ConnectionOptions) -> Self {
+        Self { options, ..self }
+    }
+
+    /// Set the time allowed for establishing a connection.
+    #[must_use]
+    pub const fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(connect_timeout);
+        self
+    }
+
+    /// Set the time allowed for each read from a connection.
+    #[must_use]
+    pub const fn with_read_timeout(mut self, read_timeout: Duration) -> Self {
+        self.options.read_timeout = Some(read_timeout);
+        self
+    }
+
+    /// Set the `User-Agent` header sent with each request.
+    #[must_use]
+    pub fn with_user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+        self.options.user_agent = Some(user_agent.into());
+        self
+    }
+
+    /// Send all requests through a proxy.
+    #[must_use]
+    pub fn with_proxy<S: Into<String>>(mut self, proxy: S) -> Self {
+        self.options.proxy = Some(proxy.into());
+        self
+    }
+
+    /// Limit the number of redirects followed for a request.
+    #[must_use]
+    pub const fn with_max_redirects(mut self, max_redirects: usize) -> Self {
+        self.options.max_redirects = Some(max_redirects);
+        self
+    }
+
+    /// Limit the number of idle connections kept open for each host.
+    #[must_use]
+    pub const fn with_pool_max_idle_per_host(mut self, pool_max_idle_per_host: usize) -> Self {
+        self.options.pool_max_idle_per_host = Some(pool_max_idle_per_host);
+        self
+    }
+
+    /// Close idle connections after the given time.
+    #[must_use]
+    pub const fn with_pool_idle_timeout(mut self, pool_idle_timeout: Duration) -> Self {
+        self.options.pool_idle_timeout = Some(pool_idle_timeout);
+        self
+    }
+
+    #[must_use]
+    pub const fn with_http_version(mut self, http_version: HttpVersion) -> Self {
+        self.options.http_version = http_version;
+        self
+    }
+
+    #[must_use]
+    pub const fn with_ip_version(mut self, ip_version: IpVersion) -> Self {
+        self.options.ip_version = ip_version;
+        self
+    }
+
+    /// Check URLs, the addresses that hosts resolve to, and redirects against a policy.
+    #[must_use]
+    pub fn with_url_policy(mut self, url_policy: Arc<UrlPolicy>) -> Self {
+        self.options.url_policy = Some(url_policy);
+        self
+    }
+
+    /// Build the client (failing if the proxy URL is invalid, for example).
+    pub fn build(self) -> Result<Client<B>, Error> {
+        Client::new(self.store).with_connection_options(&self.options)
+    }
 }
 
+/// A download client that saves images to a store backend (by default the file system).
 #[derive(Clone)]
-pub struct Client {
+pub struct Client<B = Store> {
     underlying: reqwest::Client,
-    store: Store,
+    store: B,
+    hooks: Hooks,
+    normalizer: Normalizer,
+    refresh_policy: RefreshPolicy,
+    header_templates: HeaderTemplates,
+    chunked_downloads: Option<ChunkedDownloads>,
+    history: Option<(Arc<dyn DownloadHistory>, Duration)>,
+    url_policy: Option<Arc<UrlPolicy>>,
 }
 
-impl Client {
+impl<B: StoreBackend> Client<B> {
+    /// Create a client that uses the HTTP client's default connection settings.
+    ///
+    /// See [`Client::builder`] for configuring connections.
     #[must_use]
-    pub fn new(store: Store) -> Self {
+    pub fn new(store: B) -> Self {
         Self {
             underlying: reqwest::Client::default(),
             store,
+            hooks: Hooks::default(),
+            normalizer: Normalizer::default(),
+            refresh_policy: RefreshPolicy::default(),
+            header_templates: HeaderTemplates::default(),
+            chunked_downloads: None,
+            history: None,
+            url_policy: None,
+        }
+    }
+
+    /// Start building a client with custom connection settings.
+    #[must_use]
+    pub fn builder(store: B) -> ClientBuilder<B> {
+        ClientBuilder {
+            store,
+            options: ConnectionOptions::default(),
+        }
+    }
+
+    /// Create a client that checks a download history (such as an index) before downloading.
+    ///
+    /// See [`Client::with_history`].
+    #[must_use]
+    pub fn new_with_history(
+        store: B,
+        history: Arc<dyn DownloadHistory>,
+        failure_backoff: Duration,
+    ) -> Self {
+        Self::new(store).with_history(history, failure_backoff)
+    }
+
+    /// Check a download history (such as an index) before downloading each URL.
+    ///
+    /// Downloads are skipped (returning an action with `skipped` set) if the URL's last download
+    /// succeeded, the image is still in the store, and it isn't stale according to the refresh
+    /// policy. Downloads fail immediately with [`Error::RecentlyFailed`] if the last download
+    /// failed less than `failure_backoff` ago.
+    #[must_use]
+    pub fn with_history(
+        self,
+        history: Arc<dyn DownloadHistory>,
+        failure_backoff: Duration,
+    ) -> Self {
+        Self {
+            history: Some((history, failure_backoff)),
+            ..self
+        }
+    }
+
+    /// Set the HTTP client used for requests (which may be shared with other clients).
+    #[must_use]
+    pub fn with_http_client(self, underlying: reqwest::Client) -> Self {
+        Self { underlying, ..self }
+    }
+
+    /// Use a new HTTP client with the given connection pool and protocol settings.
+    ///
+    /// If the options include a URL policy, it is also checked before each request.
+    pub fn with_connection_options(self, options: &ConnectionOptions) -> Result<Self, Error> {
+        let client = self.with_http_client(options.build()?);
+
+        Ok(match &options.url_policy {
+            Some(url_policy) => client.with_url_policy(url_policy.clone()),
+            None => client,
+        })
+    }
+
+    /// Check each URL against a policy before it is requested (failing with [`Error::Blocked`]).
+    ///
+    /// The addresses that hosts resolve to and redirects are only checked if the HTTP client was
+    /// built with the same policy (see [`ConnectionOptions::url_policy`]).
+    #[must_use]
+    pub fn with_url_policy(self, url_policy: Arc<UrlPolicy>) -> Self {
+        Self {
+            url_policy: Some(url_policy),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn url_policy(&self) -> Option<&UrlPolicy> {
+        self.url_policy.as_deref()
+    }
+
+    /// Check a (normalized) URL against the URL policy, if there is one.
+    fn check_url(&self, url: &str) -> Result<(), Error> {
+        match &self.url_policy {
+            Some(url_policy) => Ok(url_policy.check(url)?),
+            None => Ok(()),
+        }
+    }
+
+    /// Set the normalizer that is applied to URLs before they are requested.
+    #[must_use]
+    pub fn with_normalizer(self, normalizer: Normalizer) -> Self {
+        Self { normalizer, ..self }
+    }
+
+    #[must_use]
+    pub const fn normalizer(&self) -> &Normalizer {
+        &self.normalizer
+    }
+
+    /// Set the policy that determines when previously downloaded images should be downloaded again.
+    #[must_use]
+    pub fn with_refresh_policy(self, refresh_policy: RefreshPolicy) -> Self {
+        Self {
+            refresh_policy,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub const fn refresh_policy(&self) -> &RefreshPolicy {
+        &self.refresh_policy
+    }
+
+    /// Check whether an image downloaded from the URL at the given time should be downloaded again.
+    #[must_use]
+    pub fn is_stale(&self, url: &str, downloaded_at: SystemTime) -> bool {
+        self.refresh_policy.is_stale(
+            &self.normalizer.normalize_or_keep(url),
+            downloaded_at,
+            SystemTime::now(),
+        )
+    }
+
+    /// Set what is done with downloads that aren't recognized as images.
+    ///
+    /// Downloads that are rejected fail with [`crate::store::Error::NotAnImage`].
+    #[must_use]
+    pub fn with_non_image_policy(self, non_image_policy: NonImagePolicy) -> Self {
+        Self {
+            store: self.store.with_non_image_policy(non_image_policy),
+            ..self
+        }
+    }
+
+    /// Set the headers that are added to requests for specific hosts.
+    #[must_use]
+    pub fn with_header_templates(self, header_templates: HeaderTemplates) -> Self {
+        Self {
+            header_templates,
+            ..self
+        }
+    }
+
+    /// Download large files from servers that support range requests in concurrent chunks.
+    #[must_use]
+    pub fn with_chunked_downloads(self, chunked_downloads: ChunkedDownloads) -> Self {
+        Self {
+            chunked_downloads: Some(chunked_downloads),
+            ..self
+        }
+    }
+
+    /// Build a request for a (normalized) URL, including any templated headers for its host.
+    fn request(&self, method: reqwest::Method, url: &str) -> reqwest::RequestBuilder {
+        self.header_templates.headers(url).into_iter().fold(
+            self.underlying.request(method, url),
+            |request, (name, value)| request.header(name, value),
+        )
+    }
+
+    fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.request(reqwest::Method::GET, url)
+    }
+
+    /// Set the hooks that are run after each image is saved.
+    #[must_use]
+    pub fn with_hooks(self, hooks: Hooks) -> Self {
+        Self { hooks, ..self }
+    }
+
+    /// Check the download history for a (normalized) URL, returning the stored image if the download
+    /// can be skipped.
+    async fn check_history(&self, url: &str) -> Result<Option<(bytes::Bytes, Action)>, Error> {
+        let Some((history, failure_backoff)) = &self.history else {
+            return Ok(None);
+        };
+
+        let now = SystemTime::now();
+
+        match history.last_download(url).map_err(Error::History)? {
+            Some(LastDownload::Downloaded {
+                digest,
+                image_type,
+                timestamp,
+            }) if !self.refresh_policy.is_stale(url, timestamp, now) => {
+                // Images that have been removed from the store are downloaded again.
+                let bytes = self.store.read_async(digest).await?;
+
+                Ok(self.store.lookup(digest).zip(bytes).map(|(entry, bytes)| {
+                    (
+                        bytes::Bytes::from(bytes),
+                        Action {
+                            entry,
+                            image_type,
+                            added: false,
+                            quarantined: false,
+                            skipped: true,
+                            transformation: None,
+                            evicted: vec![],
+                        },
+                    )
+                }))
+            }
+            Some(LastDownload::Failed { timestamp, status })
+                if now.duration_since(timestamp).unwrap_or_default() < *failure_backoff =>
+            {
+                Err(Error::RecentlyFailed { status })
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Request a URL again, using the validators from a previous response to make the request
+    /// conditional.
+    ///
+    /// If the server confirms that the image hasn't changed, nothing is saved, and the returned
+    /// validators are the ones it sent (or the given ones if it didn't send any).
+    pub async fn revalidate(
+        &self,
+        url: &str,
+        validators: &Validators,
+    ) -> Result<Result<Revalidation, http::StatusCode>, Error> {
+        let url = self.normalizer.normalize_or_keep(url);
+        self.check_url(&url)?;
+
+        let mut request = self.get(&url);
+
+        if let Some(etag) = &validators.etag {
+            request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+        }
+
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(reqwest::header::IF_MODIFIED_SINCE, last_modified);
+        }
+
+        let response = request.send().await?;
+        let status_code = response.status();
+        let response_validators = response_validators(response.headers());
+
+        match status_code {
+            reqwest::StatusCode::NOT_MODIFIED => Ok(Ok(Revalidation::NotModified {
+                validators: if response_validators.is_empty() {
+                    validators.clone()
+                } else {
+                    response_validators
+                },
+            })),
+            reqwest::StatusCode::OK => {
+                let metadata = response_metadata(&url, response.headers());
+                let action = self
+                    .save_body(&url, response, vec![], None, metadata, &mut |_| {})
+                    .await?;
+
+                Ok(Ok(Revalidation::Downloaded {
+                    action,
+                    validators: response_validators,
+                }))
+            }
+            _ => Ok(Err(status_code)),
+        }
+    }
+
+    /// Make a `HEAD` request for a URL (without downloading or saving the image).
+    ///
+    /// This can be used to check that a previously downloaded image is still available.
+    pub async fn head(&self, url: &str) -> Result<RemoteHead, Error> {
+        let url = self.normalizer.normalize_or_keep(url);
+        self.check_url(&url)?;
+
+        let response = self.request(reqwest::Method::HEAD, &url).send().await?;
+        let headers = response.headers();
+
+        Ok(RemoteHead {
+            status: response.status(),
+            content_length: headers
+                .get(reqwest::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok()),
+            etag: response_validators(headers).etag,
+        })
+    }
+
+    /// Record where an added image came from, if the store keeps metadata (on a thread where
+    /// blocking is allowed).
+    ///
+    /// Failures are logged, but do not cause the download to fail.
+    async fn record_metadata(&self, action: &Action, metadata: Metadata) {
+        if action.added && !action.quarantined {
+            let store = self.store.clone();
+            let digest = action.entry.digest;
+
+            match tokio::task::spawn_blocking(move || store.record_metadata(digest, &metadata))
+                .await
+            {
+                Ok(Ok(_)) => {}
+                Ok(Err(error)) => log::warn!("Unable to record image metadata: {error}"),
+                Err(error) => log::error!("Metadata task failed: {error}"),
+            }
         }
     }
 
+    /// Run the hooks for a saved image (on a thread where blocking is allowed).
+    ///
+    /// Hook failures are logged, but do not cause the download to fail.
+    async fn run_hooks(&self, url: &str, action: &Action) {
+        if !self.hooks.is_empty() {
+            let hooks = self.hooks.clone();
+            let url = url.to_string();
+            let action = action.clone();
+
+            let errors = tokio::task::spawn_blocking(move || hooks.run(&url, &action)).await;
+
+            match errors {
+                Ok(errors) => {
+                    for error in errors {
+                        log::warn!("Post-download hook failed: {error}");
+                    }
+                }
+                Err(error) => log::error!("Post-download hook task failed: {error}"),
+            }
+        }
+    }
+
+    #[tracing::instrument(
+        name = "download",
+        skip(self),
+        fields(status = Empty, bytes = Empty, duration_ms = Empty),
+        err(Display)
+    )]
     pub async fn download(
         &self,
         url: &str,
     ) -> Result<Result<(bytes::Bytes, Action), http::StatusCode>, Error> {
-        let response = self.underlying.get(url).send().await?;
+        let start = Instant::now();
+
+        let result: Result<Result<(bytes::Bytes, Action), http::StatusCode>, Error> = async {
+            let url = self.normalizer.normalize_or_keep(url);
+            self.check_url(&url)?;
+
+            if let Some(skipped) = self.check_history(&url).await? {
+                return Ok(Ok(skipped));
+            }
+
+            // The body is written to the store as it arrives, and also collected to be returned.
+            let mut buffer = bytes::BytesMut::new();
+            let result = self
+                .save_response(&url, &mut |chunk: &bytes::Bytes| {
+                    buffer.extend_from_slice(chunk);
+                })
+                .await?;
+
+            Ok(match result {
+                Ok(action) => Ok((self.saved_bytes(buffer.freeze(), &action).await?, action)),
+                Err(status_code) => Err(status_code),
+            })
+        }
+        .await;
+
+        record_duration(start);
+
+        result
+    }
+
+    /// Return the contents of a downloaded file as they were saved, which differ from the
+    /// downloaded bytes if the store's pipeline transformed them.
+    async fn saved_bytes(
+        &self,
+        bytes: bytes::Bytes,
+        action: &Action,
+    ) -> Result<bytes::Bytes, Error> {
+        if action.transformation.is_some()
+            && let Some(saved) = self.store.read_async(action.entry.digest).await?
+        {
+            Ok(bytes::Bytes::from(saved))
+        } else {
+            Ok(bytes)
+        }
+    }
+
+    /// Download an image, saving it to the store as it arrives.
+    ///
+    /// Each chunk of the response body is passed to the given function after it has been queued to
+    /// be written to the store's temporary file (so these are the downloaded bytes, before any
+    /// transformations applied by the store's pipeline).
+    #[tracing::instrument(
+        name = "download",
+        skip(self, on_chunk),
+        fields(status = Empty, bytes = Empty, duration_ms = Empty),
+        err(Display)
+    )]
+    pub async fn download_with<F: FnMut(&bytes::Bytes)>(
+        &self,
+        url: &str,
+        mut on_chunk: F,
+    ) -> Result<Result<Action, http::StatusCode>, Error> {
+        let start = Instant::now();
+
+        let url = self.normalizer.normalize_or_keep(url);
+        let checked = match self.check_url(&url) {
+            Ok(()) => self.check_history(&url).await,
+            Err(error) => Err(error),
+        };
+
+        let result = match checked {
+            Ok(Some((bytes, action))) => {
+                on_chunk(&bytes);
+
+                Ok(Ok(action))
+            }
+            Ok(None) => self.save_response(&url, &mut on_chunk).await,
+            Err(error) => Err(error),
+        };
+
+        record_duration(start);
+
+        result
+    }
+
+    /// Request a (normalized) URL and write the response body to the store.
+    ///
+    /// If chunked downloads are enabled and the server supports range requests, the rest of the
+    /// file after the first chunk is requested in concurrent ranges.
+    async fn save_response<F: FnMut(&bytes::Bytes)>(
+        &self,
+        url: &str,
+        on_chunk: &mut F,
+    ) -> Result<Result<Action, http::StatusCode>, Error> {
+        let response = match self.chunked_downloads {
+            Some(chunked_downloads) => {
+                let response = self
+                    .get(url)
+                    .header(
+                        reqwest::header::RANGE,
+                        range_header(0, chunked_downloads.chunk_size.get()),
+                    )
+                    .send()
+                    .await?;
+
+                // Empty files can't be requested as a range.
+                if response.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+                    self.get(url).send().await?
+                } else {
+                    response
+                }
+            }
+            None => self.get(url).send().await?,
+        };
+
         let status_code = response.status();
+        Span::current().record("status", status_code.as_u16());
+
+        let (remaining_ranges, if_range) = match (status_code, self.chunked_downloads) {
+            (reqwest::StatusCode::OK, _) => (vec![], None),
+            (reqwest::StatusCode::PARTIAL_CONTENT, Some(chunked_downloads)) => {
+                let (_, last, len) = content_range(response.headers())
+                    .filter(|(first, _, _)| *first == 0)
+                    .ok_or_else(|| Error::InvalidRange {
+                        range: range_header(0, chunked_downloads.chunk_size.get()),
+                        status: status_code,
+                    })?;
 
-        if status_code == reqwest::StatusCode::OK {
-            let bytes = response.bytes().await?;
-            let action = self.store.save(&bytes)?;
+                let chunk_size = chunked_downloads.chunk_size.get();
+                let mut ranges = vec![];
+                let mut start = last + 1;
+
+                while start < len {
+                    ranges.push((start, (start + chunk_size).min(len), len));
+                    start += chunk_size;
+                }
+
+                // Weak validators can't be used for range requests.
+                let if_range = response
+                    .headers()
+                    .get(reqwest::header::ETAG)
+                    .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+                    .cloned();
+
+                (ranges, if_range)
+            }
+            _ => return Ok(Err(status_code)),
+        };
+
+        // The content length of a partial response is the length of the first range.
+        let metadata = response_metadata(url, response.headers());
+        let metadata = match remaining_ranges.last() {
+            Some((_, _, len)) => metadata.with_content_length(Some(*len)),
+            None => metadata,
+        };
+
+        self.save_body(
+            url,
+            response,
+            remaining_ranges,
+            if_range,
+            metadata,
+            on_chunk,
+        )
+        .await
+        .map(Ok)
+    }
 
-            Ok(Ok((bytes, action)))
+    /// Write a response body to the store as it arrives, followed by the contents of any remaining
+    /// ranges of the file (requested with the given validator).
+    async fn save_body<F: FnMut(&bytes::Bytes)>(
+        &self,
+        url: &str,
+        mut response: reqwest::Response,
+        remaining_ranges: Vec<(u64, u64, u64)>,
+        if_range: Option<reqwest::header::HeaderValue>,
+        metadata: Metadata,
+        on_chunk: &mut F,
+    ) -> Result<Action, Error> {
+        let mut writer = BackgroundWriter::new(self.store.clone());
+        let mut bytes = 0;
+
+        while let Some(chunk) = response.chunk().await? {
+            bytes += chunk.len();
+            on_chunk(&chunk);
+
+            writer.write(chunk).await?;
+        }
+
+        if let Some(chunked_downloads) = self.chunked_downloads {
+            // Ranges are requested concurrently, but their contents are returned in order.
+            let mut chunks = futures::stream::iter(remaining_ranges)
+                .map(|(start, end, len)| self.get_range(url, start, end, len, if_range.as_ref()))
+                .buffered(chunked_downloads.concurrency.get());
+
+            while let Some(chunk) = chunks.next().await {
+                let chunk = chunk?;
+
+                bytes += chunk.len();
+                on_chunk(&chunk);
+
+                writer.write(chunk).await?;
+            }
+        }
+
+        Span::current().record("bytes", bytes);
+
+        let action = writer.finish().await?;
+
+        self.record_metadata(&action, metadata).await;
+        self.run_hooks(url, &action).await;
+
+        Ok(action)
+    }
+
+    /// Request a range of a file (from `start` up to but not including `end`), checking that the
+    /// response contains exactly that range.
+    async fn get_range(
+        &self,
+        url: &str,
+        start: u64,
+        end: u64,
+        len: u64,
+        if_range: Option<&reqwest::header::HeaderValue>,
+    ) -> Result<bytes::Bytes, Error> {
+        let range = range_header(start, end);
+        let mut request = self.get(url).header(reqwest::header::RANGE, &range);
+
+        // If the file has changed, the server will respond with the full file, which is rejected.
+        if let Some(if_range) = if_range {
+            request = request.header(reqwest::header::IF_RANGE, if_range);
+        }
+
+        let response = request.send().await?;
+        let status = response.status();
+
+        if status != reqwest::StatusCode::PARTIAL_CONTENT
+            || content_range(response.headers()) != Some((start, end - 1, len))
+        {
+            return Err(Error::InvalidRange { range, status });
+        }
+
+        let bytes = response.bytes().await?;
+
+        if bytes.len() as u64 == end - start {
+            Ok(bytes)
         } else {
-            Ok(Err(status_code))
+            Err(Error::InvalidRange { range, status })
+        }
+    }
+}
+
+/// Describe a response for the store's metadata.
+fn response_metadata(url: &str, headers: &reqwest::header::HeaderMap) -> Metadata {
+    Metadata::new(url)
+        .with_content_type(
+            headers
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok()),
+        )
+        .with_content_length(
+            headers
+                .get(reqwest::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok()),
+        )
+}
+
+/// Read the cache validators from a response's headers.
+fn response_validators(headers: &reqwest::header::HeaderMap) -> Validators {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+    };
+
+    Validators {
+        etag: header(reqwest::header::ETAG),
+        last_modified: header(reqwest::header::LAST_MODIFIED),
+    }
+}
+
+/// Build a `Range` header value for the bytes from `start` up to but not including `end`.
+fn range_header(start: u64, end: u64) -> String {
+    format!("bytes={start}-{}", end - 1)
+}
+
+/// Parse a `Content-Range` header (e.g. `bytes 0-1023/4096`), returning the positions of the first
+/// and last bytes and the total length.
+fn content_range(headers: &reqwest::header::HeaderMap) -> Option<(u64, u64, u64)> {
+    let value = headers.get(reqwest::header::CONTENT_RANGE)?.to_str().ok()?;
+    let (range, len) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+
+    Some((first.parse().ok()?, last.parse().ok()?, len.parse().ok()?))
+}
+
+/// Record the time since the start of a download on the current download span.
+fn record_duration(start: Instant) {
+    Span::current().record("duration_ms", start.elapsed().as_secs_f64() * 1000.0);
+}
+
+/// Tracks the time of the most recent request to each host, so that requests to the same host can
+/// be spaced out while requests to different hosts are made immediately.
+///
+/// URLs that can't be parsed (or don't have a host) are all treated as having the same host.
+#[derive(Clone, Debug)]
+pub struct HostLimiter {
+    delay: Duration,
+    last_requests: HashMap<String, Instant>,
+}
+
+impl HostLimiter {
+    #[must_use]
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            last_requests: HashMap::new(),
+        }
+    }
+
+    fn host(url: &str) -> String {
+        reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_lowercase))
+            .unwrap_or_default()
+    }
+
+    /// Return how long to wait before a request may be made for the given URL.
+    #[must_use]
+    pub fn wait_time(&self, url: &str, now: Instant) -> Duration {
+        self.last_requests
+            .get(&Self::host(url))
+            .map_or(Duration::ZERO, |last_request| {
+                (*last_request + self.delay).saturating_duration_since(now)
+            })
+    }
+
+    /// Record that a request has been made for the given URL.
+    pub fn record(&mut self, url: &str, now: Instant) {
+        // Hosts whose delay has passed don't need to be tracked.
+        self.last_requests
+            .retain(|_, last_request| now.saturating_duration_since(*last_request) < self.delay);
+
+        self.last_requests.insert(Self::host(url), now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Client, ConnectionOptions, Error, HostLimiter, HttpVersion, IpVersion, Resolver};
+    use crate::history::{DownloadHistory, FailureKind, LastDownload};
+    use crate::store::Store;
+    use crate::transform::{Pipeline, StripMetadata};
+    use crate::url_policy::UrlPolicy;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant, SystemTime};
+
+    struct FixedHistory(Option<LastDownload>);
+
+    impl DownloadHistory for FixedHistory {
+        fn last_download(
+            &self,
+            _url: &str,
+        ) -> Result<Option<LastDownload>, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(self.0)
+        }
+    }
+
+    /// A response from the test server.
+    struct TestResponse {
+        /// Signal to wait for before responding
+        release: Option<Arc<tokio::sync::Notify>>,
+        status: &'static str,
+        location: Option<String>,
+        body: Vec<u8>,
+    }
+
+    impl TestResponse {
+        fn ok(body: impl Into<Vec<u8>>) -> Self {
+            Self {
+                release: None,
+                status: "200 OK",
+                location: None,
+                body: body.into(),
+            }
         }
     }
+
+    /// Respond to each request with the response for its head, returning the server's address.
+    async fn serve<F: Fn(&str) -> TestResponse + Send + Sync + 'static>(
+        respond: F,
+    ) -> std::io::Result<std::net::SocketAddr> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let address = listener.local_addr()?;
+        let respond = Arc::new(respond);
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let respond = respond.clone();
+
+                // Connections are handled concurrently, since responses may be delayed.
+                tokio::spawn(async move {
+                    let mut request = vec![];
+                    let mut buffer = [0; 1024];
+
+                    while !request.ends_with(b"\r\n\r\n") {
+                        match stream.read(&mut buffer).await {
+                            Ok(0) | Err(_) => break,
+                            Ok(len) => request.extend_from_slice(&buffer[..len]),
+                        }
+                    }
+
+                    let response = respond(&String::from_utf8_lossy(&request));
+
+                    if let Some(release) = &response.release {
+                        release.notified().await;
+                    }
+
+                    let location = response
+                        .location
+                        .map(|location| format!("location: {location}\r\n"))
+                        .unwrap_or_default();
+                    let head = format!(
+                        "HTTP/1.1 {}\r\n{location}content-length: {}\r\nconnection: close\r\n\r\n",
+                        response.status,
+                        response.body.len()
+                    );
+                    let _ = stream
+                        .write_all(&[head.as_bytes(), &response.body].concat())
+                        .await;
+                });
+            }
+        });
+
+        Ok(address)
+    }
+
+    #[tokio::test]
+    async fn test_history() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = Store::new(base.path());
+        let saved = store.save(b"foo")?;
+        let url = "https://example.com/a.png";
+
+        let client = Client::new_with_history(
+            store.clone(),
+            Arc::new(FixedHistory(Some(LastDownload::Downloaded {
+                digest: saved.entry.digest,
+                image_type: saved.image_type,
+                timestamp: SystemTime::now(),
+            }))),
+            Duration::from_mins(1),
+        );
+
+        let (bytes, action) = client
+            .download(url)
+            .await?
+            .map_err(|status| status.to_string())?;
+
+        assert_eq!(bytes.as_ref(), b"foo");
+        assert!(action.skipped);
+        assert!(!action.added);
+        assert_eq!(action.entry, saved.entry);
+
+        let client = Client::new_with_history(
+            store,
+            Arc::new(FixedHistory(Some(LastDownload::Failed {
+                timestamp: SystemTime::now(),
+                status: Some(503),
+            }))),
+            Duration::from_mins(1),
+        );
+
+        assert!(matches!(
+            client.download(url).await,
+            Err(Error::RecentlyFailed { status: Some(503) })
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_transformed() -> Result<(), Box<dyn std::error::Error>> {
+        // A JFIF segment and a short scan, with and without a comment segment.
+        let jfif = [
+            0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, b'J', b'F', b'I', b'F', 0x00, 0x01, 0x01, 0x00,
+            0x00, 0x01, 0x00, 0x01, 0x00, 0x00,
+        ];
+        let scan = [0xFF, 0xDA, 0x00, 0x02, 0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD9];
+        let jpg = [&jfif[..], &scan].concat();
+        let commented = [&jfif[..], &[0xFF, 0xFE, 0x00, 0x04, b'h', b'i'], &scan].concat();
+
+        let address = serve(move |_| TestResponse::ok(commented.clone())).await?;
+        let base = tempfile::tempdir()?;
+        let store = Store::new(base.path()).with_pipeline(Pipeline::new().with(StripMetadata));
+        let url = format!("http://{address}/a.jpg");
+
+        let (bytes, action) = Client::new(store.clone())
+            .download(&url)
+            .await?
+            .map_err(|status| status.to_string())?;
+
+        assert_eq!(bytes.as_ref(), jpg);
+        assert!(action.transformation.is_some());
+
+        let (bytes, _) = Client::new(store)
+            .with_chunked_downloads(super::ChunkedDownloads {
+                chunk_size: std::num::NonZeroU64::MIN,
+                concurrency: std::num::NonZeroUsize::MIN,
+            })
+            .download(&url)
+            .await?
+            .map_err(|status| status.to_string())?;
+
+        assert_eq!(bytes.as_ref(), jpg);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_content_range() {
+        let mut headers = reqwest::header::HeaderMap::new();
+
+        assert_eq!(super::content_range(&headers), None);
+
+        headers.insert(
+            reqwest::header::CONTENT_RANGE,
+            reqwest::header::HeaderValue::from_static("bytes 0-1023/4096"),
+        );
+
+        assert_eq!(super::content_range(&headers), Some((0, 1023, 4096)));
+
+        headers.insert(
+            reqwest::header::CONTENT_RANGE,
+            reqwest::header::HeaderValue::from_static("bytes 0-1023/*"),
+        );
+
+        assert_eq!(super::content_range(&headers), None);
+        assert_eq!(super::range_header(1024, 2048), "bytes=1024-2047");
+    }
+
+    #[test]
+    fn test_http_version_from_str() {
+        assert_eq!("negotiate".parse(), Ok(HttpVersion::Negotiate));
+        assert_eq!("http1".parse(), Ok(HttpVersion::Http1));
+        assert_eq!("http2".parse(), Ok(HttpVersion::Http2));
+        assert!("http3".parse::<HttpVersion>().is_err());
+    }
+
+    #[test]
+    fn test_ip_version_from_str() {
+        assert_eq!("any".parse(), Ok(IpVersion::Any));
+        assert_eq!("ipv4".parse(), Ok(IpVersion::V4));
+        assert_eq!("ipv6".parse(), Ok(IpVersion::V6));
+        assert_eq!("prefer-ipv4".parse(), Ok(IpVersion::PreferV4));
+        assert_eq!("prefer-ipv6".parse(), Ok(IpVersion::PreferV6));
+        assert!("ipv5".parse::<IpVersion>().is_err());
+    }
+
+    #[test]
+    fn test_resolver_sort() -> Result<(), Box<dyn std::error::Error>> {
+        let v4_a = "192.0.2.1:0".parse()?;
+        let v4_b = "192.0.2.2:0".parse()?;
+        let v6_a = "[2001:db8::1]:0".parse()?;
+        let v6_b = "[2001:db8::2]:0".parse()?;
+
+        let mut addrs = vec![v6_a, v4_a, v6_b, v4_b];
+        Resolver {
+            prefer_v6: Some(false),
+            url_policy: None,
+        }
+        .sort(&mut addrs);
+        assert_eq!(addrs, vec![v4_a, v4_b, v6_a, v6_b]);
+
+        Resolver {
+            prefer_v6: Some(true),
+            url_policy: None,
+        }
+        .sort(&mut addrs);
+        assert_eq!(addrs, vec![v6_a, v6_b, v4_a, v4_b]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolver_filter() -> Result<(), Box<dyn std::error::Error>> {
+        let public = "93.184.216.34:0".parse()?;
+        let private = "10.0.0.1:0".parse()?;
+        let mapped = "[::ffff:127.0.0.1]:0".parse()?;
+
+        let resolver = Resolver {
+            prefer_v6: None,
+            url_policy: Some(Arc::new(UrlPolicy::new().with_private_ranges_denied())),
+        };
+
+        assert_eq!(
+            resolver.filter(vec![private, public, mapped])?,
+            vec![public]
+        );
+        assert!(resolver.filter(vec![private, mapped]).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_url_policy() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let client = Client::new(Store::new(base.path()))
+            .with_url_policy(Arc::new(UrlPolicy::new().with_private_ranges_denied()));
+
+        let result = client
+            .download("http://169.254.169.254/latest/meta-data/")
+            .await;
+
+        assert!(matches!(result, Err(Error::Blocked(_))));
+        assert!(result.is_err_and(|error| error.failure_kind() == FailureKind::Blocked));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_options_build() -> Result<(), Box<dyn std::error::Error>> {
+        // Requests are echoed, except for slow responses (which aren't sent until the client has
+        // timed out) and chains of redirects.
+        let release = Arc::new(tokio::sync::Notify::new());
+        let server_release = release.clone();
+
+        let address = serve(move |request| {
+            let path = request.split(' ').nth(1).unwrap_or_default();
+
+            if path == "/slow" {
+                TestResponse {
+                    release: Some(server_release.clone()),
+                    ..TestResponse::ok("slow")
+                }
+            } else if let Some(remaining) = path
+                .strip_prefix("/redirect/")
+                .and_then(|remaining| remaining.parse::<usize>().ok())
+                .filter(|remaining| *remaining > 0)
+            {
+                TestResponse {
+                    status: "302 Found",
+                    location: Some(format!("/redirect/{}", remaining - 1)),
+                    ..TestResponse::ok("")
+                }
+            } else {
+                TestResponse::ok(request)
+            }
+        })
+        .await?;
+
+        let options = ConnectionOptions {
+            read_timeout: Some(Duration::from_millis(200)),
+            user_agent: Some("image-scraper".to_string()),
+            max_redirects: Some(3),
+            ..ConnectionOptions::default()
+        };
+
+        // Redirects are limited whether or not there's a URL policy (which checks each redirect).
+        for options in [
+            options.clone(),
+            ConnectionOptions {
+                url_policy: Some(Arc::new(UrlPolicy::new())),
+                ..options.clone()
+            },
+        ] {
+            let client = options.build()?;
+
+            let echoed = client
+                .get(format!("http://{address}/echo"))
+                .send()
+                .await?
+                .text()
+                .await?;
+
+            assert!(
+                echoed
+                    .to_ascii_lowercase()
+                    .contains("\r\nuser-agent: image-scraper\r\n")
+            );
+
+            let slow = client.get(format!("http://{address}/slow")).send().await;
+
+            assert!(slow.is_err_and(|error| error.is_timeout()));
+            release.notify_one();
+
+            let redirected = client
+                .get(format!("http://{address}/redirect/3"))
+                .send()
+                .await?;
+
+            assert_eq!(redirected.url().path(), "/redirect/0");
+
+            let redirected = client
+                .get(format!("http://{address}/redirect/4"))
+                .send()
+                .await;
+
+            assert!(redirected.is_err_and(|error| error.is_redirect()));
+        }
+
+        // Requests are sent to the proxy in absolute form.
+        let proxied = ConnectionOptions {
+            proxy: Some(format!("http://{address}")),
+            ..options.clone()
+        }
+        .build()?
+        .get("http://example.invalid/echo")
+        .send()
+        .await?
+        .text()
+        .await?;
+
+        assert!(proxied.starts_with("GET http://example.invalid/echo HTTP/1.1\r\n"));
+
+        assert!(
+            ConnectionOptions {
+                proxy: Some("http://[::1".to_string()),
+                ..options
+            }
+            .build()
+            .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_builder() -> Result<(), Box<dyn std::error::Error>> {
+        let address = serve(|request| TestResponse::ok(request)).await?;
+        let base = tempfile::tempdir()?;
+
+        let client = Client::builder(Store::new(base.path()))
+            .with_user_agent("image-scraper")
+            .with_max_redirects(3)
+            .with_url_policy(Arc::new(UrlPolicy::new()))
+            .build()?;
+
+        assert!(client.url_policy().is_some());
+
+        let echoed = client
+            .underlying
+            .get(format!("http://{address}/echo"))
+            .send()
+            .await?
+            .text()
+            .await?;
+
+        assert!(
+            echoed
+                .to_ascii_lowercase()
+                .contains("\r\nuser-agent: image-scraper\r\n")
+        );
+
+        assert!(
+            Client::builder(Store::new(base.path()))
+                .with_proxy("http://[::1")
+                .build()
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_limiter() {
+        let mut limiter = HostLimiter::new(Duration::from_millis(500));
+        let start = Instant::now();
+
+        assert_eq!(
+            limiter.wait_time("https://a.example.com/1.png", start),
+            Duration::ZERO
+        );
+
+        limiter.record("https://a.example.com/1.png", start);
+
+        let later = start + Duration::from_millis(200);
+
+        assert_eq!(
+            limiter.wait_time("https://A.example.com/2.png", later),
+            Duration::from_millis(300)
+        );
+        assert_eq!(
+            limiter.wait_time("https://b.example.com/1.png", later),
+            Duration::ZERO
+        );
+        assert_eq!(
+            limiter.wait_time(
+                "https://a.example.com/3.png",
+                start + Duration::from_secs(1)
+            ),
+            Duration::ZERO
+        );
+    }
 }
@@ -0,0 +1,396 @@
+use std::borrow::Cow;
+use std::fmt::{Debug, Display, LowerHex};
+use std::str::FromStr;
+
+/// The hash function used to compute a store's digests.
+///
+/// MD5 is the default (and the only kind used by stores created before the kind was configurable).
+/// The other kinds are available with the `sha256` and `blake3` features.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum DigestKind {
+    #[default]
+    Md5,
+    #[cfg(feature = "sha256")]
+    Sha256,
+    #[cfg(feature = "blake3")]
+    Blake3,
+}
+
+impl DigestKind {
+    /// The kinds supported with the enabled features.
+    pub const ALL: &[Self] = &[
+        Self::Md5,
+        #[cfg(feature = "sha256")]
+        Self::Sha256,
+        #[cfg(feature = "blake3")]
+        Self::Blake3,
+    ];
+
+    /// Number of bytes in a digest of this kind.
+    #[must_use]
+    pub const fn byte_len(self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            #[cfg(feature = "sha256")]
+            Self::Sha256 => 32,
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => 32,
+        }
+    }
+
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            #[cfg(feature = "sha256")]
+            Self::Sha256 => "sha256",
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    #[must_use]
+    pub const fn to_byte(self) -> u8 {
+        match self {
+            Self::Md5 => 0,
+            #[cfg(feature = "sha256")]
+            Self::Sha256 => 1,
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => 2,
+        }
+    }
+
+    #[must_use]
+    pub const fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Md5),
+            #[cfg(feature = "sha256")]
+            1 => Some(Self::Sha256),
+            #[cfg(feature = "blake3")]
+            2 => Some(Self::Blake3),
+            _ => None,
+        }
+    }
+
+    /// Compute the digest of the given data.
+    pub fn compute<T: AsRef<[u8]>>(self, data: T) -> Digest {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+
+    #[must_use]
+    pub fn hasher(self) -> Hasher {
+        Hasher(match self {
+            Self::Md5 => HasherState::Md5(md5::Context::new()),
+            #[cfg(feature = "sha256")]
+            Self::Sha256 => HasherState::Sha256(sha2::Sha256::default()),
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => HasherState::Blake3(Box::new(blake3::Hasher::new())),
+        })
+    }
+}
+
+impl Display for DigestKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DigestKind {
+    type Err = KindParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|kind| kind.name() == s)
+            .ok_or_else(|| KindParseError(s.to_string()))
+    }
+}
+
+impl<'de> serde::de::Deserialize<'de> for DigestKind {
+    fn deserialize<D: serde::de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let as_str: Cow<'de, str> = serde::de::Deserialize::deserialize(deserializer)?;
+
+        as_str.parse::<Self>().map_err(|_| {
+            serde::de::Error::invalid_value(serde::de::Unexpected::Str(&as_str), &"a digest kind")
+        })
+    }
+}
+
+impl serde::ser::Serialize for DigestKind {
+    fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+/// The digest of a file's contents, which is used to identify it in a store.
+///
+/// The textual representation (used by [`Display`], [`FromStr`], and serde) is lowercase
+/// hexadecimal, prefixed by the kind and a colon for kinds other than MD5 (e.g. `sha256:...`).
+/// File names in a store are always plain hexadecimal ([`LowerHex`]).
+#[derive(Clone, Copy, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Digest {
+    kind: DigestKind,
+    // Shorter digests are padded with zeros.
+    bytes: [u8; Self::MAX_LEN],
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid digest: {0}")]
+pub struct ParseError(String);
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid digest kind: {0}")]
+pub struct KindParseError(String);
+
+impl Digest {
+    /// Maximum number of bytes in a digest (of any kind).
+    pub const MAX_LEN: usize = 32;
+
+    /// Create an MD5 digest from its bytes.
+    #[must_use]
+    pub const fn from_bytes(bytes: [u8; 16]) -> Self {
+        let mut padded = [0; Self::MAX_LEN];
+        let mut i = 0;
+
+        while i < bytes.len() {
+            padded[i] = bytes[i];
+            i += 1;
+        }
+
+        Self {
+            kind: DigestKind::Md5,
+            bytes: padded,
+        }
+    }
+
+    /// Create a digest of the given kind from its bytes, if it is the right length.
+    #[must_use]
+    pub fn from_slice(kind: DigestKind, bytes: &[u8]) -> Option<Self> {
+        (bytes.len() == kind.byte_len()).then(|| {
+            let mut padded = [0; Self::MAX_LEN];
+            padded[..bytes.len()].copy_from_slice(bytes);
+
+            Self {
+                kind,
+                bytes: padded,
+            }
+        })
+    }
+
+    /// Parse a digest of the given kind from plain hexadecimal (as used in file names).
+    pub fn from_hex(kind: DigestKind, s: &str) -> Result<Self, ParseError> {
+        hex::decode(s)
+            .ok()
+            .and_then(|bytes| Self::from_slice(kind, &bytes))
+            .ok_or_else(|| ParseError(s.to_string()))
+    }
+
+    #[must_use]
+    pub const fn kind(&self) -> DigestKind {
+        self.kind
+    }
+
+    #[must_use]
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.kind.byte_len()]
+    }
+
+    /// Compute the MD5 digest of the given data.
+    ///
+    /// See [`DigestKind::compute`] for other kinds.
+    pub fn compute<T: AsRef<[u8]>>(data: T) -> Self {
+        DigestKind::Md5.compute(data)
+    }
+}
+
+impl Display for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.kind != DigestKind::Md5 {
+            write!(f, "{}:", self.kind)?;
+        }
+
+        LowerHex::fmt(self, f)
+    }
+}
+
+impl LowerHex for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.as_bytes() {
+            write!(f, "{byte:02x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Debug for Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Digest")
+            .field(&format_args!("{self}"))
+            .finish()
+    }
+}
+
+impl FromStr for Digest {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((kind, hex)) => kind
+                .parse()
+                .map_err(|_| ParseError(s.to_string()))
+                .and_then(|kind| Self::from_hex(kind, hex)),
+            None => Self::from_hex(DigestKind::Md5, s),
+        }
+    }
+}
+
+impl<'de> serde::de::Deserialize<'de> for Digest {
+    fn deserialize<D: serde::de::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let as_str: Cow<'de, str> = serde::de::Deserialize::deserialize(deserializer)?;
+
+        as_str.parse::<Self>().map_err(|_| {
+            serde::de::Error::invalid_value(
+                serde::de::Unexpected::Str(&as_str),
+                &"a hexadecimal digest",
+            )
+        })
+    }
+}
+
+impl serde::ser::Serialize for Digest {
+    fn serialize<S: serde::ser::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Computes a digest from data that arrives incrementally.
+#[derive(Clone)]
+pub struct Hasher(HasherState);
+
+#[derive(Clone)]
+enum HasherState {
+    Md5(md5::Context),
+    #[cfg(feature = "sha256")]
+    Sha256(sha2::Sha256),
+    #[cfg(feature = "blake3")]
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    /// Create an MD5 hasher.
+    ///
+    /// See [`DigestKind::hasher`] for other kinds.
+    #[must_use]
+    pub fn new() -> Self {
+        DigestKind::Md5.hasher()
+    }
+
+    pub fn update<T: AsRef<[u8]>>(&mut self, data: T) {
+        match &mut self.0 {
+            HasherState::Md5(context) => context.consume(data),
+            #[cfg(feature = "sha256")]
+            HasherState::Sha256(hasher) => sha2::Digest::update(hasher, data),
+            #[cfg(feature = "blake3")]
+            HasherState::Blake3(hasher) => {
+                hasher.update(data.as_ref());
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn finalize(self) -> Digest {
+        match self.0 {
+            HasherState::Md5(context) => Digest::from_bytes(context.finalize().0),
+            #[cfg(feature = "sha256")]
+            HasherState::Sha256(hasher) => Digest {
+                kind: DigestKind::Sha256,
+                bytes: sha2::Digest::finalize(hasher).into(),
+            },
+            #[cfg(feature = "blake3")]
+            HasherState::Blake3(hasher) => Digest {
+                kind: DigestKind::Blake3,
+                bytes: *hasher.finalize().as_bytes(),
+            },
+        }
+    }
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Digest, Hasher};
+
+    #[test]
+    fn test_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let digest = Digest::compute(b"foo bar baz");
+        let as_string = digest.to_string();
+
+        assert_eq!(as_string, "ab07acbb1e496801937adfa772424bf7");
+        assert_eq!(format!("{digest:x}"), as_string);
+        assert_eq!(as_string.parse::<Digest>()?, digest);
+        assert!("ab07acbb".parse::<Digest>().is_err());
+
+        let mut hasher = Hasher::new();
+        hasher.update(b"foo ");
+        hasher.update(b"bar baz");
+
+        assert_eq!(hasher.finalize(), digest);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(all(feature = "sha256", feature = "blake3"))]
+    fn test_kinds() -> Result<(), Box<dyn std::error::Error>> {
+        use super::DigestKind;
+
+        let sha256 = DigestKind::Sha256.compute(b"abc");
+        let blake3 = DigestKind::Blake3.compute(b"abc");
+
+        assert_eq!(
+            format!("{sha256:x}"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            format!("{blake3:x}"),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(
+            sha256.to_string(),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        for &kind in DigestKind::ALL {
+            let digest = kind.compute(b"foo bar baz");
+
+            let mut hasher = kind.hasher();
+            hasher.update(b"foo ");
+            hasher.update(b"bar baz");
+
+            assert_eq!(digest.kind(), kind);
+            assert_eq!(digest.as_bytes().len(), kind.byte_len());
+            assert_eq!(hasher.finalize(), digest);
+            assert_eq!(digest.to_string().parse::<Digest>()?, digest);
+            assert_eq!(Digest::from_hex(kind, &format!("{digest:x}"))?, digest);
+            assert_eq!(kind.name().parse::<DigestKind>()?, kind);
+        }
+
+        assert!("md6".parse::<DigestKind>().is_err());
+        assert!(
+            "sha256:ab07acbb1e496801937adfa772424bf7"
+                .parse::<Digest>()
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt::Display;
+use std::str::FromStr;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Expected PATTERN=NAME: VALUE: {0}")]
+    InvalidFormat(String),
+    #[error("Invalid host pattern: {0}")]
+    InvalidPattern(String),
+    #[error("Invalid header name: {0}")]
+    InvalidName(String),
+    #[error("Invalid header value: {0}")]
+    InvalidValue(String),
+}
+
+/// A host, or a wildcard matching all subdomains of a domain (e.g. `*.example.com`).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HostPattern {
+    Exact(String),
+    Subdomains(String),
+}
+
+impl HostPattern {
+    #[must_use]
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            Self::Exact(pattern) => host.eq_ignore_ascii_case(pattern),
+            Self::Subdomains(domain) => host
+                .to_ascii_lowercase()
+                .strip_suffix(domain.as_str())
+                .and_then(|subdomain| subdomain.strip_suffix('.'))
+                .is_some_and(|subdomain| !subdomain.is_empty()),
+        }
+    }
+}
+
+impl FromStr for HostPattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let domain = s.strip_prefix("*.").unwrap_or(s);
+
+        if domain.is_empty()
+            || !domain
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-'))
+        {
+            return Err(Error::InvalidPattern(s.to_string()));
+        }
+
+        let domain = domain.to_ascii_lowercase();
+
+        Ok(if s.starts_with("*.") {
+            Self::Subdomains(domain)
+        } else {
+            Self::Exact(domain)
+        })
+    }
+}
+
+impl Display for HostPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Exact(host) => f.write_str(host),
+            Self::Subdomains(domain) => write!(f, "*.{domain}"),
+        }
+    }
+}
+
+/// A header that is sent with every request for URLs whose host matches the pattern.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HeaderTemplate {
+    pub host_pattern: HostPattern,
+    pub name: String,
+    pub value: String,
+}
+
+impl FromStr for HeaderTemplate {
+    type Err = Error;
+
+    /// Parse a host pattern and header (e.g. `*.example.com=Referer: https://example.com/`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host_pattern, header) = s
+            .split_once('=')
+            .ok_or_else(|| Error::InvalidFormat(s.to_string()))?;
+        let (name, value) = header
+            .split_once(':')
+            .ok_or_else(|| Error::InvalidFormat(s.to_string()))?;
+
+        let name = name.trim();
+        let value = value.trim();
+
+        // Header names are tokens (RFC 9110).
+        if name.is_empty()
+            || !name
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+        {
+            return Err(Error::InvalidName(name.to_string()));
+        }
+
+        if !value
+            .bytes()
+            .all(|byte| byte == b' ' || byte.is_ascii_graphic())
+        {
+            return Err(Error::InvalidValue(value.to_string()));
+        }
+
+        Ok(Self {
+            host_pattern: host_pattern.parse()?,
+            name: name.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+/// Headers that are added to requests depending on the URL's host.
+///
+/// Some CDNs only serve images for requests with specific headers (such as a `Referer`).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HeaderTemplates {
+    templates: Vec<HeaderTemplate>,
+}
+
+impl HeaderTemplates {
+    #[must_use]
+    pub fn new<I: IntoIterator<Item = HeaderTemplate>>(templates: I) -> Self {
+        Self {
+            templates: templates.into_iter().collect(),
+        }
+    }
+
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.templates.is_empty()
+    }
+
+    /// Return the headers for a URL, in the order the templates were provided.
+    ///
+    /// URLs that can't be parsed (or don't have a host) don't have any headers.
+    #[must_use]
+    pub fn headers(&self, url: &str) -> Vec<(&str, &str)> {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string));
+
+        host.map(|host| {
+            self.templates
+                .iter()
+                .filter(|template| template.host_pattern.matches(&host))
+                .map(|template| (template.name.as_str(), template.value.as_str()))
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HeaderTemplate, HeaderTemplates};
+
+    #[test]
+    fn test_headers() -> Result<(), Box<dyn std::error::Error>> {
+        let templates = HeaderTemplates::new([
+            "*.fbcdn.net=Referer: https://www.facebook.com/".parse::<HeaderTemplate>()?,
+            "*.fbcdn.net=Accept: image/webp,*/*".parse()?,
+            "example.com=Referer: https://example.com/".parse()?,
+        ]);
+
+        assert_eq!(
+            templates.headers("https://scontent.xx.FBCDN.net/a.jpg"),
+            vec![
+                ("Referer", "https://www.facebook.com/"),
+                ("Accept", "image/webp,*/*")
+            ]
+        );
+        assert!(templates.headers("https://fbcdn.net/a.jpg").is_empty());
+        assert!(templates.headers("https://notfbcdn.net/a.jpg").is_empty());
+        assert_eq!(
+            templates.headers("https://example.com/a.jpg"),
+            vec![("Referer", "https://example.com/")]
+        );
+        assert!(
+            templates
+                .headers("https://www.example.com/a.jpg")
+                .is_empty()
+        );
+        assert!(templates.headers("not a url").is_empty());
+
+        assert!("example.com".parse::<HeaderTemplate>().is_err());
+        assert!(
+            "example.com=Bad Name: value"
+                .parse::<HeaderTemplate>()
+                .is_err()
+        );
+        assert!("*.=Referer: value".parse::<HeaderTemplate>().is_err());
+
+        Ok(())
+    }
+}
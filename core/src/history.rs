@@ -0,0 +1,119 @@
+use crate::digest::Digest;
+use crate::image_type::ImageType;
+use std::time::SystemTime;
+
+/// The result of the most recent download of a URL.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LastDownload {
+    Downloaded {
+        digest: Digest,
+        image_type: ImageType,
+        timestamp: SystemTime,
+    },
+    Failed {
+        timestamp: SystemTime,
+        status: Option<u16>,
+    },
+}
+
+/// The kind of problem that caused a download to fail.
+///
+/// This distinguishes (for example) hosts that no longer exist from requests that were blocked.
+#[derive(
+    Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// The server responded with an unsuccessful HTTP status
+    Status,
+    /// The host name couldn't be resolved
+    Dns,
+    /// A connection couldn't be made
+    Connect,
+    /// The TLS handshake failed (e.g. because of an invalid certificate)
+    Tls,
+    /// The request timed out
+    Timeout,
+    /// A redirect was invalid (or there were too many)
+    Redirect,
+    /// The response body couldn't be read
+    Body,
+    /// The response wasn't an image (and non-images are rejected)
+    NotAnImage,
+    /// The URL (or an address its host resolved to) isn't allowed by the URL policy
+    Blocked,
+    /// The response was an image that was rejected as corrupt before it was saved
+    Corrupt,
+    Other,
+}
+
+impl FailureKind {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Status => "status",
+            Self::Dns => "dns",
+            Self::Connect => "connect",
+            Self::Tls => "tls",
+            Self::Timeout => "timeout",
+            Self::Redirect => "redirect",
+            Self::Body => "body",
+            Self::NotAnImage => "not_an_image",
+            Self::Blocked => "blocked",
+            Self::Corrupt => "corrupt",
+            Self::Other => "other",
+        }
+    }
+}
+
+impl std::fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for FailureKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "status" => Ok(Self::Status),
+            "dns" => Ok(Self::Dns),
+            "connect" => Ok(Self::Connect),
+            "tls" => Ok(Self::Tls),
+            "timeout" => Ok(Self::Timeout),
+            "redirect" => Ok(Self::Redirect),
+            "body" => Ok(Self::Body),
+            "not_an_image" => Ok(Self::NotAnImage),
+            "blocked" => Ok(Self::Blocked),
+            "corrupt" => Ok(Self::Corrupt),
+            "other" => Ok(Self::Other),
+            other => Err(format!("Invalid failure kind: {other}")),
+        }
+    }
+}
+
+/// A record of previous downloads (such as an index) that is checked before downloading a URL.
+pub trait DownloadHistory: Send + Sync {
+    /// Return the result of the most recent download of a (normalized) URL, if there is one.
+    fn last_download(
+        &self,
+        url: &str,
+    ) -> Result<Option<LastDownload>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Cache validators from a response, which allow later requests for the same URL to be conditional.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Validators {
+    /// The value of the `ETag` header
+    pub etag: Option<String>,
+    /// The value of the `Last-Modified` header
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+}
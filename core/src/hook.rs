@@ -0,0 +1,234 @@
+use crate::digest::Digest;
+use crate::store::Action;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Command failed")]
+    CommandFailed {
+        program: PathBuf,
+        status: std::process::ExitStatus,
+    },
+    #[error("Hook error")]
+    Other(#[from] Box<dyn std::error::Error + Send + Sync>),
+}
+
+/// An action that is run after an image has been saved to the store.
+///
+/// Hooks are run for every successful download, including images that were already in the store
+/// (which can be distinguished with [`Action::added`]).
+pub trait Hook: Send + Sync {
+    fn run(&self, url: &str, action: &Action) -> Result<(), Error>;
+}
+
+impl<F: Fn(&str, &Action) -> Result<(), Error> + Send + Sync> Hook for F {
+    fn run(&self, url: &str, action: &Action) -> Result<(), Error> {
+        self(url, action)
+    }
+}
+
+/// Run an external command with the path of the saved file and the image URL as its final
+/// arguments.
+#[derive(Clone, Debug)]
+pub struct Command {
+    program: PathBuf,
+    args: Vec<String>,
+}
+
+impl Command {
+    pub fn new<P: AsRef<Path>>(program: P) -> Self {
+        Self {
+            program: program.as_ref().to_path_buf(),
+            args: vec![],
+        }
+    }
+
+    #[must_use]
+    pub fn with_args<I: IntoIterator<Item = S>, S: Into<String>>(self, args: I) -> Self {
+        Self {
+            args: args.into_iter().map(Into::into).collect(),
+            ..self
+        }
+    }
+}
+
+impl Hook for Command {
+    fn run(&self, url: &str, action: &Action) -> Result<(), Error> {
+        let status = std::process::Command::new(&self.program)
+            .args(&self.args)
+            .arg(&action.entry.path)
+            .arg(url)
+            .status()?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(Error::CommandFailed {
+                program: self.program.clone(),
+                status,
+            })
+        }
+    }
+}
+
+/// A sequence of hooks that are run in order.
+#[derive(Clone, Default)]
+pub struct Hooks(Vec<Arc<dyn Hook>>);
+
+impl Hooks {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[must_use]
+    pub fn with<H: Hook + 'static>(mut self, hook: H) -> Self {
+        self.0.push(Arc::new(hook));
+        self
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Run every hook, returning the errors for any that failed.
+    ///
+    /// A failure does not prevent later hooks from running.
+    #[must_use]
+    pub fn run(&self, url: &str, action: &Action) -> Vec<Error> {
+        self.0
+            .iter()
+            .filter_map(|hook| hook.run(url, action).err())
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Hooks").field(&self.0.len()).finish()
+    }
+}
+
+type SaveObserver = Arc<dyn Fn(&Action) + Send + Sync>;
+type DeleteObserver = Arc<dyn Fn(Digest) + Send + Sync>;
+
+/// Functions that are called when a store's contents change, whatever changed them (downloads,
+/// imports, quota evictions, deletions, etc.).
+///
+/// Unlike [`Hooks`], observers don't know which URL an image came from and can't fail. They are
+/// called synchronously by the thread that changed the store, so they should be quick (or hand any
+/// slow work off to another thread).
+#[derive(Clone, Default)]
+pub struct Observers {
+    added: Vec<SaveObserver>,
+    found: Vec<SaveObserver>,
+    deleted: Vec<DeleteObserver>,
+}
+
+impl Observers {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a function that is called after a file is added to the store.
+    #[must_use]
+    pub fn on_added<F: Fn(&Action) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.added.push(Arc::new(f));
+        self
+    }
+
+    /// Add a function that is called after a save finds that the file is already in the store.
+    #[must_use]
+    pub fn on_found<F: Fn(&Action) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.found.push(Arc::new(f));
+        self
+    }
+
+    /// Add a function that is called after a file is removed from the store.
+    #[must_use]
+    pub fn on_deleted<F: Fn(Digest) + Send + Sync + 'static>(mut self, f: F) -> Self {
+        self.deleted.push(Arc::new(f));
+        self
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.found.is_empty() && self.deleted.is_empty()
+    }
+
+    pub(crate) fn has_added(&self) -> bool {
+        !self.added.is_empty()
+    }
+
+    /// Call the observers for a saved file (depending on whether it was added).
+    pub(crate) fn saved(&self, action: &Action) {
+        let observers = if action.added {
+            &self.added
+        } else {
+            &self.found
+        };
+
+        for observer in observers {
+            observer(action);
+        }
+    }
+
+    pub(crate) fn deleted(&self, digest: Digest) {
+        for observer in &self.deleted {
+            observer(digest);
+        }
+    }
+}
+
+impl std::fmt::Debug for Observers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Observers")
+            .field("added", &self.added.len())
+            .field("found", &self.found.len())
+            .field("deleted", &self.deleted.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, Hooks};
+    use crate::store::Action;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_hooks() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = crate::store::Store::new(base.path());
+        let action = store.save(b"foo bar baz")?;
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let first_count = count.clone();
+        let second_count = count.clone();
+
+        let hooks = Hooks::new()
+            .with(move |_: &str, _: &Action| {
+                first_count.fetch_add(1, Ordering::SeqCst);
+                Err(Error::Other("failed".into()))
+            })
+            .with(move |url: &str, action: &Action| {
+                assert_eq!(url, "https://example.com/a.txt");
+                assert!(action.added);
+                second_count.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+
+        let errors = hooks.run("https://example.com/a.txt", &action);
+
+        assert_eq!(errors.len(), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+}
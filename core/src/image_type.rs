@@ -3,6 +3,23 @@ use std::borrow::Cow;
 use std::fmt::Display;
 use std::str::FromStr;
 
+/// A function that detects the type of an image from its initial bytes.
+///
+/// Only the first 32 bytes of a file are guaranteed to be provided.
+pub type Detector = fn(&[u8]) -> Option<Type>;
+
+/// Detect the type of an image using `imghdr` (the default [`Detector`]).
+#[must_use]
+pub fn detect(header: &[u8]) -> Option<Type> {
+    // The image type check will fail with an error if there aren't enough bytes (it reads up to
+    // 12 for some formats).
+    if header.len() < 12 {
+        None
+    } else {
+        imghdr::from_bytes(header)
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct ImageType(Option<Type>);
 
@@ -169,6 +186,35 @@ impl serde::ser::Serialize for ImageType {
     }
 }
 
+/// Serde support for image types that are known to be present, using the same representation as
+/// [`ImageType`].
+///
+/// This module is intended to be used with `#[serde(with = "image_scraper::image_type::known")]`.
+pub mod known {
+    use super::ImageType;
+    use imghdr::Type;
+
+    pub fn serialize<S: serde::ser::Serializer>(
+        image_type: &Type,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serde::ser::Serialize::serialize(&ImageType::from(*image_type), serializer)
+    }
+
+    pub fn deserialize<'de, D: serde::de::Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Type, D::Error> {
+        let image_type: ImageType = serde::de::Deserialize::deserialize(deserializer)?;
+
+        image_type.value().ok_or_else(|| {
+            serde::de::Error::invalid_value(
+                serde::de::Unexpected::Str(""),
+                &"a non-empty image extension",
+            )
+        })
+    }
+}
+
 impl<C> bincode::de::Decode<C> for ImageType {
     fn decode<D: bincode::de::Decoder<Context = C>>(
         decoder: &mut D,
@@ -0,0 +1,115 @@
+use crate::digest::DigestKind;
+use std::path::Path;
+
+/// Name of the layout file in a store's base directory.
+pub const FILE_NAME: &str = ".store.toml";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid layout file")]
+    Parse(#[from] toml::de::Error),
+    #[error("Layout serialization error")]
+    Serialize(#[from] toml::ser::Error),
+    #[error("Unsupported layout version")]
+    UnsupportedVersion(u32),
+}
+
+/// A description of how a store's files are arranged, which is recorded in its base directory.
+///
+/// This makes it possible to open a store without inferring its prefix part lengths from its files
+/// (which isn't possible for an empty store).
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Layout {
+    pub version: u32,
+    pub prefix_part_lengths: Vec<usize>,
+    #[serde(default)]
+    pub digest_kind: DigestKind,
+}
+
+impl Layout {
+    pub const CURRENT_VERSION: u32 = 1;
+
+    #[must_use]
+    pub const fn new(prefix_part_lengths: Vec<usize>, digest_kind: DigestKind) -> Self {
+        Self {
+            version: Self::CURRENT_VERSION,
+            prefix_part_lengths,
+            digest_kind,
+        }
+    }
+
+    /// Parse the contents of a layout file.
+    pub fn parse(contents: &str) -> Result<Self, Error> {
+        let layout = toml::from_str::<Self>(contents)?;
+
+        if layout.version > Self::CURRENT_VERSION {
+            Err(Error::UnsupportedVersion(layout.version))
+        } else {
+            Ok(layout)
+        }
+    }
+
+    /// Serialize the layout in the format of a layout file.
+    pub fn to_toml(&self) -> Result<String, Error> {
+        Ok(toml::to_string(self)?)
+    }
+
+    /// Read the layout recorded in the given store directory, if there is one.
+    pub fn read<P: AsRef<Path>>(base: P) -> Result<Option<Self>, Error> {
+        match std::fs::read_to_string(base.as_ref().join(FILE_NAME)) {
+            Ok(contents) => Self::parse(&contents).map(Some),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(Error::from(error)),
+        }
+    }
+
+    /// Record the layout in the given store directory (which is created if necessary), replacing
+    /// any existing layout file.
+    pub fn write<P: AsRef<Path>>(&self, base: P) -> Result<(), Error> {
+        let base = base.as_ref();
+        let contents = self.to_toml()?;
+        let temp_path = base.join(format!("{FILE_NAME}.tmp"));
+
+        std::fs::create_dir_all(base)?;
+        std::fs::write(&temp_path, contents)?;
+        std::fs::rename(temp_path, base.join(FILE_NAME))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "sha256"))]
+mod tests {
+    use super::Layout;
+    use crate::digest::DigestKind;
+
+    #[test]
+    fn test_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+
+        assert_eq!(Layout::read(directory.path())?, None);
+
+        let layout = Layout::new(vec![2, 2], DigestKind::Sha256);
+        layout.write(directory.path())?;
+
+        assert_eq!(Layout::read(directory.path())?, Some(layout));
+
+        let contents = std::fs::read_to_string(directory.path().join(super::FILE_NAME))?;
+
+        assert!(contents.contains("digest_kind = \"sha256\""));
+
+        std::fs::write(
+            directory.path().join(super::FILE_NAME),
+            "version = 2\nprefix_part_lengths = []\n",
+        )?;
+
+        assert!(matches!(
+            Layout::read(directory.path()),
+            Err(super::Error::UnsupportedVersion(2))
+        ));
+
+        Ok(())
+    }
+}
@@ -1,6 +1,31 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery, rust_2018_idioms)]
 #![allow(clippy::missing_errors_doc)]
 #![forbid(unsafe_code)]
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "client")]
 pub mod client;
+pub mod digest;
+#[cfg(feature = "urls")]
+pub mod header_template;
+pub mod history;
+pub mod hook;
 pub mod image_type;
+pub mod layout;
+pub mod manifest;
+pub mod metadata;
+pub mod pack;
+pub mod quarantine;
+pub mod quota;
+pub mod read_cache;
+#[cfg(feature = "urls")]
+pub mod refresh;
+#[cfg(feature = "s3")]
+pub mod s3;
 pub mod store;
+pub mod sync;
+pub mod transform;
+#[cfg(feature = "urls")]
+pub mod url_norm;
+#[cfg(feature = "urls")]
+pub mod url_policy;
@@ -0,0 +1,188 @@
+use crate::digest::Digest;
+use crate::image_type::ImageType;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Name of the manifest file in a store's base directory.
+pub const FILE_NAME: &str = ".manifest";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid manifest line")]
+    InvalidLine { number: usize, line: String },
+}
+
+/// A file in the store, as described by the manifest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Record {
+    pub digest: Digest,
+    pub size: u64,
+    pub image_type: ImageType,
+    pub added_at: SystemTime,
+}
+
+/// A line of the manifest.
+///
+/// Lines are tab-separated, and start with `+` for added files or `-` for removed files. Added
+/// lines include the digest, size in bytes, image type, and time added (in seconds since the
+/// epoch), and removed lines only include the digest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Line {
+    Added(Record),
+    Removed(Digest),
+}
+
+impl Line {
+    fn parse(line: &str) -> Option<Self> {
+        let mut fields = line.split('\t');
+
+        let line = match (fields.next()?, fields.next()?.parse().ok()?) {
+            ("+", digest) => Self::Added(Record {
+                digest,
+                size: fields.next()?.parse().ok()?,
+                image_type: fields.next()?.parse().ok()?,
+                added_at: SystemTime::UNIX_EPOCH
+                    + Duration::from_secs(fields.next()?.parse().ok()?),
+            }),
+            ("-", digest) => Self::Removed(digest),
+            _ => return None,
+        };
+
+        fields.next().is_none().then_some(line)
+    }
+}
+
+impl std::fmt::Display for Line {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Added(record) => write!(
+                f,
+                "+\t{}\t{}\t{}\t{}",
+                record.digest,
+                record.size,
+                record.image_type,
+                record
+                    .added_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            ),
+            Self::Removed(digest) => write!(f, "-\t{digest}"),
+        }
+    }
+}
+
+/// Read a manifest, returning the current records sorted by digest.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<Record>, Error> {
+    let mut records = BTreeMap::new();
+
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+
+        match Line::parse(&line) {
+            Some(Line::Added(record)) => {
+                records.insert(record.digest, record);
+            }
+            Some(Line::Removed(digest)) => {
+                records.remove(&digest);
+            }
+            None => {
+                return Err(Error::InvalidLine {
+                    number: index + 1,
+                    line,
+                });
+            }
+        }
+    }
+
+    Ok(records.into_values().collect())
+}
+
+/// Append a line to a manifest, returning `false` (and doing nothing) if the manifest doesn't
+/// exist.
+pub fn append<P: AsRef<Path>>(path: P, line: Line) -> Result<bool, Error> {
+    match File::options().append(true).open(path) {
+        Ok(mut file) => {
+            // The line is written with a single call so that concurrent appends aren't interleaved.
+            file.write_all(format!("{line}\n").as_bytes())?;
+
+            Ok(true)
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(error) => Err(Error::from(error)),
+    }
+}
+
+/// Replace a manifest with the given records.
+///
+/// The new manifest is written to a temporary file that is then moved into place.
+pub fn write<P: AsRef<Path>, I: IntoIterator<Item = Record>>(
+    path: P,
+    records: I,
+) -> Result<(), Error> {
+    let temp_path = path.as_ref().with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+
+    for record in records {
+        writeln!(writer, "{}", Line::Added(record))?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(std::io::IntoInnerError::into_error)?
+        .sync_all()?;
+    std::fs::rename(temp_path, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Line, Record};
+    use crate::digest::Digest;
+    use crate::image_type::ImageType;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_manifest() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join(super::FILE_NAME);
+
+        let record = |data: &[u8]| Record {
+            digest: Digest::compute(data),
+            size: data.len() as u64,
+            image_type: ImageType::new(Some(imghdr::Type::Png)),
+            added_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+
+        let a = record(b"a");
+        let b = record(b"bb");
+
+        assert!(!super::append(&path, Line::Added(a))?);
+
+        super::write(&path, [a])?;
+
+        assert!(super::append(&path, Line::Added(b))?);
+        assert!(super::append(&path, Line::Removed(a.digest))?);
+
+        assert_eq!(super::read(&path)?, vec![b]);
+        assert_eq!(
+            std::fs::read_to_string(&path)?.lines().last(),
+            Some(format!("-\t{}", a.digest).as_str())
+        );
+
+        std::fs::write(&path, "+\tnot a digest\n")?;
+
+        assert!(matches!(
+            super::read(&path),
+            Err(super::Error::InvalidLine { number: 1, .. })
+        ));
+
+        Ok(())
+    }
+}
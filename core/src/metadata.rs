@@ -0,0 +1,218 @@
+use crate::digest::Digest;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Name of the metadata file in each prefix directory (or the base directory of a flat store).
+pub const FILE_NAME: &str = ".metadata";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid metadata line")]
+    InvalidLine { number: usize, line: String },
+}
+
+/// Where and when a stored file was downloaded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Metadata {
+    pub url: String,
+    pub fetched_at: SystemTime,
+    pub content_type: Option<String>,
+    pub content_length: Option<u64>,
+}
+
+impl Metadata {
+    /// Create metadata for a file fetched now.
+    ///
+    /// The time is truncated to whole seconds, since that's the precision that's recorded.
+    #[must_use]
+    pub fn new(url: &str) -> Self {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Self {
+            url: url.to_string(),
+            fetched_at: SystemTime::UNIX_EPOCH + Duration::from_secs(now.as_secs()),
+            content_type: None,
+            content_length: None,
+        }
+    }
+
+    #[must_use]
+    pub fn with_content_type(self, content_type: Option<&str>) -> Self {
+        Self {
+            content_type: content_type.map(ToString::to_string),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn with_content_length(self, content_length: Option<u64>) -> Self {
+        Self {
+            content_length,
+            ..self
+        }
+    }
+
+    /// Parse a line, returning the digest it describes.
+    ///
+    /// Lines are tab-separated, and include the digest, time fetched (in seconds since the epoch),
+    /// content length and content type (which may be empty), and URL.
+    fn parse(line: &str) -> Option<(Digest, Self)> {
+        let mut fields = line.splitn(5, '\t');
+
+        let digest = fields.next()?.parse().ok()?;
+        let fetched_at = SystemTime::UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+        let content_length = match fields.next()? {
+            "" => None,
+            content_length => Some(content_length.parse().ok()?),
+        };
+        let content_type = Some(fields.next()?)
+            .filter(|content_type| !content_type.is_empty())
+            .map(ToString::to_string);
+        let url = fields.next()?.to_string();
+
+        Some((
+            digest,
+            Self {
+                url,
+                fetched_at,
+                content_type,
+                content_length,
+            },
+        ))
+    }
+
+    fn line(&self, digest: Digest) -> String {
+        // Tabs and newlines would break the format (URLs can't contain them once parsed).
+        let clean = |value: &str| value.replace(['\t', '\n', '\r'], " ");
+
+        format!(
+            "{digest}\t{}\t{}\t{}\t{}\n",
+            self.fetched_at
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            self.content_length
+                .map(|content_length| content_length.to_string())
+                .unwrap_or_default(),
+            self.content_type.as_deref().map(clean).unwrap_or_default(),
+            clean(&self.url)
+        )
+    }
+}
+
+/// Read every record in a metadata file, in the order they were added.
+pub fn read<P: AsRef<Path>>(path: P) -> Result<Vec<(Digest, Metadata)>, Error> {
+    let mut records = vec![];
+
+    for (index, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+
+        match Metadata::parse(&line) {
+            Some(record) => records.push(record),
+            None => {
+                return Err(Error::InvalidLine {
+                    number: index + 1,
+                    line,
+                });
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+/// Find the first record for a digest in a metadata file (which may not exist).
+pub fn find<P: AsRef<Path>>(path: P, digest: Digest) -> Result<Option<Metadata>, Error> {
+    match read(path) {
+        Ok(records) => Ok(records
+            .into_iter()
+            .find_map(|(record_digest, metadata)| (record_digest == digest).then_some(metadata))),
+        Err(Error::Io(error)) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Append a record to a metadata file, creating it if necessary.
+pub fn append<P: AsRef<Path>>(path: P, digest: Digest, metadata: &Metadata) -> Result<(), Error> {
+    let mut file = File::options().create(true).append(true).open(path)?;
+
+    // The line is written with a single call so that concurrent appends aren't interleaved.
+    file.write_all(metadata.line(digest).as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Metadata;
+    use crate::digest::Digest;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join(super::FILE_NAME);
+        let foo = Digest::compute(b"foo");
+        let bar = Digest::compute(b"bar");
+
+        let metadata = Metadata {
+            url: "https://example.com/foo.png?a=b\tc".to_string(),
+            fetched_at: SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            content_type: Some("image/png".to_string()),
+            content_length: Some(3),
+        };
+
+        assert_eq!(super::find(&path, foo)?, None);
+
+        super::append(&path, foo, &metadata)?;
+        super::append(&path, bar, &Metadata::new("https://example.com/bar"))?;
+        super::append(&path, foo, &Metadata::new("https://example.com/other"))?;
+
+        let found = super::find(&path, foo)?.unwrap();
+
+        assert_eq!(found.url, "https://example.com/foo.png?a=b c");
+        assert_eq!(
+            found,
+            Metadata {
+                url: found.url.clone(),
+                ..metadata
+            }
+        );
+
+        let bar_metadata = super::find(&path, bar)?.unwrap();
+
+        assert_eq!(bar_metadata.content_type, None);
+        assert_eq!(bar_metadata.content_length, None);
+        assert_eq!(super::read(&path)?.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let path = directory.path().join(super::FILE_NAME);
+        let foo = Digest::compute(b"foo");
+        let metadata = Metadata::new("https://example.com/foo.png");
+
+        assert_eq!(
+            metadata
+                .fetched_at
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .subsec_nanos(),
+            0
+        );
+
+        super::append(&path, foo, &metadata)?;
+
+        assert_eq!(super::find(&path, foo)?, Some(metadata));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+use crate::digest::Digest;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Name of the directory (in a store's base directory) containing packs.
+pub const DIRECTORY_NAME: &str = ".packs";
+
+/// Size at which a new pack is started.
+const MAX_PACK_LEN: u64 = 256 * 1024 * 1024;
+
+const INDEX_FILE_NAME: &str = "index";
+
+/// Size of an index record (digest, pack number, offset, and length).
+const RECORD_LEN: usize = 32;
+
+/// Pack number used in index records for removed objects.
+const REMOVED: u32 = u32::MAX;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Object too large for a pack")]
+    TooLarge(Digest),
+}
+
+/// The location of an object's contents within a pack file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Span {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl Span {
+    /// Read the object's contents from the pack file.
+    pub fn read<P: AsRef<Path>>(&self, pack_path: P) -> Result<Vec<u8>, std::io::Error> {
+        let mut file = File::open(pack_path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+
+        let mut bytes = vec![0; usize::try_from(self.len).map_err(std::io::Error::other)?];
+        file.read_exact(&mut bytes)?;
+
+        Ok(bytes)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Location {
+    pack: u32,
+    span: Span,
+}
+
+#[derive(Debug)]
+struct State {
+    locations: HashMap<Digest, Location>,
+    current_pack: u32,
+    current_len: u64,
+}
+
+/// Append-only storage for small objects, which avoids using a file (and inode) for each one.
+///
+/// Objects are appended to numbered pack files, and their locations are recorded in an append-only
+/// index that is loaded into memory when the packs are opened. Space used by removed objects is
+/// not reclaimed.
+#[derive(Clone, Debug)]
+pub struct Packs {
+    directory: PathBuf,
+    threshold: u64,
+    state: Arc<Mutex<State>>,
+}
+
+impl Packs {
+    /// Open (or create) the packs in a directory.
+    ///
+    /// Only objects smaller than the threshold (in bytes) are packed.
+    pub fn open<P: AsRef<Path>>(directory: P, threshold: u64) -> Result<Self, Error> {
+        let directory = directory.as_ref().to_path_buf();
+        std::fs::create_dir_all(&directory)?;
+
+        let index_bytes = match std::fs::read(directory.join(INDEX_FILE_NAME)) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(error) => return Err(Error::from(error)),
+        };
+
+        let mut locations = HashMap::new();
+        let mut current_pack = 0;
+
+        // A partial record at the end (from an interrupted write) is ignored.
+        for record in index_bytes.chunks_exact(RECORD_LEN) {
+            let (digest, location) = decode_record(record);
+
+            if location.pack == REMOVED {
+                locations.remove(&digest);
+            } else {
+                current_pack = current_pack.max(location.pack);
+                locations.insert(digest, location);
+            }
+        }
+
+        let current_len = match std::fs::metadata(pack_path(&directory, current_pack)) {
+            Ok(metadata) => metadata.len(),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(error) => return Err(Error::from(error)),
+        };
+
+        Ok(Self {
+            directory,
+            threshold,
+            state: Arc::new(Mutex::new(State {
+                locations,
+                current_pack,
+                current_len,
+            })),
+        })
+    }
+
+    #[must_use]
+    pub const fn threshold(&self) -> u64 {
+        self.threshold
+    }
+
+    /// Check whether an object of the given size should be packed.
+    #[must_use]
+    pub const fn accepts(&self, len: u64) -> bool {
+        len < self.threshold
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Return the pack file path and span for an object, if it is packed.
+    #[must_use]
+    pub fn locate(&self, digest: Digest) -> Option<(PathBuf, Span)> {
+        self.state()
+            .locations
+            .get(&digest)
+            .map(|location| (pack_path(&self.directory, location.pack), location.span))
+    }
+
+    pub fn read(&self, digest: Digest) -> Result<Option<Vec<u8>>, Error> {
+        self.locate(digest)
+            .map(|(path, span)| span.read(path))
+            .transpose()
+            .map_err(Error::from)
+    }
+
+    /// Add an object, returning `false` if it was already packed.
+    pub fn put(&self, digest: Digest, bytes: &[u8]) -> Result<bool, Error> {
+        let len = bytes.len() as u64;
+
+        if len >= MAX_PACK_LEN {
+            return Err(Error::TooLarge(digest));
+        }
+
+        let mut state = self.state();
+
+        if state.locations.contains_key(&digest) {
+            return Ok(false);
+        }
+
+        if state.current_len + len > MAX_PACK_LEN {
+            state.current_pack += 1;
+            state.current_len = 0;
+        }
+
+        let location = Location {
+            pack: state.current_pack,
+            span: Span {
+                offset: state.current_len,
+                len,
+            },
+        };
+
+        let mut pack = File::options()
+            .create(true)
+            .append(true)
+            .open(pack_path(&self.directory, location.pack))?;
+        pack.write_all(bytes)?;
+        pack.sync_data()?;
+
+        self.append_record(digest, location)?;
+
+        state.current_len += len;
+        state.locations.insert(digest, location);
+        drop(state);
+
+        Ok(true)
+    }
+
+    /// Remove an object, returning whether it was packed.
+    pub fn remove(&self, digest: Digest) -> Result<bool, Error> {
+        let mut state = self.state();
+
+        if state.locations.contains_key(&digest) {
+            self.append_record(
+                digest,
+                Location {
+                    pack: REMOVED,
+                    span: Span { offset: 0, len: 0 },
+                },
+            )?;
+
+            state.locations.remove(&digest);
+            drop(state);
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// List the packed objects, sorted by digest.
+    #[must_use]
+    pub fn list(&self) -> Vec<(Digest, PathBuf, Span)> {
+        let mut objects = self
+            .state()
+            .locations
+            .iter()
+            .map(|(digest, location)| {
+                (
+                    *digest,
+                    pack_path(&self.directory, location.pack),
+                    location.span,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        objects.sort_by_key(|(digest, _, _)| *digest);
+
+        objects
+    }
+
+    fn append_record(&self, digest: Digest, location: Location) -> Result<(), std::io::Error> {
+        let mut index = File::options()
+            .create(true)
+            .append(true)
+            .open(self.directory.join(INDEX_FILE_NAME))?;
+
+        index.write_all(&encode_record(digest, location))?;
+        index.sync_data()
+    }
+}
+
+fn pack_path(directory: &Path, pack: u32) -> PathBuf {
+    directory.join(format!("{pack:08}.pack"))
+}
+
+fn encode_record(digest: Digest, location: Location) -> [u8; RECORD_LEN] {
+    let mut record = [0; RECORD_LEN];
+    // Lengths are always less than the maximum pack size, so this will never truncate.
+    let len = u32::try_from(location.span.len).unwrap_or(u32::MAX);
+
+    record[0..16].copy_from_slice(digest.as_bytes());
+    record[16..20].copy_from_slice(&location.pack.to_be_bytes());
+    record[20..28].copy_from_slice(&location.span.offset.to_be_bytes());
+    record[28..32].copy_from_slice(&len.to_be_bytes());
+
+    record
+}
+
+fn decode_record(record: &[u8]) -> (Digest, Location) {
+    let mut digest = [0; 16];
+    let mut pack = [0; 4];
+    let mut offset = [0; 8];
+    let mut len = [0; 4];
+
+    digest.copy_from_slice(&record[0..16]);
+    pack.copy_from_slice(&record[16..20]);
+    offset.copy_from_slice(&record[20..28]);
+    len.copy_from_slice(&record[28..32]);
+
+    (
+        Digest::from_bytes(digest),
+        Location {
+            pack: u32::from_be_bytes(pack),
+            span: Span {
+                offset: u64::from_be_bytes(offset),
+                len: u64::from(u32::from_be_bytes(len)),
+            },
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Packs;
+    use crate::digest::Digest;
+
+    #[test]
+    fn test_packs() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let a = b"foo".to_vec();
+        let b = b"bar baz".to_vec();
+
+        {
+            let packs = Packs::open(directory.path(), 1024)?;
+
+            assert!(packs.put(Digest::compute(&a), &a)?);
+            assert!(packs.put(Digest::compute(&b), &b)?);
+            assert!(!packs.put(Digest::compute(&a), &a)?);
+            assert!(packs.remove(Digest::compute(&a))?);
+            assert!(!packs.remove(Digest::compute(&a))?);
+        }
+
+        // The index should survive reopening.
+        let packs = Packs::open(directory.path(), 1024)?;
+
+        assert_eq!(packs.read(Digest::compute(&a))?, None);
+        assert_eq!(packs.read(Digest::compute(&b))?, Some(b));
+        assert_eq!(packs.list().len(), 1);
+
+        assert!(packs.put(Digest::compute(&a), &a)?);
+        assert_eq!(packs.read(Digest::compute(&a))?, Some(a));
+
+        Ok(())
+    }
+}
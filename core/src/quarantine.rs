@@ -0,0 +1,219 @@
+use crate::digest::{Digest, DigestKind};
+use crate::store::Error;
+use std::fmt::Display;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Number of initial bytes read when classifying a quarantined file.
+const SNIFF_LEN: u64 = 512;
+
+/// A rough classification of a payload that isn't an image.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PayloadKind {
+    Html,
+    Json,
+    Text,
+    Binary,
+}
+
+impl PayloadKind {
+    /// Classify a payload from its initial bytes.
+    #[must_use]
+    pub fn detect(bytes: &[u8]) -> Self {
+        let trimmed = bytes.trim_ascii_start();
+        let lowercase = trimmed
+            .iter()
+            .take(16)
+            .map(u8::to_ascii_lowercase)
+            .collect::<Vec<_>>();
+
+        if lowercase.starts_with(b"<!doctype html") || lowercase.starts_with(b"<html") {
+            Self::Html
+        } else if trimmed.starts_with(b"{") || trimmed.starts_with(b"[") {
+            Self::Json
+        } else if is_text(bytes) {
+            Self::Text
+        } else {
+            Self::Binary
+        }
+    }
+
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Html => "html",
+            Self::Json => "json",
+            Self::Text => "text",
+            Self::Binary => "binary",
+        }
+    }
+}
+
+impl Display for PayloadKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Check whether bytes are UTF-8 (allowing a truncated character at the end).
+const fn is_text(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(error) => error.error_len().is_none(),
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QuarantinedFile {
+    pub path: PathBuf,
+    pub digest: Digest,
+    pub size: u64,
+    pub modified: SystemTime,
+    pub kind: PayloadKind,
+}
+
+/// A directory of payloads that were downloaded but not recognized as images.
+///
+/// See [`crate::store::NonImagePolicy::Quarantine`].
+#[derive(Clone, Debug)]
+pub struct Quarantine {
+    base: PathBuf,
+    digest_kind: DigestKind,
+}
+
+impl Quarantine {
+    pub fn new<P: AsRef<Path>>(base: P) -> Self {
+        Self {
+            base: base.as_ref().to_path_buf(),
+            digest_kind: DigestKind::default(),
+        }
+    }
+
+    /// Set the digest kind used by the store the files were quarantined from.
+    #[must_use]
+    pub fn with_digest_kind(self, digest_kind: DigestKind) -> Self {
+        Self {
+            digest_kind,
+            ..self
+        }
+    }
+
+    /// List the quarantined files, sorted by digest.
+    ///
+    /// The list is empty if the directory doesn't exist.
+    pub fn files(&self) -> Result<Vec<QuarantinedFile>, Error> {
+        if !self.base.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut files = vec![];
+
+        for entry in std::fs::read_dir(&self.base)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            let digest = entry
+                .file_name()
+                .to_str()
+                .and_then(|file_name| Digest::from_hex(self.digest_kind, file_name).ok())
+                .ok_or_else(|| Error::InvalidFileName(path.clone()))?;
+
+            let metadata = entry.metadata()?;
+            let mut header = vec![];
+            std::fs::File::open(&path)?
+                .take(SNIFF_LEN)
+                .read_to_end(&mut header)?;
+
+            files.push(QuarantinedFile {
+                path,
+                digest,
+                size: metadata.len(),
+                modified: metadata.modified()?,
+                kind: PayloadKind::detect(&header),
+            });
+        }
+
+        files.sort_by_key(|file| file.digest);
+
+        Ok(files)
+    }
+
+    /// Remove quarantined files, optionally only those last modified before the given age.
+    ///
+    /// Returns the removed files.
+    pub fn purge(&self, older_than: Option<Duration>) -> Result<Vec<QuarantinedFile>, Error> {
+        let now = SystemTime::now();
+        let mut removed = vec![];
+
+        for file in self.files()? {
+            let age = now.duration_since(file.modified).unwrap_or_default();
+
+            if older_than.is_none_or(|older_than| age >= older_than) {
+                std::fs::remove_file(&file.path)?;
+                removed.push(file);
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PayloadKind, Quarantine};
+    use crate::store::{Error, NonImagePolicy, Store};
+    use std::time::Duration;
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            PayloadKind::detect(b"\n  <!DOCTYPE html><html></html>"),
+            PayloadKind::Html
+        );
+        assert_eq!(PayloadKind::detect(b"<HTML>"), PayloadKind::Html);
+        assert_eq!(PayloadKind::detect(b" {\"error\": 1}"), PayloadKind::Json);
+        assert_eq!(PayloadKind::detect(b"Not found"), PayloadKind::Text);
+        assert_eq!(
+            PayloadKind::detect(&[0, 159, 146, 150]),
+            PayloadKind::Binary
+        );
+    }
+
+    #[test]
+    fn test_quarantine() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let quarantine_base = base.path().join(".quarantine");
+
+        let store = Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_non_image_policy(NonImagePolicy::Quarantine(quarantine_base.clone()));
+
+        let action = store.save(b"<html>Not found</html>")?;
+
+        assert!(action.quarantined);
+        assert!(action.entry.path.starts_with(&quarantine_base));
+        assert_eq!(store.entries().count(), 0);
+
+        let quarantine = Quarantine::new(&quarantine_base);
+        let files = quarantine.files()?;
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].digest, action.entry.digest);
+        assert_eq!(files[0].kind, PayloadKind::Html);
+
+        assert!(quarantine.purge(Some(Duration::from_hours(1)))?.is_empty());
+        assert_eq!(quarantine.purge(None)?, files);
+        assert!(quarantine.files()?.is_empty());
+
+        let store = store.with_non_image_policy(NonImagePolicy::Reject);
+
+        assert!(matches!(
+            store.save(b"Not found"),
+            Err(Error::NotAnImage(digest)) if digest == crate::digest::Digest::compute(b"Not found")
+        ));
+        assert_eq!(store.entries().count(), 0);
+
+        Ok(())
+    }
+}
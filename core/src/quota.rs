@@ -0,0 +1,210 @@
+use crate::digest::Digest;
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+/// How files are chosen for eviction when saving a file would put a store over its [`Quota`].
+#[derive(Clone, Default)]
+pub enum Eviction {
+    /// Evict the least recently accessed files first (by modification time if the file system
+    /// doesn't record access times).
+    #[default]
+    LeastRecentlyUsed,
+    /// Evict the least recently saved files first (by modification time).
+    OldestModified,
+    /// Evict files in order of the time returned for their digests (e.g. when they were last
+    /// downloaded, according to an index), with files that have no time first.
+    ByTimestamp(Arc<dyn Fn(Digest) -> Option<SystemTime> + Send + Sync>),
+}
+
+impl Debug for Eviction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LeastRecentlyUsed => f.write_str("LeastRecentlyUsed"),
+            Self::OldestModified => f.write_str("OldestModified"),
+            Self::ByTimestamp(_) => f.write_str("ByTimestamp(..)"),
+        }
+    }
+}
+
+impl std::str::FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lru" => Ok(Self::LeastRecentlyUsed),
+            "oldest" => Ok(Self::OldestModified),
+            other => Err(format!("Invalid eviction policy: {other}")),
+        }
+    }
+}
+
+/// The number of files in a store and their total size.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Usage {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct State {
+    /// The store's current usage (computed when it's first needed)
+    pub(crate) usage: Option<Usage>,
+    /// Files that can be evicted, with their sizes, in reverse order of eviction (so that the next
+    /// one can be popped from the end)
+    pub(crate) candidates: Vec<(Digest, u64)>,
+}
+
+/// Limits on the number of files in a store and their total size.
+///
+/// When saving a new file would exceed a limit, files are evicted (according to the [`Eviction`]
+/// policy) first. The store's usage is computed by walking it when it's first needed, and the
+/// eviction order is computed by walking it again whenever the previous order has been used up
+/// (so files saved after it was computed aren't evicted until then). Clones share the same state,
+/// but it isn't locked while the store is walked or files are evicted.
+#[derive(Clone, Debug, Default)]
+pub struct Quota {
+    max_bytes: Option<u64>,
+    max_files: Option<u64>,
+    eviction: Eviction,
+    state: Arc<Mutex<State>>,
+}
+
+impl Quota {
+    #[must_use]
+    pub fn new(eviction: Eviction) -> Self {
+        Self {
+            eviction,
+            ..Self::default()
+        }
+    }
+
+    /// Limit the total size of the files in the store (in bytes).
+    #[must_use]
+    pub fn with_max_bytes(self, max_bytes: u64) -> Self {
+        Self {
+            max_bytes: Some(max_bytes),
+            ..self
+        }
+    }
+
+    /// Limit the number of files in the store.
+    #[must_use]
+    pub fn with_max_files(self, max_files: u64) -> Self {
+        Self {
+            max_files: Some(max_files),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub const fn eviction(&self) -> &Eviction {
+        &self.eviction
+    }
+
+    /// The store's usage, if it has been computed.
+    #[must_use]
+    pub fn usage(&self) -> Option<Usage> {
+        self.state().usage
+    }
+
+    /// Whether a file of the given size could ever be saved within the limits.
+    pub(crate) fn fits(&self, size: u64) -> bool {
+        self.max_files.is_none_or(|max_files| max_files > 0)
+            && self.max_bytes.is_none_or(|max_bytes| size <= max_bytes)
+    }
+
+    /// Whether adding a file of the given size would exceed a limit.
+    pub(crate) fn exceeded_by(&self, usage: Usage, size: u64) -> bool {
+        self.max_files
+            .is_some_and(|max_files| usage.files + 1 > max_files)
+            || self
+                .max_bytes
+                .is_some_and(|max_bytes| usage.bytes + size > max_bytes)
+    }
+
+    /// Record that a file was removed from the store.
+    pub(crate) fn release(&self, size: u64) {
+        let mut state = self.state();
+
+        if let Some(usage) = &mut state.usage {
+            usage.files = usage.files.saturating_sub(1);
+            usage.bytes = usage.bytes.saturating_sub(size);
+        }
+    }
+
+    pub(crate) fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Room reserved for a new file within a [`Quota`], which is given back when it's dropped (e.g. on
+/// an error) unless the file was added.
+#[must_use]
+pub(crate) struct Reservation<'a> {
+    quota: Option<&'a Quota>,
+    size: u64,
+    evicted: Vec<Digest>,
+}
+
+impl<'a> Reservation<'a> {
+    pub(crate) const fn new(quota: &'a Quota, size: u64, evicted: Vec<Digest>) -> Self {
+        Self {
+            quota: Some(quota),
+            size,
+            evicted,
+        }
+    }
+
+    /// No room was reserved (because there's no quota, or the file doesn't count toward it).
+    pub(crate) const fn none() -> Self {
+        Self {
+            quota: None,
+            size: 0,
+            evicted: vec![],
+        }
+    }
+
+    /// Keep the room if the file was added (or give it back if not), returning the files that were
+    /// evicted to make it.
+    pub(crate) fn settle(mut self, added: bool) -> Vec<Digest> {
+        if added {
+            self.quota = None;
+        }
+
+        std::mem::take(&mut self.evicted)
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(quota) = self.quota {
+            quota.release(self.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Eviction, Quota, Usage};
+
+    #[test]
+    fn test_limits() {
+        let quota = Quota::new(Eviction::OldestModified)
+            .with_max_bytes(100)
+            .with_max_files(2);
+        let usage = Usage {
+            files: 1,
+            bytes: 60,
+        };
+
+        assert!(quota.fits(100));
+        assert!(!quota.fits(101));
+        assert!(!quota.exceeded_by(usage, 40));
+        assert!(quota.exceeded_by(usage, 41));
+        assert!(quota.exceeded_by(Usage { files: 2, bytes: 0 }, 0));
+        assert!(!Quota::default().exceeded_by(usage, u64::MAX / 2));
+        assert!("lru".parse::<Eviction>().is_ok());
+        assert!("newest".parse::<Eviction>().is_err());
+    }
+}
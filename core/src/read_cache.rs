@@ -0,0 +1,223 @@
+use crate::digest::Digest;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, PoisonError};
+
+#[derive(Debug)]
+struct State<K> {
+    contents: HashMap<K, (Arc<[u8]>, u64)>,
+    /// Keys ordered by their most recent use
+    order: BTreeMap<u64, K>,
+    next_use: u64,
+    len: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl<K> Default for State<K> {
+    fn default() -> Self {
+        Self {
+            contents: HashMap::new(),
+            order: BTreeMap::new(),
+            next_use: 0,
+            len: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> State<K> {
+    fn touch(&mut self, key: &K) -> Option<Arc<[u8]>> {
+        let next_use = self.next_use;
+        let (contents, last_use) = self.contents.get_mut(key)?;
+
+        self.order.remove(last_use);
+        self.order.insert(next_use, key.clone());
+        *last_use = next_use;
+        self.next_use += 1;
+
+        Some(contents.clone())
+    }
+
+    fn remove(&mut self, key: &K) -> bool {
+        match self.contents.remove(key) {
+            Some((contents, last_use)) => {
+                self.order.remove(&last_use);
+                self.len -= contents.len();
+
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Counts describing the use of a [`ReadCache`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ReadCacheStats {
+    pub entries: usize,
+    /// Total size of the cached contents in bytes
+    pub len: usize,
+    pub hits: u64,
+    pub misses: u64,
+    /// Number of entries removed to make space for others
+    pub evictions: u64,
+}
+
+/// An in-memory cache of file contents, keyed by digest, that holds up to a given number of bytes.
+///
+/// When the cache is full, the least recently used contents are evicted. Only files up to a maximum
+/// size are cached, so that a few large files don't evict many small ones. Clones share the same
+/// cache.
+///
+/// Other keys can be used for contents derived from files (such as resized images, which could be
+/// keyed by digest and size).
+#[derive(Debug)]
+pub struct ReadCache<K = Digest> {
+    capacity: usize,
+    max_entry_len: usize,
+    state: Arc<Mutex<State<K>>>,
+}
+
+impl<K> Clone for ReadCache<K> {
+    fn clone(&self) -> Self {
+        Self {
+            capacity: self.capacity,
+            max_entry_len: self.max_entry_len,
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash> ReadCache<K> {
+    /// Create a cache holding up to `capacity` bytes, of files up to `max_entry_len` bytes.
+    #[must_use]
+    pub fn new(capacity: usize, max_entry_len: usize) -> Self {
+        Self {
+            capacity,
+            max_entry_len: max_entry_len.min(capacity),
+            state: Arc::new(Mutex::new(State::default())),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State<K>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Check whether a file of the given size would be cached.
+    #[must_use]
+    pub fn accepts(&self, len: u64) -> bool {
+        usize::try_from(len).is_ok_and(|len| len <= self.max_entry_len)
+    }
+
+    /// Return the cached contents for a key, marking them as recently used.
+    #[must_use]
+    pub fn get(&self, key: &K) -> Option<Arc<[u8]>> {
+        let mut state = self.state();
+        let contents = state.touch(key);
+
+        if contents.is_some() {
+            state.hits += 1;
+        } else {
+            state.misses += 1;
+        }
+
+        contents
+    }
+
+    /// Add contents to the cache (if they aren't too large), evicting others as necessary.
+    pub fn insert(&self, key: K, contents: Arc<[u8]>) {
+        if !self.accepts(contents.len() as u64) {
+            return;
+        }
+
+        let mut state = self.state();
+        state.remove(&key);
+
+        while state.len + contents.len() > self.capacity {
+            let Some((_, evicted)) = state.order.pop_first() else {
+                break;
+            };
+
+            if let Some((evicted_contents, _)) = state.contents.remove(&evicted) {
+                state.len -= evicted_contents.len();
+                state.evictions += 1;
+            }
+        }
+
+        let next_use = state.next_use;
+        state.len += contents.len();
+        state.order.insert(next_use, key.clone());
+        state.contents.insert(key, (contents, next_use));
+        state.next_use += 1;
+    }
+
+    /// Remove the contents for a key (for example when the file is deleted).
+    pub fn remove(&self, key: &K) -> bool {
+        self.state().remove(key)
+    }
+
+    #[must_use]
+    pub fn stats(&self) -> ReadCacheStats {
+        let state = self.state();
+
+        ReadCacheStats {
+            entries: state.contents.len(),
+            len: state.len,
+            hits: state.hits,
+            misses: state.misses,
+            evictions: state.evictions,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReadCache, ReadCacheStats};
+    use crate::digest::Digest;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_read_cache() {
+        let cache = ReadCache::new(10, 4);
+        let a = Digest::compute(b"a");
+        let b = Digest::compute(b"b");
+        let c = Digest::compute(b"c");
+        let d = Digest::compute(b"d");
+
+        cache.insert(a, Arc::from(&b"aaaa"[..]));
+        cache.insert(b, Arc::from(&b"bbbb"[..]));
+
+        // Using the first entry means that the second is evicted first.
+        assert_eq!(cache.get(&a).as_deref(), Some(&b"aaaa"[..]));
+
+        cache.insert(c, Arc::from(&b"cc"[..]));
+        cache.insert(d, Arc::from(&b"dd"[..]));
+
+        assert_eq!(cache.get(&b), None);
+        assert_eq!(cache.get(&c).as_deref(), Some(&b"cc"[..]));
+        assert_eq!(cache.get(&d).as_deref(), Some(&b"dd"[..]));
+
+        // Contents that are too large aren't cached.
+        cache.insert(b, Arc::from(&b"bbbbb"[..]));
+        assert_eq!(cache.get(&b), None);
+
+        assert!(cache.remove(&a));
+        assert!(!cache.remove(&a));
+        assert_eq!(cache.get(&a), None);
+
+        assert_eq!(
+            cache.stats(),
+            ReadCacheStats {
+                entries: 2,
+                len: 4,
+                hits: 3,
+                misses: 3,
+                evictions: 1,
+            }
+        );
+    }
+}
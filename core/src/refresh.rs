@@ -0,0 +1,156 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// A maximum age for images from a domain (and its subdomains).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DomainMaxAge {
+    pub domain: String,
+    pub max_age: Duration,
+}
+
+impl FromStr for DomainMaxAge {
+    type Err = String;
+
+    /// Parse a domain and a number of seconds (e.g. `example.com=3600`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (domain, seconds) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected DOMAIN=SECONDS: {s}"))?;
+
+        if domain.is_empty() {
+            return Err(format!("Missing domain: {s}"));
+        }
+
+        let seconds = seconds
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid number of seconds: {seconds}"))?;
+
+        Ok(Self {
+            domain: domain.to_ascii_lowercase(),
+            max_age: Duration::from_secs(seconds),
+        })
+    }
+}
+
+/// Determines when a downloaded image is old enough that it should be downloaded again.
+///
+/// This is useful for images that change in place (e.g. avatars or logos). Domain-specific ages
+/// apply to the domain and its subdomains (the most specific domain is used), and otherwise the
+/// global age applies. By default images are never refreshed.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RefreshPolicy {
+    max_age: Option<Duration>,
+    domain_max_ages: BTreeMap<String, Duration>,
+}
+
+impl RefreshPolicy {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the age after which images from any domain without a specific age are refreshed.
+    #[must_use]
+    pub fn with_max_age(self, max_age: Duration) -> Self {
+        Self {
+            max_age: Some(max_age),
+            ..self
+        }
+    }
+
+    /// Set the age after which images from the given domain (and its subdomains) are refreshed.
+    #[must_use]
+    pub fn with_domain_max_age(mut self, domain_max_age: DomainMaxAge) -> Self {
+        self.domain_max_ages
+            .insert(domain_max_age.domain, domain_max_age.max_age);
+        self
+    }
+
+    /// Check whether the policy ever refreshes images.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.max_age.is_none() && self.domain_max_ages.is_empty()
+    }
+
+    /// Return the maximum age for images from the given URL, if they should be refreshed.
+    #[must_use]
+    pub fn max_age(&self, url: &str) -> Option<Duration> {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_ascii_lowercase));
+
+        host.and_then(|host| {
+            self.domain_max_ages
+                .iter()
+                .filter(|(domain, _)| {
+                    host.strip_suffix(domain.as_str())
+                        .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+                })
+                .max_by_key(|(domain, _)| domain.len())
+                .map(|(_, max_age)| *max_age)
+        })
+        .or(self.max_age)
+    }
+
+    /// Check whether an image downloaded from the URL at the given time should be refreshed.
+    #[must_use]
+    pub fn is_stale(&self, url: &str, downloaded_at: SystemTime, now: SystemTime) -> bool {
+        self.max_age(url)
+            .is_some_and(|max_age| now.duration_since(downloaded_at).unwrap_or_default() >= max_age)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DomainMaxAge, RefreshPolicy};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_refresh_policy() -> Result<(), Box<dyn std::error::Error>> {
+        let policy = RefreshPolicy::new()
+            .with_max_age(Duration::from_hours(24))
+            .with_domain_max_age("example.com=3600".parse()?)
+            .with_domain_max_age("avatars.example.com=60".parse()?);
+
+        assert_eq!(
+            policy.max_age("https://example.com/a.png"),
+            Some(Duration::from_hours(1))
+        );
+        assert_eq!(
+            policy.max_age("https://cdn.Example.com/a.png"),
+            Some(Duration::from_hours(1))
+        );
+        assert_eq!(
+            policy.max_age("https://avatars.example.com/a.png"),
+            Some(Duration::from_mins(1))
+        );
+        assert_eq!(
+            policy.max_age("https://notexample.com/a.png"),
+            Some(Duration::from_hours(24))
+        );
+
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+
+        assert!(policy.is_stale(
+            "https://avatars.example.com/a.png",
+            now - Duration::from_mins(1),
+            now
+        ));
+        assert!(!policy.is_stale(
+            "https://example.com/a.png",
+            now - Duration::from_mins(1),
+            now
+        ));
+        assert!(!RefreshPolicy::new().is_stale(
+            "https://example.com/a.png",
+            SystemTime::UNIX_EPOCH,
+            now
+        ));
+
+        assert!("example.com".parse::<DomainMaxAge>().is_err());
+        assert!("example.com=soon".parse::<DomainMaxAge>().is_err());
+
+        Ok(())
+    }
+}
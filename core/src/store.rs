@@ -1,10 +1,35 @@
-use crate::image_type::ImageType;
-use hex::FromHex;
+use crate::digest::{Digest, DigestKind, Hasher};
+use crate::hook::Observers;
+use crate::image_type::{Detector, ImageType};
+use crate::layout::Layout;
+use crate::manifest::{Line, Record};
+use crate::metadata::Metadata;
+use crate::pack::{Packs, Span as PackSpan};
+use crate::quota::{Eviction, Quota, Reservation, Usage};
+use crate::read_cache::ReadCache;
+use crate::transform::{Pipeline, Transformation};
 use imghdr::Type;
-use md5::Digest;
+use std::collections::BTreeMap;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+#[cfg(feature = "tracing")]
+use tracing::{Span, field::Empty};
+
+/// Extension added to the names of files that are stored compressed.
+pub const COMPRESSED_EXTENSION: &str = "zst";
+
+/// Number of initial bytes retained by [`Writer`] for image type detection.
+const HEADER_LEN: usize = 32;
+
+/// Maximum size of a zstd frame header (only exposed by `zstd` as an experimental constant).
+#[cfg(feature = "compression")]
+const ZSTD_FRAME_HEADER_MAX_LEN: usize = 18;
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -18,12 +43,54 @@ pub enum Error {
     UnexpectedDigest { expected: Digest, actual: Digest },
     #[error("Iteration error")]
     Iteration(#[from] IterationError),
+    #[error("Not an image")]
+    NotAnImage(Digest),
+    #[error("Manifest error")]
+    Manifest(#[from] crate::manifest::Error),
+    #[error("Metadata error")]
+    Metadata(#[from] crate::metadata::Error),
+    #[error("Transformation error")]
+    Transform(#[from] crate::transform::Error),
+    #[error("Pack error")]
+    Pack(#[from] crate::pack::Error),
+    #[error("Initialization error")]
+    Initialization(#[from] InitializationError),
+    #[error("Packed file")]
+    Packed(Digest),
+    #[error("Compressed file")]
+    Compressed(Digest),
+    #[error("Quota exceeded")]
+    QuotaExceeded(Digest),
+    #[error("Invalid digest kind")]
+    InvalidDigestKind(#[from] crate::digest::KindParseError),
+    #[error("Digest kind mismatch")]
+    DigestKindMismatch {
+        expected: DigestKind,
+        found: DigestKind,
+    },
+    #[error("Unsupported digest kind")]
+    UnsupportedDigestKind(DigestKind),
+    #[error("Layout error")]
+    Layout(#[from] crate::layout::Error),
+    #[error("Layout mismatch")]
+    LayoutMismatch { expected: Layout, found: Layout },
+    #[cfg(feature = "archive")]
+    #[error("Archive error")]
+    Archive(#[from] crate::archive::Error),
+    #[cfg(feature = "s3")]
+    #[error("S3 error")]
+    S3(#[from] crate::s3::Error),
+    #[cfg(feature = "client")]
+    #[error("Blocking task failed")]
+    Task(#[from] tokio::task::JoinError),
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum InitializationError {
     #[error("Invalid prefix part lengths")]
     InvalidPrefixPartLengths(Vec<usize>),
+    #[error("Invalid prefix range")]
+    InvalidPrefixRange { start: String, end: String },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -38,18 +105,75 @@ pub enum IterationError {
     ExpectedFile(PathBuf),
     #[error("Hex parse error")]
     Hex(#[from] hex::FromHexError),
+    #[error("Manifest error")]
+    Manifest(#[from] crate::manifest::Error),
+    #[cfg(feature = "s3")]
+    #[error("S3 error")]
+    S3(#[from] crate::s3::Error),
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct Entry {
+    /// The file containing the contents (a pack file for packed entries)
     pub path: PathBuf,
     pub digest: Digest,
+    /// The location of the contents within the pack file, for packed entries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub packed: Option<PackSpan>,
+    /// Whether the file is zstd-compressed (the digest is always for the original contents)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub compressed: bool,
 }
 
 impl Entry {
+    #[must_use]
+    pub const fn file(path: PathBuf, digest: Digest) -> Self {
+        Self {
+            path,
+            digest,
+            packed: None,
+            compressed: false,
+        }
+    }
+
+    #[must_use]
+    pub const fn compressed_file(path: PathBuf, digest: Digest) -> Self {
+        Self {
+            path,
+            digest,
+            packed: None,
+            compressed: true,
+        }
+    }
+
+    /// Read the (decompressed) contents of the entry.
+    pub fn read(&self) -> Result<Vec<u8>, std::io::Error> {
+        match self.packed {
+            Some(span) => span.read(&self.path),
+            None if self.compressed => {
+                let mut bytes = vec![];
+                decompress(File::open(&self.path)?)?.read_to_end(&mut bytes)?;
+                Ok(bytes)
+            }
+            None => std::fs::read(&self.path),
+        }
+    }
+
+    /// Return the size of the (decompressed) contents in bytes.
+    pub fn size(&self) -> Result<u64, std::io::Error> {
+        match self.packed {
+            Some(span) => Ok(span.len),
+            None if self.compressed => match compressed_content_size(&self.path) {
+                Some(size) => Ok(size),
+                None => Ok(self.read()?.len() as u64),
+            },
+            None => Ok(std::fs::metadata(&self.path)?.len()),
+        }
+    }
+
     pub fn validate(&self) -> Result<Result<(), Digest>, std::io::Error> {
-        let bytes = std::fs::read(&self.path)?;
-        let digest = md5::compute(&bytes);
+        let bytes = self.read()?;
+        let digest = self.digest.kind().compute(&bytes);
 
         if digest == self.digest {
             Ok(Ok(()))
@@ -59,13 +183,111 @@ impl Entry {
     }
 }
 
+/// Summary statistics for the files in a store.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stats {
+    pub files: u64,
+    pub bytes: u64,
+    /// Number of files of each type
+    pub image_types: BTreeMap<ImageType, u64>,
+}
+
+/// File counts for a store.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Counts {
+    pub total: u64,
+    /// Number of files under each top-level prefix (empty if the store doesn't use prefixes)
+    pub prefixes: BTreeMap<String, u64>,
+}
+
+/// How files are transferred by [`Store::merge_from`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum MergeMode {
+    /// Hard-link files (so both stores must be on the same file system)
+    #[default]
+    Link,
+    /// Move files, removing them from the source store
+    Move,
+}
+
+/// The results of merging one store into another.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MergeCounts {
+    /// Number of files added
+    pub added: u64,
+    /// Number of files that were already present (which are left unchanged)
+    pub existing: u64,
+    /// Source entries whose contents don't match their digests (which are not merged)
+    pub invalid: Vec<Entry>,
+}
+
+/// The results of importing an archive into a store.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ImportCounts {
+    /// Number of files added
+    pub added: u64,
+    /// Number of files that were already present (which are left unchanged)
+    pub existing: u64,
+    /// Names of archive files that aren't named by the digests of their contents (which are not
+    /// imported)
+    pub invalid: Vec<String>,
+}
+
+/// The results of migrating a store to a new prefix layout.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MigrationCounts {
+    /// Number of files moved (or that would be moved, for a dry run)
+    pub moved: u64,
+    /// Number of files that were already in the right place
+    pub unchanged: u64,
+}
+
+/// What [`Store::repair`] does with files whose contents don't match their names.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum RepairMode {
+    /// Move the file to the path for the digest of its contents.
+    #[default]
+    Rename,
+    /// Move the file (named by the digest of its contents) to a separate directory.
+    Quarantine(PathBuf),
+}
+
+/// A file whose contents don't match its name.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MismatchedFile {
+    pub path: PathBuf,
+    /// The digest given by the file's name
+    pub expected: Digest,
+    /// The digest of the file's contents
+    pub actual: Digest,
+    /// Where the file was moved, or `None` if it was removed (because a file with the same contents
+    /// was already there)
+    pub destination: Option<PathBuf>,
+}
+
+/// The results of repairing a store.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RepairReport {
+    /// Number of files whose contents match their names
+    pub valid: u64,
+    /// Files whose contents don't match their names (which have been moved)
+    pub mismatched: Vec<MismatchedFile>,
+    /// Zero-byte files whose names aren't the digest of an empty file (which have been removed)
+    pub removed_empty: Vec<PathBuf>,
+}
+
 #[derive(Clone, Debug)]
 pub struct PrefixPartLengths(pub Vec<usize>);
 
 impl std::str::FromStr for PrefixPartLengths {
     type Err = String;
 
+    // The empty string represents a flat store.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Ok(Self(vec![]));
+        }
+
         s.split('/')
             .map(str::parse)
             .collect::<Result<Vec<_>, _>>()
@@ -74,13 +296,153 @@ impl std::str::FromStr for PrefixPartLengths {
     }
 }
 
+/// The position of an iteration over a store (the digest of the last file processed), which can be
+/// saved (e.g. to a checkpoint file) and used to continue the iteration with [`Entries::resume`].
+///
+/// Cursors are serialized as digests.
+#[derive(
+    Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd, serde::Deserialize, serde::Serialize,
+)]
+#[serde(transparent)]
+pub struct Cursor {
+    last: Digest,
+}
+
+impl Cursor {
+    /// The position after the file with the given digest.
+    #[must_use]
+    pub const fn after(last: Digest) -> Self {
+        Self { last }
+    }
+
+    #[must_use]
+    pub const fn last(&self) -> Digest {
+        self.last
+    }
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.last.fmt(f)
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = crate::digest::ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.trim().parse().map(Self::after)
+    }
+}
+
+/// A range of digests, given by the (inclusive) first and last hexadecimal prefixes of their file
+/// names.
+///
+/// For example, the range from `00` to `3f` contains the first quarter of digests. The prefixes
+/// don't need to have the same length, and an empty start or end prefix leaves that side of the
+/// range unbounded.
 #[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PrefixRange {
+    start: String,
+    end: String,
+}
+
+impl PrefixRange {
+    pub fn new(start: &str, end: &str) -> Result<Self, InitializationError> {
+        let is_valid = |prefix: &str| {
+            prefix
+                .bytes()
+                .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+        };
+
+        if is_valid(start) && is_valid(end) {
+            Ok(Self {
+                start: start.to_string(),
+                end: end.to_string(),
+            })
+        } else {
+            Err(InitializationError::InvalidPrefixRange {
+                start: start.to_string(),
+                end: end.to_string(),
+            })
+        }
+    }
+
+    #[must_use]
+    pub fn start(&self) -> &str {
+        &self.start
+    }
+
+    #[must_use]
+    pub fn end(&self) -> &str {
+        &self.end
+    }
+
+    #[must_use]
+    pub fn contains(&self, digest: Digest) -> bool {
+        self.may_contain_prefix(&format!("{digest:x}"))
+    }
+
+    /// The unbounded range starting at a digest.
+    fn starting_at(digest: Digest) -> Self {
+        Self {
+            start: format!("{digest:x}"),
+            end: String::new(),
+        }
+    }
+
+    /// Whether the given prefix directory (below the store's base directory) may contain digests in
+    /// the range.
+    fn may_contain_directory(&self, base: &Path, directory: &Path) -> bool {
+        directory.strip_prefix(base).map_or(true, |relative| {
+            let prefix = relative
+                .iter()
+                .map(|part| part.to_string_lossy())
+                .collect::<String>();
+
+            self.may_contain_prefix(&prefix)
+        })
+    }
+
+    /// Whether any digest starting with the given hexadecimal prefix may be in the range.
+    fn may_contain_prefix(&self, prefix: &str) -> bool {
+        let start_len = prefix.len().min(self.start.len());
+        let end_len = prefix.len().min(self.end.len());
+
+        let prefix = prefix.as_bytes();
+
+        prefix[..start_len] >= self.start.as_bytes()[..start_len]
+            && prefix[..end_len] <= self.end.as_bytes()[..end_len]
+    }
+}
+
+impl std::str::FromStr for PrefixRange {
+    type Err = String;
+
+    // The prefixes are separated by a hyphen (e.g. `00-3f`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_once('-')
+            .and_then(|(start, end)| Self::new(start, end).ok())
+            .ok_or_else(|| s.to_string())
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
 pub enum ValidationResult {
     Valid { entry: Entry },
     Invalid { entry: Entry, actual: Digest },
 }
 
 impl ValidationResult {
+    /// Check an entry's contents against its digest.
+    pub fn for_entry(entry: Entry) -> Result<Self, std::io::Error> {
+        Ok(match entry.validate()? {
+            Ok(()) => Self::Valid { entry },
+            Err(actual) => Self::Invalid { entry, actual },
+        })
+    }
+
     pub fn result(self) -> Result<Entry, Error> {
         match self {
             Self::Valid { entry } => Ok(entry),
@@ -92,11 +454,182 @@ impl ValidationResult {
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// A file whose contents don't match its digest.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize)]
+pub struct InvalidFile {
+    pub path: PathBuf,
+    pub expected: Digest,
+    pub actual: Digest,
+}
+
+/// A file that couldn't be read (or an error walking the store, which may not have a path).
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize)]
+pub struct FileError {
+    pub path: Option<PathBuf>,
+    pub message: String,
+}
+
+/// The results of validating the files in a store.
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde::Serialize)]
+pub struct ValidationReport {
+    /// Number of files whose contents match their digests
+    pub valid: u64,
+    pub invalid: Vec<InvalidFile>,
+    pub errors: Vec<FileError>,
+    /// Total size of the (decompressed) contents read
+    pub bytes_read: u64,
+    #[serde(rename = "elapsed_seconds", serialize_with = "serialize_seconds")]
+    pub elapsed: std::time::Duration,
+}
+
+impl ValidationReport {
+    /// Number of files checked so far (including any that couldn't be read).
+    #[must_use]
+    pub const fn files(&self) -> u64 {
+        self.valid + self.invalid.len() as u64 + self.errors.len() as u64
+    }
+
+    /// Validate entries concurrently, calling the given function with the running totals after
+    /// each one.
+    ///
+    /// The order of the invalid files and errors in the report depends on scheduling.
+    #[cfg(feature = "parallel")]
+    pub fn from_par_entries<I, F>(entries: I, progress: F) -> Self
+    where
+        I: rayon::iter::ParallelIterator<Item = Result<Entry, IterationError>>,
+        F: Fn(&Self) + Sync,
+    {
+        let started = std::time::Instant::now();
+        let report = std::sync::Mutex::new(Self::default());
+
+        entries.for_each(|entry| {
+            // Files are read and checked before the lock is taken.
+            let outcome = ValidationOutcome::for_entry(entry);
+            let mut report = report
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+            report.add(outcome);
+            report.elapsed = started.elapsed();
+            progress(&report);
+            drop(report);
+        });
+
+        let mut report = report
+            .into_inner()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        report.elapsed = started.elapsed();
+
+        report
+    }
+
+    fn add(&mut self, outcome: ValidationOutcome) {
+        match outcome {
+            ValidationOutcome::Valid { bytes_read } => {
+                self.valid += 1;
+                self.bytes_read += bytes_read;
+            }
+            ValidationOutcome::Invalid { file, bytes_read } => {
+                self.invalid.push(file);
+                self.bytes_read += bytes_read;
+            }
+            ValidationOutcome::Error(error) => {
+                self.errors.push(error);
+            }
+        }
+    }
+}
+
+fn serialize_seconds<S: serde::Serializer>(
+    duration: &std::time::Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+/// The result of checking a single entry for a [`ValidationReport`].
+enum ValidationOutcome {
+    Valid { bytes_read: u64 },
+    Invalid { file: InvalidFile, bytes_read: u64 },
+    Error(FileError),
+}
+
+impl ValidationOutcome {
+    fn for_entry(entry: Result<Entry, IterationError>) -> Self {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                let path = match &error {
+                    IterationError::ExpectedFile(path)
+                    | IterationError::ExpectedDirectory(path)
+                    | IterationError::InvalidFileName(path) => Some(path.clone()),
+                    _ => None,
+                };
+
+                return Self::Error(FileError {
+                    path,
+                    message: error_message(&error),
+                });
+            }
+        };
+
+        match entry.read() {
+            Ok(bytes) => {
+                let bytes_read = bytes.len() as u64;
+                let actual = entry.digest.kind().compute(&bytes);
+
+                if actual == entry.digest {
+                    Self::Valid { bytes_read }
+                } else {
+                    Self::Invalid {
+                        file: InvalidFile {
+                            path: entry.path,
+                            expected: entry.digest,
+                            actual,
+                        },
+                        bytes_read,
+                    }
+                }
+            }
+            Err(error) => Self::Error(FileError {
+                path: Some(entry.path),
+                message: error.to_string(),
+            }),
+        }
+    }
+}
+
+/// Describe an error along with its sources (our error messages are generally terse).
+fn error_message(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+
+    while let Some(error) = source {
+        message.push_str(": ");
+        message.push_str(&error.to_string());
+        source = error.source();
+    }
+
+    message
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct Action {
     pub entry: Entry,
     pub image_type: ImageType,
     pub added: bool,
+    /// Whether the file was saved to the quarantine directory instead of the store.
+    #[serde(default)]
+    pub quarantined: bool,
+    /// Whether the download was skipped because the URL had already been downloaded recently.
+    #[serde(default)]
+    pub skipped: bool,
+    /// How the file was changed before it was saved (if it was).
+    #[serde(default)]
+    pub transformation: Option<Transformation>,
+    /// Files that were removed to keep the store within its quota.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub evicted: Vec<Digest>,
 }
 
 impl Action {
@@ -106,10 +639,51 @@ impl Action {
     }
 }
 
+/// What to do with files whose contents aren't recognized as an image (HTML error pages, etc.).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum NonImagePolicy {
+    /// Save the file in the store (with no image type).
+    #[default]
+    Store,
+    /// Don't save the file, and fail with [`Error::NotAnImage`].
+    Reject,
+    /// Save the file (named by its digest) in a separate directory.
+    ///
+    /// The directory can be inside the store if its name starts with a dot (e.g. `.quarantine`).
+    Quarantine(PathBuf),
+}
+
+impl std::str::FromStr for NonImagePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "store" => Ok(Self::Store),
+            "reject" => Ok(Self::Reject),
+            other => other
+                .strip_prefix("quarantine=")
+                .filter(|directory| !directory.is_empty())
+                .map(|directory| Self::Quarantine(PathBuf::from(directory)))
+                .ok_or_else(|| format!("Invalid non-image policy: {other}")),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Store {
     pub base: PathBuf,
     pub prefix_part_lengths: Vec<usize>,
+    detector: Detector,
+    non_image_policy: NonImagePolicy,
+    packs: Option<Packs>,
+    read_cache: Option<ReadCache>,
+    quota: Option<Quota>,
+    compression: bool,
+    metadata: bool,
+    observers: Observers,
+    pipeline: Pipeline,
+    fsync: bool,
+    digest_kind: DigestKind,
 }
 
 impl Store {
@@ -117,412 +691,4633 @@ impl Store {
         Self {
             base: base.as_ref().to_path_buf(),
             prefix_part_lengths: vec![],
+            detector: crate::image_type::detect,
+            non_image_policy: NonImagePolicy::default(),
+            packs: None,
+            read_cache: None,
+            quota: None,
+            compression: false,
+            metadata: false,
+            observers: Observers::default(),
+            pipeline: Pipeline::default(),
+            fsync: false,
+            digest_kind: DigestKind::default(),
         }
     }
 
-    pub fn with_prefix_part_lengths<T: AsRef<[usize]>>(
-        self,
-        prefix_part_lengths: T,
-    ) -> Result<Self, InitializationError> {
-        if prefix_part_lengths.as_ref().iter().copied().sum::<usize>() > 32
-            || prefix_part_lengths.as_ref().contains(&0)
+    /// Create a store, using the layout recorded in its base directory (if there is one).
+    ///
+    /// Stores that don't record a layout use MD5 digests and no prefixes (the prefix part lengths
+    /// can be inferred with [`Store::infer_prefix_part_lengths`]). If the store has packs, they are
+    /// opened, so that packed files are read, listed, and validated along with individual files,
+    /// but new files aren't packed unless a threshold is set with [`Store::with_packs`].
+    pub fn load<P: AsRef<Path>>(base: P) -> Result<Self, Error> {
+        let store = Self::new(base);
+
+        let store = match Layout::read(&store.base)? {
+            Some(layout) => Self {
+                digest_kind: layout.digest_kind,
+                ..store.with_prefix_part_lengths(layout.prefix_part_lengths)?
+            },
+            None => store,
+        };
+
+        if store.digest_kind == DigestKind::Md5
+            && store.base.join(crate::pack::DIRECTORY_NAME).is_dir()
         {
-            Err(InitializationError::InvalidPrefixPartLengths(
-                prefix_part_lengths.as_ref().to_vec(),
-            ))
+            store.with_packs(0)
         } else {
-            Ok(Self {
-                base: self.base,
-                prefix_part_lengths: prefix_part_lengths.as_ref().to_vec(),
-            })
+            Ok(store)
         }
     }
 
-    /// Infer the prefix part lengths used to create a store.
+    /// Use the given digest kind.
     ///
-    /// The result will be empty if and only if the store has no files (even if there are directories).
-    ///
-    /// If this function returns a result, it is guaranteed to be correct if the store is valid, but the validity is not checked.
-    pub fn infer_prefix_part_lengths<P: AsRef<Path>>(base: P) -> Result<Option<Vec<usize>>, Error> {
-        if base.as_ref().is_dir() {
-            let first = std::fs::read_dir(base)?
-                .next()
-                .map_or(Ok(None), |entry| entry.map(|entry| Some(entry.path())))?;
-
-            let mut acc = vec![];
-
-            let is_empty = first
-                .map(|first| Self::infer_prefix_part_lengths_rec(&first, &mut acc))
-                .map_or(Ok(true), |value| value)?;
+    /// This fails if the store records a different kind, or if it has files but no recorded layout
+    /// (in which case it uses MD5). Packs only support MD5 digests. The kind of a new store should
+    /// be recorded with [`Store::record_layout`].
+    pub fn with_digest_kind(self, digest_kind: DigestKind) -> Result<Self, Error> {
+        let found = match Layout::read(&self.base)? {
+            Some(layout) => layout.digest_kind,
+            None if self.base.is_dir() && Self::first_visible_path(&self.base)?.is_some() => {
+                DigestKind::Md5
+            }
+            None => digest_kind,
+        };
 
-            Ok(if is_empty { None } else { Some(acc) })
-        } else {
-            Err(Error::ExpectedDirectory(base.as_ref().to_path_buf()))
+        if found != digest_kind {
+            return Err(Error::DigestKindMismatch {
+                expected: digest_kind,
+                found,
+            });
         }
-    }
-
-    // Return value indicates whether the store has no files.
-    fn infer_prefix_part_lengths_rec<P: AsRef<Path>>(
-        current: P,
-        acc: &mut Vec<usize>,
-    ) -> Result<bool, Error> {
-        if current.as_ref().is_file() {
-            Ok(false)
-        } else {
-            let file_name = current
-                .as_ref()
-                .file_name()
-                .ok_or_else(|| Error::InvalidFileName(current.as_ref().to_path_buf()))?;
 
-            acc.push(file_name.len());
+        if digest_kind != DigestKind::Md5 && self.packs.is_some() {
+            return Err(Error::UnsupportedDigestKind(digest_kind));
+        }
 
-            let next = std::fs::read_dir(current)?
-                .next()
-                .map_or(Ok(None), |entry| entry.map(|entry| Some(entry.path())))?;
+        Ok(Self {
+            digest_kind,
+            ..self
+        })
+    }
 
-            next.map_or(Ok(true), |next| {
-                Self::infer_prefix_part_lengths_rec(next, acc)
-            })
-        }
+    #[must_use]
+    pub const fn digest_kind(&self) -> DigestKind {
+        self.digest_kind
     }
 
+    /// The store's layout (as it would be recorded in its base directory).
     #[must_use]
-    pub fn entries(&self) -> Entries<'_> {
-        Entries {
-            stack: vec![vec![self.base.clone()]],
-            level: None,
-            prefix_part_lengths: &self.prefix_part_lengths,
-        }
+    pub fn layout(&self) -> Layout {
+        Layout::new(self.prefix_part_lengths.clone(), self.digest_kind)
     }
 
-    pub fn save<T: AsRef<[u8]> + Copy>(&self, bytes: T) -> Result<Action, Error> {
-        // The image type check will fail with an error if there aren't enough bytes.
-        let image_type = if bytes.as_ref().len() < 8 {
-            None
-        } else {
-            imghdr::from_bytes(bytes.as_ref())
-        };
+    #[must_use]
+    pub fn layout_path(&self) -> PathBuf {
+        self.base.join(crate::layout::FILE_NAME)
+    }
 
-        let digest = md5::compute(bytes);
-        let path = self.path(digest);
+    /// Record the store's layout in its base directory, or check that it matches the recorded one.
+    pub fn record_layout(&self) -> Result<(), Error> {
+        let expected = self.layout();
 
-        // We construct the path, so we know there will always be a parent.
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
+        match Layout::read(&self.base)? {
+            Some(found)
+                if found.prefix_part_lengths == expected.prefix_part_lengths
+                    && found.digest_kind == expected.digest_kind =>
+            {
+                Ok(())
+            }
+            Some(found) => Err(Error::LayoutMismatch { expected, found }),
+            None => Ok(expected.write(&self.base)?),
         }
+    }
 
-        let added = if path.exists() {
-            false
-        } else {
-            let mut file = File::create(&path)?;
-            file.write_all(bytes.as_ref())?;
-
-            true
-        };
+    /// Store files smaller than the threshold (in bytes) in packs instead of individual files.
+    ///
+    /// Packs are kept in a `.packs` directory in the store's base directory. Files that have
+    /// already been saved individually are not moved. Packs are only supported for stores that use
+    /// MD5 digests.
+    pub fn with_packs(self, threshold: u64) -> Result<Self, Error> {
+        if self.digest_kind != DigestKind::Md5 {
+            return Err(Error::UnsupportedDigestKind(self.digest_kind));
+        }
 
-        Ok(Action {
-            entry: Entry { path, digest },
-            image_type: ImageType::new(image_type),
-            added,
+        Ok(Self {
+            packs: Some(Packs::open(
+                self.base.join(crate::pack::DIRECTORY_NAME),
+                threshold,
+            )?),
+            ..self
         })
     }
 
     #[must_use]
-    pub fn path(&self, digest: Digest) -> PathBuf {
-        let digest_string = format!("{digest:x}");
-        let mut digest_remaining = digest_string.as_str();
-        let mut path = self.base.clone();
-
-        for prefix_part_length in &self.prefix_part_lengths {
-            let next = &digest_remaining[0..*prefix_part_length];
-            digest_remaining = &digest_remaining[*prefix_part_length..];
+    pub const fn packs(&self) -> Option<&Packs> {
+        self.packs.as_ref()
+    }
 
-            path.push(next);
+    /// Keep the contents of recently read files in memory (the cache may be shared with other
+    /// stores, since contents are identified by digest).
+    #[must_use]
+    pub fn with_read_cache(self, read_cache: ReadCache) -> Self {
+        Self {
+            read_cache: Some(read_cache),
+            ..self
         }
-
-        path.push(digest_string);
-
-        path
     }
-}
-
-pub struct Entries<'a> {
-    stack: Vec<Vec<PathBuf>>,
-    level: Option<usize>,
-    prefix_part_lengths: &'a [usize],
-}
 
-impl Entries<'_> {
-    fn is_last(&self) -> bool {
-        self.level == Some(self.prefix_part_lengths.len())
+    #[must_use]
+    pub const fn read_cache(&self) -> Option<&ReadCache> {
+        self.read_cache.as_ref()
     }
 
-    fn current_prefix_part_length(&self) -> Option<usize> {
-        self.level
-            .and_then(|level| self.prefix_part_lengths.get(level))
-            .copied()
-    }
+    /// Limit the number of files in the store or their total size, evicting files when a new file
+    /// would exceed the limit.
+    ///
+    /// The quota should be shared (by cloning the store) by everything saving to the store in this
+    /// process. Files that are quarantined don't count towards it.
+    #[must_use]
+    pub fn with_quota(self, quota: Quota) -> Self {
+        Self {
+            quota: Some(quota),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub const fn quota(&self) -> Option<&Quota> {
+        self.quota.as_ref()
+    }
+
+    /// Store images in formats that aren't compressed (BMP, PBM, PGM, PPM, and TIFF) with zstd
+    /// compression.
+    ///
+    /// Compressed files are named by the digest of their original contents, with a `.zst`
+    /// extension, and are decompressed transparently when they are read. Compressed files are
+    /// read whether or not this is enabled, and files that were saved before it was enabled aren't
+    /// compressed. Packed and quarantined files are never compressed.
+    #[cfg(feature = "compression")]
+    #[must_use]
+    pub fn with_compression(self, compression: bool) -> Self {
+        Self {
+            compression,
+            ..self
+        }
+    }
+
+    /// Whether a file of the given type should be compressed when it's saved individually.
+    const fn compresses(&self, image_type: Option<Type>, quarantined: bool) -> bool {
+        self.compression
+            && !quarantined
+            && matches!(
+                image_type,
+                Some(Type::Bmp | Type::Pbm | Type::Pgm | Type::Ppm | Type::Tiff)
+            )
+    }
+
+    /// Record sidecar metadata (the URL, fetch time, content type, and content length) for files
+    /// added by downloads, which can be looked up with [`Store::metadata`].
+    ///
+    /// Records are appended to a hidden metadata file in each file's prefix directory, and are kept
+    /// when files are removed.
+    #[must_use]
+    pub fn with_metadata(self, metadata: bool) -> Self {
+        Self { metadata, ..self }
+    }
+
+    /// Call a function after each file is added to the store (e.g. to update an index or metrics).
+    ///
+    /// This includes files added by imports and merges, but not quarantined files. See
+    /// [`Observers`] for details.
+    #[must_use]
+    pub fn on_added<F: Fn(&Action) + Send + Sync + 'static>(self, f: F) -> Self {
+        Self {
+            observers: self.observers.on_added(f),
+            ..self
+        }
+    }
+
+    /// Call a function after each save that finds the file already in the store.
+    #[must_use]
+    pub fn on_found<F: Fn(&Action) + Send + Sync + 'static>(self, f: F) -> Self {
+        Self {
+            observers: self.observers.on_found(f),
+            ..self
+        }
+    }
+
+    /// Call a function after each file is removed from the store, whether it was deleted, evicted
+    /// to stay within the quota, or removed by a repair.
+    #[must_use]
+    pub fn on_deleted<F: Fn(Digest) + Send + Sync + 'static>(self, f: F) -> Self {
+        Self {
+            observers: self.observers.on_deleted(f),
+            ..self
+        }
+    }
+
+    /// Notify the observers of a save (unless the file was quarantined).
+    fn saved(&self, action: Action) -> Action {
+        if !action.quarantined {
+            self.observers.saved(&action);
+        }
+
+        action
+    }
+
+    /// Notify the observers of a file added without an action (e.g. by a merge).
+    fn notify_added(&self, digest: Digest, image_type: ImageType) {
+        if self.observers.has_added() {
+            self.observers.saved(&Action {
+                entry: self.entry(digest),
+                image_type,
+                added: true,
+                quarantined: false,
+                skipped: false,
+                transformation: None,
+                evicted: vec![],
+            });
+        }
+    }
+
+    /// Return the path of the metadata file that would contain a digest's record.
+    #[must_use]
+    pub fn metadata_path(&self, digest: Digest) -> PathBuf {
+        self.path(digest).with_file_name(crate::metadata::FILE_NAME)
+    }
+
+    /// Return the first metadata recorded for a digest, if there is any.
+    pub fn metadata(&self, digest: Digest) -> Result<Option<Metadata>, Error> {
+        Ok(crate::metadata::find(self.metadata_path(digest), digest)?)
+    }
+
+    /// Record metadata for a file if metadata is enabled, returning whether it was recorded.
+    pub fn record_metadata(&self, digest: Digest, metadata: &Metadata) -> Result<bool, Error> {
+        if self.metadata {
+            self.append_metadata(digest, metadata)?;
+        }
+
+        Ok(self.metadata)
+    }
+
+    fn append_metadata(&self, digest: Digest, metadata: &Metadata) -> Result<(), Error> {
+        let path = self.metadata_path(digest);
+
+        // We construct the path, so we know there will always be a parent.
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        Ok(crate::metadata::append(path, digest, metadata)?)
+    }
+
+    /// Save a file, recording the given metadata if metadata is enabled and the file was added.
+    pub fn save_with_metadata<T: AsRef<[u8]> + Copy>(
+        &self,
+        bytes: T,
+        metadata: &Metadata,
+    ) -> Result<Action, Error> {
+        let action = self.save(bytes)?;
+
+        if action.added && !action.quarantined {
+            self.record_metadata(action.entry.digest, metadata)?;
+        }
+
+        Ok(action)
+    }
+
+    /// Flush each saved file (and its directory) to disk before the save completes.
+    ///
+    /// Files are always written to a temporary file and renamed into place, so a crash can't leave
+    /// a partially written file under a digest, but without this the save may be lost.
+    #[must_use]
+    pub fn with_fsync(self, fsync: bool) -> Self {
+        Self { fsync, ..self }
+    }
+
+    /// Apply a pipeline of transforms to images before they are saved.
+    ///
+    /// Files that aren't recognized as images are saved unchanged.
+    #[must_use]
+    pub fn with_pipeline(self, pipeline: Pipeline) -> Self {
+        Self { pipeline, ..self }
+    }
+
+    #[must_use]
+    pub const fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    /// Use a different function to detect the types of saved images.
+    #[must_use]
+    pub fn with_detector(self, detector: Detector) -> Self {
+        Self { detector, ..self }
+    }
+
+    /// Set what is done with saved files that aren't recognized as images.
+    #[must_use]
+    pub fn with_non_image_policy(self, non_image_policy: NonImagePolicy) -> Self {
+        Self {
+            non_image_policy,
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub const fn non_image_policy(&self) -> &NonImagePolicy {
+        &self.non_image_policy
+    }
+
+    pub fn with_prefix_part_lengths<T: AsRef<[usize]>>(
+        self,
+        prefix_part_lengths: T,
+    ) -> Result<Self, InitializationError> {
+        check_prefix_part_lengths(prefix_part_lengths.as_ref())?;
+
+        Ok(Self {
+            prefix_part_lengths: prefix_part_lengths.as_ref().to_vec(),
+            ..self
+        })
+    }
+
+    /// Infer the prefix part lengths used to create a store.
+    ///
+    /// If the store records a layout, its prefix part lengths are returned. Otherwise they are
+    /// inferred from the files, and the result will be empty if and only if the store has no files
+    /// (even if there are directories).
+    ///
+    /// If this function returns a result, it is guaranteed to be correct if the store is valid, but the validity is not checked.
+    pub fn infer_prefix_part_lengths<P: AsRef<Path>>(base: P) -> Result<Option<Vec<usize>>, Error> {
+        if base.as_ref().is_dir() {
+            if let Some(layout) = Layout::read(&base)? {
+                return Ok(Some(layout.prefix_part_lengths));
+            }
+
+            let first = Self::first_visible_path(base)?;
+
+            let mut acc = vec![];
+
+            let is_empty = first
+                .map(|first| Self::infer_prefix_part_lengths_rec(&first, &mut acc))
+                .map_or(Ok(true), |value| value)?;
+
+            Ok(if is_empty { None } else { Some(acc) })
+        } else {
+            Err(Error::ExpectedDirectory(base.as_ref().to_path_buf()))
+        }
+    }
+
+    // Return value indicates whether the store has no files.
+    fn infer_prefix_part_lengths_rec<P: AsRef<Path>>(
+        current: P,
+        acc: &mut Vec<usize>,
+    ) -> Result<bool, Error> {
+        if current.as_ref().is_file() {
+            Ok(false)
+        } else {
+            let file_name = current
+                .as_ref()
+                .file_name()
+                .ok_or_else(|| Error::InvalidFileName(current.as_ref().to_path_buf()))?;
+
+            acc.push(file_name.len());
+
+            let next = Self::first_visible_path(current)?;
+
+            next.map_or(Ok(true), |next| {
+                Self::infer_prefix_part_lengths_rec(next, acc)
+            })
+        }
+    }
+
+    fn first_visible_path<P: AsRef<Path>>(directory: P) -> Result<Option<PathBuf>, Error> {
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+
+            if !is_hidden(&entry.file_name()) {
+                return Ok(Some(entry.path()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Iterate over the files in the store, in order of digest.
+    ///
+    /// If the store has a manifest, it is used instead of walking the directory tree.
+    #[must_use]
+    pub fn entries(&self) -> Entries<'_> {
+        if self.has_manifest() {
+            let entries = match crate::manifest::read(self.manifest_path()) {
+                Ok(records) => records
+                    .into_iter()
+                    .map(|record| Ok(self.entry(record.digest)))
+                    .collect(),
+                Err(error) => vec![Err(IterationError::from(error))],
+            };
+
+            Entries {
+                base: None,
+                sorted: true,
+                stack: vec![],
+                prefix_part_lengths: &self.prefix_part_lengths,
+                digest_kind: self.digest_kind,
+                manifest: Some(entries.into_iter()),
+                packed: vec![].into_iter().peekable(),
+                pending: None,
+                root: self.base.clone(),
+                range: None,
+                after: None,
+                last: None,
+            }
+        } else {
+            self.walk()
+        }
+    }
+
+    /// Iterate over the files in the store whose digests are in the given range of hexadecimal
+    /// prefixes (inclusive), in order of digest.
+    ///
+    /// This makes it possible to split work on a large store (such as validation) across machines
+    /// or runs. Prefix directories outside the range aren't read.
+    pub fn entries_in_range(
+        &self,
+        start_prefix: &str,
+        end_prefix: &str,
+    ) -> Result<Entries<'_>, InitializationError> {
+        Ok(self
+            .entries()
+            .in_range(PrefixRange::new(start_prefix, end_prefix)?))
+    }
+
+    /// Iterate over the files in the store by walking the directory tree (ignoring any manifest).
+    ///
+    /// Packed files are included.
+    #[must_use]
+    pub fn walk(&self) -> Entries<'_> {
+        self.walk_with_order(true)
+    }
+
+    /// Iterate over the files in the store by walking the directory tree, in the order returned by
+    /// the file system.
+    ///
+    /// This avoids reading and sorting each directory's contents before returning its files, which
+    /// is faster (and uses less memory) for large stores. The order is unspecified, and packed
+    /// files are returned before (or interleaved with) individual files.
+    #[must_use]
+    pub fn walk_unsorted(&self) -> Entries<'_> {
+        self.walk_with_order(false)
+    }
+
+    fn walk_with_order(&self, sorted: bool) -> Entries<'_> {
+        Entries {
+            base: Some(self.base.clone()),
+            sorted,
+            stack: vec![],
+            prefix_part_lengths: &self.prefix_part_lengths,
+            digest_kind: self.digest_kind,
+            manifest: None,
+            packed: self.packed_entries().into_iter().peekable(),
+            pending: None,
+            root: self.base.clone(),
+            range: None,
+            after: None,
+            last: None,
+        }
+    }
+
+    /// Iterate over the files in the store by walking the top-level prefix directories
+    /// concurrently on Rayon's thread pool (ignoring any manifest).
+    ///
+    /// This is much faster than [`Store::walk_unsorted`] for large stores (especially when each
+    /// entry is also validated). The order is unspecified. An error only ends the walk of the
+    /// prefix directory it occurred in (and is returned like any other item). Packed files are
+    /// included.
+    #[cfg(feature = "parallel")]
+    #[must_use]
+    pub fn par_entries(
+        &self,
+    ) -> impl rayon::iter::ParallelIterator<Item = Result<Entry, IterationError>> + '_ {
+        self.par_entries_with_range(None)
+    }
+
+    /// Iterate concurrently over the files in the store whose digests are in the given range of
+    /// hexadecimal prefixes (inclusive).
+    ///
+    /// See [`Store::par_entries`] and [`Store::entries_in_range`].
+    #[cfg(feature = "parallel")]
+    pub fn par_entries_in_range(
+        &self,
+        start_prefix: &str,
+        end_prefix: &str,
+    ) -> Result<
+        impl rayon::iter::ParallelIterator<Item = Result<Entry, IterationError>> + '_,
+        InitializationError,
+    > {
+        Ok(self.par_entries_with_range(Some(PrefixRange::new(start_prefix, end_prefix)?)))
+    }
+
+    #[cfg(feature = "parallel")]
+    fn par_entries_with_range(
+        &self,
+        range: Option<PrefixRange>,
+    ) -> impl rayon::iter::ParallelIterator<Item = Result<Entry, IterationError>> + '_ {
+        use rayon::iter::{Either, IntoParallelIterator, ParallelBridge, ParallelIterator};
+
+        // Stores without prefix directories don't have any subtrees to walk separately.
+        let Some((_, prefix_part_lengths)) = self.prefix_part_lengths.split_first() else {
+            let entries = self.walk_unsorted();
+
+            return Either::Left(
+                match range {
+                    Some(range) => entries.in_range(range),
+                    None => entries,
+                }
+                .par_bridge(),
+            );
+        };
+
+        let packed = self
+            .packed_entries()
+            .into_iter()
+            .filter(|entry| {
+                range
+                    .as_ref()
+                    .is_none_or(|range| range.contains(entry.digest))
+            })
+            .collect::<Vec<_>>();
+
+        let subtrees = match Children::read(
+            self.base.clone(),
+            self.prefix_part_lengths.first().copied(),
+            false,
+        ) {
+            Ok(mut children) => std::iter::from_fn(|| children.next_path())
+                .filter(|subtree| {
+                    subtree.as_ref().map_or(true, |path| {
+                        range
+                            .as_ref()
+                            .is_none_or(|range| range.may_contain_directory(&self.base, path))
+                    })
+                })
+                .collect::<Vec<_>>(),
+            Err(error) => vec![Err(error)],
+        };
+
+        Either::Right(
+            subtrees
+                .into_par_iter()
+                .flat_map_iter(move |subtree| {
+                    let (base, pending) = match subtree {
+                        Ok(path) => (Some(path), None),
+                        Err(error) => (None, Some(Err(error))),
+                    };
+
+                    Entries {
+                        base,
+                        sorted: false,
+                        stack: vec![],
+                        prefix_part_lengths,
+                        digest_kind: self.digest_kind,
+                        manifest: None,
+                        packed: vec![].into_iter().peekable(),
+                        pending,
+                        root: self.base.clone(),
+                        range: range.clone(),
+                        after: None,
+                        last: None,
+                    }
+                })
+                .chain(packed.into_par_iter().map(Ok)),
+        )
+    }
+
+    fn packed_entries(&self) -> Vec<Entry> {
+        self.packs
+            .as_ref()
+            .map(Packs::list)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(digest, path, span)| Entry {
+                path,
+                digest,
+                packed: Some(span),
+                compressed: false,
+            })
+            .collect()
+    }
+
+    /// Return the entry for a digest (which may not exist).
+    ///
+    /// This is a packed entry if the file is in a pack, a compressed entry if there is only a
+    /// compressed file, and otherwise an individual file.
+    #[must_use]
+    pub fn entry(&self, digest: Digest) -> Entry {
+        self.packs
+            .as_ref()
+            .and_then(|packs| packs.locate(digest))
+            .map_or_else(
+                || {
+                    let path = self.path(digest);
+
+                    if !path.exists() {
+                        let compressed_path = self.compressed_path(digest);
+
+                        if compressed_path.is_file() {
+                            return Entry::compressed_file(compressed_path, digest);
+                        }
+                    }
+
+                    Entry::file(path, digest)
+                },
+                |(path, span)| Entry {
+                    path,
+                    digest,
+                    packed: Some(span),
+                    compressed: false,
+                },
+            )
+    }
+
+    /// Return the entry for a digest if the file is in the store.
+    #[must_use]
+    pub fn lookup(&self, digest: Digest) -> Option<Entry> {
+        let entry = self.entry(digest);
+
+        (entry.packed.is_some() || entry.path.is_file()).then_some(entry)
+    }
+
+    /// Read the contents of a file, if it is in the store.
+    pub fn read(&self, digest: Digest) -> Result<Option<Vec<u8>>, Error> {
+        if let Some(contents) = self
+            .read_cache
+            .as_ref()
+            .and_then(|cache| cache.get(&digest))
+        {
+            return Ok(Some(contents.to_vec()));
+        }
+
+        self.lookup(digest)
+            .map(|entry| {
+                let contents = entry.read()?;
+
+                if let Some(cache) = &self.read_cache {
+                    cache.insert(digest, Arc::from(contents.as_slice()));
+                }
+
+                Ok::<_, std::io::Error>(contents)
+            })
+            .transpose()
+            .map_err(Error::from)
+    }
+
+    /// Open the file for a digest, if it is in the store.
+    ///
+    /// Packed and compressed files can't be opened individually (use [`Store::read`] instead).
+    pub fn open(&self, digest: Digest) -> Result<Option<File>, Error> {
+        let entry = self.entry(digest);
+
+        if entry.packed.is_some() {
+            return Err(Error::Packed(digest));
+        }
+
+        if entry.compressed {
+            return Err(Error::Compressed(digest));
+        }
+
+        match File::open(&entry.path) {
+            Ok(file) => Ok(Some(file)),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(Error::from(error)),
+        }
+    }
+
+    /// Read the contents of an entry, using the read cache if there is one.
+    pub fn read_entry(&self, entry: &Entry) -> Result<Arc<[u8]>, std::io::Error> {
+        match &self.read_cache {
+            Some(cache) => {
+                if let Some(contents) = cache.get(&entry.digest) {
+                    Ok(contents)
+                } else {
+                    let contents = Arc::<[u8]>::from(entry.read()?);
+                    cache.insert(entry.digest, contents.clone());
+
+                    Ok(contents)
+                }
+            }
+            None => Ok(Arc::from(entry.read()?)),
+        }
+    }
+
+    #[must_use]
+    pub fn manifest_path(&self) -> PathBuf {
+        self.base.join(crate::manifest::FILE_NAME)
+    }
+
+    /// Check whether the store has a manifest (which is then maintained by saves and deletions).
+    #[must_use]
+    pub fn has_manifest(&self) -> bool {
+        self.manifest_path().is_file()
+    }
+
+    /// Create (or replace) the manifest by walking the directory tree, returning the number of files.
+    ///
+    /// Image types are detected from the files' contents, and the time each file was added is taken
+    /// to be its modification time.
+    pub fn rebuild_manifest(&self) -> Result<usize, Error> {
+        let mut records = vec![];
+
+        for entry in self.walk_unsorted() {
+            let entry = entry?;
+            let metadata = std::fs::metadata(&entry.path)?;
+
+            records.push(Record {
+                digest: entry.digest,
+                size: entry.size()?,
+                image_type: self.image_type(&entry)?,
+                added_at: metadata.modified()?,
+            });
+        }
+
+        records.sort_unstable_by_key(|record| record.digest);
+
+        let count = records.len();
+
+        std::fs::create_dir_all(&self.base)?;
+        crate::manifest::write(self.manifest_path(), records)?;
+
+        Ok(count)
+    }
+
+    /// Create a store at the given directory containing the entries that match the filter.
+    ///
+    /// Files are hard-linked rather than copied (so the destination must be on the same file
+    /// system), except for packed files, which are written individually. The destination uses the
+    /// same prefix part lengths and digest kind, and files that are already present there are
+    /// skipped.
+    ///
+    /// Returns the number of files added to the destination.
+    pub fn export_linked<P: AsRef<Path>, F: FnMut(&Entry) -> bool>(
+        &self,
+        dest: P,
+        mut filter: F,
+    ) -> Result<usize, Error> {
+        let dest = Self {
+            prefix_part_lengths: self.prefix_part_lengths.clone(),
+            ..Self::new(dest)
+        }
+        .with_digest_kind(self.digest_kind)?;
+
+        dest.record_layout()?;
+        let mut count = 0;
+
+        for entry in self.entries() {
+            let entry = entry?;
+
+            if filter(&entry) {
+                let path = dest.entry_path(&entry);
+
+                if !path.exists() {
+                    // We construct the path, so we know there will always be a parent.
+                    if let Some(parent) = path.parent() {
+                        std::fs::create_dir_all(parent)?;
+                    }
+
+                    if entry.packed.is_some() {
+                        std::fs::write(&path, entry.read()?)?;
+                    } else {
+                        std::fs::hard_link(&entry.path, &path)?;
+                    }
+
+                    count += 1;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Add every file from another store that isn't already in this one.
+    ///
+    /// The stores may use different prefix part lengths. Each file's contents are checked against
+    /// its digest before it is added, and files that don't match are skipped (and left in the
+    /// source store). Packed source files are written individually (or packed, if this store uses
+    /// packs), and other files are hard-linked or moved. Both stores must use the same digest kind.
+    pub fn merge_from(&self, source: &Self, mode: MergeMode) -> Result<MergeCounts, Error> {
+        if source.digest_kind != self.digest_kind {
+            return Err(Error::DigestKindMismatch {
+                expected: self.digest_kind,
+                found: source.digest_kind,
+            });
+        }
+
+        let mut counts = MergeCounts::default();
+
+        for entry in source.walk() {
+            let entry = entry?;
+
+            if self.lookup(entry.digest).is_some() {
+                counts.existing += 1;
+                continue;
+            }
+
+            let bytes = entry.read()?;
+
+            if self.digest_kind.compute(&bytes) != entry.digest {
+                counts.invalid.push(entry);
+                continue;
+            }
+
+            let size = bytes.len() as u64;
+            let path = self.entry_path(&entry);
+            let mut moved = false;
+
+            if let Some(packs) = self.packs_for(size, &path, false) {
+                packs.put(entry.digest, &bytes)?;
+            } else {
+                // We construct the path, so we know there will always be a parent.
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+
+                match (entry.packed, mode) {
+                    (Some(_), _) => std::fs::write(&path, &bytes)?,
+                    (None, MergeMode::Link) => std::fs::hard_link(&entry.path, &path)?,
+                    (None, MergeMode::Move) => {
+                        move_file(&entry.path, &path)?;
+                        moved = true;
+                    }
+                }
+            }
+
+            let image_type = ImageType::new((self.detector)(&bytes));
+            self.record_added(entry.digest, size, image_type)?;
+            self.notify_added(entry.digest, image_type);
+
+            if moved {
+                crate::manifest::append(source.manifest_path(), Line::Removed(entry.digest))?;
+            } else if mode == MergeMode::Move {
+                source.delete(entry.digest)?;
+            }
+
+            counts.added += 1;
+        }
+
+        Ok(counts)
+    }
+
+    /// Write the files for which the filter returns `true` to an archive, returning the number of
+    /// files written.
+    ///
+    /// Files are named by their paths relative to the base directory, so the prefix layout is
+    /// preserved, and the layout file is written first. Packed files are written individually, and
+    /// compressed files are written as they are. Other files are streamed from disk.
+    #[cfg(feature = "archive")]
+    pub fn export_archive<W: Write + std::io::Seek, F: FnMut(&Entry) -> bool>(
+        &self,
+        writer: W,
+        format: crate::archive::Format,
+        mut filter: F,
+    ) -> Result<usize, Error> {
+        let mut archive = crate::archive::Writer::new(writer, format);
+        let layout = self.layout().to_toml()?;
+
+        archive.append(
+            crate::layout::FILE_NAME,
+            layout.len() as u64,
+            SystemTime::now(),
+            layout.as_bytes(),
+        )?;
+
+        let mut count = 0;
+
+        for entry in self.entries() {
+            let entry = entry?;
+
+            if filter(&entry) {
+                let name = self.archive_name(&self.entry_path(&entry));
+                let metadata = std::fs::metadata(&entry.path)?;
+
+                if entry.packed.is_some() {
+                    let bytes = entry.read()?;
+
+                    archive.append(
+                        &name,
+                        bytes.len() as u64,
+                        metadata.modified()?,
+                        bytes.as_slice(),
+                    )?;
+                } else {
+                    let file = File::open(&entry.path)?;
+
+                    archive.append(&name, metadata.len(), metadata.modified()?, file)?;
+                }
+
+                count += 1;
+            }
+        }
+
+        archive.finish()?;
+
+        Ok(count)
+    }
+
+    /// Add every file from an archive (e.g. one written by [`Store::export_archive`]) that isn't
+    /// already in the store.
+    ///
+    /// Files are identified by their names (the archive's prefix layout doesn't need to match the
+    /// store's), and each file's contents are checked against its digest before it is added. If
+    /// the archive has a layout file, its digest kind must match the store's. Files are added
+    /// without being transformed.
+    #[cfg(feature = "archive")]
+    pub fn import_archive<R: Read + std::io::Seek>(
+        &self,
+        reader: R,
+        format: crate::archive::Format,
+    ) -> Result<ImportCounts, Error> {
+        let mut counts = ImportCounts::default();
+
+        crate::archive::for_each_file(reader, format, |name, contents| {
+            if name == crate::layout::FILE_NAME {
+                let mut layout = String::new();
+                contents.read_to_string(&mut layout)?;
+                let layout = Layout::parse(&layout)?;
+
+                if layout.digest_kind != self.digest_kind {
+                    return Err(Error::DigestKindMismatch {
+                        expected: self.digest_kind,
+                        found: layout.digest_kind,
+                    });
+                }
+
+                return Ok(());
+            }
+
+            let file_name = name.rsplit('/').next().unwrap_or(name);
+
+            if is_hidden(std::ffi::OsStr::new(file_name)) {
+                return Ok(());
+            }
+
+            let (file_name, compressed) =
+                split_compressed_extension(std::ffi::OsStr::new(file_name));
+            let digest = std::str::from_utf8(file_name)
+                .ok()
+                .and_then(|file_name| Digest::from_hex(self.digest_kind, file_name).ok());
+
+            let mut bytes = vec![];
+
+            if compressed {
+                decompress(contents)?.read_to_end(&mut bytes)?;
+            } else {
+                contents.read_to_end(&mut bytes)?;
+            }
+
+            match digest {
+                Some(digest) if self.digest_kind.compute(&bytes) == digest => {
+                    if self.lookup(digest).is_none() && self.add_verified(digest, &bytes)? {
+                        counts.added += 1;
+                    } else {
+                        counts.existing += 1;
+                    }
+                }
+                _ => counts.invalid.push(name.to_string()),
+            }
+
+            Ok(())
+        })?;
+
+        Ok(counts)
+    }
+
+    /// Add a file whose contents are known to match its digest, without transforming it.
+    ///
+    /// Returns whether the file was added (i.e. was not already present).
+    #[cfg(feature = "archive")]
+    fn add_verified(&self, digest: Digest, bytes: &[u8]) -> Result<bool, Error> {
+        let size = bytes.len() as u64;
+        let image_type = (self.detector)(bytes);
+        let path = self.path(digest);
+
+        let added = if let Some(packs) = self.packs_for(size, &path, false) {
+            packs.put(digest, bytes)?
+        } else if self.compresses(image_type, false) {
+            !path.exists() && self.write_atomic(&self.compressed_path(digest), &compress(bytes)?)?
+        } else {
+            self.write_atomic(&path, bytes)?
+        };
+
+        if added {
+            self.record_added(digest, size, ImageType::new(image_type))?;
+            self.notify_added(digest, ImageType::new(image_type));
+        }
+
+        Ok(added)
+    }
+
+    /// Return the name of a file in an archive (its path relative to the base directory, using `/`
+    /// as the separator).
+    #[cfg(feature = "archive")]
+    fn archive_name(&self, path: &Path) -> String {
+        path.strip_prefix(&self.base)
+            .unwrap_or(path)
+            .iter()
+            .map(|part| part.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Add every file in a directory that isn't organized as a store (e.g. an existing collection
+    /// of downloaded images), including files in its subdirectories.
+    ///
+    /// Each file is read and hashed, and is then hard-linked into place, so the original files
+    /// shouldn't be modified afterwards. Files are copied instead if the directory is on a
+    /// different file system, or if they are packed, compressed, or changed by the pipeline.
+    /// Hidden files and directories are skipped, as is the store's own base directory, and symbolic
+    /// links aren't followed.
+    ///
+    /// Returns the path of each file (in sorted order) and the action for saving it, which
+    /// indicates whether it was added or was already present.
+    pub fn import_dir<P: AsRef<Path>>(&self, path: P) -> Result<Vec<(PathBuf, Action)>, Error> {
+        let mut paths = vec![];
+        self.find_import_files(path.as_ref(), &mut paths)?;
+        paths.sort_unstable();
+
+        paths
+            .into_iter()
+            .map(|path| {
+                let action = self.import_file(&path)?;
+
+                Ok((path, action))
+            })
+            .collect()
+    }
+
+    fn find_import_files(&self, directory: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+
+            if is_hidden(&entry.file_name()) {
+                continue;
+            }
+
+            if file_type.is_dir() {
+                let path = entry.path();
+
+                if path != self.base {
+                    self.find_import_files(&path, paths)?;
+                }
+            } else if file_type.is_file() {
+                paths.push(entry.path());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn import_file(&self, source: &Path) -> Result<Action, Error> {
+        let bytes = std::fs::read(source)?;
+        let image_type = (self.detector)(&bytes);
+
+        if let Some((transformed, transformation)) = self.transform(image_type, &bytes)? {
+            return self.save_detected(
+                &transformed,
+                (self.detector)(&transformed),
+                Some(transformation),
+            );
+        }
+
+        let digest = self.digest_kind.compute(&bytes);
+        let (path, quarantined) = self.destination(digest, image_type)?;
+        let size = bytes.len() as u64;
+
+        if self.packs_for(size, &path, quarantined).is_some()
+            || self.compresses(image_type, quarantined)
+        {
+            return self.save_detected(&bytes, image_type, None);
+        }
+
+        let reservation = self.reserve(digest, size, quarantined)?;
+        let added = !path.exists() && self.link_new(source, &path, &bytes)?;
+        let evicted = reservation.settle(added);
+
+        if added && !quarantined {
+            self.record_added(digest, size, ImageType::new(image_type))?;
+        }
+
+        record_action(digest, added);
+
+        Ok(self.saved(Action {
+            entry: Entry::file(path, digest),
+            image_type: ImageType::new(image_type),
+            added,
+            quarantined,
+            skipped: false,
+            transformation: None,
+            evicted,
+        }))
+    }
+
+    /// Hard-link a file into place if the destination doesn't exist, falling back to writing the
+    /// given contents if the file is on a different file system, and returning whether it was
+    /// added.
+    fn link_new(&self, source: &Path, path: &Path, bytes: &[u8]) -> Result<bool, std::io::Error> {
+        let directory = path.parent().unwrap_or(&self.base);
+        std::fs::create_dir_all(directory)?;
+
+        match std::fs::hard_link(source, path) {
+            Ok(()) => {
+                if self.fsync {
+                    sync_directory(directory)?;
+                }
+
+                Ok(true)
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+            Err(error) if error.kind() == std::io::ErrorKind::CrossesDevices => {
+                self.write_atomic(path, bytes)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Move every file into the given prefix layout (e.g. to re-shard a flat store).
+    ///
+    /// Files are found by walking the whole directory tree, not just the current layout, so a
+    /// migration that was interrupted can be resumed by running it again. Metadata records are
+    /// moved to the new layout's metadata files, prefix directories that are left empty are
+    /// removed, and the new layout is recorded. Nothing is changed in a dry run.
+    /// Packed files aren't affected, and the store shouldn't be used by anything else while it is
+    /// being migrated.
+    pub fn migrate_to<T: AsRef<[usize]>>(
+        &self,
+        prefix_part_lengths: T,
+        dry_run: bool,
+    ) -> Result<MigrationCounts, Error> {
+        let target = self.clone().with_prefix_part_lengths(prefix_part_lengths)?;
+        let mut counts = MigrationCounts::default();
+        let mut moves = vec![];
+
+        // Everything is listed before anything is moved, so that moved files aren't seen twice.
+        self.find_misplaced(&target, &self.base, &mut moves, &mut counts)?;
+
+        counts.moved = moves.len() as u64;
+
+        if !dry_run {
+            for (from, to) in moves {
+                // We construct the path, so we know there will always be a parent.
+                if let Some(parent) = to.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+
+                // A file with the same name has the same contents.
+                if to.exists() {
+                    std::fs::remove_file(&from)?;
+                } else {
+                    move_file(&from, &to)?;
+                }
+            }
+
+            self.migrate_metadata(&target)?;
+            Self::remove_empty_directories(&self.base)?;
+            target.layout().write(&self.base)?;
+        }
+
+        Ok(counts)
+    }
+
+    /// Move metadata records into the metadata files for the given layout.
+    fn migrate_metadata(&self, target: &Self) -> Result<(), Error> {
+        let mut paths = vec![];
+        Self::find_metadata_files(&self.base, &mut paths)?;
+
+        let mut records = vec![];
+
+        for path in &paths {
+            records.extend(crate::metadata::read(path)?);
+        }
+
+        for path in paths {
+            std::fs::remove_file(path)?;
+        }
+
+        for (digest, metadata) in records {
+            target.append_metadata(digest, &metadata)?;
+        }
+
+        Ok(())
+    }
+
+    fn find_metadata_files(directory: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Error> {
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+
+            if entry.file_name() == crate::metadata::FILE_NAME {
+                paths.push(entry.path());
+            } else if !is_hidden(&entry.file_name()) && entry.file_type()?.is_dir() {
+                Self::find_metadata_files(&entry.path(), paths)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn find_misplaced(
+        &self,
+        target: &Self,
+        directory: &Path,
+        moves: &mut Vec<(PathBuf, PathBuf)>,
+        counts: &mut MigrationCounts,
+    ) -> Result<(), Error> {
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+            let path = entry.path();
+
+            if is_hidden(&entry.file_name()) {
+                continue;
+            }
+
+            if entry.file_type()?.is_dir() {
+                self.find_misplaced(target, &path, moves, counts)?;
+            } else {
+                let file_name = entry.file_name();
+                let (file_name, compressed) = split_compressed_extension(&file_name);
+
+                let digest = std::str::from_utf8(file_name)
+                    .ok()
+                    .and_then(|file_name| Digest::from_hex(self.digest_kind, file_name).ok())
+                    .ok_or_else(|| Error::InvalidFileName(path.clone()))?;
+
+                let new_path = if compressed {
+                    target.compressed_path(digest)
+                } else {
+                    target.path(digest)
+                };
+
+                if new_path == path {
+                    counts.unchanged += 1;
+                } else {
+                    moves.push((path, new_path));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check the contents of every file against its name, moving files that don't match and
+    /// removing zero-byte leftovers.
+    ///
+    /// Files that don't match are moved to the path for the digest of their contents (or to a
+    /// quarantine directory), and removed if a file is already there. The manifest (if there is
+    /// one) is updated. Packed files aren't checked.
+    pub fn repair(&self, mode: &RepairMode) -> Result<RepairReport, Error> {
+        let mut report = RepairReport::default();
+        let mut mismatched = vec![];
+
+        // Everything is checked before anything is moved, so that moved files aren't seen twice.
+        for entry in self.walk() {
+            let entry = entry?;
+
+            if entry.packed.is_some() {
+                continue;
+            }
+
+            match entry.validate()? {
+                Ok(()) => {
+                    report.valid += 1;
+                }
+                Err(actual) => mismatched.push((entry, actual)),
+            }
+        }
+
+        for (entry, actual) in mismatched {
+            let size = entry.size()?;
+
+            if size == 0 {
+                std::fs::remove_file(&entry.path)?;
+                self.forget(&entry)?;
+                report.removed_empty.push(entry.path);
+
+                continue;
+            }
+
+            let destination = match mode {
+                RepairMode::Rename if entry.compressed => self.compressed_path(actual),
+                RepairMode::Rename => self.path(actual),
+                RepairMode::Quarantine(directory) => directory.join(format!("{actual:x}")),
+            };
+
+            // A file with the same name has the same contents.
+            let destination = if destination.exists() {
+                std::fs::remove_file(&entry.path)?;
+
+                None
+            } else {
+                // We construct the path, so we know there will always be a parent.
+                if let Some(parent) = destination.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+
+                if entry.compressed && *mode != RepairMode::Rename {
+                    // Quarantined files are never compressed.
+                    std::fs::write(&destination, entry.read()?)?;
+                    std::fs::remove_file(&entry.path)?;
+                } else {
+                    move_file(&entry.path, &destination)?;
+                }
+
+                if *mode == RepairMode::Rename {
+                    let image_type = self.image_type(&Entry {
+                        compressed: entry.compressed,
+                        ..Entry::file(destination.clone(), actual)
+                    })?;
+                    self.record_added(actual, size, image_type)?;
+                    self.notify_added(actual, image_type);
+                }
+
+                Some(destination)
+            };
+
+            self.forget(&entry)?;
+
+            report.mismatched.push(MismatchedFile {
+                path: entry.path,
+                expected: entry.digest,
+                actual,
+                destination,
+            });
+        }
+
+        Ok(report)
+    }
+
+    /// Update the manifest, read cache, and prefix directories after an entry's file is removed.
+    fn forget(&self, entry: &Entry) -> Result<(), Error> {
+        self.remove_empty_prefix_directories(&entry.path)?;
+
+        if let Some(cache) = &self.read_cache {
+            cache.remove(&entry.digest);
+        }
+
+        crate::manifest::append(self.manifest_path(), Line::Removed(entry.digest))?;
+        self.observers.deleted(entry.digest);
+
+        Ok(())
+    }
+
+    /// Remove the (non-hidden) directories below the given one that are empty after their own
+    /// empty subdirectories are removed, returning whether the given directory is now empty.
+    fn remove_empty_directories(directory: &Path) -> Result<bool, std::io::Error> {
+        let mut is_empty = true;
+
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+
+            if !is_hidden(&entry.file_name())
+                && entry.file_type()?.is_dir()
+                && Self::remove_empty_directories(&entry.path())?
+            {
+                std::fs::remove_dir(entry.path())?;
+            } else {
+                is_empty = false;
+            }
+        }
+
+        Ok(is_empty)
+    }
+
+    /// Remove the prefix directories that are empty (from the innermost out), returning the number
+    /// of directories removed.
+    ///
+    /// Deletions and interrupted writes can leave empty directories, which slow down iteration.
+    /// Only directories in the prefix layout are removed (hidden directories are skipped), and a
+    /// directory that a file is saved to concurrently is left in place.
+    pub fn gc_empty_dirs(&self) -> Result<usize, Error> {
+        let mut removed = 0;
+
+        Self::remove_empty_prefix_directories_below(
+            &self.base,
+            self.prefix_part_lengths.len(),
+            &mut removed,
+        )?;
+
+        Ok(removed)
+    }
+
+    /// Remove the empty prefix directories below the given one (which has the given number of
+    /// prefix levels below it), returning whether the given directory is now empty.
+    fn remove_empty_prefix_directories_below(
+        directory: &Path,
+        depth: usize,
+        removed: &mut usize,
+    ) -> Result<bool, std::io::Error> {
+        let mut is_empty = true;
+
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+
+            if depth > 0
+                && !is_hidden(&entry.file_name())
+                && entry.file_type()?.is_dir()
+                && Self::remove_empty_prefix_directories_below(&entry.path(), depth - 1, removed)?
+            {
+                match std::fs::remove_dir(entry.path()) {
+                    Ok(()) => {
+                        *removed += 1;
+                    }
+                    // A file may have been saved under the prefix since it was checked.
+                    Err(error) if error.kind() == std::io::ErrorKind::DirectoryNotEmpty => {
+                        is_empty = false;
+                    }
+                    Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(error) => return Err(error),
+                }
+            } else {
+                is_empty = false;
+            }
+        }
+
+        Ok(is_empty)
+    }
+
+    /// Count the files in the store, in total and for each top-level prefix.
+    ///
+    /// This walks the directory tree (ignoring any manifest), but doesn't parse file names or open
+    /// files, so it is much faster than iterating over [`Store::walk`] for large stores. File
+    /// names are not validated. Packed files are included.
+    pub fn count_entries(&self) -> Result<Counts, Error> {
+        let mut counts = Counts::default();
+
+        match self.prefix_part_lengths.split_first() {
+            Some((_, rest)) => {
+                for entry in std::fs::read_dir(&self.base)? {
+                    let entry = entry?;
+
+                    if !is_hidden(&entry.file_name()) && entry.file_type()?.is_dir() {
+                        let count = Self::count_files(&entry.path(), rest.len())?;
+
+                        counts.total += count;
+                        counts
+                            .prefixes
+                            .insert(entry.file_name().to_string_lossy().into_owned(), count);
+                    }
+                }
+            }
+            None => {
+                counts.total = Self::count_files(&self.base, 0)?;
+            }
+        }
+
+        if let Some(packs) = &self.packs {
+            for (digest, _, _) in packs.list() {
+                counts.total += 1;
+
+                if let Some(first_length) = self.prefix_part_lengths.first() {
+                    let hex = format!("{digest:x}");
+
+                    *counts
+                        .prefixes
+                        .entry(hex[..*first_length].to_string())
+                        .or_default() += 1;
+                }
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Count the (non-hidden) files in the directories at the given depth below a directory.
+    fn count_files(directory: &Path, depth: usize) -> Result<u64, Error> {
+        let mut count = 0;
+
+        for entry in std::fs::read_dir(directory)? {
+            let entry = entry?;
+
+            if !is_hidden(&entry.file_name()) {
+                let file_type = entry.file_type()?;
+
+                if depth == 0 {
+                    if file_type.is_file() {
+                        count += 1;
+                    }
+                } else if file_type.is_dir() {
+                    count += Self::count_files(&entry.path(), depth - 1)?;
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Compute summary statistics, using the manifest if there is one.
+    ///
+    /// Without a manifest, every file has to be opened to determine its type.
+    pub fn stats(&self) -> Result<Stats, Error> {
+        let mut stats = Stats::default();
+        let mut add = |size: u64, image_type: ImageType| {
+            stats.files += 1;
+            stats.bytes += size;
+            *stats.image_types.entry(image_type).or_default() += 1;
+        };
+
+        if self.has_manifest() {
+            for record in crate::manifest::read(self.manifest_path())? {
+                add(record.size, record.image_type);
+            }
+        } else {
+            for entry in self.walk_unsorted() {
+                let entry = entry?;
+
+                add(entry.size()?, self.image_type(&entry)?);
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Detect the image type of an entry from its contents.
+    pub fn image_type(&self, entry: &Entry) -> Result<ImageType, std::io::Error> {
+        let header = match entry.packed {
+            Some(span) => {
+                let mut header = span.read(&entry.path)?;
+                header.truncate(HEADER_LEN);
+                header
+            }
+            None if entry.compressed => {
+                let mut header = Vec::with_capacity(HEADER_LEN);
+                decompress(File::open(&entry.path)?)?
+                    .take(HEADER_LEN as u64)
+                    .read_to_end(&mut header)?;
+                header
+            }
+            None => {
+                let mut header = Vec::with_capacity(HEADER_LEN);
+                File::open(&entry.path)?
+                    .take(HEADER_LEN as u64)
+                    .read_to_end(&mut header)?;
+                header
+            }
+        };
+
+        Ok(ImageType::new((self.detector)(&header)))
+    }
+
+    /// Record a newly added file in the manifest (if there is one).
+    fn record_added(&self, digest: Digest, size: u64, image_type: ImageType) -> Result<(), Error> {
+        crate::manifest::append(
+            self.manifest_path(),
+            Line::Added(Record {
+                digest,
+                size,
+                image_type,
+                added_at: SystemTime::now(),
+            }),
+        )?;
+
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "save",
+            skip_all,
+            fields(bytes = bytes.as_ref().len(), digest = Empty, added = Empty)
+        )
+    )]
+    pub fn save<T: AsRef<[u8]> + Copy>(&self, bytes: T) -> Result<Action, Error> {
+        let image_type = (self.detector)(bytes.as_ref());
+
+        match self.transform(image_type, bytes.as_ref())? {
+            Some((transformed, transformation)) => self.save_detected(
+                &transformed,
+                (self.detector)(&transformed),
+                Some(transformation),
+            ),
+            None => self.save_detected(bytes.as_ref(), image_type, None),
+        }
+    }
+
+    /// Save a file whose digest is already known (e.g. when re-importing from logs or another
+    /// store), failing before anything is written if its contents don't match.
+    ///
+    /// The contents are saved as they are (the pipeline isn't applied), so that the saved file has
+    /// the expected digest.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "save",
+            skip_all,
+            fields(bytes = bytes.as_ref().len(), digest = Empty, added = Empty)
+        )
+    )]
+    pub fn save_with_expected<T: AsRef<[u8]>>(
+        &self,
+        bytes: T,
+        expected: Digest,
+    ) -> Result<Action, Error> {
+        if expected.kind() != self.digest_kind {
+            return Err(Error::DigestKindMismatch {
+                expected: self.digest_kind,
+                found: expected.kind(),
+            });
+        }
+
+        let actual = self.digest_kind.compute(bytes.as_ref());
+
+        if actual != expected {
+            return Err(Error::UnexpectedDigest { expected, actual });
+        }
+
+        self.save_detected(bytes.as_ref(), (self.detector)(bytes.as_ref()), None)
+    }
+
+    /// Apply the pipeline to an image, returning the result if it was changed.
+    fn transform(
+        &self,
+        image_type: Option<Type>,
+        bytes: &[u8],
+    ) -> Result<Option<(Vec<u8>, Transformation)>, Error> {
+        match image_type {
+            Some(image_type) if !self.pipeline.is_empty() => {
+                Ok(self.pipeline.apply(image_type, bytes, self.digest_kind)?)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn save_detected(
+        &self,
+        bytes: &[u8],
+        image_type: Option<Type>,
+        transformation: Option<Transformation>,
+    ) -> Result<Action, Error> {
+        let digest = self.digest_kind.compute(bytes);
+        let (path, quarantined) = self.destination(digest, image_type)?;
+        let size = bytes.len() as u64;
+        let reservation = self.reserve(digest, size, quarantined)?;
+
+        let (entry, added) = if let Some(packs) = self.packs_for(size, &path, quarantined) {
+            let added = packs.put(digest, bytes)?;
+
+            (self.entry(digest), added)
+        } else if self.compresses(image_type, quarantined) {
+            let compressed_path = self.compressed_path(digest);
+
+            let added = !path.exists()
+                && !compressed_path.exists()
+                && self.write_atomic(&compressed_path, &compress(bytes)?)?;
+
+            (self.entry(digest), added)
+        } else {
+            let added = !path.exists() && self.write_atomic(&path, bytes)?;
+
+            (Entry::file(path, digest), added)
+        };
+
+        let evicted = reservation.settle(added);
+
+        if added && !quarantined {
+            self.record_added(digest, size, ImageType::new(image_type))?;
+        }
+
+        record_action(digest, added);
+
+        Ok(self.saved(Action {
+            entry,
+            image_type: ImageType::new(image_type),
+            added,
+            quarantined,
+            skipped: false,
+            transformation,
+            evicted,
+        }))
+    }
+
+    /// Make room for a new file if the store has a quota, evicting files if necessary.
+    ///
+    /// No room is reserved if there's no quota, or if the file is quarantined or already present.
+    /// The quota's state is only locked to update the usage and take candidates for eviction, not
+    /// while the store is walked or files are removed.
+    fn reserve(
+        &self,
+        digest: Digest,
+        size: u64,
+        quarantined: bool,
+    ) -> Result<Reservation<'_>, Error> {
+        let Some(quota) = &self.quota else {
+            return Ok(Reservation::none());
+        };
+
+        if quarantined || self.lookup(digest).is_some() {
+            return Ok(Reservation::none());
+        }
+
+        if !quota.fits(size) {
+            return Err(Error::QuotaExceeded(digest));
+        }
+
+        let mut evicted = vec![];
+        // Whether the eviction order has been computed for this file.
+        let mut walked = false;
+
+        loop {
+            let mut state = quota.state();
+
+            let Some(usage) = state.usage else {
+                drop(state);
+                let usage = self.usage()?;
+                quota.state().usage.get_or_insert(usage);
+
+                continue;
+            };
+
+            if !quota.exceeded_by(usage, size) {
+                state.usage = Some(Usage {
+                    files: usage.files + 1,
+                    bytes: usage.bytes + size,
+                });
+
+                break;
+            }
+
+            let candidate = state.candidates.pop();
+            drop(state);
+
+            match candidate {
+                Some((candidate, candidate_size)) => {
+                    if candidate != digest && self.remove(candidate)? {
+                        quota.release(candidate_size);
+                        evicted.push(candidate);
+                    }
+                }
+                None if !walked => {
+                    let candidates = self.eviction_candidates(quota.eviction())?;
+                    quota.state().candidates = candidates;
+                    walked = true;
+                }
+                None => {
+                    // The usage was out of date (e.g. because files were removed by another
+                    // process).
+                    let usage = self.usage()?;
+                    quota.state().usage = Some(usage);
+
+                    if quota.exceeded_by(usage, size) {
+                        return Err(Error::QuotaExceeded(digest));
+                    }
+                }
+            }
+        }
+
+        #[cfg(feature = "tracing")]
+        if !evicted.is_empty() {
+            tracing::info!(count = evicted.len(), "evicted files to stay within quota");
+        }
+
+        Ok(Reservation::new(quota, size, evicted))
+    }
+
+    /// Count the files in the store and their total size by walking it.
+    fn usage(&self) -> Result<Usage, Error> {
+        let mut usage = Usage::default();
+
+        for entry in self.walk_unsorted() {
+            usage.files += 1;
+            usage.bytes += entry?.size()?;
+        }
+
+        Ok(usage)
+    }
+
+    /// List the files in the store with their sizes, in reverse order of eviction.
+    fn eviction_candidates(&self, eviction: &Eviction) -> Result<Vec<(Digest, u64)>, Error> {
+        let mut candidates = vec![];
+
+        for entry in self.walk_unsorted() {
+            let entry = entry?;
+
+            // Packed files use the times of their pack files.
+            let time = match eviction {
+                Eviction::LeastRecentlyUsed => {
+                    let metadata = std::fs::metadata(&entry.path)?;
+
+                    metadata.accessed().or_else(|_| metadata.modified())?
+                }
+                Eviction::OldestModified => std::fs::metadata(&entry.path)?.modified()?,
+                Eviction::ByTimestamp(timestamp) => {
+                    timestamp(entry.digest).unwrap_or(SystemTime::UNIX_EPOCH)
+                }
+            };
+
+            candidates.push((std::cmp::Reverse(time), entry.digest, entry.size()?));
+        }
+
+        candidates.sort_unstable();
+
+        Ok(candidates
+            .into_iter()
+            .map(|(_, digest, size)| (digest, size))
+            .collect())
+    }
+
+    /// Write a new file by writing a temporary file in the same directory and moving it into place,
+    /// returning whether the file was added (`false` if it already exists).
+    fn write_atomic(&self, path: &Path, bytes: &[u8]) -> Result<bool, std::io::Error> {
+        let directory = path.parent().unwrap_or(&self.base);
+        let temp_path = temp_file_path(directory);
+
+        let result = std::fs::create_dir_all(directory)
+            .and_then(|()| write_new(&temp_path, bytes, self.fsync))
+            .and_then(|()| persist_new(&temp_path, path))
+            .and_then(|added| {
+                if self.fsync && added {
+                    sync_directory(directory)?;
+                }
+
+                Ok(added)
+            });
+
+        if result.is_err() {
+            // The temporary file may not exist (or may already have been removed).
+            let _ = std::fs::remove_file(&temp_path);
+        }
+
+        result
+    }
+
+    /// Return the packs if a file should be packed.
+    ///
+    /// Files are never packed if they are quarantined or already saved individually.
+    fn packs_for(&self, size: u64, path: &Path, quarantined: bool) -> Option<&Packs> {
+        self.packs
+            .as_ref()
+            .filter(|packs| !quarantined && packs.accepts(size) && !path.exists())
+    }
+
+    /// Determine where a file should be saved, returning whether it is quarantined.
+    fn destination(
+        &self,
+        digest: Digest,
+        image_type: Option<Type>,
+    ) -> Result<(PathBuf, bool), Error> {
+        match (image_type, &self.non_image_policy) {
+            (None, NonImagePolicy::Reject) => Err(Error::NotAnImage(digest)),
+            (None, NonImagePolicy::Quarantine(directory)) => {
+                Ok((directory.join(format!("{digest:x}")), true))
+            }
+            _ => Ok((self.path(digest), false)),
+        }
+    }
+
+    /// Start writing a file whose contents will arrive incrementally.
+    ///
+    /// The contents are written to a temporary file in the store's base directory, and the digest
+    /// is computed as the data arrives.
+    pub fn writer(&self) -> Result<Writer<'_>, Error> {
+        std::fs::create_dir_all(&self.base)?;
+
+        let temp_path = temp_file_path(&self.base);
+
+        let file = File::options()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+
+        Ok(Writer {
+            store: self,
+            temp_path,
+            file: Some(file),
+            hasher: self.digest_kind.hasher(),
+            header: Vec::with_capacity(HEADER_LEN),
+            size: 0,
+        })
+    }
+
+    /// Remove the file for the given digest, returning whether a file was removed.
+    ///
+    /// Prefix directories that are left empty are also removed.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(digest = %format!("{digest:x}")))
+    )]
+    pub fn delete(&self, digest: Digest) -> Result<bool, Error> {
+        // The size is only needed to keep the quota's usage up to date.
+        let size = match &self.quota {
+            Some(_) => self.entry(digest).size().unwrap_or_default(),
+            None => 0,
+        };
+
+        let removed = self.remove(digest)?;
+
+        if removed && let Some(quota) = &self.quota {
+            quota.release(size);
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove the file for the given digest without updating the quota's usage.
+    fn remove(&self, digest: Digest) -> Result<bool, Error> {
+        let mut removed = false;
+
+        // There should only be one of these, but both could be saved by concurrent processes.
+        for path in [self.path(digest), self.compressed_path(digest)] {
+            match std::fs::remove_file(&path) {
+                Ok(()) => {
+                    self.remove_empty_prefix_directories(&path)?;
+
+                    removed = true;
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+                Err(error) => return Err(Error::from(error)),
+            }
+        }
+
+        if !removed && let Some(packs) = &self.packs {
+            removed = packs.remove(digest)?;
+        }
+
+        if let Some(cache) = &self.read_cache {
+            cache.remove(&digest);
+        }
+
+        if removed {
+            crate::manifest::append(self.manifest_path(), Line::Removed(digest))?;
+            self.observers.deleted(digest);
+        }
+
+        Ok(removed)
+    }
+
+    /// Remove the prefix directories containing a path that are empty (from the innermost out).
+    fn remove_empty_prefix_directories(&self, path: &Path) -> Result<(), std::io::Error> {
+        for directory in path
+            .ancestors()
+            .skip(1)
+            .take(self.prefix_part_lengths.len())
+        {
+            match std::fs::remove_dir(directory) {
+                Ok(()) => {}
+                // Another file may be saved under the prefix at any point, so this isn't an error.
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        std::io::ErrorKind::DirectoryNotEmpty | std::io::ErrorKind::NotFound
+                    ) =>
+                {
+                    break;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn path(&self, digest: Digest) -> PathBuf {
+        let digest_string = format!("{digest:x}");
+        let mut digest_remaining = digest_string.as_str();
+        let mut path = self.base.clone();
+
+        for prefix_part_length in &self.prefix_part_lengths {
+            let next = &digest_remaining[0..*prefix_part_length];
+            digest_remaining = &digest_remaining[*prefix_part_length..];
+
+            path.push(next);
+        }
+
+        path.push(digest_string);
+
+        path
+    }
+
+    /// Return the path of the compressed file for a digest (which may not exist).
+    #[must_use]
+    pub fn compressed_path(&self, digest: Digest) -> PathBuf {
+        self.path(digest).with_extension(COMPRESSED_EXTENSION)
+    }
+
+    /// Return the path of the file for an entry in this store's layout.
+    fn entry_path(&self, entry: &Entry) -> PathBuf {
+        if entry.compressed {
+            self.compressed_path(entry.digest)
+        } else {
+            self.path(entry.digest)
+        }
+    }
+}
+
+/// Where downloaded files are saved.
+///
+/// [`Store`] is the default implementation, which saves files on the local file system. Entries
+/// returned by other backends can use their paths for any key that identifies the file.
+pub trait StoreBackend: Clone + Send + Sync + 'static {
+    type Writer<'a>: StoreWriter
+    where
+        Self: 'a;
+
+    /// Save a file, returning what was done with it.
+    fn save(&self, bytes: &[u8]) -> Result<Action, Error>;
+
+    /// Start writing a file whose contents will arrive incrementally.
+    fn writer(&self) -> Result<Self::Writer<'_>, Error>;
+
+    /// Return the entry for a digest if the file is in the store.
+    fn lookup(&self, digest: Digest) -> Option<Entry>;
+
+    fn exists(&self, digest: Digest) -> bool {
+        self.lookup(digest).is_some()
+    }
+
+    /// Return the location the file for a digest is (or would be) saved at.
+    fn path(&self, digest: Digest) -> PathBuf;
+
+    /// Read the contents of a file, if it is in the store.
+    fn read(&self, digest: Digest) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Save a file on a thread where blocking is allowed, so that the runtime isn't blocked.
+    #[cfg(feature = "client")]
+    fn save_async(
+        &self,
+        bytes: bytes::Bytes,
+    ) -> impl Future<Output = Result<Action, Error>> + Send {
+        let backend = self.clone();
+
+        async move { tokio::task::spawn_blocking(move || backend.save(&bytes)).await? }
+    }
+
+    /// Save a file whose contents arrive as a stream of chunks, without keeping it in memory.
+    ///
+    /// The chunks are written with [`StoreBackend::writer`] (which computes the digest as they
+    /// arrive) on a thread where blocking is allowed, and errors from the stream are returned
+    /// unchanged.
+    #[cfg(feature = "client")]
+    fn save_stream<S, E>(&self, stream: S) -> impl Future<Output = Result<Action, E>> + Send
+    where
+        S: futures::Stream<Item = Result<bytes::Bytes, E>> + Send,
+        E: From<Error> + Send,
+    {
+        let mut writer = BackgroundWriter::new(self.clone());
+
+        async move {
+            let mut stream = std::pin::pin!(stream);
+
+            while let Some(chunk) = futures::StreamExt::next(&mut stream).await {
+                match chunk {
+                    Ok(chunk) => writer.write(chunk).await?,
+                    Err(error) => {
+                        writer.abort().await;
+
+                        return Err(error);
+                    }
+                }
+            }
+
+            Ok(writer.finish().await?)
+        }
+    }
+
+    /// Read the contents of a file (if it is in the store) on a thread where blocking is allowed.
+    #[cfg(feature = "client")]
+    fn read_async(
+        &self,
+        digest: Digest,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, Error>> + Send {
+        let backend = self.clone();
+
+        async move { tokio::task::spawn_blocking(move || backend.read(digest)).await? }
+    }
+
+    /// Iterate over the files in the store, in order of digest.
+    fn entries(&self) -> Box<dyn Iterator<Item = Result<Entry, IterationError>> + '_>;
+
+    /// Iterate over the files in the store that come after the given position, in order of digest.
+    fn entries_after(
+        &self,
+        cursor: Cursor,
+    ) -> Box<dyn Iterator<Item = Result<Entry, IterationError>> + '_>;
+
+    /// Remove the file for the given digest, returning whether a file was removed.
+    fn delete(&self, digest: Digest) -> Result<bool, Error>;
+
+    /// Record where a saved file came from, returning whether it was recorded (by default backends
+    /// don't keep metadata).
+    fn record_metadata(&self, _digest: Digest, _metadata: &Metadata) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    /// Set what is done with files whose contents aren't recognized as an image.
+    #[must_use]
+    fn with_non_image_policy(self, non_image_policy: NonImagePolicy) -> Self;
+}
+
+/// An in-progress save of a file whose contents arrive incrementally.
+pub trait StoreWriter: Write + Send {
+    /// Save the file, returning what was done with it.
+    fn finish(self) -> Result<Action, Error>;
+}
+
+/// Number of chunks that can be waiting to be written by a [`BackgroundWriter`].
+#[cfg(feature = "client")]
+const WRITER_CHANNEL_CAPACITY: usize = 16;
+
+/// A store writer that runs on a thread where blocking is allowed, so that writing downloaded chunks
+/// doesn't block the runtime.
+///
+/// If it's dropped before it's finished, the file isn't saved.
+#[cfg(feature = "client")]
+pub(crate) struct BackgroundWriter {
+    /// Chunks to write, or `None` when the file is complete
+    sender: tokio::sync::mpsc::Sender<Option<bytes::Bytes>>,
+    handle: tokio::task::JoinHandle<Result<Action, Error>>,
+}
+
+#[cfg(feature = "client")]
+impl BackgroundWriter {
+    pub(crate) fn new<B: StoreBackend>(backend: B) -> Self {
+        let (sender, mut receiver) =
+            tokio::sync::mpsc::channel::<Option<bytes::Bytes>>(WRITER_CHANNEL_CAPACITY);
+
+        let handle = tokio::task::spawn_blocking(move || {
+            let mut writer = backend.writer()?;
+
+            loop {
+                match receiver.blocking_recv() {
+                    Some(Some(chunk)) => writer.write_all(&chunk)?,
+                    Some(None) => return writer.finish(),
+                    // The download failed, and the temporary file is removed when the writer is
+                    // dropped.
+                    None => {
+                        return Err(std::io::Error::from(std::io::ErrorKind::Interrupted).into());
+                    }
+                }
+            }
+        });
+
+        Self { sender, handle }
+    }
+
+    /// Queue a chunk to be written, failing if an earlier write failed.
+    pub(crate) async fn write(&mut self, chunk: bytes::Bytes) -> Result<(), Error> {
+        if self.sender.send(Some(chunk)).await.is_ok() {
+            Ok(())
+        } else {
+            // The writer only stops receiving chunks early if it fails.
+            Err(match (&mut self.handle).await? {
+                Err(error) => error,
+                Ok(_) => std::io::Error::from(std::io::ErrorKind::BrokenPipe).into(),
+            })
+        }
+    }
+
+    /// Stop without saving the file, waiting until its temporary file has been removed.
+    pub(crate) async fn abort(self) {
+        drop(self.sender);
+
+        // The writer fails (and removes the temporary file) when the channel is closed.
+        let _ = self.handle.await;
+    }
+
+    /// Save the file once all of its chunks have been queued.
+    pub(crate) async fn finish(self) -> Result<Action, Error> {
+        // If the writer has already failed, its error is returned below.
+        let _ = self.sender.send(None).await;
+
+        self.handle.await?
+    }
+}
+
+impl StoreBackend for Store {
+    type Writer<'a> = Writer<'a>;
+
+    fn save(&self, bytes: &[u8]) -> Result<Action, Error> {
+        Self::save(self, bytes)
+    }
+
+    fn writer(&self) -> Result<Writer<'_>, Error> {
+        Self::writer(self)
+    }
+
+    fn lookup(&self, digest: Digest) -> Option<Entry> {
+        Self::lookup(self, digest)
+    }
+
+    fn path(&self, digest: Digest) -> PathBuf {
+        Self::path(self, digest)
+    }
+
+    fn read(&self, digest: Digest) -> Result<Option<Vec<u8>>, Error> {
+        Self::read(self, digest)
+    }
+
+    fn entries(&self) -> Box<dyn Iterator<Item = Result<Entry, IterationError>> + '_> {
+        Box::new(Self::entries(self))
+    }
+
+    fn entries_after(
+        &self,
+        cursor: Cursor,
+    ) -> Box<dyn Iterator<Item = Result<Entry, IterationError>> + '_> {
+        Box::new(Self::entries(self).resume(cursor))
+    }
+
+    fn delete(&self, digest: Digest) -> Result<bool, Error> {
+        Self::delete(self, digest)
+    }
+
+    fn record_metadata(&self, digest: Digest, metadata: &Metadata) -> Result<bool, Error> {
+        Self::record_metadata(self, digest, metadata)
+    }
+
+    fn with_non_image_policy(self, non_image_policy: NonImagePolicy) -> Self {
+        Self::with_non_image_policy(self, non_image_policy)
+    }
+}
+
+/// Prefix parts must be non-empty, and can't be longer than a digest in total.
+pub(crate) fn check_prefix_part_lengths(
+    prefix_part_lengths: &[usize],
+) -> Result<(), InitializationError> {
+    if prefix_part_lengths.iter().copied().sum::<usize>() > 32 || prefix_part_lengths.contains(&0) {
+        Err(InitializationError::InvalidPrefixPartLengths(
+            prefix_part_lengths.to_vec(),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// Record the result of saving a file on the current save span.
+#[cfg(feature = "tracing")]
+pub(crate) fn record_action(digest: Digest, added: bool) {
+    let span = Span::current();
+    span.record(
+        "digest",
+        tracing::field::display(format_args!("{digest:x}")),
+    );
+    span.record("added", added);
+
+    tracing::debug!("image saved");
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) const fn record_action(_digest: Digest, _added: bool) {}
+
+/// Return a new temporary file path in a directory (which will be hidden from iteration).
+fn temp_file_path(directory: &Path) -> PathBuf {
+    directory.join(format!(
+        ".tmp-{}-{}",
+        std::process::id(),
+        TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Write the contents of a file that must not already exist.
+fn write_new(path: &Path, bytes: &[u8], fsync: bool) -> Result<(), std::io::Error> {
+    let mut file = File::options().write(true).create_new(true).open(path)?;
+    file.write_all(bytes)?;
+
+    if fsync {
+        file.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Flush a directory's entries to disk, so that a file renamed into it isn't lost.
+fn sync_directory(directory: &Path) -> Result<(), std::io::Error> {
+    if cfg!(unix) {
+        File::open(directory)?.sync_all()
+    } else {
+        Ok(())
+    }
+}
+
+/// Move a file into place if the destination doesn't exist (creating its parent directory if
+/// necessary), and otherwise remove it, returning whether it was moved.
+///
+/// The destination is created with a hard link, so that checking whether it exists and creating it
+/// are a single atomic operation. This makes it safe for several processes (e.g. the CLI and the
+/// service) to save files to the same store at once: a file saved concurrently is only reported
+/// as added once, and an existing file is never replaced.
+fn persist_new(from: &Path, to: &Path) -> Result<bool, std::io::Error> {
+    // The parent directory may be removed by another process (if it deletes the last file in a
+    // prefix directory) between being created and being linked into, so this is retried.
+    const ATTEMPTS: usize = 3;
+
+    let mut attempt = 1;
+
+    loop {
+        if let Some(parent) = to.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        match std::fs::hard_link(from, to) {
+            Ok(()) => {
+                std::fs::remove_file(from)?;
+
+                return Ok(true);
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => {
+                std::fs::remove_file(from)?;
+
+                return Ok(false);
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound && attempt < ATTEMPTS => {
+                attempt += 1;
+            }
+            // The quarantine directory may be on a different file system, so the file is copied to
+            // a temporary file next to the destination first.
+            Err(error) if error.kind() == std::io::ErrorKind::CrossesDevices => {
+                let directory = to.parent().unwrap_or_else(|| Path::new("."));
+                let temp_path = temp_file_path(directory);
+
+                std::fs::copy(from, &temp_path)?;
+                std::fs::remove_file(from)?;
+
+                return persist_new(&temp_path, to);
+            }
+            // Some file systems don't support hard links (and some report this as a permission
+            // error), so we fall back to a check that isn't atomic.
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::Unsupported | std::io::ErrorKind::PermissionDenied
+                ) =>
+            {
+                return if to.exists() {
+                    std::fs::remove_file(from)?;
+
+                    Ok(false)
+                } else {
+                    std::fs::rename(from, to)?;
+
+                    Ok(true)
+                };
+            }
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+/// Move a file, copying it if the destination is on a different file system (which is possible
+/// for the quarantine directory).
+fn move_file(from: &Path, to: &Path) -> Result<(), std::io::Error> {
+    match std::fs::rename(from, to) {
+        Err(error) if error.kind() == std::io::ErrorKind::CrossesDevices => {
+            std::fs::copy(from, to)?;
+            std::fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+/// Split the compressed file extension from a file name, returning whether it was present.
+fn split_compressed_extension(file_name: &std::ffi::OsStr) -> (&[u8], bool) {
+    let bytes = file_name.as_encoded_bytes();
+
+    bytes
+        .strip_suffix(COMPRESSED_EXTENSION.as_bytes())
+        .and_then(|bytes| bytes.strip_suffix(b"."))
+        .map_or((bytes, false), |bytes| (bytes, true))
+}
+
+/// Compress a file's contents with zstd.
+#[cfg(feature = "compression")]
+fn compress<R: Read>(source: R) -> Result<Vec<u8>, std::io::Error> {
+    zstd::encode_all(source, 0)
+}
+
+/// Decompress a file's contents as they are read.
+#[cfg(feature = "compression")]
+fn decompress<'a, R: Read + 'a>(source: R) -> Result<impl Read + 'a, std::io::Error> {
+    zstd::Decoder::new(source)
+}
+
+/// Read the size of a compressed file's contents from its frame header, if it is recorded.
+///
+/// Errors are left to be reported when the contents are read instead.
+#[cfg(feature = "compression")]
+fn compressed_content_size(path: &Path) -> Option<u64> {
+    let mut header = Vec::with_capacity(ZSTD_FRAME_HEADER_MAX_LEN);
+    File::open(path)
+        .and_then(|file| {
+            file.take(ZSTD_FRAME_HEADER_MAX_LEN as u64)
+                .read_to_end(&mut header)
+        })
+        .ok()?;
+
+    zstd::zstd_safe::get_frame_content_size(&header)
+        .ok()
+        .flatten()
+}
+
+#[cfg(not(feature = "compression"))]
+fn compress<R: Read>(_source: R) -> Result<Vec<u8>, std::io::Error> {
+    Err(compression_unsupported())
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress<R: Read>(_source: R) -> Result<R, std::io::Error> {
+    Err(compression_unsupported())
+}
+
+#[cfg(not(feature = "compression"))]
+const fn compressed_content_size(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Compressed files can be found but not read or written without the `compression` feature.
+#[cfg(not(feature = "compression"))]
+fn compression_unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "zstd compression requires the compression feature",
+    )
+}
+
+/// Names starting with a dot are reserved for files that are not images (temporary files, etc.).
+fn is_hidden(file_name: &std::ffi::OsStr) -> bool {
+    file_name.as_encoded_bytes().first() == Some(&b'.')
+}
+
+/// An in-progress save of a file whose contents arrive incrementally.
+///
+/// The temporary file is removed if the writer is dropped without successfully calling
+/// [`Writer::finish`].
+pub struct Writer<'a> {
+    store: &'a Store,
+    temp_path: PathBuf,
+    file: Option<File>,
+    hasher: Hasher,
+    header: Vec<u8>,
+    size: u64,
+}
+
+impl Writer<'_> {
+    /// Move the file into place in the store.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "save", skip_all, fields(digest = Empty, added = Empty))
+    )]
+    pub fn finish(mut self) -> Result<Action, Error> {
+        if let Some(mut file) = self.file.take() {
+            file.flush()?;
+
+            if self.store.fsync {
+                file.sync_all()?;
+            }
+        }
+
+        let image_type = (self.store.detector)(&self.header);
+
+        // Transforms need the whole file, so it's only read when there are any.
+        if image_type.is_some() && !self.store.pipeline.is_empty() {
+            let bytes = std::fs::read(&self.temp_path)?;
+
+            if let Some((transformed, transformation)) = self.store.transform(image_type, &bytes)? {
+                std::fs::remove_file(&self.temp_path)?;
+
+                return self.store.save_detected(
+                    &transformed,
+                    (self.store.detector)(&transformed),
+                    Some(transformation),
+                );
+            }
+        }
+
+        let digest = std::mem::take(&mut self.hasher).finalize();
+        let (path, quarantined) = self.store.destination(digest, image_type)?;
+        let reservation = self.store.reserve(digest, self.size, quarantined)?;
+
+        let (entry, added) =
+            if let Some(packs) = self.store.packs_for(self.size, &path, quarantined) {
+                let added = packs.put(digest, &std::fs::read(&self.temp_path)?)?;
+                std::fs::remove_file(&self.temp_path)?;
+
+                (self.store.entry(digest), added)
+            } else if self.store.compresses(image_type, quarantined) {
+                let compressed_path = self.store.compressed_path(digest);
+
+                let added = !path.exists()
+                    && !compressed_path.exists()
+                    && self
+                        .store
+                        .write_atomic(&compressed_path, &compress(File::open(&self.temp_path)?)?)?;
+
+                std::fs::remove_file(&self.temp_path)?;
+
+                (self.store.entry(digest), added)
+            } else {
+                let added = if path.exists() {
+                    std::fs::remove_file(&self.temp_path)?;
+
+                    false
+                } else {
+                    persist_new(&self.temp_path, &path)?
+                };
+
+                if self.store.fsync
+                    && added
+                    && let Some(parent) = path.parent()
+                {
+                    sync_directory(parent)?;
+                }
+
+                (Entry::file(path, digest), added)
+            };
+
+        let evicted = reservation.settle(added);
+
+        if added && !quarantined {
+            self.store
+                .record_added(digest, self.size, ImageType::new(image_type))?;
+        }
+
+        record_action(digest, added);
+
+        Ok(self.store.saved(Action {
+            entry,
+            image_type: ImageType::new(image_type),
+            added,
+            quarantined,
+            skipped: false,
+            transformation: None,
+            evicted,
+        }))
+    }
+}
+
+impl Write for Writer<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| std::io::Error::other("writer already finished"))?;
+
+        let written = file.write(buf)?;
+
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+
+        let header_remaining = HEADER_LEN.saturating_sub(self.header.len());
+        self.header
+            .extend_from_slice(&buf[..written.min(header_remaining)]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.as_mut().map_or(Ok(()), File::flush)
+    }
+}
+
+impl StoreWriter for Writer<'_> {
+    fn finish(self) -> Result<Action, Error> {
+        Writer::finish(self)
+    }
+}
+
+impl Drop for Writer<'_> {
+    fn drop(&mut self) {
+        // The temporary file will already have been moved or removed if the save finished, and
+        // there's nothing useful we can do if this fails.
+        drop(self.file.take());
+        let _ = std::fs::remove_file(&self.temp_path);
+    }
+}
+
+/// The remaining children of a directory that is being walked.
+struct Children {
+    directory: PathBuf,
+    /// Expected length of the children's names (if they are prefix directories)
+    prefix_part_length: Option<usize>,
+    names: ChildNames,
+}
+
+enum ChildNames {
+    /// Names sorted in reverse order (so that the next one can be popped from the end)
+    Sorted(Vec<std::ffi::OsString>),
+    /// Names read lazily, in the order returned by the file system
+    Unsorted(std::fs::ReadDir),
+}
+
+impl Children {
+    fn read(
+        directory: PathBuf,
+        prefix_part_length: Option<usize>,
+        sorted: bool,
+    ) -> Result<Self, IterationError> {
+        if !directory.is_dir() {
+            return Err(IterationError::ExpectedDirectory(directory));
+        }
+
+        let read_dir = std::fs::read_dir(&directory)?;
+
+        // Only names are collected (rather than full paths), to keep large directories small.
+        let names = if sorted {
+            let mut names = read_dir
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .filter(|name| name.as_ref().map_or(true, |name| !is_hidden(name)))
+                .collect::<Result<Vec<_>, std::io::Error>>()?;
+
+            names.sort_unstable_by(|a, b| b.cmp(a));
+
+            ChildNames::Sorted(names)
+        } else {
+            ChildNames::Unsorted(read_dir)
+        };
+
+        Ok(Self {
+            directory,
+            prefix_part_length,
+            names,
+        })
+    }
+
+    fn next_name(&mut self) -> Option<Result<std::ffi::OsString, IterationError>> {
+        match &mut self.names {
+            ChildNames::Sorted(names) => names.pop().map(Ok),
+            ChildNames::Unsorted(read_dir) => read_dir
+                .map(|entry| entry.map(|entry| entry.file_name()))
+                .find(|name| name.as_ref().map_or(true, |name| !is_hidden(name)))
+                .map(|name| name.map_err(IterationError::from)),
+        }
+    }
+
+    /// Return the path of the next child, checking that prefix directory names are valid.
+    fn next_path(&mut self) -> Option<Result<PathBuf, IterationError>> {
+        self.next_name().map(|name| {
+            let path = self.directory.join(name?);
+
+            match (self.prefix_part_length, path.file_name()) {
+                (_, None) => Err(IterationError::InvalidFileName(path)),
+                (Some(prefix_part_length), Some(file_name))
+                    if file_name.len() != prefix_part_length
+                        && file_name
+                            .as_encoded_bytes()
+                            .iter()
+                            .any(|byte| !Entries::is_valid_char(*byte)) =>
+                {
+                    Err(IterationError::InvalidFileName(path))
+                }
+                _ => Ok(path),
+            }
+        })
+    }
+}
+
+pub struct Entries<'a> {
+    /// The base directory, if it hasn't been read yet
+    base: Option<PathBuf>,
+    /// Whether directories are read in sorted order
+    sorted: bool,
+    stack: Vec<Children>,
+    prefix_part_lengths: &'a [usize],
+    digest_kind: DigestKind,
+    /// Entries read from the manifest (if one is used)
+    manifest: Option<std::vec::IntoIter<Result<Entry, IterationError>>>,
+    /// Packed entries that haven't been returned yet
+    packed: std::iter::Peekable<std::vec::IntoIter<Entry>>,
+    /// An individual file that is waiting for preceding packed entries to be returned
+    pending: Option<Result<Entry, IterationError>>,
+    /// The store's base directory
+    root: PathBuf,
+    /// The range of digests that are returned (all if there isn't one)
+    range: Option<PrefixRange>,
+    /// The digest that iteration resumes after (if any)
+    after: Option<Digest>,
+    /// The digest of the last entry returned
+    last: Option<Digest>,
+}
+
+impl Entries<'_> {
+    /// Only return entries whose digests are in the given range.
+    ///
+    /// Prefix directories outside the range aren't read, and validation (with
+    /// [`Entries::validate`] or [`Entries::validate_fail_fast`]) is restricted to the same range.
+    #[must_use]
+    pub fn in_range(self, range: PrefixRange) -> Self {
+        Self {
+            range: Some(range),
+            ..self
+        }
+    }
+
+    /// Only return entries after the given position (e.g. to continue an iteration that was
+    /// interrupted).
+    ///
+    /// Prefix directories that come entirely before the position aren't read. This assumes that
+    /// entries are returned in order of digest, which isn't the case for [`Store::walk_unsorted`].
+    #[must_use]
+    pub fn resume(self, cursor: Cursor) -> Self {
+        Self {
+            after: Some(cursor.last),
+            ..self
+        }
+    }
+
+    /// Return the position after the last entry returned (or the position iteration was resumed
+    /// from, if no entries have been returned), which can be saved to resume iteration later.
+    #[must_use]
+    pub fn cursor(&self) -> Option<Cursor> {
+        self.last.or(self.after).map(Cursor::after)
+    }
+
+    /// Whether the given prefix directory may contain entries that should be returned.
+    fn may_contain_directory(&self, path: &Path) -> bool {
+        self.range
+            .as_ref()
+            .is_none_or(|range| range.may_contain_directory(&self.root, path))
+            && self.after.is_none_or(|after| {
+                PrefixRange::starting_at(after).may_contain_directory(&self.root, path)
+            })
+    }
+
+    /// Whether an entry should be returned.
+    fn includes(&self, digest: Digest) -> bool {
+        self.range
+            .as_ref()
+            .is_none_or(|range| range.contains(digest))
+            && self.after.is_none_or(|after| digest > after)
+    }
+
+    /// Return the next individual file from the directory walk.
+    fn next_file(&mut self) -> Option<Result<Entry, IterationError>> {
+        if let Some(base) = self.base.take() {
+            match Children::read(base, self.prefix_part_lengths.first().copied(), self.sorted) {
+                Ok(children) => self.stack.push(children),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+
+        loop {
+            let depth = self.stack.len();
+
+            match self.stack.last_mut()?.next_path() {
+                None => {
+                    self.stack.pop();
+                }
+                Some(Err(error)) => return Some(Err(error)),
+                // The files are in the directories at the last prefix level.
+                Some(Ok(path)) if depth > self.prefix_part_lengths.len() => {
+                    return Some(Self::path_to_entry(path, self.digest_kind));
+                }
+                Some(Ok(path)) if !self.may_contain_directory(&path) => {}
+                Some(Ok(path)) => {
+                    let prefix_part_length = self.prefix_part_lengths.get(depth).copied();
+
+                    match Children::read(path, prefix_part_length, self.sorted) {
+                        Ok(children) => self.stack.push(children),
+                        Err(error) => return Some(Err(error)),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Return the next entry, ignoring the range and resume position.
+    fn next_unfiltered(&mut self) -> Option<Result<Entry, IterationError>> {
+        if let Some(manifest) = &mut self.manifest {
+            return manifest.next();
+        }
+
+        // Individual files and packed files are merged in order of digest.
+        match self.pending.take().or_else(|| self.next_file()) {
+            Some(Ok(entry)) => match self.packed.next_if(|packed| packed.digest < entry.digest) {
+                Some(packed) => {
+                    self.pending = Some(Ok(entry));
+
+                    Some(Ok(packed))
+                }
+                None => Some(Ok(entry)),
+            },
+            Some(Err(error)) => Some(Err(error)),
+            None => self.packed.next().map(Ok),
+        }
+    }
+
+    const fn is_valid_char(byte: u8) -> bool {
+        byte.is_ascii_lowercase() || byte.is_ascii_digit()
+    }
+
+    fn path_to_entry(path: PathBuf, digest_kind: DigestKind) -> Result<Entry, IterationError> {
+        if path.is_file() {
+            path.file_name()
+                .ok_or_else(|| IterationError::InvalidFileName(path.clone()))
+                .and_then(|file_name| {
+                    let (file_name_bytes, compressed) = split_compressed_extension(file_name);
+
+                    if file_name_bytes
+                        .iter()
+                        .all(|byte| Self::is_valid_char(*byte))
+                    {
+                        let bytes = hex::decode(file_name_bytes)?;
+
+                        Digest::from_slice(digest_kind, &bytes)
+                            .map(|digest| (digest, compressed))
+                            .ok_or_else(|| IterationError::InvalidFileName(path.clone()))
+                    } else {
+                        Err(IterationError::InvalidFileName(path.clone()))
+                    }
+                })
+                .map(|(digest, compressed)| Entry {
+                    path,
+                    digest,
+                    packed: None,
+                    compressed,
+                })
+        } else {
+            Err(IterationError::ExpectedFile(path))
+        }
+    }
+
+    pub fn validate(self) -> impl Iterator<Item = Result<ValidationResult, IterationError>> {
+        self.map(|entry| Ok(ValidationResult::for_entry(entry?)?))
+    }
+
+    pub fn validate_fail_fast(self) -> impl Iterator<Item = Result<Entry, Error>> {
+        self.validate().map(|result| {
+            result
+                .map_err(Error::from)
+                .and_then(ValidationResult::result)
+        })
+    }
+
+    /// Validate every entry, calling the given function with the running totals after each one.
+    ///
+    /// Unlike [`Entries::validate_fail_fast`], this doesn't stop at the first invalid file or
+    /// error, which are collected in the report instead.
+    pub fn validate_with_report<F: FnMut(&ValidationReport)>(
+        self,
+        mut progress: F,
+    ) -> ValidationReport {
+        let started = std::time::Instant::now();
+        let mut report = ValidationReport::default();
+
+        for entry in self {
+            report.add(ValidationOutcome::for_entry(entry));
+            report.elapsed = started.elapsed();
+            progress(&report);
+        }
+
+        report.elapsed = started.elapsed();
+
+        report
+    }
+}
+
+impl Iterator for Entries<'_> {
+    type Item = Result<Entry, IterationError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_unfiltered()? {
+                Ok(entry) if !self.includes(entry.digest) => {}
+                Ok(entry) => {
+                    self.last = Some(entry.digest);
+
+                    return Some(Ok(entry));
+                }
+                result => return Some(result),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hex::FromHex;
+
+    const MINIMAL_JPG_HEX: &str = "ffd8ffe000104a46494600010100000100010000ffdb004300080606070605080707070909080a0c140d0c0b0b0c1912130f141d1a1f1e1d1a1c1c20242e2720222c231c1c2837292c30313434341f27393d38323c2e333432ffdb0043010909090c0b0c180d0d1832211c21323232323232323232323232323232323232323232323232323232323232323232323232323232ffc00011080001000103011100021101031101ffc4001f00000105010101010101000000000000000102030405060708090a0bffc400b51000020103030204030505040400017d010203000411051221314106135161712232819114a1b1c1d1f0e123f1ffda000c03010002110311003f00ff00ffd9";
+    const MINIMAL_PNG_HEX: &str = "89504e470d0a1a0a0000000d4948445200000001000000010802000000907724d90000000a49444154789c6360000002000185d114090000000049454e44ae426082";
+
+    fn minimal_jpg_bytes() -> Vec<u8> {
+        hex::decode(MINIMAL_JPG_HEX).unwrap()
+    }
+
+    fn minimal_png_bytes() -> Vec<u8> {
+        hex::decode(MINIMAL_PNG_HEX).unwrap()
+    }
+
+    fn empty_bytes() -> Vec<u8> {
+        vec![]
+    }
+
+    fn text_bytes() -> Vec<u8> {
+        "foo bar baz".as_bytes().to_vec()
+    }
+
+    fn minimal_jpg_digest() -> [u8; 16] {
+        FromHex::from_hex("79c09c11a8f92599f3c6d389564dd24d").unwrap()
+    }
+
+    fn minimal_png_digest() -> [u8; 16] {
+        FromHex::from_hex("ddf93a3305d41f70e19bb8a04ac673a5").unwrap()
+    }
+
+    fn empty_digest() -> [u8; 16] {
+        FromHex::from_hex("d41d8cd98f00b204e9800998ecf8427e").unwrap()
+    }
+
+    fn text_digest() -> [u8; 16] {
+        FromHex::from_hex("ab07acbb1e496801937adfa772424bf7").unwrap()
+    }
+
+    fn test_save(
+        prefix_part_lengths: Vec<usize>,
+    ) -> Result<Vec<super::Entry>, Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+
+        let store = super::Store::new(base.path().to_path_buf())
+            .with_prefix_part_lengths(&prefix_part_lengths)?;
+        let minimal_jpg_action = store.save(&minimal_jpg_bytes())?;
+        let minimal_png_action = store.save(&minimal_png_bytes())?;
+        let empty_action = store.save(&empty_bytes())?;
+        let text_action = store.save(&text_bytes())?;
+
+        assert!(minimal_jpg_action.added);
+        assert!(minimal_png_action.added);
+        assert!(empty_action.added);
+        assert!(text_action.added);
+
+        assert_eq!(minimal_jpg_action.image_type(), Some(imghdr::Type::Jpeg));
+        assert_eq!(minimal_png_action.image_type(), Some(imghdr::Type::Png));
+        assert_eq!(empty_action.image_type(), None);
+        assert_eq!(text_action.image_type(), None);
+
+        let repeat_minimal_jpg_action = store.save(&minimal_jpg_bytes())?;
+        let repeat_minimal_png_action = store.save(&minimal_png_bytes())?;
+        let repeat_empty_action = store.save(&empty_bytes())?;
+        let repeat_text_action = store.save(&text_bytes())?;
+
+        assert!(!repeat_minimal_jpg_action.added);
+        assert!(!repeat_minimal_png_action.added);
+        assert!(!repeat_empty_action.added);
+        assert!(!repeat_text_action.added);
+
+        let inferred_prefix_parts_length = super::Store::infer_prefix_part_lengths(base.path())?;
+
+        assert_eq!(inferred_prefix_parts_length, Some(prefix_part_lengths));
+
+        let entries = store.entries().collect::<Result<Vec<_>, _>>()?;
+        let digests = entries
+            .iter()
+            .map(|entry| entry.digest.as_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        let expected_digests = vec![
+            minimal_jpg_digest(),
+            text_digest(),
+            empty_digest(),
+            minimal_png_digest(),
+        ];
+
+        assert_eq!(entries.len(), 4);
+        assert_eq!(digests, expected_digests);
+
+        let mut unsorted_digests = store
+            .walk_unsorted()
+            .map(|entry| entry.map(|entry| entry.digest.as_bytes().to_vec()))
+            .collect::<Result<Vec<_>, _>>()?;
+        unsorted_digests.sort_unstable();
+
+        let mut sorted_expected_digests = expected_digests;
+        sorted_expected_digests.sort_unstable();
+
+        assert_eq!(unsorted_digests, sorted_expected_digests);
+
+        Ok(entries)
+    }
+
+    #[test]
+    fn test_save_empty() -> Result<(), Box<dyn std::error::Error>> {
+        test_save(vec![])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_1() -> Result<(), Box<dyn std::error::Error>> {
+        test_save(vec![1])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_2_2() -> Result<(), Box<dyn std::error::Error>> {
+        test_save(vec![2, 2])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_16_3() -> Result<(), Box<dyn std::error::Error>> {
+        test_save(vec![16, 3])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_19_13() -> Result<(), Box<dyn std::error::Error>> {
+        test_save(vec![19, 13])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+
+        let action = store.save(&minimal_jpg_bytes())?;
+
+        assert!(store.delete(action.entry.digest)?);
+        assert!(!action.entry.path.exists());
+        assert!(!store.delete(action.entry.digest)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_prefix_directories() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([1, 2])?;
+
+        let jpg_action = store.save(&minimal_jpg_bytes())?;
+        let png_action = store.save(&minimal_png_bytes())?;
+        let empty_action = store.save(&empty_bytes())?;
+
+        assert!(store.delete(jpg_action.entry.digest)?);
+        assert!(!base.path().join("7").exists());
+
+        // The PNG and the empty file share the first prefix directory.
+        assert!(store.delete(png_action.entry.digest)?);
+        assert!(!base.path().join("d").join("df").exists());
+        assert!(empty_action.entry.path.exists());
+
+        assert!(store.delete(empty_action.entry.digest)?);
+        assert!(!base.path().join("d").exists());
+        assert!(base.path().is_dir());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_and_open() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::Read;
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+
+        let action = store.save(&minimal_jpg_bytes())?;
+        let missing = crate::digest::Digest::compute(b"missing");
+
+        assert_eq!(store.read(action.entry.digest)?, Some(minimal_jpg_bytes()));
+        assert_eq!(store.read(missing)?, None);
+
+        let mut contents = vec![];
+        store
+            .open(action.entry.digest)?
+            .unwrap()
+            .read_to_end(&mut contents)?;
+
+        assert_eq!(contents, minimal_jpg_bytes());
+        assert!(store.open(missing)?.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let cache = crate::read_cache::ReadCache::new(1024, 1024);
+        let store = super::Store::new(base.path()).with_read_cache(cache.clone());
+
+        let action = store.save(&minimal_jpg_bytes())?;
+        let digest = action.entry.digest;
+
+        assert_eq!(store.read(digest)?, Some(minimal_jpg_bytes()));
+        assert_eq!(cache.stats().entries, 1);
+
+        // Cached contents are used even if the file has been removed by something else.
+        std::fs::remove_file(&action.entry.path)?;
+        assert_eq!(
+            store.read_entry(&action.entry)?.as_ref(),
+            minimal_jpg_bytes().as_slice()
+        );
+
+        // Deletion removes the contents from the cache.
+        store.delete(digest)?;
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(store.read(digest)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_writer() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::Write;
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+
+        let mut writer = store.writer()?;
+
+        for chunk in minimal_png_bytes().chunks(5) {
+            writer.write_all(chunk)?;
+        }
+
+        // Unfinished writes should not be visible to iteration.
+        let unfinished = store.writer()?;
+        assert_eq!(store.entries().count(), 0);
+        drop(unfinished);
+
+        let action = writer.finish()?;
+
+        assert!(action.added);
+        assert_eq!(action.entry.digest.as_bytes(), minimal_png_digest());
+        assert_eq!(action.image_type(), Some(imghdr::Type::Png));
+        assert_eq!(std::fs::read(&action.entry.path)?, minimal_png_bytes());
+        assert!(!store.save(&minimal_png_bytes())?.added);
+
+        let entries = store.entries().collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(entries, vec![action.entry]);
+        assert_eq!(std::fs::read_dir(base.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_save_stream() -> Result<(), Box<dyn std::error::Error>> {
+        use super::StoreBackend;
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+
+        let chunks = minimal_png_bytes()
+            .chunks(5)
+            .map(|chunk| Ok::<_, super::Error>(bytes::Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        let action = store.save_stream(futures::stream::iter(chunks)).await?;
+
+        assert!(action.added);
+        assert_eq!(action.entry.digest.as_bytes(), minimal_png_digest());
+        assert_eq!(std::fs::read(&action.entry.path)?, minimal_png_bytes());
+
+        // The temporary file is removed if the stream fails.
+        let failing = futures::stream::iter(vec![
+            Ok(bytes::Bytes::from_static(b"foo")),
+            Err(super::Error::NotAnImage(action.entry.digest)),
+        ]);
+
+        assert!(store.save_stream(failing).await.is_err());
+        assert_eq!(std::fs::read_dir(base.path())?.count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fsync() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::Write;
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_fsync(true);
+
+        let png_action = store.save(&minimal_png_bytes())?;
+
+        let mut writer = store.writer()?;
+        writer.write_all(&minimal_jpg_bytes())?;
+        let jpg_action = writer.finish()?;
+
+        assert!(png_action.added);
+        assert!(jpg_action.added);
+        assert_eq!(std::fs::read(&png_action.entry.path)?, minimal_png_bytes());
+        assert_eq!(std::fs::read(&jpg_action.entry.path)?, minimal_jpg_bytes());
+
+        // No temporary files should be left in the base directory or the prefix directories.
+        for directory in std::fs::read_dir(base.path())? {
+            let directory = directory?.path();
+
+            assert!(directory.is_dir());
+            assert_eq!(std::fs::read_dir(&directory)?.count(), 1);
+        }
+
+        Ok(())
+    }
+
+    fn save_in_chunks<B: super::StoreBackend>(
+        backend: &B,
+        bytes: &[u8],
+    ) -> Result<super::Action, Box<dyn std::error::Error>> {
+        use super::StoreWriter;
+        use std::io::Write;
+
+        let mut writer = backend.writer()?;
+
+        for chunk in bytes.chunks(5) {
+            writer.write_all(chunk)?;
+        }
+
+        Ok(writer.finish()?)
+    }
+
+    #[test]
+    #[cfg(all(feature = "sha256", feature = "blake3"))]
+    fn test_digest_kind() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::digest::DigestKind;
+        use std::io::Write;
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_digest_kind(DigestKind::Sha256)?;
+
+        store.record_layout()?;
+
+        let png_action = store.save(&minimal_png_bytes())?;
+
+        let mut writer = store.writer()?;
+        writer.write_all(&minimal_jpg_bytes())?;
+        let jpg_action = writer.finish()?;
+
+        let png_digest = DigestKind::Sha256.compute(minimal_png_bytes());
+        let jpg_digest = DigestKind::Sha256.compute(minimal_jpg_bytes());
+
+        assert_eq!(png_action.entry.digest, png_digest);
+        assert_eq!(jpg_action.entry.digest, jpg_digest);
+        assert!(png_action.entry.path.ends_with(format!("{png_digest:x}")));
+
+        // The layout is detected when the store is opened again.
+        let reopened = super::Store::load(base.path())?;
+
+        assert_eq!(reopened.digest_kind(), DigestKind::Sha256);
+        assert_eq!(reopened.prefix_part_lengths, vec![2]);
+        assert_eq!(reopened.lookup(png_digest), Some(png_action.entry));
+
+        let mut digests = reopened
+            .entries()
+            .validate_fail_fast()
+            .map(|entry| entry.map(|entry| entry.digest))
+            .collect::<Result<Vec<_>, _>>()?;
+        digests.sort_unstable();
+
+        let mut expected_digests = vec![png_digest, jpg_digest];
+        expected_digests.sort_unstable();
+
+        assert_eq!(digests, expected_digests);
+
+        // Other kinds (and packs) are rejected.
+        assert!(matches!(
+            super::Store::load(base.path())?.with_digest_kind(DigestKind::Blake3),
+            Err(super::Error::DigestKindMismatch { .. })
+        ));
+        assert!(matches!(
+            reopened.clone().with_packs(1024),
+            Err(super::Error::UnsupportedDigestKind(DigestKind::Sha256))
+        ));
+
+        // Stores that have files but don't record a kind use MD5.
+        let md5_base = tempfile::tempdir()?;
+        let md5_store = super::Store::new(md5_base.path()).with_prefix_part_lengths([2])?;
+        md5_store.save(&minimal_png_bytes())?;
+
+        assert_eq!(
+            super::Store::load(md5_base.path())?.digest_kind(),
+            DigestKind::Md5
+        );
+        assert!(matches!(
+            reopened.merge_from(&md5_store, super::MergeMode::Link),
+            Err(super::Error::DigestKindMismatch { .. })
+        ));
+        assert!(matches!(
+            md5_store.with_digest_kind(DigestKind::Blake3),
+            Err(super::Error::DigestKindMismatch { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backend() -> Result<(), Box<dyn std::error::Error>> {
+        use super::StoreBackend;
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+        let backend = &store;
+
+        let png_action = save_in_chunks(backend, &minimal_png_bytes())?;
+        let jpg_action = StoreBackend::save(backend, &minimal_jpg_bytes())?;
+        let digest = jpg_action.entry.digest;
+
+        assert!(png_action.added);
+        assert!(jpg_action.added);
+        assert!(StoreBackend::exists(backend, digest));
+        assert_eq!(StoreBackend::path(backend, digest), jpg_action.entry.path);
+        assert_eq!(
+            StoreBackend::read(backend, digest)?,
+            Some(minimal_jpg_bytes())
+        );
+        assert_eq!(
+            StoreBackend::entries(backend).collect::<Result<Vec<_>, _>>()?,
+            vec![jpg_action.entry, png_action.entry]
+        );
+
+        assert!(StoreBackend::delete(backend, digest)?);
+        assert!(!StoreBackend::exists(backend, digest));
+        assert_eq!(StoreBackend::read(backend, digest)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pipeline() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::transform::{Pipeline, RejectCorrupt, StripMetadata};
+        use std::io::Write;
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_pipeline(Pipeline::new().with(RejectCorrupt).with(StripMetadata));
+
+        // The minimal JPEG's tables are truncated, so a complete one is built from its JFIF segment
+        // (which has to stay first for detection) and a short scan, and a comment is added.
+        let jfif = &minimal_jpg_bytes()[..20];
+        let scan = [0xFF, 0xDA, 0x00, 0x02, 0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD9];
+        let jpg = [jfif, &scan].concat();
+        let commented = [jfif, &[0xFF, 0xFE, 0x00, 0x04, b'h', b'i'], &scan].concat();
+
+        let action = store.save(&commented)?;
+        let transformation = action.transformation.clone().unwrap();
+
+        assert!(action.added);
+        assert_eq!(action.entry.digest, crate::digest::Digest::compute(&jpg));
+        assert_eq!(action.image_type(), Some(imghdr::Type::Jpeg));
+        assert_eq!(
+            transformation.original_digest,
+            crate::digest::Digest::compute(&commented)
+        );
+        assert_eq!(transformation.original_size, commented.len() as u64);
+        assert_eq!(transformation.steps, vec!["strip-metadata".to_string()]);
+
+        let mut writer = store.writer()?;
+        writer.write_all(&commented)?;
+
+        let action = writer.finish()?;
+
+        assert!(!action.added);
+        assert_eq!(action.entry.digest, crate::digest::Digest::compute(&jpg));
+        assert_eq!(action.transformation, Some(transformation));
+
+        // Unchanged images aren't recorded as transformed, and corrupt images aren't saved.
+        let gif = b"GIF89a\x01\x00\x01\x00\x00\x00\x00\x3B";
+
+        assert_eq!(store.save(gif)?.transformation, None);
+        assert!(matches!(
+            store.save(&commented[..commented.len() - 2]),
+            Err(super::Error::Transform(_))
+        ));
+        assert_eq!(store.entries().count(), 2);
+        assert_eq!(std::fs::read_dir(base.path())?.count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_manifest() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+
+        let jpg_action = store.save(&minimal_jpg_bytes())?;
+        store.save(&text_bytes())?;
+
+        let expected_stats = store.stats()?;
+
+        assert!(!store.has_manifest());
+        assert_eq!(store.rebuild_manifest()?, 2);
+        assert!(store.has_manifest());
+        assert_eq!(store.stats()?, expected_stats);
+
+        let png_action = store.save(&minimal_png_bytes())?;
+        assert!(store.delete(jpg_action.entry.digest)?);
+
+        let entries = store.entries().collect::<Result<Vec<_>, _>>()?;
+        let walked = store.walk().collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(entries, walked);
+        assert!(entries.contains(&png_action.entry));
+
+        let stats = store.stats()?;
+
+        assert_eq!(stats.files, 2);
+        assert_eq!(
+            stats.bytes,
+            (minimal_png_bytes().len() + text_bytes().len()) as u64
+        );
+        assert_eq!(
+            stats.image_types.get(&png_action.image_type).copied(),
+            Some(1)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_packs() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_packs(100)?;
+
+        let jpg_action = store.save(&minimal_jpg_bytes())?;
+        let png_action = store.save(&minimal_png_bytes())?;
+        let text_action = store.save(&text_bytes())?;
+
+        // The JPEG is larger than the threshold.
+        assert!(jpg_action.entry.packed.is_none());
+        assert!(png_action.entry.packed.is_some());
+        assert!(!store.path(png_action.entry.digest).exists());
+        assert!(!store.save(&minimal_png_bytes())?.added);
+        assert!(matches!(
+            store.open(png_action.entry.digest),
+            Err(super::Error::Packed(_))
+        ));
+
+        let mut expected = vec![
+            jpg_action.entry.clone(),
+            png_action.entry.clone(),
+            text_action.entry,
+        ];
+        expected.sort_by_key(|entry| entry.digest);
+
+        let entries = store.entries().collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(entries, expected);
+        assert!(
+            store
+                .entries()
+                .validate_fail_fast()
+                .all(|result| result.is_ok())
+        );
+
+        assert_eq!(
+            store.read(png_action.entry.digest)?,
+            Some(minimal_png_bytes())
+        );
+        assert_eq!(
+            store.read(jpg_action.entry.digest)?,
+            Some(minimal_jpg_bytes())
+        );
+
+        // Loading the store opens its packs, but doesn't pack new files.
+        store.record_layout()?;
+        let loaded = super::Store::load(base.path())?;
+
+        assert_eq!(loaded.entries().collect::<Result<Vec<_>, _>>()?, expected);
+        assert_eq!(
+            loaded.read(png_action.entry.digest)?,
+            Some(minimal_png_bytes())
+        );
+        assert!(loaded.packs().is_some_and(|packs| !packs.accepts(1)));
+
+        assert!(store.delete(png_action.entry.digest)?);
+        assert_eq!(store.read(png_action.entry.digest)?, None);
+        assert!(store.lookup(png_action.entry.digest).is_none());
+
+        Ok(())
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_par_entries() -> Result<(), Box<dyn std::error::Error>> {
+        use rayon::iter::ParallelIterator;
+
+        for prefix_part_lengths in [vec![], vec![2], vec![1, 3]] {
+            let base = tempfile::tempdir()?;
+            let store = super::Store::new(base.path())
+                .with_prefix_part_lengths(&prefix_part_lengths)?
+                .with_packs(100)?;
+
+            for bytes in [minimal_jpg_bytes(), minimal_png_bytes(), text_bytes()] {
+                store.save(&bytes)?;
+            }
+
+            let expected = store.walk().collect::<Result<Vec<_>, _>>()?;
+            let mut entries = store.par_entries().collect::<Result<Vec<_>, _>>()?;
+            entries.sort_by_key(|entry| entry.digest);
+
+            assert_eq!(entries, expected);
+        }
+
+        // Errors are returned as items, and don't stop the walk of other prefix directories.
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+        store.save(&minimal_jpg_bytes())?;
+        std::fs::write(base.path().join("ab"), b"not a directory")?;
+
+        let results = store.par_entries().collect::<Vec<_>>();
+
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert_eq!(results.iter().filter(|result| result.is_err()).count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_with_report() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+
+        for bytes in [minimal_jpg_bytes(), minimal_png_bytes(), text_bytes()] {
+            store.save(&bytes)?;
+        }
+
+        let png = super::Digest::from_bytes(minimal_png_digest());
+        std::fs::write(store.path(png), b"not a png")?;
+        // A file in place of a prefix directory can't be walked.
+        std::fs::write(base.path().join("zz"), b"not a directory")?;
+
+        let mut updates = vec![];
+        let report = store
+            .entries()
+            .validate_with_report(|report| updates.push(report.files()));
+
+        assert_eq!(report.valid, 2);
+        assert_eq!(
+            report.invalid,
+            vec![super::InvalidFile {
+                path: store.path(png),
+                expected: png,
+                actual: super::Digest::compute(b"not a png"),
+            }]
+        );
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].path, Some(base.path().join("zz")));
+        assert_eq!(
+            report.bytes_read,
+            (minimal_jpg_bytes().len() + text_bytes().len() + b"not a png".len()) as u64
+        );
+        assert_eq!(updates, vec![1, 2, 3, 4]);
+
+        #[cfg(feature = "parallel")]
+        {
+            let par_report = super::ValidationReport::from_par_entries(store.par_entries(), |_| {});
+
+            assert_eq!(par_report.valid, report.valid);
+            assert_eq!(par_report.invalid, report.invalid);
+            assert_eq!(par_report.errors.len(), 1);
+            assert_eq!(par_report.bytes_read, report.bytes_read);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resume() -> Result<(), Box<dyn std::error::Error>> {
+        for prefix_part_lengths in [vec![], vec![2], vec![1, 3]] {
+            let base = tempfile::tempdir()?;
+            let store = super::Store::new(base.path())
+                .with_prefix_part_lengths(&prefix_part_lengths)?
+                .with_packs(10)?;
+
+            for bytes in [
+                minimal_jpg_bytes(),
+                minimal_png_bytes(),
+                text_bytes(),
+                empty_bytes(),
+            ] {
+                store.save(&bytes)?;
+            }
+
+            let expected = store.entries().collect::<Result<Vec<_>, _>>()?;
+
+            let mut entries = store.entries();
+            let first = entries.by_ref().take(2).collect::<Result<Vec<_>, _>>()?;
+            let cursor = entries.cursor().unwrap();
+
+            assert_eq!(cursor.last(), first[1].digest);
+
+            // The cursor can be saved and restored.
+            let cursor = cursor.to_string().parse::<super::Cursor>()?;
+            let mut resumed = store.entries().resume(cursor);
+
+            assert_eq!(resumed.cursor(), Some(cursor));
+
+            let rest = resumed.by_ref().collect::<Result<Vec<_>, _>>()?;
+
+            assert_eq!([first, rest].concat(), expected);
+            assert_eq!(
+                resumed.cursor().map(|cursor| cursor.last()),
+                Some(expected[3].digest)
+            );
+        }
+
+        // Prefix directories before the position aren't read.
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+        store.save(&minimal_jpg_bytes())?;
+        store.save(&minimal_png_bytes())?;
+        std::fs::write(base.path().join("00"), b"not a directory")?;
+
+        let jpg = super::Digest::from_bytes(minimal_jpg_digest());
+        let png = super::Digest::from_bytes(minimal_png_digest());
+
+        assert!(store.entries().any(|entry| entry.is_err()));
+        assert_eq!(
+            store
+                .entries()
+                .resume(super::Cursor::after(jpg))
+                .map(|entry| entry.map(|entry| entry.digest))
+                .collect::<Result<Vec<_>, _>>()?,
+            vec![png]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_entries_in_range() -> Result<(), Box<dyn std::error::Error>> {
+        let jpg = super::Digest::from_bytes(minimal_jpg_digest());
+        let png = super::Digest::from_bytes(minimal_png_digest());
+        let empty = super::Digest::from_bytes(empty_digest());
+        let text = super::Digest::from_bytes(text_digest());
+
+        for prefix_part_lengths in [vec![], vec![2], vec![1, 3]] {
+            let base = tempfile::tempdir()?;
+            let store =
+                super::Store::new(base.path()).with_prefix_part_lengths(&prefix_part_lengths)?;
+
+            for bytes in [
+                minimal_jpg_bytes(),
+                minimal_png_bytes(),
+                empty_bytes(),
+                text_bytes(),
+            ] {
+                store.save(&bytes)?;
+            }
+
+            for ((start, end), expected) in [
+                (("00", "af"), vec![jpg, text]),
+                (("b", "d4"), vec![empty]),
+                (("d41e", "dd"), vec![png]),
+                (("de", ""), vec![]),
+                (("", ""), vec![jpg, text, empty, png]),
+            ] {
+                let digests = store
+                    .entries_in_range(start, end)?
+                    .validate_fail_fast()
+                    .map(|entry| entry.map(|entry| entry.digest))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                assert_eq!(digests, expected);
+
+                #[cfg(feature = "parallel")]
+                {
+                    use rayon::iter::ParallelIterator;
+
+                    let mut digests = store
+                        .par_entries_in_range(start, end)?
+                        .map(|entry| entry.map(|entry| entry.digest))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    digests.sort_unstable();
+
+                    let mut expected = expected;
+                    expected.sort_unstable();
+
+                    assert_eq!(digests, expected);
+                }
+            }
+        }
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+        store.save(&minimal_jpg_bytes())?;
+
+        // Prefix directories outside the range aren't read.
+        std::fs::write(base.path().join("ab"), b"not a directory")?;
+
+        assert_eq!(store.entries_in_range("00", "7f")?.count(), 1);
+        assert!(
+            store
+                .entries_in_range("00", "ff")?
+                .any(|entry| entry.is_err())
+        );
+        assert!(store.entries_in_range("00", "FF").is_err());
+        assert_eq!(
+            "00-3f".parse::<super::PrefixRange>()?,
+            super::PrefixRange::new("00", "3f")?
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+
+        for bytes in [minimal_jpg_bytes(), minimal_png_bytes(), empty_bytes()] {
+            store.save(&bytes)?;
+        }
+
+        let write_misnamed = |name: &[u8], bytes: &[u8]| -> std::io::Result<std::path::PathBuf> {
+            let path = store.path(super::Digest::compute(name));
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(&path, bytes)?;
+
+            Ok(path)
+        };
+
+        let misnamed_text = write_misnamed(b"qux", &text_bytes())?;
+        let duplicate_png = write_misnamed(b"quux", &minimal_png_bytes())?;
+        let truncated = write_misnamed(b"corge", &empty_bytes())?;
+
+        let report = store.repair(&super::RepairMode::Rename)?;
+        let text = super::Digest::from_bytes(text_digest());
+
+        assert_eq!(report.valid, 3);
+        assert_eq!(report.removed_empty, vec![truncated.clone()]);
+        assert_eq!(report.mismatched.len(), 2);
+        assert!(report.mismatched.contains(&super::MismatchedFile {
+            path: misnamed_text.clone(),
+            expected: super::Digest::compute(b"qux"),
+            actual: text,
+            destination: Some(store.path(text)),
+        }));
+        assert!(report.mismatched.contains(&super::MismatchedFile {
+            path: duplicate_png.clone(),
+            expected: super::Digest::compute(b"quux"),
+            actual: super::Digest::from_bytes(minimal_png_digest()),
+            destination: None,
+        }));
+
+        for path in [&misnamed_text, &duplicate_png, &truncated] {
+            assert!(!path.exists());
+            assert!(!path.parent().unwrap().exists());
+        }
+
+        let digests = store
+            .walk()
+            .validate_fail_fast()
+            .map(|entry| entry.map(|entry| entry.digest.as_bytes().to_vec()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(
+            digests,
+            vec![
+                minimal_jpg_digest(),
+                text_digest(),
+                empty_digest(),
+                minimal_png_digest()
+            ]
+        );
+
+        // Files can be quarantined instead.
+        let quarantine = tempfile::tempdir()?;
+        let misnamed_jpg = write_misnamed(b"qux", &minimal_jpg_bytes())?;
+        let report = store.repair(&super::RepairMode::Quarantine(
+            quarantine.path().to_path_buf(),
+        ))?;
+
+        assert_eq!(report.valid, 4);
+        assert_eq!(report.mismatched.len(), 1);
+        assert!(!misnamed_jpg.exists());
+        assert!(
+            quarantine
+                .path()
+                .join("79c09c11a8f92599f3c6d389564dd24d")
+                .exists()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_saves() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::Write;
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2, 2])?;
+
+        let actions = std::thread::scope(|scope| {
+            // Every thread has to be spawned before any is joined.
+            #[allow(clippy::needless_collect)]
+            let handles = (0..8)
+                .map(|i| {
+                    let store = &store;
+
+                    scope.spawn(move || {
+                        if i % 2 == 0 {
+                            store.save(&minimal_png_bytes())
+                        } else {
+                            let mut writer = store.writer()?;
+                            writer.write_all(&minimal_png_bytes())?;
+                            writer.finish()
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Result<Vec<_>, _>>()
+        })?;
+
+        assert_eq!(actions.iter().filter(|action| action.added).count(), 1);
+        assert_eq!(std::fs::read(&actions[0].entry.path)?, minimal_png_bytes());
+        assert_eq!(store.walk().validate_fail_fast().count(), 1);
+
+        // No temporary files are left behind.
+        let mut directories = vec![base.path().to_path_buf()];
+
+        while let Some(directory) = directories.pop() {
+            for entry in std::fs::read_dir(directory)? {
+                let entry = entry?;
+
+                assert!(!entry.file_name().to_string_lossy().starts_with(".tmp-"));
+
+                if entry.file_type()?.is_dir() {
+                    directories.push(entry.path());
+                }
+            }
+        }
+
+        // An existing file is never replaced.
+        let source = base.path().join(".tmp-source");
+        std::fs::write(&source, b"other contents")?;
+
+        assert!(!super::persist_new(&source, &actions[0].entry.path)?);
+        assert!(!source.exists());
+        assert_eq!(std::fs::read(&actions[0].entry.path)?, minimal_png_bytes());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::quota::{Eviction, Quota, Usage};
+        use std::time::{Duration, SystemTime};
+
+        let jpg = super::Digest::from_bytes(minimal_jpg_digest());
+        let png = super::Digest::from_bytes(minimal_png_digest());
+        let text = super::Digest::from_bytes(text_digest());
+
+        // The JPEG is the oldest (and files without a time would be evicted first).
+        let eviction = Eviction::ByTimestamp(std::sync::Arc::new(move |digest| {
+            let offset = match digest {
+                digest if digest == jpg => 1,
+                digest if digest == png => 2,
+                _ => return None,
+            };
+
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(offset))
+        }));
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_quota(Quota::new(eviction).with_max_files(2).with_max_bytes(1000));
+
+        assert!(store.save(&minimal_jpg_bytes())?.evicted.is_empty());
+        assert!(store.save(&minimal_png_bytes())?.evicted.is_empty());
+        assert!(store.save(&minimal_png_bytes())?.evicted.is_empty());
+
+        let action = store.save(&text_bytes())?;
+
+        assert!(action.added);
+        assert_eq!(action.evicted, vec![jpg]);
+        assert_eq!(store.lookup(jpg), None);
+
+        let quota = store.quota().unwrap();
+        let usage = Usage {
+            files: 2,
+            bytes: (minimal_png_bytes().len() + text_bytes().len()) as u64,
+        };
+
+        assert_eq!(quota.usage(), Some(usage));
+
+        // The eviction order is used up before the store is walked again, so the text file isn't
+        // evicted yet.
+        let mut writer = store.writer()?;
+        std::io::Write::write_all(&mut writer, &minimal_jpg_bytes())?;
+        let action = writer.finish()?;
+
+        assert_eq!(action.evicted, vec![png]);
+
+        assert!(store.delete(text)?);
+        assert_eq!(
+            quota.usage(),
+            Some(Usage {
+                files: 1,
+                bytes: minimal_jpg_bytes().len() as u64
+            })
+        );
+
+        assert!(matches!(
+            store.save([0; 1001]),
+            Err(super::Error::QuotaExceeded(_))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota_reservation_released() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::quota::{Eviction, Quota, Usage};
+
+        let png = super::Digest::from_bytes(minimal_png_digest());
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_quota(Quota::new(Eviction::OldestModified).with_max_files(2));
+
+        store.save(&minimal_jpg_bytes())?;
+
+        let quota = store.quota().unwrap();
+        let usage = quota.usage().unwrap();
 
-    fn increment_level(&mut self) {
-        self.level = Some(self.level.take().map_or(0, |level| level + 1));
-    }
+        // A file in place of the prefix directory makes saving the PNG fail after room is reserved.
+        let prefix_path = store.path(png).parent().unwrap().to_path_buf();
+        std::fs::write(&prefix_path, b"")?;
 
-    const fn decrement_level(&mut self) {
-        if let Some(level) = self.level.take()
-            && level != 0
-        {
-            self.level = Some(level - 1);
-        }
+        assert!(store.save(&minimal_png_bytes()).is_err());
+        assert_eq!(quota.usage(), Some(usage));
+
+        std::fs::remove_file(&prefix_path)?;
+
+        assert!(store.save(&minimal_png_bytes())?.added);
+        assert_eq!(
+            quota.usage(),
+            Some(Usage {
+                files: 2,
+                bytes: usage.bytes + minimal_png_bytes().len() as u64,
+            })
+        );
+
+        Ok(())
     }
 
-    const fn is_valid_char(byte: u8) -> bool {
-        byte.is_ascii_lowercase() || byte.is_ascii_digit()
+    #[test]
+    #[cfg(feature = "compression")]
+    fn test_compression() -> Result<(), Box<dyn std::error::Error>> {
+        let mut bmp_bytes = b"BM".to_vec();
+        bmp_bytes.extend_from_slice(&[0; 1024]);
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_compression(true);
+
+        let action = store.save(&bmp_bytes)?;
+        let digest = action.entry.digest;
+
+        assert!(action.added);
+        assert!(action.entry.compressed);
+        assert_eq!(action.entry.path, store.compressed_path(digest));
+        assert!(!store.path(digest).exists());
+        assert!(std::fs::metadata(&action.entry.path)?.len() < bmp_bytes.len() as u64);
+        assert!(!store.save(&bmp_bytes)?.added);
+
+        // Other formats are stored as they are.
+        let png_action = store.save(&minimal_png_bytes())?;
+
+        assert!(!png_action.entry.compressed);
+        assert_eq!(std::fs::read(&png_action.entry.path)?, minimal_png_bytes());
+
+        let entry = store.lookup(digest).unwrap();
+
+        assert!(entry.compressed);
+        assert_eq!(entry.size()?, bmp_bytes.len() as u64);
+        assert_eq!(store.read(digest)?, Some(bmp_bytes.clone()));
+        assert_eq!(
+            store.image_type(&entry)?,
+            super::ImageType::new(Some(imghdr::Type::Bmp))
+        );
+        assert!(matches!(
+            store.open(digest),
+            Err(super::Error::Compressed(_))
+        ));
+
+        let entries = store.entries().collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains(&entry));
+        assert!(
+            store
+                .entries()
+                .validate_fail_fast()
+                .all(|result| result.is_ok())
+        );
+
+        // Compressed files are still read when compression isn't enabled.
+        let reloaded = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+
+        assert_eq!(reloaded.read(digest)?, Some(bmp_bytes));
+        assert!(reloaded.delete(digest)?);
+        assert_eq!(store.lookup(digest), None);
+
+        Ok(())
     }
 
-    fn path_to_entry(path: PathBuf) -> Result<Entry, IterationError> {
-        if path.is_file() {
-            path.file_name()
-                .ok_or_else(|| IterationError::InvalidFileName(path.clone()))
-                .and_then(|file_name| {
-                    let file_name_bytes = file_name.as_encoded_bytes();
+    #[test]
+    fn test_gc_empty_dirs() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2, 2])?;
 
-                    if file_name_bytes
-                        .iter()
-                        .all(|byte| Self::is_valid_char(*byte))
-                    {
-                        <[u8; 16]>::from_hex(file_name_bytes).map_err(IterationError::from)
-                    } else {
-                        Err(IterationError::InvalidFileName(path.clone()))
-                    }
-                })
-                .map(Digest)
-                .map(|digest| Entry { path, digest })
-        } else {
-            Err(IterationError::ExpectedFile(path))
-        }
+        let png_action = store.save(&minimal_png_bytes())?;
+        store.save(&minimal_jpg_bytes())?;
+
+        // Empty directories left behind by an interrupted write or removed file.
+        std::fs::create_dir_all(base.path().join("00").join("00"))?;
+        std::fs::create_dir_all(base.path().join("ff"))?;
+        std::fs::create_dir_all(png_action.entry.path.parent().unwrap().with_file_name("00"))?;
+        std::fs::create_dir_all(base.path().join(".hidden").join("00"))?;
+
+        assert_eq!(store.gc_empty_dirs()?, 4);
+        assert_eq!(store.gc_empty_dirs()?, 0);
+
+        assert!(!base.path().join("00").exists());
+        assert!(!base.path().join("ff").exists());
+        assert!(base.path().join(".hidden").join("00").exists());
+        assert_eq!(store.entries().count(), 2);
+
+        // Removing the only file in a prefix leaves nothing to collect.
+        assert!(store.delete(png_action.entry.digest)?);
+        assert_eq!(store.gc_empty_dirs()?, 0);
+
+        Ok(())
     }
 
-    fn path_to_paths(
-        path: PathBuf,
-        prefix_part_length: Option<usize>,
-    ) -> Result<Vec<PathBuf>, IterationError> {
-        if path.is_dir() {
-            let mut paths = std::fs::read_dir(path)?
-                .map(|entry| entry.map(|entry| entry.path()))
-                .collect::<Result<Vec<PathBuf>, std::io::Error>>()
-                .map_err(IterationError::from)?;
-
-            paths.sort();
-            paths.reverse();
-
-            match prefix_part_length {
-                Some(prefix_part_length) => {
-                    let invalid_path = paths.iter().find(|path| {
-                        path.file_name().is_none_or(|file_name| {
-                            file_name.len() != prefix_part_length
-                                && file_name
-                                    .as_encoded_bytes()
-                                    .iter()
-                                    .any(|byte| !Self::is_valid_char(*byte))
-                        })
-                    });
+    #[test]
+    fn test_save_with_expected() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
+        let png = super::Digest::from_bytes(minimal_png_digest());
+        let jpg = super::Digest::from_bytes(minimal_jpg_digest());
 
-                    // Clippy is wrong here.
-                    #[allow(clippy::option_if_let_else)]
-                    match invalid_path {
-                        Some(invalid_path) => {
-                            Err(IterationError::InvalidFileName(invalid_path.clone()))
-                        }
-                        None => Ok(paths),
-                    }
-                }
-                None => Ok(paths),
-            }
-        } else {
-            Err(IterationError::ExpectedDirectory(path))
+        assert!(matches!(
+            store.save_with_expected(minimal_png_bytes(), jpg),
+            Err(super::Error::UnexpectedDigest { expected, actual })
+                if expected == jpg && actual == png
+        ));
+        assert_eq!(store.lookup(jpg), None);
+        assert_eq!(store.lookup(png), None);
+
+        let action = store.save_with_expected(minimal_png_bytes(), png)?;
+
+        assert!(action.added);
+        assert_eq!(action.entry.digest, png);
+        assert_eq!(store.read(png)?, Some(minimal_png_bytes()));
+
+        #[cfg(feature = "sha256")]
+        {
+            let sha256 = super::DigestKind::Sha256.compute(minimal_png_bytes());
+
+            assert!(matches!(
+                store.save_with_expected(minimal_png_bytes(), sha256),
+                Err(super::Error::DigestKindMismatch { .. })
+            ));
         }
+
+        Ok(())
     }
 
-    pub fn validate(self) -> impl Iterator<Item = Result<ValidationResult, IterationError>> {
-        self.map(|entry| {
-            let entry = entry?;
+    #[test]
+    fn test_observers() -> Result<(), Box<dyn std::error::Error>> {
+        use std::sync::{Arc, Mutex};
 
-            Ok(match entry.validate()? {
-                Ok(()) => ValidationResult::Valid { entry },
-                Err(actual) => ValidationResult::Invalid { entry, actual },
+        let jpg = super::Digest::from_bytes(minimal_jpg_digest());
+        let png = super::Digest::from_bytes(minimal_png_digest());
+        let text = super::Digest::from_bytes(text_digest());
+
+        let events = Arc::new(Mutex::new(vec![]));
+        let added_events = events.clone();
+        let found_events = events.clone();
+        let deleted_events = events.clone();
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_non_image_policy(super::NonImagePolicy::Quarantine(base.path().join(".q")))
+            .on_added(move |action| {
+                added_events
+                    .lock()
+                    .unwrap()
+                    .push(("added", action.entry.digest));
             })
-        })
-    }
+            .on_found(move |action| {
+                found_events
+                    .lock()
+                    .unwrap()
+                    .push(("found", action.entry.digest));
+            })
+            .on_deleted(move |digest| deleted_events.lock().unwrap().push(("deleted", digest)));
 
-    pub fn validate_fail_fast(self) -> impl Iterator<Item = Result<Entry, Error>> {
-        self.validate().map(|result| {
-            result
-                .map_err(Error::from)
-                .and_then(ValidationResult::result)
-        })
+        store.save(&minimal_jpg_bytes())?;
+        store.save(&minimal_jpg_bytes())?;
+        // Quarantined files aren't in the store.
+        store.save(&text_bytes())?;
+        store.delete(jpg)?;
+        store.delete(text)?;
+
+        let source_base = tempfile::tempdir()?;
+        let source = super::Store::new(source_base.path());
+        source.save(&minimal_png_bytes())?;
+        store.merge_from(&source, super::MergeMode::Link)?;
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                ("added", jpg),
+                ("found", jpg),
+                ("deleted", jpg),
+                ("added", png)
+            ]
+        );
+
+        Ok(())
     }
-}
 
-impl Iterator for Entries<'_> {
-    type Item = Result<Entry, IterationError>;
+    #[test]
+    fn test_metadata() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::metadata::Metadata;
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.stack.pop().and_then(|mut next_paths| {
-            if self.is_last() {
-                if let Some(next_path) = next_paths.pop() {
-                    self.stack.push(next_paths);
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_metadata(true);
+        let metadata = Metadata::new("https://example.com/a.png")
+            .with_content_type(Some("image/png"))
+            .with_content_length(Some(minimal_png_bytes().len() as u64));
 
-                    Some(Self::path_to_entry(next_path))
-                } else {
-                    self.decrement_level();
+        let action = store.save_with_metadata(&minimal_png_bytes(), &metadata)?;
+        let digest = action.entry.digest;
 
-                    self.next()
-                }
-            } else if let Some(next_path) = next_paths.pop() {
-                Self::path_to_paths(next_path, self.current_prefix_part_length()).map_or_else(
-                    |error| Some(Err(error)),
-                    |next_level| {
-                        self.stack.push(next_paths);
-                        self.stack.push(next_level);
-                        self.increment_level();
+        assert_eq!(store.metadata(digest)?, Some(metadata.clone()));
+        assert_eq!(
+            store.metadata_path(digest).parent(),
+            action.entry.path.parent()
+        );
 
-                        self.next()
-                    },
-                )
-            } else {
-                self.decrement_level();
+        // Metadata is only recorded when a file is added.
+        store.save_with_metadata(
+            &minimal_png_bytes(),
+            &Metadata::new("https://example.com/b.png"),
+        )?;
 
-                self.next()
-            }
-        })
-    }
-}
+        assert_eq!(crate::metadata::read(store.metadata_path(digest))?.len(), 1);
 
-#[cfg(test)]
-mod tests {
-    use hex::FromHex;
+        let jpg_action = store
+            .clone()
+            .with_metadata(false)
+            .save_with_metadata(&minimal_jpg_bytes(), &metadata)?;
 
-    const MINIMAL_JPG_HEX: &str = "ffd8ffe000104a46494600010100000100010000ffdb004300080606070605080707070909080a0c140d0c0b0b0c1912130f141d1a1f1e1d1a1c1c20242e2720222c231c1c2837292c30313434341f27393d38323c2e333432ffdb0043010909090c0b0c180d0d1832211c21323232323232323232323232323232323232323232323232323232323232323232323232323232ffc00011080001000103011100021101031101ffc4001f00000105010101010101000000000000000102030405060708090a0bffc400b51000020103030204030505040400017d010203000411051221314106135161712232819114a1b1c1d1f0e123f1ffda000c03010002110311003f00ff00ffd9";
-    const MINIMAL_PNG_HEX: &str = "89504e470d0a1a0a0000000d4948445200000001000000010802000000907724d90000000a49444154789c6360000002000185d114090000000049454e44ae426082";
+        assert!(jpg_action.added);
+        assert_eq!(store.metadata(jpg_action.entry.digest)?, None);
 
-    fn minimal_jpg_bytes() -> Vec<u8> {
-        hex::decode(MINIMAL_JPG_HEX).unwrap()
-    }
+        // Records are moved when the store is migrated, and kept when files are removed.
+        store.migrate_to([1, 1], false)?;
+        let migrated = super::Store::load(base.path())?;
 
-    fn minimal_png_bytes() -> Vec<u8> {
-        hex::decode(MINIMAL_PNG_HEX).unwrap()
-    }
+        assert_eq!(migrated.metadata(digest)?, Some(metadata.clone()));
+        assert!(migrated.delete(digest)?);
+        assert_eq!(migrated.metadata(digest)?, Some(metadata));
+        assert_eq!(migrated.entries().count(), 1);
 
-    fn empty_bytes() -> Vec<u8> {
-        vec![]
+        Ok(())
     }
 
-    fn text_bytes() -> Vec<u8> {
-        "foo bar baz".as_bytes().to_vec()
-    }
+    #[test]
+    fn test_import_dir() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let source = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2])?;
 
-    fn minimal_jpg_digest() -> [u8; 16] {
-        FromHex::from_hex("79c09c11a8f92599f3c6d389564dd24d").unwrap()
-    }
+        store.save(&minimal_png_bytes())?;
 
-    fn minimal_png_digest() -> [u8; 16] {
-        FromHex::from_hex("ddf93a3305d41f70e19bb8a04ac673a5").unwrap()
-    }
+        std::fs::create_dir_all(source.path().join("nested/.hidden"))?;
+        std::fs::write(source.path().join("a.jpg"), minimal_jpg_bytes())?;
+        std::fs::write(source.path().join("nested/copy.jpg"), minimal_jpg_bytes())?;
+        std::fs::write(source.path().join("nested/b.png"), minimal_png_bytes())?;
+        std::fs::write(source.path().join("nested/.hidden/c.txt"), text_bytes())?;
+        std::fs::write(source.path().join(".ignored"), text_bytes())?;
 
-    fn empty_digest() -> [u8; 16] {
-        FromHex::from_hex("d41d8cd98f00b204e9800998ecf8427e").unwrap()
+        let actions = store.import_dir(source.path())?;
+        let jpg = super::Digest::from_bytes(minimal_jpg_digest());
+        let png = super::Digest::from_bytes(minimal_png_digest());
+
+        assert_eq!(
+            actions
+                .iter()
+                .map(|(path, action)| (
+                    path.strip_prefix(source.path()).unwrap().to_path_buf(),
+                    action.entry.digest,
+                    action.added
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (std::path::PathBuf::from("a.jpg"), jpg, true),
+                (std::path::PathBuf::from("nested/b.png"), png, false),
+                (std::path::PathBuf::from("nested/copy.jpg"), jpg, false),
+            ]
+        );
+        assert_eq!(store.read(jpg)?, Some(minimal_jpg_bytes()));
+        assert_eq!(store.entries().count(), 2);
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            assert_eq!(
+                std::fs::metadata(store.path(jpg))?.ino(),
+                std::fs::metadata(source.path().join("a.jpg"))?.ino()
+            );
+        }
+
+        Ok(())
     }
 
-    fn text_digest() -> [u8; 16] {
-        FromHex::from_hex("ab07acbb1e496801937adfa772424bf7").unwrap()
+    #[test]
+    fn test_record_layout() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_prefix_part_lengths([2, 2])?;
+
+        // The prefix part lengths of an empty store can't be inferred from its files.
+        assert_eq!(super::Store::infer_prefix_part_lengths(base.path())?, None);
+
+        store.record_layout()?;
+        store.record_layout()?;
+
+        assert_eq!(
+            super::Store::infer_prefix_part_lengths(base.path())?,
+            Some(vec![2, 2])
+        );
+        assert_eq!(super::Store::load(base.path())?.layout(), store.layout());
+        assert!(matches!(
+            store.with_prefix_part_lengths([2])?.record_layout(),
+            Err(super::Error::LayoutMismatch { .. })
+        ));
+
+        Ok(())
     }
 
-    fn test_save(
-        prefix_part_lengths: Vec<usize>,
-    ) -> Result<Vec<super::Entry>, Box<dyn std::error::Error>> {
+    #[test]
+    fn test_migrate_to() -> Result<(), Box<dyn std::error::Error>> {
         let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path());
 
-        let store = super::Store::new(base.path().to_path_buf())
-            .with_prefix_part_lengths(&prefix_part_lengths)?;
-        let minimal_jpg_action = store.save(&minimal_jpg_bytes())?;
-        let minimal_png_action = store.save(&minimal_png_bytes())?;
-        let empty_action = store.save(&empty_bytes())?;
-        let text_action = store.save(&text_bytes())?;
+        for bytes in [
+            minimal_jpg_bytes(),
+            minimal_png_bytes(),
+            empty_bytes(),
+            text_bytes(),
+        ] {
+            store.save(&bytes)?;
+        }
 
-        assert!(minimal_jpg_action.added);
-        assert!(minimal_png_action.added);
-        assert!(empty_action.added);
-        assert!(text_action.added);
+        let expected = store
+            .walk()
+            .map(|entry| entry.map(|entry| entry.digest))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        assert_eq!(minimal_jpg_action.image_type(), Some(imghdr::Type::Jpeg));
-        assert_eq!(minimal_png_action.image_type(), Some(imghdr::Type::Png));
-        assert_eq!(empty_action.image_type(), None);
-        assert_eq!(text_action.image_type(), None);
+        let dry_run = store.migrate_to([2, 2], true)?;
 
-        let repeat_minimal_jpg_action = store.save(&minimal_jpg_bytes())?;
-        let repeat_minimal_png_action = store.save(&minimal_png_bytes())?;
-        let repeat_empty_action = store.save(&empty_bytes())?;
-        let repeat_text_action = store.save(&text_bytes())?;
+        assert_eq!(dry_run.moved, 4);
+        assert_eq!(dry_run.unchanged, 0);
+        assert_eq!(
+            super::Store::infer_prefix_part_lengths(base.path())?,
+            Some(vec![])
+        );
 
-        assert!(!repeat_minimal_jpg_action.added);
-        assert!(!repeat_minimal_png_action.added);
-        assert!(!repeat_empty_action.added);
-        assert!(!repeat_text_action.added);
+        // Simulate an interrupted migration by moving one file by hand.
+        let migrated = super::Store::new(base.path()).with_prefix_part_lengths([2, 2])?;
+        let first = migrated.path(expected[0]);
+        std::fs::create_dir_all(first.parent().unwrap())?;
+        std::fs::rename(store.path(expected[0]), &first)?;
 
-        let inferred_prefix_parts_length = super::Store::infer_prefix_part_lengths(base.path())?;
+        let counts = store.migrate_to([2, 2], false)?;
 
-        assert_eq!(inferred_prefix_parts_length, Some(prefix_part_lengths));
+        assert_eq!(counts.moved, 3);
+        assert_eq!(counts.unchanged, 1);
+        assert_eq!(
+            super::Store::infer_prefix_part_lengths(base.path())?,
+            Some(vec![2, 2])
+        );
 
-        let entries = store.entries().collect::<Result<Vec<_>, _>>()?;
-        let digests = entries
-            .iter()
-            .map(|entry| entry.digest.0)
-            .collect::<Vec<_>>();
+        let digests = migrated
+            .walk()
+            .validate_fail_fast()
+            .map(|entry| entry.map(|entry| entry.digest))
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let expected_digests = vec![
-            minimal_jpg_digest(),
-            text_digest(),
-            empty_digest(),
-            minimal_png_digest(),
+        assert_eq!(digests, expected);
+
+        // Migrating back removes the prefix directories.
+        let counts = migrated.migrate_to([1], false)?;
+
+        assert_eq!(counts.moved, 4);
+        assert_eq!(
+            super::Store::load(base.path())?.prefix_part_lengths,
+            vec![1]
+        );
+        assert_eq!(
+            std::fs::read_dir(base.path())?
+                .filter(|entry| entry
+                    .as_ref()
+                    .is_ok_and(|entry| !super::is_hidden(&entry.file_name())))
+                .count(),
+            expected
+                .iter()
+                .map(|digest| format!("{digest:x}").chars().next())
+                .collect::<std::collections::BTreeSet<_>>()
+                .len()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_count_entries() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2, 2])?
+            .with_packs(100)?;
+
+        let digests = [
+            store.save(&minimal_jpg_bytes())?.entry.digest,
+            store.save(&minimal_png_bytes())?.entry.digest,
+            store.save(&text_bytes())?.entry.digest,
         ];
 
-        assert_eq!(entries.len(), 4);
-        assert_eq!(digests, expected_digests);
+        store.rebuild_manifest()?;
 
-        Ok(entries)
+        let counts = store.count_entries()?;
+        let mut expected = std::collections::BTreeMap::<String, u64>::new();
+
+        for digest in digests {
+            *expected
+                .entry(format!("{digest:x}")[..2].to_string())
+                .or_default() += 1;
+        }
+
+        assert_eq!(counts.total, 3);
+        assert_eq!(counts.prefixes, expected);
+
+        let flat_base = tempfile::tempdir()?;
+        let flat_store = super::Store::new(flat_base.path());
+        flat_store.save(&minimal_jpg_bytes())?;
+
+        let flat_counts = flat_store.count_entries()?;
+
+        assert_eq!(flat_counts.total, 1);
+        assert!(flat_counts.prefixes.is_empty());
+
+        Ok(())
     }
 
     #[test]
-    fn test_save_empty() -> Result<(), Box<dyn std::error::Error>> {
-        test_save(vec![])?;
+    fn test_export_linked() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let dest = tempfile::tempdir()?;
+        let store = super::Store::new(base.path())
+            .with_prefix_part_lengths([2])?
+            .with_packs(100)?;
+
+        let jpg_action = store.save(&minimal_jpg_bytes())?;
+        let png_action = store.save(&minimal_png_bytes())?;
+        store.save(&text_bytes())?;
+
+        let count = store.export_linked(dest.path(), |entry| {
+            store
+                .image_type(entry)
+                .is_ok_and(|image_type| image_type.value().is_some())
+        })?;
+
+        assert_eq!(count, 2);
+
+        let exported = super::Store::new(dest.path()).with_prefix_part_lengths([2])?;
+        let digests = exported
+            .entries()
+            .map(|entry| entry.map(|entry| entry.digest))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut expected = vec![jpg_action.entry.digest, png_action.entry.digest];
+        expected.sort();
+
+        assert_eq!(digests, expected);
+        assert_eq!(
+            std::fs::read(exported.path(png_action.entry.digest))?,
+            minimal_png_bytes()
+        );
+
+        // Only the file that was previously filtered out is added.
+        assert_eq!(store.export_linked(dest.path(), |_| true)?, 1);
 
         Ok(())
     }
 
     #[test]
-    fn test_save_1() -> Result<(), Box<dyn std::error::Error>> {
-        test_save(vec![1])?;
+    fn test_merge_from() -> Result<(), Box<dyn std::error::Error>> {
+        let source_base = tempfile::tempdir()?;
+        let dest_base = tempfile::tempdir()?;
+        let source = super::Store::new(source_base.path())
+            .with_prefix_part_lengths([2])?
+            .with_packs(100)?;
+        let dest = super::Store::new(dest_base.path()).with_prefix_part_lengths([1, 3])?;
+
+        let jpg_action = source.save(&minimal_jpg_bytes())?;
+        let png_action = source.save(&minimal_png_bytes())?;
+        let text_action = source.save(&text_bytes())?;
+        dest.save(&minimal_png_bytes())?;
+
+        // A file whose contents don't match its digest shouldn't be merged.
+        let corrupt_digest = crate::digest::Digest::compute(b"corrupt");
+        std::fs::create_dir_all(source.path(corrupt_digest).parent().unwrap())?;
+        std::fs::write(source.path(corrupt_digest), b"not corrupt")?;
+
+        let counts = dest.merge_from(&source, super::MergeMode::Move)?;
+
+        assert_eq!(counts.added, 2);
+        assert_eq!(counts.existing, 1);
+        assert_eq!(
+            counts
+                .invalid
+                .iter()
+                .map(|entry| entry.digest)
+                .collect::<Vec<_>>(),
+            vec![corrupt_digest]
+        );
+
+        assert_eq!(
+            std::fs::read(dest.path(jpg_action.entry.digest))?,
+            minimal_jpg_bytes()
+        );
+        assert_eq!(
+            std::fs::read(dest.path(text_action.entry.digest))?,
+            text_bytes()
+        );
+
+        // Merged files are removed from the source, and the rest are left unchanged.
+        let remaining = source
+            .walk()
+            .map(|entry| entry.map(|entry| entry.digest))
+            .collect::<Result<std::collections::BTreeSet<_>, _>>()?;
+
+        assert_eq!(
+            remaining,
+            [png_action.entry.digest, corrupt_digest]
+                .into_iter()
+                .collect()
+        );
+
+        assert_eq!(dest.merge_from(&source, super::MergeMode::Link)?.added, 0);
 
         Ok(())
     }
 
+    #[cfg(feature = "archive")]
     #[test]
-    fn test_save_2_2() -> Result<(), Box<dyn std::error::Error>> {
-        test_save(vec![2, 2])?;
+    fn test_archive() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::archive::Format;
+        use std::io::Cursor;
+
+        let source_base = tempfile::tempdir()?;
+        let source = super::Store::new(source_base.path())
+            .with_prefix_part_lengths([2])?
+            .with_packs(100)?;
+
+        let jpg_action = source.save(&minimal_jpg_bytes())?;
+        let png_action = source.save(&minimal_png_bytes())?;
+        let text_action = source.save(&text_bytes())?;
+
+        for format in [Format::Tar, Format::Zip] {
+            let mut archive = Cursor::new(vec![]);
+            let count = source.export_archive(&mut archive, format, |_| true)?;
+
+            assert_eq!(count, 3);
+
+            let dest_base = tempfile::tempdir()?;
+            let dest = super::Store::new(dest_base.path()).with_prefix_part_lengths([1, 3])?;
+            dest.save(&minimal_png_bytes())?;
+
+            archive.set_position(0);
+            let counts = dest.import_archive(&mut archive, format)?;
+
+            assert_eq!(counts.added, 2);
+            assert_eq!(counts.existing, 1);
+            assert!(counts.invalid.is_empty());
+            assert_eq!(
+                std::fs::read(dest.path(jpg_action.entry.digest))?,
+                minimal_jpg_bytes()
+            );
+            assert_eq!(dest.read(text_action.entry.digest)?, Some(text_bytes()));
+
+            // The prefix layout is preserved in the archive.
+            let extracted = tempfile::tempdir()?;
+            archive.set_position(0);
+            crate::archive::for_each_file(&mut archive, format, |name, contents| {
+                let path = extracted.path().join(name);
+                std::fs::create_dir_all(path.parent().unwrap())?;
+                std::io::copy(contents, &mut std::fs::File::create(path)?)?;
+
+                Ok::<_, crate::archive::Error>(())
+            })?;
+
+            let extracted = super::Store::load(extracted.path())?;
+
+            assert_eq!(extracted.layout(), source.layout());
+            assert_eq!(
+                std::fs::read(extracted.path(png_action.entry.digest))?,
+                minimal_png_bytes()
+            );
+        }
+
+        // Only the selected files are exported.
+        let mut archive = Cursor::new(vec![]);
+        let count = source.export_archive(&mut archive, Format::Tar, |entry| {
+            entry.digest == jpg_action.entry.digest
+        })?;
+
+        assert_eq!(count, 1);
+
+        // Files that don't match their names aren't imported.
+        let mut writer = crate::archive::Writer::new(Cursor::new(vec![]), Format::Zip);
+        let name = format!("{:x}", png_action.entry.digest);
+        writer.append(&name, 3, std::time::SystemTime::now(), &b"foo"[..])?;
+        let mut archive = writer.finish()?;
+        archive.set_position(0);
+
+        let dest_base = tempfile::tempdir()?;
+        let dest = super::Store::new(dest_base.path()).with_prefix_part_lengths([2])?;
+        let counts = dest.import_archive(archive, Format::Zip)?;
+
+        assert_eq!(counts.added, 0);
+        assert_eq!(counts.invalid, vec![name]);
 
         Ok(())
     }
 
     #[test]
-    fn test_save_16_3() -> Result<(), Box<dyn std::error::Error>> {
-        test_save(vec![16, 3])?;
+    fn test_detector() -> Result<(), Box<dyn std::error::Error>> {
+        use std::io::Write;
+
+        fn detect_text(header: &[u8]) -> Option<imghdr::Type> {
+            header.starts_with(b"foo").then_some(imghdr::Type::Xbm)
+        }
+
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path()).with_detector(detect_text);
+
+        assert_eq!(
+            store.save(&text_bytes())?.image_type(),
+            Some(imghdr::Type::Xbm)
+        );
+        assert_eq!(store.save(&minimal_png_bytes())?.image_type(), None);
+
+        let mut writer = store.writer()?;
+        writer.write_all(&text_bytes())?;
+
+        assert_eq!(writer.finish()?.image_type(), Some(imghdr::Type::Xbm));
 
         Ok(())
     }
 
     #[test]
-    fn test_save_19_13() -> Result<(), Box<dyn std::error::Error>> {
-        test_save(vec![19, 13])?;
+    fn test_action_serde() -> Result<(), Box<dyn std::error::Error>> {
+        let base = tempfile::tempdir()?;
+        let store = super::Store::new(base.path());
+        let action = store.save(&minimal_png_bytes())?;
+
+        let json = serde_json::to_value(&action)?;
+
+        assert_eq!(json["entry"]["digest"], "ddf93a3305d41f70e19bb8a04ac673a5");
+        assert_eq!(json["image_type"], "png");
+        assert_eq!(json["added"], true);
+        assert_eq!(serde_json::from_value::<super::Action>(json)?, action);
+
+        let result = super::ValidationResult::Invalid {
+            entry: action.entry,
+            actual: crate::digest::Digest::from_bytes(text_digest()),
+        };
+
+        let json = serde_json::to_value(&result)?;
+
+        assert_eq!(json["result"], "invalid");
+        assert_eq!(json["actual"], "ab07acbb1e496801937adfa772424bf7");
+        assert_eq!(
+            serde_json::from_value::<super::ValidationResult>(json)?,
+            result
+        );
 
         Ok(())
     }
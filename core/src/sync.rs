@@ -0,0 +1,141 @@
+use crate::digest::Digest;
+use crate::store::{Error, StoreBackend};
+
+/// The results of syncing one store to another.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SyncCounts {
+    /// Number of files copied (or that would be copied, for a dry run)
+    pub copied: u64,
+    /// Number of files that were already present in the destination
+    pub existing: u64,
+    /// Source files whose contents don't match their digests (which are not copied)
+    pub invalid: Vec<Digest>,
+    /// Files whose copies didn't match their digests when read back (which are removed)
+    pub failed: Vec<Digest>,
+}
+
+/// Copy every file in the source store that is missing from the destination.
+///
+/// Both stores are iterated in order of digest, so files that are already present don't need to
+/// be looked up individually (which matters for remote backends). Each file is checked against its
+/// digest before it is copied, and the copy is read back and checked again. Nothing is copied in a
+/// dry run.
+pub fn sync<S: StoreBackend, D: StoreBackend>(
+    source: &S,
+    dest: &D,
+    dry_run: bool,
+) -> Result<SyncCounts, Error> {
+    let mut counts = SyncCounts::default();
+    let mut dest_entries = dest.entries().peekable();
+
+    for entry in source.entries() {
+        let digest = entry?.digest;
+
+        while let Some(dest_entry) = dest_entries.next_if(|dest_entry| {
+            dest_entry
+                .as_ref()
+                .map_or(true, |dest_entry| dest_entry.digest < digest)
+        }) {
+            dest_entry?;
+        }
+
+        if dest_entries
+            .next_if(|dest_entry| {
+                dest_entry
+                    .as_ref()
+                    .is_ok_and(|dest_entry| dest_entry.digest == digest)
+            })
+            .is_some()
+        {
+            counts.existing += 1;
+            continue;
+        }
+
+        if dry_run {
+            counts.copied += 1;
+            continue;
+        }
+
+        // The file may have been removed since iteration started.
+        let Some(bytes) = source.read(digest)? else {
+            continue;
+        };
+
+        if digest.kind().compute(&bytes) != digest {
+            counts.invalid.push(digest);
+            continue;
+        }
+
+        let action = dest.save(&bytes)?;
+
+        let verified = action.entry.digest == digest
+            && dest
+                .read(digest)?
+                .is_some_and(|copy| digest.kind().compute(copy) == digest);
+
+        if verified {
+            counts.copied += 1;
+        } else {
+            if action.added {
+                dest.delete(action.entry.digest)?;
+            }
+
+            counts.failed.push(digest);
+        }
+    }
+
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::Store;
+
+    #[test]
+    fn test_sync() -> Result<(), Box<dyn std::error::Error>> {
+        let source_base = tempfile::tempdir()?;
+        let dest_base = tempfile::tempdir()?;
+        let source = Store::new(source_base.path()).with_prefix_part_lengths([2])?;
+        let dest = Store::new(dest_base.path()).with_prefix_part_lengths([1, 3])?;
+
+        let contents = (0..10)
+            .map(|i| format!("file {i}").into_bytes())
+            .collect::<Vec<_>>();
+
+        for bytes in &contents {
+            source.save(bytes)?;
+        }
+
+        for bytes in &contents[..4] {
+            dest.save(bytes)?;
+        }
+
+        let dry_run = super::sync(&source, &dest, true)?;
+
+        assert_eq!(dry_run.copied, 6);
+        assert_eq!(dry_run.existing, 4);
+
+        // A file whose contents don't match its digest shouldn't be copied.
+        let corrupt_digest = crate::digest::Digest::compute(b"corrupt");
+        std::fs::create_dir_all(source.path(corrupt_digest).parent().unwrap())?;
+        std::fs::write(source.path(corrupt_digest), b"not corrupt")?;
+
+        let counts = super::sync(&source, &dest, false)?;
+
+        assert_eq!(counts.copied, 6);
+        assert_eq!(counts.existing, 4);
+        assert_eq!(counts.invalid, vec![corrupt_digest]);
+        assert!(counts.failed.is_empty());
+
+        for bytes in &contents {
+            let digest = crate::digest::Digest::compute(bytes);
+
+            assert_eq!(dest.read(digest)?.as_ref(), Some(bytes));
+        }
+
+        assert_eq!(dest.lookup(corrupt_digest), None);
+        assert_eq!(super::sync(&source, &dest, false)?.copied, 0);
+
+        Ok(())
+    }
+}
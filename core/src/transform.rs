@@ -0,0 +1,435 @@
+use crate::digest::{Digest, DigestKind};
+use imghdr::Type;
+use std::ops::Range;
+use std::sync::Arc;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+/// JPEG segments that only contain metadata (EXIF and XMP in APP1, IPTC in APP13, and comments).
+const JPEG_METADATA_MARKERS: [u8; 3] = [0xE1, 0xED, 0xFE];
+
+/// PNG chunks that only contain metadata.
+const PNG_METADATA_CHUNKS: [&[u8; 4]; 5] = [b"tEXt", b"zTXt", b"iTXt", b"tIME", b"eXIf"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Corrupt image: {0}")]
+    Corrupt(&'static str),
+    #[error("Transformation failed: {name}")]
+    Failed {
+        name: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+}
+
+/// A record of the changes made to an image before it was saved.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Transformation {
+    /// The digest of the image as it was received
+    pub original_digest: Digest,
+    /// The size in bytes of the image as it was received
+    pub original_size: u64,
+    /// The names of the transforms that changed the image, in the order they were applied
+    pub steps: Vec<String>,
+}
+
+/// A change that may be made to an image before it is saved (or a check that may reject it).
+pub trait Transform: Send + Sync {
+    /// A short name that is recorded when the transform changes an image.
+    fn name(&self) -> &str;
+
+    /// Return the transformed image, or `None` if it doesn't need to be changed.
+    fn apply(&self, image_type: Type, bytes: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+}
+
+/// A sequence of transforms that are applied to images before they are saved.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    transforms: Vec<Arc<dyn Transform>>,
+}
+
+impl std::fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list()
+            .entries(self.transforms.iter().map(|transform| transform.name()))
+            .finish()
+    }
+}
+
+impl Pipeline {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a transform to the end of the pipeline.
+    #[must_use]
+    pub fn with<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    /// Apply each transform in order, returning the result if any of them changed the image.
+    ///
+    /// The original image's digest is computed with the given kind.
+    pub fn apply(
+        &self,
+        image_type: Type,
+        bytes: &[u8],
+        digest_kind: DigestKind,
+    ) -> Result<Option<(Vec<u8>, Transformation)>, Error> {
+        let mut current: Option<Vec<u8>> = None;
+        let mut steps = vec![];
+
+        for transform in &self.transforms {
+            if let Some(transformed) =
+                transform.apply(image_type, current.as_deref().unwrap_or(bytes))?
+            {
+                steps.push(transform.name().to_string());
+                current = Some(transformed);
+            }
+        }
+
+        Ok(current.map(|transformed| {
+            (
+                transformed,
+                Transformation {
+                    original_digest: digest_kind.compute(bytes),
+                    original_size: bytes.len() as u64,
+                    steps,
+                },
+            )
+        }))
+    }
+}
+
+/// Remove metadata (such as EXIF, XMP, and text chunks) from JPEG and PNG images.
+///
+/// Color profiles are kept, but EXIF orientation is removed along with the rest of the EXIF data.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StripMetadata;
+
+impl Transform for StripMetadata {
+    fn name(&self) -> &'static str {
+        "strip-metadata"
+    }
+
+    fn apply(&self, image_type: Type, bytes: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match image_type {
+            Type::Jpeg => {
+                let (segments, scan_start) = jpeg_header_segments(bytes)?;
+
+                if segments
+                    .iter()
+                    .any(|(marker, _)| JPEG_METADATA_MARKERS.contains(marker))
+                {
+                    let mut stripped = Vec::with_capacity(bytes.len());
+                    stripped.extend_from_slice(&bytes[0..2]);
+
+                    for (marker, range) in segments {
+                        if !JPEG_METADATA_MARKERS.contains(&marker) {
+                            stripped.extend_from_slice(&bytes[range]);
+                        }
+                    }
+
+                    stripped.extend_from_slice(&bytes[scan_start..]);
+
+                    Ok(Some(stripped))
+                } else {
+                    Ok(None)
+                }
+            }
+            Type::Png => {
+                let chunks = png_chunks(bytes)?;
+
+                if chunks
+                    .iter()
+                    .any(|(chunk_type, _)| PNG_METADATA_CHUNKS.contains(&chunk_type))
+                {
+                    let mut stripped = Vec::with_capacity(bytes.len());
+                    stripped.extend_from_slice(&PNG_SIGNATURE);
+
+                    for (chunk_type, range) in chunks {
+                        if !PNG_METADATA_CHUNKS.contains(&&chunk_type) {
+                            stripped.extend_from_slice(&bytes[range]);
+                        }
+                    }
+
+                    Ok(Some(stripped))
+                } else {
+                    Ok(None)
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Reject images that are structurally invalid (usually because they were truncated).
+///
+/// JPEG, PNG, GIF, and WebP images are checked without decoding them: JPEG headers must be
+/// complete and the image must have an end marker, PNG chunks must be complete and have valid
+/// checksums, GIF images must end with a trailer, and WebP images must be as long as their header
+/// says. Other images are accepted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RejectCorrupt;
+
+impl Transform for RejectCorrupt {
+    fn name(&self) -> &'static str {
+        "reject-corrupt"
+    }
+
+    fn apply(&self, image_type: Type, bytes: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match image_type {
+            Type::Jpeg => {
+                let (_, scan_start) = jpeg_header_segments(bytes)?;
+
+                // Marker bytes in the image data are escaped, so this can only be the end marker.
+                if !bytes[scan_start..]
+                    .windows(2)
+                    .any(|window| window == [0xFF, 0xD9])
+                {
+                    return Err(Error::Corrupt("JPEG image has no end marker"));
+                }
+            }
+            Type::Png => {
+                for (_, range) in png_chunks(bytes)? {
+                    let chunk = &bytes[range];
+                    let (contents, crc) = chunk[4..].split_at(chunk.len() - 8);
+
+                    if crc32(contents).to_be_bytes() != crc {
+                        return Err(Error::Corrupt("PNG chunk has an invalid checksum"));
+                    }
+                }
+            }
+            Type::Gif => {
+                // Some encoders pad the end of the file with zeros.
+                let end = bytes.iter().rposition(|byte| *byte != 0);
+
+                if !bytes.starts_with(b"GIF8") || end.map(|end| bytes[end]) != Some(0x3B) {
+                    return Err(Error::Corrupt("GIF image has no trailer"));
+                }
+            }
+            Type::Webp => {
+                let declared_len = bytes
+                    .get(4..8)
+                    .and_then(|len| len.try_into().ok())
+                    .map(u32::from_le_bytes)
+                    .ok_or(Error::Corrupt("WebP header is incomplete"))?;
+
+                if (bytes.len() as u64) < u64::from(declared_len) + 8 {
+                    return Err(Error::Corrupt("WebP image is shorter than its header says"));
+                }
+            }
+            _ => {}
+        }
+
+        Ok(None)
+    }
+}
+
+/// A JPEG segment marker and the byte range of the segment.
+type JpegSegment = (u8, Range<usize>);
+
+/// A PNG chunk type and the byte range of the chunk.
+type PngChunk = ([u8; 4], Range<usize>);
+
+/// Return the marker and byte range of each JPEG segment before the image data, together with the
+/// position of the start of scan segment.
+fn jpeg_header_segments(bytes: &[u8]) -> Result<(Vec<JpegSegment>, usize), Error> {
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return Err(Error::Corrupt("JPEG image has no start marker"));
+    }
+
+    let mut segments = vec![];
+    let mut position = 2;
+
+    loop {
+        // Markers may be preceded by any number of fill bytes.
+        while bytes.get(position..position + 2) == Some(&[0xFF, 0xFF][..]) {
+            position += 1;
+        }
+
+        match bytes.get(position..position + 4) {
+            Some(&[0xFF, 0xDA, _, _]) => return Ok((segments, position)),
+            Some(&[0xFF, marker, high, low]) => {
+                let len = usize::from(u16::from_be_bytes([high, low]));
+                let end = position + 2 + len;
+
+                if len < 2 || end > bytes.len() {
+                    return Err(Error::Corrupt("JPEG segment is incomplete"));
+                }
+
+                segments.push((marker, position..end));
+                position = end;
+            }
+            _ => return Err(Error::Corrupt("JPEG header is incomplete")),
+        }
+    }
+}
+
+/// Return the type and byte range of each PNG chunk, up to and including the end chunk.
+fn png_chunks(bytes: &[u8]) -> Result<Vec<PngChunk>, Error> {
+    if !bytes.starts_with(&PNG_SIGNATURE) {
+        return Err(Error::Corrupt("PNG image has no signature"));
+    }
+
+    let mut chunks = vec![];
+    let mut position = PNG_SIGNATURE.len();
+
+    loop {
+        let Some(&[len_0, len_1, len_2, len_3, type_0, type_1, type_2, type_3]) =
+            bytes.get(position..position + 8)
+        else {
+            return Err(Error::Corrupt("PNG image has no end chunk"));
+        };
+
+        let len = u32::from_be_bytes([len_0, len_1, len_2, len_3]) as usize;
+        let chunk_type = [type_0, type_1, type_2, type_3];
+        let end = position + 12 + len;
+
+        if end > bytes.len() {
+            return Err(Error::Corrupt("PNG chunk is incomplete"));
+        }
+
+        chunks.push((chunk_type, position..end));
+        position = end;
+
+        if &chunk_type == b"IEND" {
+            return Ok(chunks);
+        }
+    }
+}
+
+/// The CRC-32 checksum used by PNG.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PNG_SIGNATURE, Pipeline, RejectCorrupt, StripMetadata, Transform, crc32};
+    use crate::digest::{Digest, DigestKind};
+    use imghdr::Type;
+
+    fn png_chunk(chunk_type: [u8; 4], contents: &[u8]) -> Vec<u8> {
+        let mut chunk = vec![];
+        let mut checked = chunk_type.to_vec();
+        checked.extend_from_slice(contents);
+
+        chunk.extend_from_slice(&u32::try_from(contents.len()).unwrap().to_be_bytes());
+        chunk.extend_from_slice(&checked);
+        chunk.extend_from_slice(&crc32(&checked).to_be_bytes());
+
+        chunk
+    }
+
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = PNG_SIGNATURE.to_vec();
+
+        for chunk in chunks {
+            bytes.extend_from_slice(chunk);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_strip_png() -> Result<(), Box<dyn std::error::Error>> {
+        let header = png_chunk(*b"IHDR", &[0; 13]);
+        let data = png_chunk(*b"IDAT", b"data");
+        let end = png_chunk(*b"IEND", b"");
+        let text = png_chunk(*b"tEXt", b"Comment\0hello");
+
+        let original = png(&[header.clone(), text, data.clone(), end.clone()]);
+        let stripped = png(&[header.clone(), data, end]);
+
+        assert_eq!(
+            StripMetadata.apply(Type::Png, &original)?,
+            Some(stripped.clone())
+        );
+        assert_eq!(StripMetadata.apply(Type::Png, &stripped)?, None);
+        assert!(RejectCorrupt.apply(Type::Png, &original).is_ok());
+
+        // Truncated images and invalid checksums are rejected.
+        assert!(
+            RejectCorrupt
+                .apply(Type::Png, &original[..original.len() - 4])
+                .is_err()
+        );
+
+        let mut invalid = stripped;
+        let data_start = PNG_SIGNATURE.len() + header.len() + 8;
+        invalid[data_start] = b'x';
+
+        assert!(RejectCorrupt.apply(Type::Png, &invalid).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_jpeg() -> Result<(), Box<dyn std::error::Error>> {
+        let jfif: [u8; 6] = [0xFF, 0xE0, 0x00, 0x04, 0x4A, 0x46];
+        let exif = [0xFF, 0xE1, 0x00, 0x05, b'E', b'x', b'i'];
+        let comment = [0xFF, 0xFE, 0x00, 0x03, b'!'];
+        let scan: [u8; 10] = [0xFF, 0xDA, 0x00, 0x02, 0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD9];
+
+        let original = [&[0xFF, 0xD8][..], &jfif, &exif, &comment, &scan].concat();
+        let stripped = [&[0xFF, 0xD8][..], &jfif, &scan].concat();
+
+        assert_eq!(
+            StripMetadata.apply(Type::Jpeg, &original)?,
+            Some(stripped.clone())
+        );
+        assert_eq!(StripMetadata.apply(Type::Jpeg, &stripped)?, None);
+        assert!(RejectCorrupt.apply(Type::Jpeg, &original).is_ok());
+        assert!(
+            RejectCorrupt
+                .apply(Type::Jpeg, &original[..original.len() - 2])
+                .is_err()
+        );
+        assert!(RejectCorrupt.apply(Type::Jpeg, &original[..10]).is_err());
+
+        let pipeline = Pipeline::new().with(RejectCorrupt).with(StripMetadata);
+        let (transformed, transformation) = pipeline
+            .apply(Type::Jpeg, &original, DigestKind::Md5)?
+            .unwrap();
+
+        assert_eq!(transformed, stripped);
+        assert_eq!(transformation.original_digest, Digest::compute(&original));
+        assert_eq!(transformation.original_size, original.len() as u64);
+        assert_eq!(transformation.steps, vec!["strip-metadata".to_string()]);
+        assert!(
+            pipeline
+                .apply(Type::Jpeg, &stripped, DigestKind::Md5)?
+                .is_none()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use std::borrow::Cow;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("URL parse error")]
+    Parse(#[from] url::ParseError),
+    #[error("Unsupported URL scheme")]
+    UnsupportedScheme(String),
+}
+
+/// Rewrites URLs into a canonical form, so that different spellings of the same URL are treated
+/// as the same image.
+///
+/// Normalization lowercases the scheme and host, removes default ports and fragments, resolves dot
+/// segments, uppercases percent-encoding escapes and decodes escaped unreserved characters, and
+/// removes the configured query parameters (the query is removed entirely if it ends up empty).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Normalizer {
+    stripped_params: Vec<String>,
+}
+
+impl Normalizer {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the query parameters that are removed.
+    ///
+    /// A name ending in `*` matches any parameter with the preceding prefix (e.g. `utm_*`).
+    #[must_use]
+    pub fn with_stripped_params<I: IntoIterator<Item = S>, S: Into<String>>(
+        self,
+        stripped_params: I,
+    ) -> Self {
+        Self {
+            stripped_params: stripped_params.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    pub fn normalize(&self, url: &str) -> Result<String, Error> {
+        let mut parsed = url::Url::parse(url.trim())?;
+
+        if parsed.scheme() != "http" && parsed.scheme() != "https" {
+            return Err(Error::UnsupportedScheme(parsed.scheme().to_string()));
+        }
+
+        parsed.set_fragment(None);
+
+        let path = normalize_percent_encoding(parsed.path()).into_owned();
+        parsed.set_path(&path);
+
+        let query = parsed.query().map(|query| {
+            query
+                .split('&')
+                .filter(|param| !param.is_empty())
+                .map(normalize_percent_encoding)
+                .filter(|param| !self.is_stripped(param.split('=').next().unwrap_or_default()))
+                .collect::<Vec<_>>()
+                .join("&")
+        });
+
+        parsed.set_query(query.as_deref().filter(|query| !query.is_empty()));
+
+        Ok(parsed.into())
+    }
+
+    /// Normalize a URL, returning it unchanged if it can't be normalized.
+    #[must_use]
+    pub fn normalize_or_keep<'a>(&self, url: &'a str) -> Cow<'a, str> {
+        self.normalize(url).map_or(Cow::Borrowed(url), Cow::Owned)
+    }
+
+    fn is_stripped(&self, name: &str) -> bool {
+        self.stripped_params.iter().any(|stripped| {
+            stripped
+                .strip_suffix('*')
+                .map_or(stripped == name, |prefix| name.starts_with(prefix))
+        })
+    }
+}
+
+/// Normalize a URL without removing any query parameters.
+pub fn normalize(url: &str) -> Result<String, Error> {
+    Normalizer::default().normalize(url)
+}
+
+/// Uppercase the hexadecimal digits in escapes, and decode escapes for unreserved characters.
+fn normalize_percent_encoding(input: &str) -> Cow<'_, str> {
+    if !input.contains('%') {
+        return Cow::Borrowed(input);
+    }
+
+    let bytes = input.as_bytes();
+    let mut result = String::with_capacity(input.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| input.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .and_then(|hex| Some((hex, u8::from_str_radix(hex, 16).ok()?)));
+
+        match escaped {
+            Some((_, byte)) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                result.push(char::from(byte));
+                i += 3;
+            }
+            Some((hex, _)) => {
+                result.push('%');
+                result.push_str(&hex.to_ascii_uppercase());
+                i += 3;
+            }
+            None => {
+                // Escapes are ASCII, so anything else can be copied up to the next escape.
+                let next = input[i + 1..]
+                    .find('%')
+                    .map_or(input.len(), |offset| i + 1 + offset);
+                result.push_str(&input[i..next]);
+                i = next;
+            }
+        }
+    }
+
+    Cow::Owned(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Normalizer, normalize};
+
+    #[test]
+    fn test_normalize() -> Result<(), Box<dyn std::error::Error>> {
+        let expected = "https://example.com/a/b.png?size=large";
+
+        for url in [
+            "https://example.com/a/b.png?size=large",
+            "HTTPS://Example.COM/a/b.png?size=large",
+            "https://example.com:443/a/b.png?size=large#top",
+            "https://example.com/a/./c/../b.png?size=large",
+            "https://example.com/%61/b%2Epng?size=large",
+            " https://example.com/a/b.png?size=large&",
+        ] {
+            assert_eq!(normalize(url)?, expected);
+        }
+
+        assert_eq!(
+            normalize("http://example.com:80/a%2fb.png?")?,
+            "http://example.com/a%2Fb.png"
+        );
+        assert_eq!(
+            normalize("http://example.com:8080/")?,
+            "http://example.com:8080/"
+        );
+        assert!(normalize("ftp://example.com/a.png").is_err());
+        assert!(normalize("not a url").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_stripped_params() -> Result<(), Box<dyn std::error::Error>> {
+        let normalizer = Normalizer::new().with_stripped_params(["utm_*", "ref"]);
+
+        assert_eq!(
+            normalizer.normalize(
+                "https://example.com/a.png?utm_source=x&size=large&ref=feed&utm_medium=y&referrer=z"
+            )?,
+            "https://example.com/a.png?size=large&referrer=z"
+        );
+        assert_eq!(
+            normalizer.normalize("https://example.com/a.png?utm_source=x&ref")?,
+            "https://example.com/a.png"
+        );
+        assert_eq!(normalizer.normalize_or_keep("not a url"), "not a url");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,540 @@
+use crate::header_template::HostPattern;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid URL: {0}")]
+    InvalidUrl(String),
+    #[error("Scheme not allowed: {0}")]
+    Scheme(String),
+    #[error("Host not allowed: {0}")]
+    Host(String),
+    #[error("Address not allowed: {0}")]
+    Address(IpAddr),
+    #[error("Invalid address range: {0}")]
+    InvalidRange(String),
+    #[error("URL not allowed by patterns: {0}")]
+    Pattern(String),
+    #[error("Invalid URL pattern: {0}")]
+    InvalidPattern(String),
+}
+
+/// Address ranges that aren't publicly routable (loopback, private, link-local, shared, multicast,
+/// and reserved addresses), and the NAT64 and 6to4 ranges that can embed any IPv4 address.
+pub const PRIVATE_RANGES: [IpRange; 18] = [
+    IpRange::v4(Ipv4Addr::UNSPECIFIED, 8),
+    IpRange::v4(Ipv4Addr::new(10, 0, 0, 0), 8),
+    IpRange::v4(Ipv4Addr::new(100, 64, 0, 0), 10),
+    IpRange::v4(Ipv4Addr::new(127, 0, 0, 0), 8),
+    IpRange::v4(Ipv4Addr::new(169, 254, 0, 0), 16),
+    IpRange::v4(Ipv4Addr::new(172, 16, 0, 0), 12),
+    IpRange::v4(Ipv4Addr::new(192, 0, 0, 0), 24),
+    IpRange::v4(Ipv4Addr::new(192, 168, 0, 0), 16),
+    IpRange::v4(Ipv4Addr::new(198, 18, 0, 0), 15),
+    IpRange::v4(Ipv4Addr::new(224, 0, 0, 0), 4),
+    IpRange::v4(Ipv4Addr::new(240, 0, 0, 0), 4),
+    IpRange::v6(Ipv6Addr::UNSPECIFIED, 128),
+    IpRange::v6(Ipv6Addr::LOCALHOST, 128),
+    IpRange::v6(Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0), 96),
+    IpRange::v6(Ipv6Addr::new(0x2002, 0, 0, 0, 0, 0, 0, 0), 16),
+    IpRange::v6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0), 7),
+    IpRange::v6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0), 10),
+    IpRange::v6(Ipv6Addr::new(0xff00, 0, 0, 0, 0, 0, 0, 0), 8),
+];
+
+/// A range of IP addresses in CIDR notation (e.g. `10.0.0.0/8` or `fc00::/7`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpRange {
+    const fn v4(network: Ipv4Addr, prefix_len: u8) -> Self {
+        Self {
+            network: IpAddr::V4(network),
+            prefix_len,
+        }
+    }
+
+    const fn v6(network: Ipv6Addr, prefix_len: u8) -> Self {
+        Self {
+            network: IpAddr::V6(network),
+            prefix_len,
+        }
+    }
+
+    /// Check whether the range contains an address (IPv4-mapped IPv6 addresses are treated as IPv4
+    /// addresses).
+    #[must_use]
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let prefix_len = u32::from(self.prefix_len);
+
+        match (self.network, addr.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => (u32::from(network) ^ u32::from(addr))
+                .checked_shr(32 - prefix_len)
+                .is_none_or(|difference| difference == 0),
+            (IpAddr::V6(network), IpAddr::V6(addr)) => (u128::from(network) ^ u128::from(addr))
+                .checked_shr(128 - prefix_len)
+                .is_none_or(|difference| difference == 0),
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = Error;
+
+    /// Parse a range, or a single address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = s
+            .split_once('/')
+            .map_or((s, None), |(network, prefix_len)| {
+                (network, Some(prefix_len))
+            });
+
+        let network = network
+            .parse::<IpAddr>()
+            .map_err(|_| Error::InvalidRange(s.to_string()))?;
+        let max_prefix_len = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_prefix_len)
+                .ok_or_else(|| Error::InvalidRange(s.to_string()))?,
+            None => max_prefix_len,
+        };
+
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl Display for IpRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// A glob pattern that is matched against a URL's host and path (e.g. `*.fbcdn.net/v/**`).
+///
+/// In the path, `*` matches any characters except `/`, `**` matches any characters, and `?` matches
+/// a single character other than `/` (the host is matched in the same way). A pattern without a `/`
+/// only restricts the host, and matches any path. Query strings and ports aren't matched.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UrlPattern {
+    pattern: String,
+}
+
+impl UrlPattern {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        glob_matches(
+            self.pattern.as_bytes(),
+            format!("{}{path}", host.to_ascii_lowercase()).as_bytes(),
+        )
+    }
+}
+
+impl FromStr for UrlPattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, path) = s.find('/').map_or((s, "/**"), |index| s.split_at(index));
+
+        // Ports aren't matched, so a host with a port would never match (except IPv6 addresses).
+        if host.is_empty()
+            || host.contains(['\\', '@'])
+            || (host.contains(':') && !host.starts_with('['))
+        {
+            return Err(Error::InvalidPattern(s.to_string()));
+        }
+
+        // Hosts are case-insensitive, but paths aren't.
+        Ok(Self {
+            pattern: format!("{}{path}", host.to_ascii_lowercase()),
+        })
+    }
+}
+
+impl Display for UrlPattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.pattern)
+    }
+}
+
+/// A single element of a glob pattern.
+#[derive(Clone, Copy)]
+enum GlobToken {
+    /// `**`, which matches any sequence of bytes
+    AnyPath,
+    /// `*`, which matches any sequence of bytes within a path segment
+    AnySegment,
+    /// `?`, which matches any byte other than a slash
+    AnyByte,
+    Byte(u8),
+}
+
+/// Match a glob pattern against a value.
+///
+/// Suffixes of the pattern are matched against every suffix of the value in turn (from the end),
+/// so the time taken is proportional to the product of their lengths however many wildcards there
+/// are.
+fn glob_matches(pattern: &[u8], value: &[u8]) -> bool {
+    let mut tokens = vec![];
+    let mut remaining = pattern;
+
+    while let Some((first, rest)) = remaining.split_first() {
+        remaining = match (first, rest) {
+            (b'*', [b'*', rest @ ..]) => {
+                tokens.push(GlobToken::AnyPath);
+                rest
+            }
+            (b'*', _) => {
+                tokens.push(GlobToken::AnySegment);
+                rest
+            }
+            (b'?', _) => {
+                tokens.push(GlobToken::AnyByte);
+                rest
+            }
+            (byte, _) => {
+                tokens.push(GlobToken::Byte(*byte));
+                rest
+            }
+        };
+    }
+
+    // Whether the suffix of the pattern after the current token matches each suffix of the value.
+    let mut matches = (0..=value.len())
+        .map(|start| start == value.len())
+        .collect::<Vec<_>>();
+
+    for token in tokens.into_iter().rev() {
+        let mut current = vec![false; value.len() + 1];
+
+        for start in (0..=value.len()).rev() {
+            let next = value.get(start);
+
+            current[start] = match token {
+                GlobToken::AnyPath => matches[start] || current.get(start + 1) == Some(&true),
+                GlobToken::AnySegment => {
+                    matches[start] || (next.is_some_and(|byte| *byte != b'/') && current[start + 1])
+                }
+                GlobToken::AnyByte => next.is_some_and(|byte| *byte != b'/') && matches[start + 1],
+                GlobToken::Byte(expected) => next == Some(&expected) && matches[start + 1],
+            };
+        }
+
+        matches = current;
+    }
+
+    matches[0]
+}
+
+/// Restrictions on the URLs that may be requested, to prevent requests to internal services (for
+/// example when URLs are provided by clients of the service).
+///
+/// Host names are checked before a request is made, and the addresses they resolve to are checked
+/// before a connection is made (when the HTTP client is built with the policy).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UrlPolicy {
+    schemes: Vec<String>,
+    allowed_hosts: Vec<HostPattern>,
+    denied_hosts: Vec<HostPattern>,
+    denied_ranges: Vec<IpRange>,
+    allowed_patterns: Vec<UrlPattern>,
+    denied_patterns: Vec<UrlPattern>,
+}
+
+impl Default for UrlPolicy {
+    /// Allow any HTTP or HTTPS URL.
+    fn default() -> Self {
+        Self {
+            schemes: vec!["http".to_string(), "https".to_string()],
+            allowed_hosts: vec![],
+            denied_hosts: vec![],
+            denied_ranges: vec![],
+            allowed_patterns: vec![],
+            denied_patterns: vec![],
+        }
+    }
+}
+
+impl UrlPolicy {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only allow URLs with the given schemes (instead of HTTP and HTTPS).
+    #[must_use]
+    pub fn with_schemes<I: IntoIterator<Item = S>, S: Into<String>>(self, schemes: I) -> Self {
+        Self {
+            schemes: schemes
+                .into_iter()
+                .map(|scheme| Into::<String>::into(scheme).to_ascii_lowercase())
+                .collect(),
+            ..self
+        }
+    }
+
+    /// Only allow hosts that match one of the given patterns (if there are any).
+    #[must_use]
+    pub fn with_allowed_hosts<I: IntoIterator<Item = HostPattern>>(self, hosts: I) -> Self {
+        Self {
+            allowed_hosts: hosts.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Reject hosts that match any of the given patterns (even if they are also allowed).
+    #[must_use]
+    pub fn with_denied_hosts<I: IntoIterator<Item = HostPattern>>(self, hosts: I) -> Self {
+        Self {
+            denied_hosts: hosts.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Only allow URLs that match one of the given patterns (if there are any).
+    #[must_use]
+    pub fn with_allowed_patterns<I: IntoIterator<Item = UrlPattern>>(self, patterns: I) -> Self {
+        Self {
+            allowed_patterns: patterns.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Reject URLs that match any of the given patterns (even if they are also allowed).
+    #[must_use]
+    pub fn with_denied_patterns<I: IntoIterator<Item = UrlPattern>>(self, patterns: I) -> Self {
+        Self {
+            denied_patterns: patterns.into_iter().collect(),
+            ..self
+        }
+    }
+
+    /// Reject addresses in any of the given ranges.
+    #[must_use]
+    pub fn with_denied_ranges<I: IntoIterator<Item = IpRange>>(mut self, ranges: I) -> Self {
+        self.denied_ranges.extend(ranges);
+        self
+    }
+
+    /// Reject addresses that aren't publicly routable (see [`PRIVATE_RANGES`]).
+    #[must_use]
+    pub fn with_private_ranges_denied(self) -> Self {
+        self.with_denied_ranges(PRIVATE_RANGES)
+    }
+
+    /// Check a URL's scheme, host (including hosts that are IP addresses), and path.
+    pub fn check(&self, url: &str) -> Result<(), Error> {
+        let url = url::Url::parse(url).map_err(|_| Error::InvalidUrl(url.to_string()))?;
+
+        if !self.schemes.iter().any(|scheme| scheme == url.scheme()) {
+            return Err(Error::Scheme(url.scheme().to_string()));
+        }
+
+        let host = url.host_str().unwrap_or_default();
+
+        if self
+            .denied_patterns
+            .iter()
+            .any(|pattern| pattern.matches(host, url.path()))
+            || (!self.allowed_patterns.is_empty()
+                && !self
+                    .allowed_patterns
+                    .iter()
+                    .any(|pattern| pattern.matches(host, url.path())))
+        {
+            return Err(Error::Pattern(url.to_string()));
+        }
+
+        match url.host() {
+            Some(url::Host::Domain(domain)) => self.check_host(domain),
+            Some(url::Host::Ipv4(addr)) => {
+                self.check_host(&addr.to_string())?;
+                self.check_address(IpAddr::V4(addr))
+            }
+            Some(url::Host::Ipv6(addr)) => {
+                self.check_host(&addr.to_string())?;
+                self.check_address(IpAddr::V6(addr))
+            }
+            None => Err(Error::InvalidUrl(url.to_string())),
+        }
+    }
+
+    fn check_host(&self, host: &str) -> Result<(), Error> {
+        if self
+            .denied_hosts
+            .iter()
+            .any(|pattern| pattern.matches(host))
+            || (!self.allowed_hosts.is_empty()
+                && !self
+                    .allowed_hosts
+                    .iter()
+                    .any(|pattern| pattern.matches(host)))
+        {
+            Err(Error::Host(host.to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Whether the policy rejects any addresses (which can only be checked when hosts are resolved
+    /// locally, and not by a proxy).
+    #[must_use]
+    pub const fn checks_addresses(&self) -> bool {
+        !self.denied_ranges.is_empty()
+    }
+
+    /// Check an address that a host resolved to.
+    pub fn check_address(&self, addr: IpAddr) -> Result<(), Error> {
+        if self.denied_ranges.iter().any(|range| range.contains(addr)) {
+            Err(Error::Address(addr))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IpRange, UrlPattern, UrlPolicy};
+
+    #[test]
+    fn test_ip_range() -> Result<(), Box<dyn std::error::Error>> {
+        let range = "10.0.0.0/8".parse::<IpRange>()?;
+
+        assert!(range.contains("10.1.2.3".parse()?));
+        assert!(range.contains("::ffff:10.1.2.3".parse()?));
+        assert!(!range.contains("11.0.0.1".parse()?));
+        assert!(!range.contains("::1".parse()?));
+
+        let range = "fe80::/10".parse::<IpRange>()?;
+
+        assert!(range.contains("fe80::1".parse()?));
+        assert!(range.contains("febf::1".parse()?));
+        assert!(!range.contains("fec0::1".parse()?));
+
+        assert!("0.0.0.0/0".parse::<IpRange>()?.contains("8.8.8.8".parse()?));
+        assert!(
+            "192.0.2.1"
+                .parse::<IpRange>()?
+                .contains("192.0.2.1".parse()?)
+        );
+        assert!(
+            !"192.0.2.1"
+                .parse::<IpRange>()?
+                .contains("192.0.2.2".parse()?)
+        );
+        assert_eq!("192.0.2.1".parse::<IpRange>()?.to_string(), "192.0.2.1/32");
+
+        assert!("10.0.0.0/33".parse::<IpRange>().is_err());
+        assert!("example.com/8".parse::<IpRange>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_url_pattern() -> Result<(), Box<dyn std::error::Error>> {
+        let pattern = "*.FBCDN.net".parse::<UrlPattern>()?;
+
+        assert_eq!(pattern.to_string(), "*.fbcdn.net/**");
+        assert!(pattern.matches("scontent.xx.fbcdn.net", "/v/a.jpg"));
+        assert!(pattern.matches("Scontent.fbcdn.net", "/"));
+        assert!(!pattern.matches("fbcdn.net", "/a.jpg"));
+        assert!(!pattern.matches("fbcdn.net.example.com", "/a.jpg"));
+
+        let pattern = "cdn.example.com/images/*.png".parse::<UrlPattern>()?;
+
+        assert!(pattern.matches("cdn.example.com", "/images/a.png"));
+        assert!(!pattern.matches("cdn.example.com", "/images/a/b.png"));
+        assert!(!pattern.matches("cdn.example.com", "/Images/a.png"));
+        assert!(!pattern.matches("cdn.example.com", "/images/a.jpg"));
+
+        let pattern = "cdn.example.com/**/?.png".parse::<UrlPattern>()?;
+
+        assert!(pattern.matches("cdn.example.com", "/a/b/c.png"));
+        assert!(!pattern.matches("cdn.example.com", "/a/b/cd.png"));
+
+        // Repeated wildcards don't cause backtracking.
+        let pattern = format!("example.com/{}b", "**a".repeat(32)).parse::<UrlPattern>()?;
+
+        assert!(!pattern.matches("example.com", &format!("/{}", "a".repeat(256))));
+        assert!(pattern.matches("example.com", &format!("/{}b", "a".repeat(256))));
+
+        assert!("".parse::<UrlPattern>().is_err());
+        assert!("/images/**".parse::<UrlPattern>().is_err());
+        assert!("example.com:8080/**".parse::<UrlPattern>().is_err());
+        assert!("user@example.com".parse::<UrlPattern>().is_err());
+
+        let policy = UrlPolicy::new()
+            .with_allowed_patterns(["*.fbcdn.net".parse()?, "example.com/images/**".parse()?])
+            .with_denied_patterns(["example.com/images/private/**".parse()?]);
+
+        assert!(policy.check("https://scontent.fbcdn.net/a.jpg?x=1").is_ok());
+        assert!(policy.check("https://example.com/images/a.jpg").is_ok());
+        assert!(policy.check("https://example.com/a.jpg").is_err());
+        assert!(
+            policy
+                .check("https://example.com/images/private/a.jpg")
+                .is_err()
+        );
+        assert!(policy.check("https://example.org/images/a.jpg").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_check() -> Result<(), Box<dyn std::error::Error>> {
+        let policy = UrlPolicy::new()
+            .with_private_ranges_denied()
+            .with_denied_hosts(["*.internal.example.com".parse()?]);
+
+        assert!(policy.check("https://example.com/a.png").is_ok());
+        assert!(policy.check("http://93.184.216.34/a.png").is_ok());
+        assert!(policy.check("ftp://example.com/a.png").is_err());
+        assert!(policy.check("file:///etc/passwd").is_err());
+        assert!(policy.check("not a url").is_err());
+        assert!(
+            policy
+                .check("http://169.254.169.254/latest/meta-data/")
+                .is_err()
+        );
+        assert!(policy.check("http://127.1/a.png").is_err());
+        assert!(policy.check("http://0x7f000001/a.png").is_err());
+        assert!(policy.check("http://[::1]/a.png").is_err());
+        assert!(policy.check("http://[::ffff:127.0.0.1]/a.png").is_err());
+        assert!(policy.check("http://[fd00::1]/a.png").is_err());
+        assert!(policy.check("http://[64:ff9b::7f00:1]/a.png").is_err());
+        assert!(policy.check("http://[2002:7f00:1::]/a.png").is_err());
+        assert!(
+            policy
+                .check("https://images.internal.example.com/a.png")
+                .is_err()
+        );
+
+        assert!(policy.check_address("8.8.8.8".parse()?).is_ok());
+        assert!(policy.check_address("192.168.1.1".parse()?).is_err());
+        assert!(policy.checks_addresses());
+        assert!(!UrlPolicy::new().checks_addresses());
+
+        let policy = UrlPolicy::new()
+            .with_schemes(["https"])
+            .with_allowed_hosts(["*.example.com".parse()?, "example.com".parse()?]);
+
+        assert!(policy.check("https://example.com/a.png").is_ok());
+        assert!(policy.check("https://cdn.example.com/a.png").is_ok());
+        assert!(policy.check("http://example.com/a.png").is_err());
+        assert!(policy.check("https://example.org/a.png").is_err());
+
+        Ok(())
+    }
+}
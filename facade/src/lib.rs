@@ -0,0 +1,282 @@
+#![warn(clippy::all, clippy::pedantic, clippy::nursery, rust_2018_idioms)]
+#![allow(clippy::missing_errors_doc)]
+#![forbid(unsafe_code)]
+use chrono::Utc;
+use image_scraper::client::Client;
+use image_scraper::digest::DigestKind;
+use image_scraper::history::FailureKind;
+use image_scraper::hook::Hooks;
+use image_scraper::image_type::ImageType;
+use image_scraper::store::Store;
+use image_scraper::url_norm::Normalizer;
+use image_scraper_index::db::Database;
+use std::path::{Path, PathBuf};
+
+pub use image_scraper as core;
+pub use image_scraper_index as index;
+pub use image_scraper_index::{Entry, Missing};
+
+/// Prefix part lengths used for new stores if none are provided.
+pub const DEFAULT_PREFIX_PART_LENGTHS: [usize; 2] = [2, 2];
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Missing store directory")]
+    MissingStore,
+    #[error("Missing index directory")]
+    MissingIndex,
+    #[error("Store initialization error")]
+    StoreInitialization(#[from] image_scraper::store::InitializationError),
+    #[error("Store error")]
+    Store(#[from] image_scraper::store::Error),
+    #[error("Index error")]
+    Index(#[from] image_scraper_index::db::Error),
+    #[error("HTTP client error")]
+    Client(#[from] image_scraper::client::Error),
+    #[error("Not an image ({image_type}): {url}")]
+    InvalidImageType { url: String, image_type: ImageType },
+}
+
+/// Configuration for a [`Scraper`].
+#[derive(Debug, Default)]
+pub struct Builder {
+    store: Option<PathBuf>,
+    prefix_part_lengths: Option<Vec<usize>>,
+    digest_kind: Option<DigestKind>,
+    index: Option<PathBuf>,
+    hooks: Hooks,
+    normalizer: Normalizer,
+}
+
+impl Builder {
+    /// Set the base directory of the image store (required).
+    #[must_use]
+    pub fn store<P: AsRef<Path>>(self, base: P) -> Self {
+        Self {
+            store: Some(base.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Set the prefix part lengths for the store.
+    ///
+    /// If these are not provided, they are inferred from an existing store, and new stores use
+    /// [`DEFAULT_PREFIX_PART_LENGTHS`].
+    #[must_use]
+    pub fn prefix_part_lengths<T: AsRef<[usize]>>(self, prefix_part_lengths: T) -> Self {
+        Self {
+            prefix_part_lengths: Some(prefix_part_lengths.as_ref().to_vec()),
+            ..self
+        }
+    }
+
+    /// Set the digest kind for the store.
+    ///
+    /// If this is not provided, an existing store's kind is used, and new stores use MD5. An
+    /// existing store must already use the given kind.
+    #[must_use]
+    pub fn digest_kind(self, digest_kind: DigestKind) -> Self {
+        Self {
+            digest_kind: Some(digest_kind),
+            ..self
+        }
+    }
+
+    /// Set the directory of the index database (required).
+    #[must_use]
+    pub fn index<P: AsRef<Path>>(self, path: P) -> Self {
+        Self {
+            index: Some(path.as_ref().to_path_buf()),
+            ..self
+        }
+    }
+
+    /// Set the hooks that are run after each image is saved.
+    #[must_use]
+    pub fn hooks(self, hooks: Hooks) -> Self {
+        Self { hooks, ..self }
+    }
+
+    /// Set the normalizer that is applied to URLs before they are indexed or downloaded.
+    #[must_use]
+    pub fn normalizer(self, normalizer: Normalizer) -> Self {
+        Self { normalizer, ..self }
+    }
+
+    pub fn build(self) -> Result<Scraper, Error> {
+        let base = self.store.ok_or(Error::MissingStore)?;
+        let index = self.index.ok_or(Error::MissingIndex)?;
+
+        let prefix_part_lengths = match self.prefix_part_lengths {
+            Some(prefix_part_lengths) => prefix_part_lengths,
+            None if base.is_dir() => Store::infer_prefix_part_lengths(&base)?
+                .unwrap_or_else(|| DEFAULT_PREFIX_PART_LENGTHS.to_vec()),
+            None => DEFAULT_PREFIX_PART_LENGTHS.to_vec(),
+        };
+
+        let store = Store::load(base)?.with_prefix_part_lengths(prefix_part_lengths)?;
+        let store = match self.digest_kind {
+            Some(digest_kind) => store.with_digest_kind(digest_kind)?,
+            None => store,
+        };
+        store.record_layout()?;
+
+        let client = Client::new(store.clone())
+            .with_hooks(self.hooks)
+            .with_normalizer(self.normalizer.clone());
+        let index = Database::open(index)?.with_normalizer(self.normalizer);
+
+        Ok(Scraper {
+            store,
+            index,
+            client,
+        })
+    }
+}
+
+/// Downloads images into a store, recording the results in an index.
+#[derive(Clone)]
+pub struct Scraper {
+    store: Store,
+    index: Database,
+    client: Client,
+}
+
+impl Scraper {
+    #[must_use]
+    pub fn builder() -> Builder {
+        Builder::default()
+    }
+
+    #[must_use]
+    pub const fn store(&self) -> &Store {
+        &self.store
+    }
+
+    #[must_use]
+    pub const fn index(&self) -> &Database {
+        &self.index
+    }
+
+    #[must_use]
+    pub const fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Return the current index entry for a URL, if it has one.
+    ///
+    /// URLs whose image has been deleted do not have a current entry.
+    pub fn lookup(&self, url: &str) -> Result<Option<Entry>, Error> {
+        Ok(self.current(url)?.and_then(Result::ok))
+    }
+
+    /// Return the current index entry for a URL, downloading the image if necessary.
+    ///
+    /// Images that have been deleted are not downloaded again.
+    pub async fn get(&self, url: &str) -> Result<Result<Entry, Missing>, Error> {
+        match self.current(url)? {
+            Some(current) => Ok(current),
+            None => self.download(url).await,
+        }
+    }
+
+    /// Download an image (even if it has already been indexed), recording the result in the index.
+    ///
+    /// Unsuccessful responses are returned (and recorded) as failures.
+    pub async fn download(&self, url: &str) -> Result<Result<Entry, Missing>, Error> {
+        match self.client.download(url).await {
+            Ok(Ok((_, action))) => match action.image_type() {
+                Some(image_type) => {
+                    let entry = Entry {
+                        timestamp: Utc::now(),
+                        digest: action.entry.digest,
+                        image_type,
+                    };
+
+                    match &action.transformation {
+                        Some(transformation) => {
+                            self.index.add_transformed(url, entry, transformation)?;
+                        }
+                        None => self.index.add(url, entry)?,
+                    }
+
+                    Ok(Ok(entry))
+                }
+                None => Err(Error::InvalidImageType {
+                    url: url.to_string(),
+                    image_type: action.image_type,
+                }),
+            },
+            Ok(Err(status_code)) => {
+                let timestamp = Utc::now();
+                let status = Some(status_code.as_u16());
+                let kind = Some(FailureKind::Status);
+
+                self.index.add_failed(url, timestamp, status, kind)?;
+
+                Ok(Err(Missing::Failed {
+                    timestamp,
+                    status,
+                    kind,
+                }))
+            }
+            Err(error) => {
+                self.index
+                    .add_failed(url, Utc::now(), None, Some(error.failure_kind()))?;
+
+                Err(Error::Client(error))
+            }
+        }
+    }
+
+    /// Find the most recent entry or tombstone for a URL (ignoring failures).
+    fn current(&self, url: &str) -> Result<Option<Result<Entry, Missing>>, Error> {
+        Ok(self
+            .index
+            .lookup(url)?
+            .into_iter()
+            .find(|record| !matches!(record, Err(Missing::Failed { .. }))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Entry, Missing, Scraper};
+
+    #[tokio::test]
+    async fn test_get_indexed() -> Result<(), Box<dyn std::error::Error>> {
+        let store = tempfile::tempdir()?;
+        let index = tempfile::tempdir()?;
+
+        let scraper = Scraper::builder()
+            .store(store.path())
+            .index(index.path())
+            .build()?;
+
+        let url = "https://example.com/a.png";
+        let entry = Entry {
+            timestamp: chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            digest: image_scraper::digest::Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        scraper.index().add(url, entry)?;
+
+        assert_eq!(scraper.lookup(url)?, Some(entry));
+        assert_eq!(scraper.get(url).await?, Ok(entry));
+
+        let timestamp = chrono::DateTime::from_timestamp(1_700_000_100, 0).unwrap();
+        scraper.index().tombstone(entry.digest, timestamp)?;
+
+        assert_eq!(scraper.lookup(url)?, None);
+        assert_eq!(
+            scraper.get(url).await?,
+            Err(Missing::Deleted {
+                timestamp,
+                digest: entry.digest
+            })
+        );
+
+        Ok(())
+    }
+}
@@ -1,16 +1,63 @@
-use crate::Entry;
+use crate::{Entry, Missing};
 use chrono::{DateTime, Utc};
+use image_scraper::digest::{Digest, DigestKind};
+use image_scraper::history::{DownloadHistory, FailureKind, LastDownload, Validators};
 use image_scraper::image_type::ImageType;
-use rocksdb::{DB, IteratorMode, Options};
+use image_scraper::transform::Transformation;
+use image_scraper::url_norm::Normalizer;
+use rocksdb::backup::{BackupEngine, BackupEngineInfo, BackupEngineOptions, RestoreOptions};
+use rocksdb::{ColumnFamily, DB, Env, IteratorMode, Options, WriteBatch};
 use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::net::Ipv4Addr;
+use std::num::NonZeroUsize;
 use std::path::Path;
 use std::sync::Arc;
 
 type DefaultConfig =
     bincode::config::Configuration<bincode::config::BigEndian, bincode::config::Fixint>;
 
+/// A record's key and value bytes, as stored in the database.
+type RawRecord = (Box<[u8]>, Box<[u8]>);
+
 const ERROR_DIGEST: [u8; 16] = [0; 16];
 
+/// Column family for URLs that have been queued for download but not yet recorded.
+const QUEUE_CF: &str = "queue";
+
+/// Column family indexing entries by timestamp (keyed by the timestamp followed by the URL).
+const RECENT_CF: &str = "recent";
+
+/// Column family mapping image digests to the file name in the first URL they were added for.
+const FILENAME_CF: &str = "filename";
+
+/// Column family mapping image digests to their earliest entry (as an epoch second timestamp
+/// followed by the image type).
+const FIRST_SEEN_CF: &str = "first-seen";
+
+/// Column family mapping URLs to the cache validators from the last response for them.
+const VALIDATORS_CF: &str = "validators";
+
+/// Column family recording how images were transformed before they were saved (keyed in the same
+/// way as the recent entries index).
+const TRANSFORMATIONS_CF: &str = "transformations";
+
+/// Column family for settings that are fixed when the database is created (such as the key scheme).
+const METADATA_CF: &str = "metadata";
+
+const KEY_SCHEME_KEY: &[u8] = b"key-scheme";
+
+/// Maximum number of changes in a single write batch when pruning or copying.
+const WRITE_BATCH_SIZE: usize = 10_000;
+
+/// Digests whose file names and first entries have already been added to a write batch.
+#[derive(Default)]
+struct BatchDigests {
+    filenames: HashSet<Digest>,
+    first_seen: HashMap<Digest, DateTime<Utc>>,
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("RocksDB error")]
@@ -25,6 +72,154 @@ pub enum Error {
     ExtraKeyBytes(Vec<u8>),
     #[error("Extra value bytes")]
     ExtraValueBytes(Vec<u8>),
+    #[error("Invalid value")]
+    InvalidValueBytes(Vec<u8>),
+    #[error("Missing column family")]
+    MissingColumnFamily(&'static str),
+    #[error("Invalid cursor")]
+    InvalidCursor(String),
+    #[error("Invalid backup timestamp")]
+    InvalidBackupTimestamp(i64),
+    #[error("Missing backup")]
+    MissingBackup,
+    #[error("Unknown key scheme: {0}")]
+    UnknownKeyScheme(u8),
+    #[error("Invalid key scheme: {0}")]
+    InvalidKeyScheme(String),
+    #[error("Key scheme mismatch (expected {expected}, found {found})")]
+    KeySchemeMismatch {
+        expected: KeyScheme,
+        found: KeyScheme,
+    },
+}
+
+/// How URLs are arranged in the keys of index records.
+///
+/// The key scheme is chosen when a database is created, and can only be changed by copying the
+/// records into a new database (see [`Database::copy_into`]).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum KeyScheme {
+    /// Keys start with the URL, so records are in URL order.
+    #[default]
+    Url,
+    /// Keys start with the URL's host with its labels reversed (e.g. `com.example.www/`), so that
+    /// the records for a domain and all of its subdomains are contiguous.
+    ReversedHost,
+}
+
+impl KeyScheme {
+    const fn to_byte(self) -> u8 {
+        match self {
+            Self::Url => 0,
+            Self::ReversedHost => 1,
+        }
+    }
+
+    const fn from_byte(byte: u8) -> Result<Self, Error> {
+        match byte {
+            0 => Ok(Self::Url),
+            1 => Ok(Self::ReversedHost),
+            other => Err(Error::UnknownKeyScheme(other)),
+        }
+    }
+
+    /// Return the string that a URL's keys start with.
+    fn encode(self, url: &str) -> Cow<'_, str> {
+        match self {
+            Self::Url => url.into(),
+            Self::ReversedHost => format!(
+                "{}/{url}",
+                url_host(url).map(reverse_host).unwrap_or_default()
+            )
+            .into(),
+        }
+    }
+
+    /// Return the URL from the string that its keys start with.
+    fn decode(self, key_url: &str) -> &str {
+        match self {
+            Self::Url => key_url,
+            // Reversed hosts never contain a slash.
+            Self::ReversedHost => key_url.split_once('/').map_or(key_url, |(_, url)| url),
+        }
+    }
+}
+
+impl std::str::FromStr for KeyScheme {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "url" => Ok(Self::Url),
+            "reversed-host" => Ok(Self::ReversedHost),
+            other => Err(Error::InvalidKeyScheme(other.to_string())),
+        }
+    }
+}
+
+impl Display for KeyScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Url => f.write_str("url"),
+            Self::ReversedHost => f.write_str("reversed-host"),
+        }
+    }
+}
+
+/// A backup in a backup directory (see [`Database::backup`]).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BackupInfo {
+    pub id: u32,
+    pub timestamp: DateTime<Utc>,
+    /// Total size of the backup's files in bytes (including files shared with other backups)
+    pub size: u64,
+}
+
+impl TryFrom<BackupEngineInfo> for BackupInfo {
+    type Error = Error;
+
+    fn try_from(info: BackupEngineInfo) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: info.backup_id,
+            timestamp: DateTime::from_timestamp(info.timestamp, 0)
+                .ok_or(Error::InvalidBackupTimestamp(info.timestamp))?,
+            size: info.size,
+        })
+    }
+}
+
+/// An opaque position in the index, used to resume iteration with [`Database::iter_from`].
+///
+/// Cursors are represented as hexadecimal strings, and remain valid across reopening the database
+/// (and after records are added).
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub struct Cursor(Vec<u8>);
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&hex::encode(&self.0))
+    }
+}
+
+impl std::str::FromStr for Cursor {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|_| Error::InvalidCursor(s.to_string()))?;
+
+        // Cursors are always keys, so they can be validated as keys.
+        Key::from_bytes(&bytes).map_err(|_| Error::InvalidCursor(s.to_string()))?;
+
+        Ok(Self(bytes))
+    }
+}
+
+/// A page of index records, in URL order.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Page {
+    pub records: Vec<(String, Result<Entry, Missing>)>,
+    /// The position of the following page (if there are more records)
+    pub next: Option<Cursor>,
 }
 
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
@@ -77,140 +272,2508 @@ impl Key<'_> {
     }
 }
 
+/// Encode a key for the recent entries column family.
+///
+/// The representation is four big-endian bytes for the epoch second timestamp, followed by the URL.
+fn recent_key(timestamp: DateTime<Utc>, url: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(url.len() + 4);
+
+    // This should always fit, but just in case.
+    let timestamp_s = u32::try_from(timestamp.timestamp()).unwrap_or(u32::MAX);
+
+    bytes.extend_from_slice(&timestamp_s.to_be_bytes());
+    bytes.extend_from_slice(url.as_bytes());
+
+    bytes
+}
+
+fn decode_recent_key(bytes: &[u8]) -> Result<(DateTime<Utc>, &str), Error> {
+    bytes
+        .split_first_chunk::<4>()
+        .and_then(|(timestamp_bytes, url_bytes)| {
+            DateTime::from_timestamp(u32::from_be_bytes(*timestamp_bytes).into(), 0)
+                .zip(std::str::from_utf8(url_bytes).ok())
+        })
+        .ok_or_else(|| Error::InvalidKeyBytes(bytes.to_vec()))
+}
+
+const fn encode_failure_kind(kind: FailureKind) -> u8 {
+    match kind {
+        FailureKind::Other => 0,
+        FailureKind::Status => 1,
+        FailureKind::Dns => 2,
+        FailureKind::Connect => 3,
+        FailureKind::Tls => 4,
+        FailureKind::Timeout => 5,
+        FailureKind::Redirect => 6,
+        FailureKind::Body => 7,
+        FailureKind::NotAnImage => 8,
+        FailureKind::Blocked => 9,
+        FailureKind::Corrupt => 10,
+    }
+}
+
+/// Unknown values (from newer versions) are decoded as [`FailureKind::Other`].
+const fn decode_failure_kind(byte: u8) -> FailureKind {
+    match byte {
+        1 => FailureKind::Status,
+        2 => FailureKind::Dns,
+        3 => FailureKind::Connect,
+        4 => FailureKind::Tls,
+        5 => FailureKind::Timeout,
+        6 => FailureKind::Redirect,
+        7 => FailureKind::Body,
+        8 => FailureKind::NotAnImage,
+        9 => FailureKind::Blocked,
+        10 => FailureKind::Corrupt,
+        _ => FailureKind::Other,
+    }
+}
+
+/// Return a URL's host (without any user information or port), if it has one.
+pub(crate) fn url_host(url: &str) -> Option<&str> {
+    let (_, after_scheme) = url.split_once("://")?;
+    let authority = after_scheme
+        .split(['/', '?', '#'])
+        .next()
+        .unwrap_or_default();
+    let host_and_port = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host_and_port)| host_and_port);
+
+    let host = if host_and_port.starts_with('[') {
+        host_and_port
+            .find(']')
+            .map_or(host_and_port, |end| &host_and_port[..=end])
+    } else {
+        host_and_port.split(':').next().unwrap_or_default()
+    };
+
+    (!host.is_empty()).then_some(host)
+}
+
+/// Check whether a URL prefix includes the URL's entire host.
+fn has_complete_host(prefix: &str) -> bool {
+    prefix
+        .split_once("://")
+        .is_some_and(|(_, after_scheme)| after_scheme.contains(['/', '?', '#']))
+}
+
+/// Lowercase a host and reverse the order of its labels (e.g. `www.example.com` becomes
+/// `com.example.www`).
+///
+/// IP addresses are only lowercased.
+fn reverse_host(host: &str) -> String {
+    let host = host.to_ascii_lowercase();
+
+    if host.starts_with('[') || host.parse::<Ipv4Addr>().is_ok() {
+        host
+    } else {
+        host.rsplit('.').collect::<Vec<_>>().join(".")
+    }
+}
+
+/// Check whether a host is the given (lowercase) domain or one of its subdomains.
+fn in_domain(host: &str, domain: &str) -> bool {
+    let host = host.to_ascii_lowercase();
+
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|subdomain| subdomain.ends_with('.'))
+}
+
+/// Return the last segment of a URL's path, if it is not empty.
+///
+/// The file name is returned as it appears in the URL (possibly including percent-encoded
+/// characters).
+fn url_filename(url: &str) -> Option<&str> {
+    let without_query = url.split(['?', '#']).next().unwrap_or_default();
+    let (_, after_scheme) = without_query.split_once("://")?;
+    let (_, path) = after_scheme.split_once('/')?;
+
+    path.rsplit('/')
+        .next()
+        .filter(|filename| !filename.is_empty())
+}
+
 #[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd, bincode::BorrowDecode, bincode::Encode)]
 struct Value {
     pub digest: [u8; 16],
     pub image_type: ImageType,
 }
 
+impl Value {
+    /// Values without an image type represent failures (if the digest is all zeros) or tombstones
+    /// (in which case the digest is the digest of the deleted image).
+    ///
+    /// Failures may be followed by a two-byte HTTP status code, which may be followed by a byte for
+    /// the kind of failure (in which case a status code of zero means that there wasn't one). Other
+    /// values are followed by the rest of the digest if it's wider than an MD5 digest (see
+    /// [`split_digest`]).
+    fn into_record(self, timestamp: DateTime<Utc>) -> Result<Entry, Missing> {
+        match self.image_type.value() {
+            Some(image_type) => Ok(Entry {
+                timestamp,
+                digest: Digest::from_bytes(self.digest),
+                image_type,
+            }),
+            None if self.digest == ERROR_DIGEST => Err(Missing::Failed {
+                timestamp,
+                status: None,
+                kind: None,
+            }),
+            None => Err(Missing::Deleted {
+                timestamp,
+                digest: Digest::from_bytes(self.digest),
+            }),
+        }
+    }
+}
+
+/// Split a digest into the bytes that are stored in a value and a suffix that follows the value.
+///
+/// The suffix is empty for MD5 digests, and is the kind followed by the remaining bytes for wider
+/// digests.
+fn split_digest(digest: Digest) -> ([u8; 16], Vec<u8>) {
+    let (head, rest) = digest.as_bytes().split_at(16);
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(head);
+
+    let suffix = if rest.is_empty() {
+        vec![]
+    } else {
+        let mut suffix = vec![digest.kind().to_byte()];
+        suffix.extend_from_slice(rest);
+        suffix
+    };
+
+    (bytes, suffix)
+}
+
+/// Reassemble a digest that was split by [`split_digest`].
+fn widen_digest(head: Digest, suffix: &[u8]) -> Option<Digest> {
+    let (kind, rest) = suffix.split_first()?;
+
+    Digest::from_slice(
+        DigestKind::from_byte(*kind)?,
+        &[head.as_bytes(), rest].concat(),
+    )
+}
+
+fn widen_record(record: Result<Entry, Missing>, suffix: &[u8]) -> Option<Result<Entry, Missing>> {
+    match record {
+        Ok(entry) => Some(Ok(Entry {
+            digest: widen_digest(entry.digest, suffix)?,
+            ..entry
+        })),
+        Err(Missing::Deleted { timestamp, digest }) => Some(Err(Missing::Deleted {
+            timestamp,
+            digest: widen_digest(digest, suffix)?,
+        })),
+        Err(Missing::Failed { .. }) => None,
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, bincode::Decode, bincode::Encode)]
+struct ValidatorsValue {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, bincode::Decode, bincode::Encode)]
+struct TransformationValue {
+    original_digest: [u8; 16],
+    original_size: u64,
+    steps: Vec<String>,
+}
+
 #[derive(Clone)]
 pub struct Database<C = DefaultConfig> {
     db: Arc<DB>,
     config: C,
+    normalizer: Normalizer,
+    key_scheme: KeyScheme,
 }
 
 impl Database<DefaultConfig> {
+    /// Open a database, creating it (with the default key scheme) if it doesn't exist.
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::open_internal(path, None)
+    }
+
+    /// Open a database, creating it with the given key scheme if it doesn't exist.
+    ///
+    /// Opening an existing database fails if it uses a different key scheme (unless it is empty).
+    pub fn open_with_key_scheme<P: AsRef<Path>>(
+        path: P,
+        key_scheme: KeyScheme,
+    ) -> Result<Self, Error> {
+        Self::open_internal(path, Some(key_scheme))
+    }
+
+    fn open_internal<P: AsRef<Path>>(
+        path: P,
+        key_scheme: Option<KeyScheme>,
+    ) -> Result<Self, Error> {
         let mut options = Options::default();
         options.create_if_missing(true);
+        options.create_missing_column_families(true);
         options.set_compression_type(rocksdb::DBCompressionType::Zstd);
 
-        let db = DB::open(&options, path)?;
+        // Listing fails if the database doesn't exist yet.
+        let existing_cfs = DB::list_cf(&options, &path).unwrap_or_default();
+
+        let db = DB::open_cf(
+            &options,
+            path,
+            [
+                rocksdb::DEFAULT_COLUMN_FAMILY_NAME,
+                QUEUE_CF,
+                RECENT_CF,
+                FILENAME_CF,
+                FIRST_SEEN_CF,
+                VALIDATORS_CF,
+                TRANSFORMATIONS_CF,
+                METADATA_CF,
+            ],
+        )?;
         let config = bincode::config::standard();
 
-        Ok(Self {
+        let mut database = Self {
             db: Arc::new(db),
             config: config.with_big_endian().with_fixed_int_encoding(),
-        })
+            normalizer: Normalizer::default(),
+            key_scheme: KeyScheme::Url,
+        };
+
+        database.key_scheme = database.init_key_scheme(key_scheme)?;
+
+        // Databases created before the recent entries index was added need it to be built.
+        if !existing_cfs.is_empty() && !existing_cfs.iter().any(|name| name == RECENT_CF) {
+            database.build_recent()?;
+        }
+
+        if !existing_cfs.is_empty() && !existing_cfs.iter().any(|name| name == FILENAME_CF) {
+            database.build_filenames()?;
+        }
+
+        if !existing_cfs.is_empty() && !existing_cfs.iter().any(|name| name == FIRST_SEEN_CF) {
+            database.build_first_seen()?;
+        }
+
+        Ok(database)
     }
 
-    pub fn lookup(&self, url: &str) -> Result<Vec<Result<Entry, DateTime<Utc>>>, Error> {
-        let mut entries = vec![];
+    /// Restore the most recent backup in a backup directory to a new database directory.
+    pub fn restore_latest<P: AsRef<Path>, Q: AsRef<Path>>(
+        backup_directory: P,
+        path: Q,
+    ) -> Result<(), Error> {
+        let mut engine = open_backup_engine(backup_directory)?;
 
-        for result in self.db.iterator(IteratorMode::From(
-            url.as_bytes(),
-            rocksdb::Direction::Forward,
-        )) {
-            let (key_bytes, value_bytes) = result?;
+        engine.restore_from_latest_backup(&path, &path, &RestoreOptions::default())?;
 
-            let key = Key::from_bytes(&key_bytes)?;
+        Ok(())
+    }
 
-            if key.url != url {
-                break;
+    /// Set the normalizer that is applied to URLs before they are recorded or looked up.
+    #[must_use]
+    pub fn with_normalizer(self, normalizer: Normalizer) -> Self {
+        Self { normalizer, ..self }
+    }
+
+    #[must_use]
+    pub const fn normalizer(&self) -> &Normalizer {
+        &self.normalizer
+    }
+
+    #[must_use]
+    pub const fn key_scheme(&self) -> KeyScheme {
+        self.key_scheme
+    }
+
+    /// Determine the key scheme from the metadata, recording the requested one for new databases.
+    ///
+    /// Databases without a recorded key scheme (including ones created before key schemes were
+    /// added) use URL keys.
+    fn init_key_scheme(&self, requested: Option<KeyScheme>) -> Result<KeyScheme, Error> {
+        let metadata = self.metadata_cf()?;
+        let recorded = self
+            .db
+            .get_pinned_cf(metadata, KEY_SCHEME_KEY)?
+            .map(|bytes| match bytes.as_ref() {
+                [byte] => KeyScheme::from_byte(*byte),
+                _ => Err(Error::InvalidValueBytes(bytes.to_vec())),
+            })
+            .transpose()?;
+
+        let is_empty = || self.db.iterator(IteratorMode::Start).next().is_none();
+
+        match (recorded, requested) {
+            (Some(recorded), Some(requested)) if recorded != requested && !is_empty() => {
+                Err(Error::KeySchemeMismatch {
+                    expected: requested,
+                    found: recorded,
+                })
             }
+            (None, Some(requested)) if requested != KeyScheme::Url && !is_empty() => {
+                Err(Error::KeySchemeMismatch {
+                    expected: requested,
+                    found: KeyScheme::Url,
+                })
+            }
+            (_, Some(requested)) => {
+                self.db
+                    .put_cf(metadata, KEY_SCHEME_KEY, [requested.to_byte()])?;
 
-            let (value, value_read) =
-                bincode::borrow_decode_from_slice::<Value, _>(&value_bytes, self.config)?;
+                Ok(requested)
+            }
+            (recorded, None) => Ok(recorded.unwrap_or_default()),
+        }
+    }
 
-            if value_read == value_bytes.len() {
-                match value.image_type.value() {
-                    Some(image_type) => {
-                        entries.push(Ok(Entry {
-                            timestamp: key.timestamp,
-                            digest: md5::Digest(value.digest),
-                            image_type,
-                        }));
-                    }
-                    None => {
-                        entries.push(Err(key.timestamp));
-                    }
-                }
+    /// Build the key for a URL's record at the given time.
+    fn key<'a>(&self, url: &'a str, timestamp: DateTime<Utc>) -> Key<'a> {
+        Key {
+            url: self.key_scheme.encode(url),
+            timestamp,
+        }
+    }
+
+    /// Decode a record, returning its URL.
+    fn decode_key_value(
+        &self,
+        key_bytes: &[u8],
+        value_bytes: &[u8],
+    ) -> Result<(String, Result<Entry, Missing>), Error> {
+        let key = Key::from_bytes(key_bytes)?;
+
+        Ok((
+            self.key_scheme.decode(&key.url).to_string(),
+            self.decode_record(key.timestamp, value_bytes)?,
+        ))
+    }
 
-                Ok(())
-            } else {
-                Err(Error::ExtraValueBytes(value_bytes.to_vec()))
-            }?;
+    fn queue(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(QUEUE_CF)
+            .ok_or(Error::MissingColumnFamily(QUEUE_CF))
+    }
+
+    fn recent_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(RECENT_CF)
+            .ok_or(Error::MissingColumnFamily(RECENT_CF))
+    }
+
+    fn filename_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(FILENAME_CF)
+            .ok_or(Error::MissingColumnFamily(FILENAME_CF))
+    }
+
+    fn first_seen_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(FIRST_SEEN_CF)
+            .ok_or(Error::MissingColumnFamily(FIRST_SEEN_CF))
+    }
+
+    fn validators_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(VALIDATORS_CF)
+            .ok_or(Error::MissingColumnFamily(VALIDATORS_CF))
+    }
+
+    fn transformations_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(TRANSFORMATIONS_CF)
+            .ok_or(Error::MissingColumnFamily(TRANSFORMATIONS_CF))
+    }
+
+    fn metadata_cf(&self) -> Result<&ColumnFamily, Error> {
+        self.db
+            .cf_handle(METADATA_CF)
+            .ok_or(Error::MissingColumnFamily(METADATA_CF))
+    }
+
+    /// Add the file name for every image in the index to the file name index.
+    ///
+    /// This requires a full scan of the index.
+    fn build_filenames(&self) -> Result<(), Error> {
+        let filenames = self.filename_cf()?;
+        let mut batch = WriteBatch::default();
+        let mut first_entries = std::collections::HashMap::new();
+
+        for result in self.iter() {
+            if let (url, Ok(entry)) = result?
+                && url_filename(&url).is_some()
+            {
+                first_entries
+                    .entry(entry.digest)
+                    .and_modify(|first: &mut (DateTime<Utc>, String)| {
+                        if entry.timestamp < first.0 {
+                            *first = (entry.timestamp, url.clone());
+                        }
+                    })
+                    .or_insert((entry.timestamp, url));
+            }
         }
 
-        entries.sort_by_key(|result| {
-            std::cmp::Reverse(match result {
-                Ok(entry) => entry.timestamp,
-                Err(timestamp) => *timestamp,
+        for (digest, (_, url)) in first_entries {
+            if let Some(filename) = url_filename(&url) {
+                batch.put_cf(filenames, digest.as_bytes(), filename.as_bytes());
+            }
+        }
+
+        Ok(self.db.write(batch)?)
+    }
+
+    /// Add the earliest entry for every image in the index to the first seen index.
+    ///
+    /// This requires a full scan of the index.
+    fn build_first_seen(&self) -> Result<(), Error> {
+        let first_seen = self.first_seen_cf()?;
+        let mut batch = WriteBatch::default();
+        let mut first_entries: HashMap<Digest, Entry> = HashMap::new();
+
+        for result in self.iter() {
+            if let (_, Ok(entry)) = result? {
+                first_entries
+                    .entry(entry.digest)
+                    .and_modify(|first| {
+                        if entry.timestamp < first.timestamp {
+                            *first = entry;
+                        }
+                    })
+                    .or_insert(entry);
+            }
+        }
+
+        for (digest, entry) in first_entries {
+            batch.put_cf(
+                first_seen,
+                digest.as_bytes(),
+                self.encode_first_seen(entry.timestamp, entry.image_type.into())?,
+            );
+        }
+
+        Ok(self.db.write(batch)?)
+    }
+
+    /// Return the earliest entry for an image (which may have been added for any URL).
+    ///
+    /// This is a single lookup, unlike finding the entry by scanning the index.
+    pub fn first_entry(&self, digest: Digest) -> Result<Option<Entry>, Error> {
+        self.db
+            .get_pinned_cf(self.first_seen_cf()?, digest.as_bytes())?
+            .map(|bytes| self.decode_first_seen(digest, &bytes))
+            .transpose()
+    }
+
+    fn encode_first_seen(
+        &self,
+        timestamp: DateTime<Utc>,
+        image_type: ImageType,
+    ) -> Result<Vec<u8>, Error> {
+        // This should always fit, but just in case.
+        let timestamp_s = u32::try_from(timestamp.timestamp()).unwrap_or(u32::MAX);
+
+        let mut bytes = timestamp_s.to_be_bytes().to_vec();
+        bytes.extend(bincode::encode_to_vec(image_type, self.config)?);
+
+        Ok(bytes)
+    }
+
+    fn decode_first_seen(&self, digest: Digest, bytes: &[u8]) -> Result<Entry, Error> {
+        let invalid = || Error::InvalidValueBytes(bytes.to_vec());
+        let (timestamp_bytes, image_type_bytes) =
+            bytes.split_first_chunk::<4>().ok_or_else(invalid)?;
+
+        let timestamp = DateTime::from_timestamp(u32::from_be_bytes(*timestamp_bytes).into(), 0)
+            .ok_or_else(invalid)?;
+        let (image_type, read) =
+            bincode::decode_from_slice::<ImageType, _>(image_type_bytes, self.config)?;
+
+        if read == image_type_bytes.len() {
+            Ok(Entry {
+                timestamp,
+                digest,
+                image_type: image_type.value().ok_or_else(invalid)?,
             })
-        });
+        } else {
+            Err(Error::ExtraValueBytes(image_type_bytes[read..].to_vec()))
+        }
+    }
 
-        Ok(entries)
+    /// Return the file name for an image, taken from the first URL it was added for.
+    ///
+    /// Images added for URLs without a file name (e.g. `https://example.com/`) don't have one.
+    pub fn filename(&self, digest: Digest) -> Result<Option<String>, Error> {
+        self.db
+            .get_pinned_cf(self.filename_cf()?, digest.as_bytes())?
+            .map(|bytes| {
+                std::str::from_utf8(&bytes)
+                    .map(str::to_string)
+                    .map_err(|_| Error::InvalidValueBytes(bytes.to_vec()))
+            })
+            .transpose()
     }
 
-    pub fn add(&self, url: &str, entry: Entry) -> Result<(), Error> {
-        let key = Key {
-            url: url.into(),
-            timestamp: entry.timestamp,
-        };
+    /// Return the cache validators recorded for a URL, if any.
+    pub fn validators(&self, url: &str) -> Result<Option<Validators>, Error> {
+        let url = self.normalizer.normalize_or_keep(url);
 
-        let value = Value {
-            digest: entry.digest.0,
-            image_type: entry.image_type.into(),
-        };
+        self.db
+            .get_pinned_cf(self.validators_cf()?, url.as_bytes())?
+            .map(|bytes| {
+                let (value, read) =
+                    bincode::decode_from_slice::<ValidatorsValue, _>(&bytes, self.config)?;
 
-        let key_bytes = key.to_bytes();
-        let value_bytes = bincode::encode_to_vec(value, self.config)?;
+                if read == bytes.len() {
+                    Ok(Validators {
+                        etag: value.etag,
+                        last_modified: value.last_modified,
+                    })
+                } else {
+                    Err(Error::ExtraValueBytes(bytes[read..].to_vec()))
+                }
+            })
+            .transpose()
+    }
+
+    /// Record the cache validators from the last response for a URL.
+    ///
+    /// Empty validators remove any that were previously recorded.
+    pub fn set_validators(&self, url: &str, validators: &Validators) -> Result<(), Error> {
+        let url = self.normalizer.normalize_or_keep(url);
+        let validators_cf = self.validators_cf()?;
 
-        Ok(self.db.put(&key_bytes, &value_bytes)?)
+        if validators.is_empty() {
+            Ok(self.db.delete_cf(validators_cf, url.as_bytes())?)
+        } else {
+            let value = ValidatorsValue {
+                etag: validators.etag.clone(),
+                last_modified: validators.last_modified.clone(),
+            };
+
+            Ok(self.db.put_cf(
+                validators_cf,
+                url.as_bytes(),
+                bincode::encode_to_vec(value, self.config)?,
+            )?)
+        }
     }
 
-    pub fn add_failed(&self, url: &str, timestamp: DateTime<Utc>) -> Result<(), Error> {
-        let key = Key {
-            url: url.into(),
-            timestamp,
-        };
+    /// Add every entry in the index to the recent entries index.
+    ///
+    /// Entries for images that were later deleted are not included. This requires a full scan of
+    /// the index.
+    fn build_recent(&self) -> Result<(), Error> {
+        let recent = self.recent_cf()?;
+        let mut batch = WriteBatch::default();
+        let mut current_url = None;
+        let mut current_entries = vec![];
 
-        let value = Value {
-            digest: ERROR_DIGEST,
-            image_type: ImageType::empty(),
-        };
+        // Index records for a URL are contiguous and sorted by ascending timestamp.
+        for result in self.iter() {
+            let (url, record) = result?;
 
-        let key_bytes = key.to_bytes();
-        let value_bytes = bincode::encode_to_vec(value, self.config)?;
+            if current_url.as_ref() != Some(&url) {
+                current_url = Some(url.clone());
+                current_entries.clear();
+            }
+
+            match record {
+                Ok(entry) => {
+                    batch.put_cf(
+                        recent,
+                        recent_key(entry.timestamp, &url),
+                        self.encode_value(entry.digest, entry.image_type.into())?,
+                    );
+
+                    current_entries.push(entry);
+                }
+                Err(Missing::Deleted { digest, .. }) => {
+                    for entry in &current_entries {
+                        if entry.digest == digest {
+                            batch.delete_cf(recent, recent_key(entry.timestamp, &url));
+                        }
+                    }
+                }
+                Err(Missing::Failed { .. }) => {}
+            }
+        }
 
-        Ok(self.db.put(&key_bytes, &value_bytes)?)
+        Ok(self.db.write(batch)?)
     }
 
-    pub fn iter(
+    fn decode_record(
         &self,
-    ) -> impl Iterator<Item = Result<(String, Result<Entry, DateTime<Utc>>), Error>> {
-        self.db.iterator(IteratorMode::Start).map(|result| {
-            let (key_bytes, value_bytes) = result?;
+        timestamp: DateTime<Utc>,
+        value_bytes: &[u8],
+    ) -> Result<Result<Entry, Missing>, Error> {
+        let (value, value_read) =
+            bincode::borrow_decode_from_slice::<Value, _>(value_bytes, self.config)?;
 
-            let key = Key::from_bytes(&key_bytes)?;
-            let (value, value_read) =
-                bincode::borrow_decode_from_slice::<Value, _>(&value_bytes, self.config)?;
-
-            if value_read == value_bytes.len() {
-                Ok((
-                    key.url.to_string(),
-                    match value.image_type.value() {
-                        Some(image_type) => Ok(Entry {
-                            timestamp: key.timestamp,
-                            digest: md5::Digest(value.digest),
-                            image_type,
-                        }),
-                        None => Err(key.timestamp),
-                    },
-                ))
-            } else {
-                Err(Error::ExtraValueBytes(value_bytes.to_vec()))
+        match (value.into_record(timestamp), &value_bytes[value_read..]) {
+            (record, []) => Ok(record),
+            (Err(Missing::Failed { timestamp, .. }), [first, second]) => Ok(Err(Missing::Failed {
+                timestamp,
+                status: Some(u16::from_be_bytes([*first, *second])),
+                kind: None,
+            })),
+            (Err(Missing::Failed { timestamp, .. }), [first, second, kind]) => {
+                let status = u16::from_be_bytes([*first, *second]);
+
+                Ok(Err(Missing::Failed {
+                    timestamp,
+                    status: (status != 0).then_some(status),
+                    kind: Some(decode_failure_kind(*kind)),
+                }))
             }
-        })
+            (record, suffix) => widen_record(record, suffix)
+                .ok_or_else(|| Error::ExtraValueBytes(value_bytes.to_vec())),
+        }
+    }
+
+    /// Encode a value, followed by the rest of the digest if it's wider than an MD5 digest.
+    fn encode_value(&self, digest: Digest, image_type: ImageType) -> Result<Vec<u8>, Error> {
+        let (digest, suffix) = split_digest(digest);
+        let mut value_bytes = bincode::encode_to_vec(Value { digest, image_type }, self.config)?;
+        value_bytes.extend_from_slice(&suffix);
+
+        Ok(value_bytes)
+    }
+
+    /// Return all records for a URL, most recent first.
+    ///
+    /// Records added under the URL's original spelling (before normalization) are also included.
+    pub fn lookup(&self, url: &str) -> Result<Vec<Result<Entry, Missing>>, Error> {
+        let normalized = self.normalizer.normalize_or_keep(url);
+        let mut entries = vec![];
+
+        self.lookup_exact(&normalized, &mut entries)?;
+
+        if normalized != url {
+            self.lookup_exact(url, &mut entries)?;
+        }
+
+        entries.sort_by_key(|result| {
+            std::cmp::Reverse(match result {
+                Ok(entry) => entry.timestamp,
+                Err(missing) => missing.timestamp(),
+            })
+        });
+
+        Ok(entries)
+    }
+
+    fn lookup_exact(
+        &self,
+        url: &str,
+        entries: &mut Vec<Result<Entry, Missing>>,
+    ) -> Result<(), Error> {
+        let key_url = self.key_scheme.encode(url);
+
+        for result in self.db.iterator(IteratorMode::From(
+            key_url.as_bytes(),
+            rocksdb::Direction::Forward,
+        )) {
+            let (key_bytes, value_bytes) = result?;
+
+            let key = Key::from_bytes(&key_bytes)?;
+
+            if key.url != key_url {
+                break;
+            }
+
+            entries.push(self.decode_record(key.timestamp, &value_bytes)?);
+        }
+
+        Ok(())
+    }
+
+    pub fn add(&self, url: &str, entry: Entry) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+
+        self.add_to_batch(&mut batch, url, entry, &mut BatchDigests::default())?;
+
+        Ok(self.db.write(batch)?)
+    }
+
+    /// Add a record for an image that was transformed before it was saved (the entry's digest is
+    /// the digest of the transformed image).
+    pub fn add_transformed(
+        &self,
+        url: &str,
+        entry: Entry,
+        transformation: &Transformation,
+    ) -> Result<(), Error> {
+        let mut batch = WriteBatch::default();
+        let timestamp = entry.timestamp;
+
+        self.add_to_batch(&mut batch, url, entry, &mut BatchDigests::default())?;
+
+        let (original_digest, suffix) = split_digest(transformation.original_digest);
+
+        let value = TransformationValue {
+            original_digest,
+            original_size: transformation.original_size,
+            steps: transformation.steps.clone(),
+        };
+
+        let mut value_bytes = bincode::encode_to_vec(value, self.config)?;
+        value_bytes.extend_from_slice(&suffix);
+
+        batch.put_cf(
+            self.transformations_cf()?,
+            recent_key(timestamp, &self.normalizer.normalize_or_keep(url)),
+            value_bytes,
+        );
+
+        Ok(self.db.write(batch)?)
+    }
+
+    /// Return how the image in an entry was transformed before it was saved (if it was).
+    pub fn transformation(
+        &self,
+        url: &str,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Option<Transformation>, Error> {
+        let url = self.normalizer.normalize_or_keep(url);
+
+        self.db
+            .get_pinned_cf(self.transformations_cf()?, recent_key(timestamp, &url))?
+            .map(|bytes| {
+                let (value, read) =
+                    bincode::decode_from_slice::<TransformationValue, _>(&bytes, self.config)?;
+
+                let original_digest = Digest::from_bytes(value.original_digest);
+                let original_digest = if read == bytes.len() {
+                    original_digest
+                } else {
+                    widen_digest(original_digest, &bytes[read..])
+                        .ok_or_else(|| Error::ExtraValueBytes(bytes[read..].to_vec()))?
+                };
+
+                Ok(Transformation {
+                    original_digest,
+                    original_size: value.original_size,
+                    steps: value.steps,
+                })
+            })
+            .transpose()
+    }
+
+    /// Add many records with a single write, returning the number of records added.
+    ///
+    /// This is much faster than calling [`Database::add`] for each record (e.g. when importing
+    /// logs).
+    pub fn add_all<'a, I: IntoIterator<Item = (&'a str, Entry)>>(
+        &self,
+        records: I,
+    ) -> Result<usize, Error> {
+        let mut batch = WriteBatch::default();
+        let mut added = BatchDigests::default();
+        let mut count = 0;
+
+        for (url, entry) in records {
+            self.add_to_batch(&mut batch, url, entry, &mut added)?;
+            count += 1;
+        }
+
+        self.db.write(batch)?;
+
+        Ok(count)
+    }
+
+    /// Add a record to a batch, removing the URL from the download queue.
+    ///
+    /// File names are only recorded for digests that don't have one yet (either in the database or
+    /// in the given digests that have already been added to the batch), and first entries are only
+    /// recorded if they are earlier than the one that is already known.
+    fn add_to_batch(
+        &self,
+        batch: &mut WriteBatch,
+        url: &str,
+        entry: Entry,
+        added: &mut BatchDigests,
+    ) -> Result<(), Error> {
+        let url = self.normalizer.normalize_or_keep(url);
+        let url = url.as_ref();
+        let key = self.key(url, entry.timestamp);
+
+        let key_bytes = key.to_bytes();
+        let value_bytes = self.encode_value(entry.digest, entry.image_type.into())?;
+
+        batch.put_cf(
+            self.recent_cf()?,
+            recent_key(entry.timestamp, url),
+            &value_bytes,
+        );
+
+        if let Some(filename) = url_filename(url) {
+            let filename_cf = self.filename_cf()?;
+
+            if !added.filenames.contains(&entry.digest)
+                && self
+                    .db
+                    .get_pinned_cf(filename_cf, entry.digest.as_bytes())?
+                    .is_none()
+            {
+                batch.put_cf(filename_cf, entry.digest.as_bytes(), filename.as_bytes());
+                added.filenames.insert(entry.digest);
+            }
+        }
+
+        let first_seen = match added.first_seen.get(&entry.digest) {
+            Some(timestamp) => Some(*timestamp),
+            None => self.first_entry(entry.digest)?.map(|first| first.timestamp),
+        };
+
+        if first_seen.is_none_or(|first_seen| entry.timestamp < first_seen) {
+            batch.put_cf(
+                self.first_seen_cf()?,
+                entry.digest.as_bytes(),
+                self.encode_first_seen(entry.timestamp, entry.image_type.into())?,
+            );
+            added.first_seen.insert(entry.digest, entry.timestamp);
+        }
+
+        batch.put(key_bytes, value_bytes);
+        batch.delete_cf(self.queue()?, url.as_bytes());
+
+        Ok(())
+    }
+
+    pub fn add_failed(
+        &self,
+        url: &str,
+        timestamp: DateTime<Utc>,
+        status: Option<u16>,
+        kind: Option<FailureKind>,
+    ) -> Result<(), Error> {
+        let url = self.normalizer.normalize_or_keep(url);
+        let url = url.as_ref();
+        let key = self.key(url, timestamp);
+
+        let value = Value {
+            digest: ERROR_DIGEST,
+            image_type: ImageType::empty(),
+        };
+
+        let key_bytes = key.to_bytes();
+        let mut value_bytes = bincode::encode_to_vec(value, self.config)?;
+
+        match (status, kind) {
+            (status, Some(kind)) => {
+                value_bytes.extend_from_slice(&status.unwrap_or(0).to_be_bytes());
+                value_bytes.push(encode_failure_kind(kind));
+            }
+            (Some(status), None) => {
+                value_bytes.extend_from_slice(&status.to_be_bytes());
+            }
+            (None, None) => {}
+        }
+
+        self.put_completing(WriteBatch::default(), url, &key_bytes, &value_bytes)
+    }
+
+    /// Write a record (together with any other changes in the batch), removing the URL from the
+    /// download queue.
+    fn put_completing(
+        &self,
+        mut batch: WriteBatch,
+        url: &str,
+        key_bytes: &[u8],
+        value_bytes: &[u8],
+    ) -> Result<(), Error> {
+        batch.put(key_bytes, value_bytes);
+        batch.delete_cf(self.queue()?, url.as_bytes());
+
+        Ok(self.db.write(batch)?)
+    }
+
+    /// Add a URL to the download queue, returning `false` if it was already queued.
+    ///
+    /// A URL remains in the queue until a record is added for it (with either [`Database::add`] or
+    /// [`Database::add_failed`]), so downloads that were interrupted can be resumed.
+    pub fn enqueue(&self, url: &str, timestamp: DateTime<Utc>) -> Result<bool, Error> {
+        let url = self.normalizer.normalize_or_keep(url);
+        let queue = self.queue()?;
+
+        if self.db.get_pinned_cf(queue, url.as_bytes())?.is_some() {
+            Ok(false)
+        } else {
+            // This should always fit, but just in case.
+            let timestamp_s = u32::try_from(timestamp.timestamp()).unwrap_or(u32::MAX);
+
+            self.db
+                .put_cf(queue, url.as_bytes(), timestamp_s.to_be_bytes())?;
+
+            Ok(true)
+        }
+    }
+
+    /// Remove a URL from the download queue without adding a record for it.
+    pub fn dequeue(&self, url: &str) -> Result<(), Error> {
+        let url = self.normalizer.normalize_or_keep(url);
+
+        Ok(self.db.delete_cf(self.queue()?, url.as_bytes())?)
+    }
+
+    /// List the URLs in the download queue, together with the time they were queued.
+    pub fn queued(&self) -> Result<Vec<(String, DateTime<Utc>)>, Error> {
+        self.db
+            .iterator_cf(self.queue()?, IteratorMode::Start)
+            .map(|result| {
+                let (key_bytes, value_bytes) = result?;
+
+                let url = std::str::from_utf8(&key_bytes)
+                    .map_err(|_| Error::InvalidKeyBytes(key_bytes.to_vec()))?;
+
+                let timestamp = <[u8; 4]>::try_from(value_bytes.as_ref())
+                    .ok()
+                    .and_then(|bytes| DateTime::from_timestamp(u32::from_be_bytes(bytes).into(), 0))
+                    .ok_or_else(|| Error::InvalidValueBytes(value_bytes.to_vec()))?;
+
+                Ok((url.to_string(), timestamp))
+            })
+            .collect()
+    }
+
+    /// List the most recently added entries, in descending timestamp order.
+    ///
+    /// Entries for images that have been deleted are not included.
+    pub fn recent(&self, limit: usize) -> Result<Vec<(String, Entry)>, Error> {
+        self.db
+            .iterator_cf(self.recent_cf()?, IteratorMode::End)
+            .take(limit)
+            .map(|result| {
+                let (key_bytes, value_bytes) = result?;
+                let (timestamp, url) = decode_recent_key(&key_bytes)?;
+
+                self.decode_record(timestamp, &value_bytes)?.map_or_else(
+                    |_| Err(Error::InvalidValueBytes(value_bytes.to_vec())),
+                    |entry| Ok((url.to_string(), entry)),
+                )
+            })
+            .collect()
+    }
+
+    /// Iterate over the entries added at or after `start` and before `end`, in ascending timestamp
+    /// order.
+    ///
+    /// Entries for images that have been deleted are not included.
+    pub fn entries_between(
+        &self,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<impl Iterator<Item = Result<(String, Entry), Error>> + '_, Error> {
+        let start_key = recent_key(start, "");
+        let end_key = recent_key(end, "");
+
+        Ok(self
+            .db
+            .iterator_cf(
+                self.recent_cf()?,
+                IteratorMode::From(&start_key, rocksdb::Direction::Forward),
+            )
+            .take_while(move |result| match result {
+                Ok((key_bytes, _)) => key_bytes.as_ref() < end_key.as_slice(),
+                // Errors are passed on to the caller.
+                Err(_) => true,
+            })
+            .map(|result| {
+                let (key_bytes, value_bytes) = result?;
+                let (timestamp, url) = decode_recent_key(&key_bytes)?;
+
+                self.decode_record(timestamp, &value_bytes)?.map_or_else(
+                    |_| Err(Error::InvalidValueBytes(value_bytes.to_vec())),
+                    |entry| Ok((url.to_string(), entry)),
+                )
+            }))
+    }
+
+    /// Return the time of the earliest entry for a URL (ignoring failures and deletions).
+    pub fn first_seen(&self, url: &str) -> Result<Option<DateTime<Utc>>, Error> {
+        Ok(self
+            .lookup(url)?
+            .into_iter()
+            .filter_map(Result::ok)
+            .map(|entry| entry.timestamp)
+            .min())
+    }
+
+    /// Find every URL that has an entry for the given digest.
+    ///
+    /// This requires a full scan of the index.
+    pub fn urls(&self, digest: Digest) -> Result<Vec<String>, Error> {
+        let mut urls = vec![];
+
+        for result in self.iter() {
+            let (url, result) = result?;
+
+            if result.is_ok_and(|entry| entry.digest == digest) && urls.last() != Some(&url) {
+                urls.push(url);
+            }
+        }
+
+        Ok(urls)
+    }
+
+    /// Record that the image with the given digest has been deleted.
+    ///
+    /// A tombstone is added for every URL that currently resolves to this digest, so that these
+    /// URLs will not resolve to the image (or be downloaded again). URLs whose latest entry is for
+    /// another image are left alone, although their older entries for this digest are removed from
+    /// the recent downloads. This requires a full scan of the index. The returned URLs are the ones
+    /// that were tombstoned.
+    pub fn tombstone(
+        &self,
+        digest: Digest,
+        timestamp: DateTime<Utc>,
+    ) -> Result<Vec<String>, Error> {
+        let recent = self.recent_cf()?;
+        let mut tombstoned = vec![];
+
+        for url in self.urls(digest)? {
+            let records = self.lookup(&url)?;
+            let mut batch = WriteBatch::default();
+
+            // Failed downloads don't change which image the URL resolves to.
+            let current = records.iter().find_map(|record| match record {
+                Ok(entry) => Some(Some(entry.digest)),
+                Err(Missing::Deleted { .. }) => Some(None),
+                Err(Missing::Failed { .. }) => None,
+            });
+            let resolves = current == Some(Some(digest));
+
+            if resolves {
+                let key = self.key(&url, timestamp);
+
+                batch.put(
+                    key.to_bytes(),
+                    self.encode_value(digest, ImageType::empty())?,
+                );
+            }
+
+            for record in records {
+                if let Ok(entry) = record
+                    && entry.digest == digest
+                {
+                    batch.delete_cf(recent, recent_key(entry.timestamp, &url));
+                }
+            }
+
+            self.db.write(batch)?;
+
+            if resolves {
+                tombstoned.push(url);
+            }
+        }
+
+        Ok(tombstoned)
+    }
+
+    /// Remove all but the `keep_latest` most recent records for each URL that matches the filter.
+    ///
+    /// If `unchanged_only` is set, older records are only removed if they are entries for the same
+    /// image as the most recent entry that is kept (so that no information about changes is lost).
+    /// This requires a full scan of the index. Returns the number of records removed.
+    pub fn prune_history<F: FnMut(&str) -> bool>(
+        &self,
+        mut url_filter: F,
+        keep_latest: NonZeroUsize,
+        unchanged_only: bool,
+    ) -> Result<usize, Error> {
+        let recent = self.recent_cf()?;
+        let transformations = self.transformations_cf()?;
+        let mut batch = WriteBatch::default();
+        let mut removed = 0;
+        let mut current_url: Option<String> = None;
+        let mut current_matches = false;
+        let mut records = vec![];
+
+        // Index records for a URL are contiguous and sorted by ascending timestamp.
+        for result in self.db.iterator(IteratorMode::Start) {
+            let (key_bytes, value_bytes) = result?;
+            let key = Key::from_bytes(&key_bytes)?;
+            let key_url = self.key_scheme.decode(&key.url);
+
+            if current_url.as_deref() != Some(key_url) {
+                if let Some(url) = current_url.replace(key_url.to_string()) {
+                    removed += Self::prune_records(
+                        &mut batch,
+                        recent,
+                        transformations,
+                        &url,
+                        &records,
+                        keep_latest,
+                        unchanged_only,
+                    );
+                }
+
+                records.clear();
+                current_matches = url_filter(key_url);
+
+                if batch.len() >= WRITE_BATCH_SIZE {
+                    self.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+
+            if current_matches {
+                records.push((key_bytes, self.decode_record(key.timestamp, &value_bytes)?));
+            }
+        }
+
+        if let Some(url) = current_url {
+            removed += Self::prune_records(
+                &mut batch,
+                recent,
+                transformations,
+                &url,
+                &records,
+                keep_latest,
+                unchanged_only,
+            );
+        }
+
+        self.db.write(batch)?;
+
+        Ok(removed)
+    }
+
+    /// Add deletions for a URL's older records (sorted by ascending timestamp) to the batch.
+    fn prune_records<K: AsRef<[u8]>>(
+        batch: &mut WriteBatch,
+        recent: &ColumnFamily,
+        transformations: &ColumnFamily,
+        url: &str,
+        records: &[(K, Result<Entry, Missing>)],
+        keep_latest: NonZeroUsize,
+        unchanged_only: bool,
+    ) -> usize {
+        let (older, kept) = records.split_at(records.len().saturating_sub(keep_latest.get()));
+
+        let latest_digest = kept
+            .iter()
+            .rev()
+            .find_map(|(_, record)| record.as_ref().ok().map(|entry| entry.digest));
+
+        let mut removed = 0;
+
+        for (key_bytes, record) in older {
+            let unchanged = matches!(record, Ok(entry) if Some(entry.digest) == latest_digest);
+
+            if !unchanged_only || unchanged {
+                batch.delete(key_bytes);
+
+                if let Ok(entry) = record {
+                    let entry_key = recent_key(entry.timestamp, url);
+
+                    batch.delete_cf(recent, &entry_key);
+                    batch.delete_cf(transformations, entry_key);
+                }
+
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    /// Remove records added before the given time, optionally only removing failures.
+    ///
+    /// Deletions are written in batches, and the progress function is called with the total
+    /// number of records removed so far after each batch. This requires a full scan of the index.
+    /// Returns the number of records removed.
+    pub fn prune_before<F: FnMut(usize)>(
+        &self,
+        before: DateTime<Utc>,
+        failed_only: bool,
+        mut on_progress: F,
+    ) -> Result<usize, Error> {
+        let recent = self.recent_cf()?;
+        let transformations = self.transformations_cf()?;
+        let mut batch = WriteBatch::default();
+        let mut removed = 0;
+
+        for result in self.db.iterator(IteratorMode::Start) {
+            let (key_bytes, value_bytes) = result?;
+            let key = Key::from_bytes(&key_bytes)?;
+
+            if key.timestamp < before {
+                match self.decode_record(key.timestamp, &value_bytes)? {
+                    Ok(entry) if !failed_only => {
+                        let entry_key =
+                            recent_key(entry.timestamp, self.key_scheme.decode(&key.url));
+
+                        batch.delete(&key_bytes);
+                        batch.delete_cf(recent, &entry_key);
+                        batch.delete_cf(transformations, entry_key);
+                    }
+                    Err(Missing::Deleted { .. }) if !failed_only => {
+                        batch.delete(&key_bytes);
+                    }
+                    Err(Missing::Failed { .. }) => {
+                        batch.delete(&key_bytes);
+                    }
+                    _ => continue,
+                }
+
+                removed += 1;
+
+                if removed % WRITE_BATCH_SIZE == 0 {
+                    self.db.write(std::mem::take(&mut batch))?;
+                    on_progress(removed);
+                }
+            }
+        }
+
+        if !batch.is_empty() {
+            self.db.write(batch)?;
+            on_progress(removed);
+        }
+
+        Ok(removed)
+    }
+
+    /// Iterate over the records for URLs that start with the given prefix, in key order.
+    ///
+    /// With [`KeyScheme::ReversedHost`], only prefixes that include the URL's entire host (e.g.
+    /// `https://example.com/`) avoid a full scan of the index.
+    pub fn iter_prefix<'a>(
+        &'a self,
+        prefix: &'a str,
+    ) -> impl Iterator<Item = Result<(String, Result<Entry, Missing>), Error>> + 'a {
+        let key_prefix = match self.key_scheme {
+            KeyScheme::Url => prefix.to_string(),
+            KeyScheme::ReversedHost if has_complete_host(prefix) => {
+                self.key_scheme.encode(prefix).into_owned()
+            }
+            KeyScheme::ReversedHost => String::new(),
+        };
+
+        self.db
+            .iterator(IteratorMode::From(
+                key_prefix.as_bytes(),
+                rocksdb::Direction::Forward,
+            ))
+            .take_while(move |result| match result {
+                Ok((key_bytes, _)) => key_bytes.starts_with(key_prefix.as_bytes()),
+                // Errors are passed on to the caller.
+                Err(_) => true,
+            })
+            .map(|result| {
+                let (key_bytes, value_bytes) = result?;
+
+                self.decode_key_value(&key_bytes, &value_bytes)
+            })
+            .filter(move |result| match result {
+                Ok((url, _)) => url.starts_with(prefix),
+                Err(_) => true,
+            })
+    }
+
+    /// Iterate over the raw records for URLs whose host is the domain or one of its subdomains.
+    fn iter_domain_keys<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> impl Iterator<Item = Result<RawRecord, Error>> + 'a {
+        let domain = domain.to_ascii_lowercase();
+        let reversed = reverse_host(&domain);
+
+        // Records for subdomains come first (since `.` is ordered before `/`), followed by records
+        // for the domain itself.
+        let start = match self.key_scheme {
+            KeyScheme::Url => String::new(),
+            KeyScheme::ReversedHost => format!("{reversed}."),
+        };
+
+        self.db
+            .iterator(IteratorMode::From(
+                start.as_bytes(),
+                rocksdb::Direction::Forward,
+            ))
+            .take_while(move |result| match (self.key_scheme, result) {
+                (KeyScheme::ReversedHost, Ok((key_bytes, _))) => key_bytes
+                    .strip_prefix(reversed.as_bytes())
+                    .is_some_and(|rest| matches!(rest.first(), Some(b'.' | b'/'))),
+                _ => true,
+            })
+            .filter_map(move |result| match result {
+                Ok((key_bytes, value_bytes)) => match Key::from_bytes(&key_bytes) {
+                    Ok(key) => url_host(self.key_scheme.decode(&key.url))
+                        .is_some_and(|host| in_domain(host, &domain))
+                        .then_some(Ok((key_bytes, value_bytes))),
+                    Err(error) => Some(Err(error)),
+                },
+                Err(error) => Some(Err(Error::from(error))),
+            })
+    }
+
+    /// Iterate over the records for URLs whose host is the domain or one of its subdomains, in key
+    /// order.
+    ///
+    /// With [`KeyScheme::ReversedHost`] only the domain's records are read, and otherwise this
+    /// requires a full scan of the index.
+    pub fn iter_domain<'a>(
+        &'a self,
+        domain: &'a str,
+    ) -> impl Iterator<Item = Result<(String, Result<Entry, Missing>), Error>> + 'a {
+        self.iter_domain_keys(domain).map(|result| {
+            let (key_bytes, value_bytes) = result?;
+
+            self.decode_key_value(&key_bytes, &value_bytes)
+        })
+    }
+
+    /// Remove every record for URLs whose host is the domain or one of its subdomains (together
+    /// with their cache validators), returning the number of records removed.
+    ///
+    /// With [`KeyScheme::ReversedHost`] only the domain's records are read, and otherwise this
+    /// requires a full scan of the index.
+    pub fn remove_domain(&self, domain: &str) -> Result<usize, Error> {
+        let recent = self.recent_cf()?;
+        let transformations = self.transformations_cf()?;
+        let validators = self.validators_cf()?;
+        let mut batch = WriteBatch::default();
+        let mut removed = 0;
+
+        for result in self.iter_domain_keys(domain) {
+            let (key_bytes, value_bytes) = result?;
+            let (url, record) = self.decode_key_value(&key_bytes, &value_bytes)?;
+
+            batch.delete(&key_bytes);
+            batch.delete_cf(validators, url.as_bytes());
+
+            if let Ok(entry) = record {
+                let entry_key = recent_key(entry.timestamp, &url);
+
+                batch.delete_cf(recent, &entry_key);
+                batch.delete_cf(transformations, entry_key);
+            }
+
+            removed += 1;
+
+            if batch.len() >= WRITE_BATCH_SIZE {
+                self.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        self.db.write(batch)?;
+
+        Ok(removed)
+    }
+
+    /// Copy every record (together with the download queue and the other indexes) into another
+    /// database, which may use a different key scheme.
+    ///
+    /// The other database should be empty. Returns the number of records copied.
+    pub fn copy_into(&self, other: &Self) -> Result<usize, Error> {
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+
+        for result in self.db.iterator(IteratorMode::Start) {
+            let (key_bytes, value_bytes) = result?;
+            let key = Key::from_bytes(&key_bytes)?;
+
+            batch.put(
+                other
+                    .key(self.key_scheme.decode(&key.url), key.timestamp)
+                    .to_bytes(),
+                value_bytes,
+            );
+
+            count += 1;
+
+            if batch.len() >= WRITE_BATCH_SIZE {
+                other.db.write(std::mem::take(&mut batch))?;
+            }
+        }
+
+        // The other column families aren't keyed by index keys, so they can be copied directly.
+        for (source, target) in [
+            (self.queue()?, other.queue()?),
+            (self.recent_cf()?, other.recent_cf()?),
+            (self.filename_cf()?, other.filename_cf()?),
+            (self.first_seen_cf()?, other.first_seen_cf()?),
+            (self.validators_cf()?, other.validators_cf()?),
+            (self.transformations_cf()?, other.transformations_cf()?),
+        ] {
+            for result in self.db.iterator_cf(source, IteratorMode::Start) {
+                let (key_bytes, value_bytes) = result?;
+
+                batch.put_cf(target, key_bytes, value_bytes);
+
+                if batch.len() >= WRITE_BATCH_SIZE {
+                    other.db.write(std::mem::take(&mut batch))?;
+                }
+            }
+        }
+
+        other.db.write(batch)?;
+
+        Ok(count)
+    }
+
+    /// Estimate the number of records in the index without reading them.
+    ///
+    /// The estimate comes from `RocksDB`'s metadata, and may be inaccurate for recently modified
+    /// databases (it includes overwritten and deleted keys that have not yet been compacted).
+    pub fn estimate_count(&self) -> Result<u64, Error> {
+        Ok(self
+            .db
+            .property_int_value("rocksdb.estimate-num-keys")?
+            .unwrap_or_default())
+    }
+
+    /// Write a backup of the database to a backup directory, and delete all but the most recent
+    /// `keep` backups there.
+    ///
+    /// Backups are incremental (files that haven't changed since an earlier backup are shared), and
+    /// can be made while the database is in use. The directory must only be used for backups of
+    /// this database.
+    pub fn backup<P: AsRef<Path>>(
+        &self,
+        directory: P,
+        keep: NonZeroUsize,
+    ) -> Result<BackupInfo, Error> {
+        let mut engine = open_backup_engine(directory)?;
+
+        engine.create_new_backup_flush(&self.db, true)?;
+        engine.purge_old_backups(keep.get())?;
+
+        // The new backup has the largest ID.
+        engine
+            .get_backup_info()
+            .into_iter()
+            .max_by_key(|info| info.backup_id)
+            .ok_or(Error::MissingBackup)?
+            .try_into()
+    }
+
+    /// List the backups in a backup directory, ordered from oldest to newest.
+    pub fn backups<P: AsRef<Path>>(directory: P) -> Result<Vec<BackupInfo>, Error> {
+        let engine = open_backup_engine(directory)?;
+        let mut backups = engine
+            .get_backup_info()
+            .into_iter()
+            .map(BackupInfo::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        backups.sort_by_key(|info| info.id);
+
+        Ok(backups)
+    }
+
+    /// Count the records that match the filter.
+    ///
+    /// This requires a full scan of the index, but no records are collected.
+    pub fn count<F: FnMut(&str, &Result<Entry, Missing>) -> bool>(
+        &self,
+        mut filter: F,
+    ) -> Result<usize, Error> {
+        let mut count = 0;
+
+        for result in self.db.iterator(IteratorMode::Start) {
+            let (key_bytes, value_bytes) = result?;
+            let key = Key::from_bytes(&key_bytes)?;
+
+            if filter(
+                self.key_scheme.decode(&key.url),
+                &self.decode_record(key.timestamp, &value_bytes)?,
+            ) {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Return at most `limit` records, starting at the cursor (or the beginning of the index).
+    pub fn iter_from(&self, cursor: Option<&Cursor>, limit: usize) -> Result<Page, Error> {
+        let mode = cursor.map_or(IteratorMode::Start, |cursor| {
+            IteratorMode::From(&cursor.0, rocksdb::Direction::Forward)
+        });
+
+        let mut records = Vec::with_capacity(limit);
+
+        for result in self.db.iterator(mode) {
+            let (key_bytes, value_bytes) = result?;
+
+            if records.len() == limit {
+                return Ok(Page {
+                    records,
+                    next: Some(Cursor(key_bytes.to_vec())),
+                });
+            }
+
+            records.push(self.decode_key_value(&key_bytes, &value_bytes)?);
+        }
+
+        Ok(Page {
+            records,
+            next: None,
+        })
+    }
+
+    /// Iterate over every record, in key order.
+    pub fn iter(&self) -> impl Iterator<Item = Result<(String, Result<Entry, Missing>), Error>> {
+        self.db.iterator(IteratorMode::Start).map(|result| {
+            let (key_bytes, value_bytes) = result?;
+
+            self.decode_key_value(&key_bytes, &value_bytes)
+        })
+    }
+}
+
+fn open_backup_engine<P: AsRef<Path>>(directory: P) -> Result<BackupEngine, Error> {
+    let options = BackupEngineOptions::new(directory)?;
+
+    Ok(BackupEngine::open(&options, &Env::new()?)?)
+}
+
+impl DownloadHistory for Database {
+    fn last_download(
+        &self,
+        url: &str,
+    ) -> Result<Option<LastDownload>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .lookup(url)?
+            .into_iter()
+            .next()
+            .and_then(|record| match record {
+                Ok(entry) => Some(LastDownload::Downloaded {
+                    digest: entry.digest,
+                    image_type: ImageType::new(Some(entry.image_type)),
+                    timestamp: entry.timestamp.into(),
+                }),
+                Err(Missing::Failed {
+                    timestamp, status, ..
+                }) => Some(LastDownload::Failed {
+                    timestamp: timestamp.into(),
+                    status,
+                }),
+                // Deleted images aren't treated as having been downloaded.
+                Err(Missing::Deleted { .. }) => None,
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cursor, Database, KeyScheme};
+    use crate::{Entry, Missing};
+    use chrono::{DateTime, Utc};
+    use image_scraper::digest::{Digest, DigestKind};
+    use image_scraper::history::FailureKind;
+    use image_scraper::transform::Transformation;
+
+    fn timestamp(s: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(s, 0).unwrap()
+    }
+
+    #[test]
+    fn test_lookup_records() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let url = "https://example.com/a.png";
+        let entry = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        db.add(url, entry)?;
+        db.add_failed(
+            url,
+            timestamp(1_700_000_100),
+            Some(404),
+            Some(FailureKind::Status),
+        )?;
+        db.add_failed(url, timestamp(1_700_000_200), None, Some(FailureKind::Dns))?;
+        db.add("https://example.com/a.png2", entry)?;
+
+        let records = db.lookup(url)?;
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(
+            records[0],
+            Err(Missing::Failed {
+                timestamp: timestamp(1_700_000_200),
+                status: None,
+                kind: Some(FailureKind::Dns)
+            })
+        );
+        assert_eq!(
+            records[1],
+            Err(Missing::Failed {
+                timestamp: timestamp(1_700_000_100),
+                status: Some(404),
+                kind: Some(FailureKind::Status)
+            })
+        );
+        assert_eq!(records[2], Ok(entry));
+
+        let tombstoned = db.tombstone(entry.digest, timestamp(1_700_000_300))?;
+
+        assert_eq!(
+            tombstoned,
+            vec![url.to_string(), "https://example.com/a.png2".to_string()]
+        );
+        assert_eq!(
+            db.lookup(url)?[0],
+            Err(Missing::Deleted {
+                timestamp: timestamp(1_700_000_300),
+                digest: entry.digest
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tombstone_changed_image() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+        let changed = "https://example.com/changed.png";
+        let unchanged = "https://example.com/unchanged.png";
+
+        let entry_a = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        let entry_b = Entry {
+            timestamp: timestamp(1_700_000_100),
+            digest: Digest::compute(b"b"),
+            image_type: imghdr::Type::Png,
+        };
+
+        db.add(changed, entry_a)?;
+        db.add(changed, entry_b)?;
+        db.add(unchanged, entry_a)?;
+
+        let tombstoned = db.tombstone(entry_a.digest, timestamp(1_700_000_200))?;
+
+        assert_eq!(tombstoned, vec![unchanged.to_string()]);
+        assert_eq!(db.lookup(changed)?, vec![Ok(entry_b), Ok(entry_a)]);
+        assert_eq!(
+            db.lookup(unchanged)?[0],
+            Err(Missing::Deleted {
+                timestamp: timestamp(1_700_000_200),
+                digest: entry_a.digest
+            })
+        );
+        assert_eq!(db.recent(10)?, vec![(changed.to_string(), entry_b)]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recent() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let entry_a = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        let entry_b = Entry {
+            timestamp: timestamp(1_700_000_200),
+            digest: Digest::compute(b"b"),
+            image_type: imghdr::Type::Gif,
+        };
+
+        let entry_c = Entry {
+            timestamp: timestamp(1_700_000_100),
+            digest: Digest::compute(b"c"),
+            image_type: imghdr::Type::Jpeg,
+        };
+
+        db.add("https://example.com/a.png", entry_a)?;
+        db.add("https://example.com/b.gif", entry_b)?;
+        db.add("https://example.com/c.jpg", entry_c)?;
+        db.add_failed(
+            "https://example.com/d.png",
+            timestamp(1_700_000_300),
+            None,
+            None,
+        )?;
+
+        assert_eq!(
+            db.recent(2)?,
+            vec![
+                ("https://example.com/b.gif".to_string(), entry_b),
+                ("https://example.com/c.jpg".to_string(), entry_c)
+            ]
+        );
+
+        db.tombstone(entry_b.digest, timestamp(1_700_000_400))?;
+
+        assert_eq!(
+            db.recent(10)?,
+            vec![
+                ("https://example.com/c.jpg".to_string(), entry_c),
+                ("https://example.com/a.png".to_string(), entry_a)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_entries_between() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let entry = |s, data: &[u8]| Entry {
+            timestamp: timestamp(s),
+            digest: Digest::compute(data),
+            image_type: imghdr::Type::Png,
+        };
+
+        let entry_a = entry(1_700_000_000, b"a");
+        let entry_b = entry(1_700_000_100, b"b");
+        let entry_c = entry(1_700_000_200, b"c");
+        let entry_a_2 = entry(1_700_000_300, b"a2");
+
+        db.add("https://example.com/a.png", entry_a)?;
+        db.add("https://example.com/b.png", entry_b)?;
+        db.add("https://example.com/c.png", entry_c)?;
+        db.add("https://example.com/a.png", entry_a_2)?;
+
+        assert_eq!(
+            db.entries_between(timestamp(1_700_000_100), timestamp(1_700_000_300))?
+                .collect::<Result<Vec<_>, _>>()?,
+            vec![
+                ("https://example.com/b.png".to_string(), entry_b),
+                ("https://example.com/c.png".to_string(), entry_c)
+            ]
+        );
+        assert_eq!(
+            db.entries_between(timestamp(1_700_000_301), timestamp(1_800_000_000))?
+                .count(),
+            0
+        );
+
+        assert_eq!(
+            db.first_seen("https://example.com/a.png")?,
+            Some(entry_a.timestamp)
+        );
+        assert_eq!(db.first_seen("https://example.com/d.png")?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_prefix() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let entry = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        db.add("https://example.com/a.png", entry)?;
+        db.add_failed(
+            "https://example.com/b.png",
+            timestamp(1_700_000_100),
+            None,
+            None,
+        )?;
+        db.add("https://example.org/a.png", entry)?;
+        db.add("http://example.com/a.png", entry)?;
+
+        let urls = db
+            .iter_prefix("https://example.com")
+            .map(|result| result.map(|(url, _)| url))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/a.png".to_string(),
+                "https://example.com/b.png".to_string()
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_from() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let entry = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        for url in ["a", "b", "c", "d", "e"] {
+            db.add(&format!("https://example.com/{url}.png"), entry)?;
+        }
+
+        db.add_failed(
+            "https://example.com/c.png",
+            timestamp(1_700_000_100),
+            None,
+            None,
+        )?;
+
+        let mut urls = vec![];
+        let mut cursor: Option<Cursor> = None;
+        let mut pages = 0;
+
+        loop {
+            let page = db.iter_from(cursor.as_ref(), 2)?;
+
+            pages += 1;
+            urls.extend(page.records.into_iter().map(|(url, _)| url));
+
+            match page.next {
+                // The cursor should survive a round trip through its string representation.
+                Some(next) => {
+                    cursor = Some(next.to_string().parse()?);
+                }
+                None => break,
+            }
+        }
+
+        let expected = db
+            .iter()
+            .map(|result| result.map(|(url, _)| url))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(pages, 3);
+        assert_eq!(urls, expected);
+        assert_eq!(urls.len(), 6);
+        assert!("not hex".parse::<Cursor>().is_err());
+        assert!("00".parse::<Cursor>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_count() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let entry = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        db.add("https://example.com/a.png", entry)?;
+        db.add("https://example.org/a.png", entry)?;
+        db.add_failed(
+            "https://example.com/b.png",
+            timestamp(1_700_000_100),
+            None,
+            None,
+        )?;
+
+        assert_eq!(db.count(|_, _| true)?, 3);
+        assert_eq!(db.count(|_, record| record.is_err())?, 1);
+        assert_eq!(
+            db.count(|url, _| url.starts_with("https://example.com/"))?,
+            2
+        );
+
+        // The estimate is approximate, but shouldn't be wildly off for a small database.
+        assert!(db.estimate_count()? <= 6);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_history() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let entry = |s: i64, data: &[u8]| Entry {
+            timestamp: timestamp(s),
+            digest: Digest::compute(data),
+            image_type: imghdr::Type::Png,
+        };
+
+        let url_a = "https://example.com/a.png";
+        let url_b = "https://example.com/b.png";
+
+        db.add(url_a, entry(1_700_000_000, b"a"))?;
+        db.add(url_a, entry(1_700_000_100, b"b"))?;
+        db.add(url_a, entry(1_700_000_200, b"b"))?;
+        db.add_failed(url_a, timestamp(1_700_000_300), Some(500), None)?;
+        db.add(url_b, entry(1_700_000_000, b"c"))?;
+        db.add(url_b, entry(1_700_000_100, b"c"))?;
+
+        let two = std::num::NonZeroUsize::new(2).unwrap();
+
+        // Only the older entry for the current image is removed, since the first entry is for a
+        // different image.
+        assert_eq!(db.prune_history(|url| url == url_a, two, true)?, 1);
+        assert_eq!(db.lookup(url_a)?.len(), 3);
+        assert_eq!(db.lookup(url_b)?.len(), 2);
+
+        assert_eq!(
+            db.prune_history(|_| true, std::num::NonZeroUsize::MIN, false)?,
+            3
+        );
+        assert_eq!(
+            db.lookup(url_a)?,
+            vec![Err(Missing::Failed {
+                timestamp: timestamp(1_700_000_300),
+                status: Some(500),
+                kind: None
+            })]
+        );
+        assert_eq!(db.lookup(url_b)?, vec![Ok(entry(1_700_000_100, b"c"))]);
+
+        // Pruned entries are also removed from the recent entries index.
+        assert_eq!(
+            db.recent(10)?,
+            vec![(url_b.to_string(), entry(1_700_000_100, b"c"))]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune_before() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let entry = |s: i64| Entry {
+            timestamp: timestamp(s),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        let url = "https://example.com/a.png";
+
+        db.add(url, entry(1_700_000_000))?;
+        db.add_failed(url, timestamp(1_700_000_100), None, None)?;
+        db.add(url, entry(1_700_000_200))?;
+        db.add_failed(url, timestamp(1_700_000_300), Some(500), None)?;
+
+        let mut progress = vec![];
+
+        assert_eq!(
+            db.prune_before(timestamp(1_700_000_250), true, |removed| {
+                progress.push(removed);
+            })?,
+            1
+        );
+        assert_eq!(progress, vec![1]);
+        assert_eq!(db.lookup(url)?.len(), 3);
+
+        assert_eq!(db.prune_before(timestamp(1_700_000_250), false, |_| {})?, 2);
+        assert_eq!(
+            db.lookup(url)?,
+            vec![Err(Missing::Failed {
+                timestamp: timestamp(1_700_000_300),
+                status: Some(500),
+                kind: None
+            })]
+        );
+        assert!(db.recent(10)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transformation() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let url = "https://example.com/a.jpg";
+        let transformation = Transformation {
+            original_digest: Digest::compute(b"original"),
+            original_size: 8,
+            steps: vec!["strip-metadata".to_string()],
+        };
+
+        db.add_transformed(
+            url,
+            Entry {
+                timestamp: timestamp(1_700_000_000),
+                digest: Digest::compute(b"stripped"),
+                image_type: imghdr::Type::Jpeg,
+            },
+            &transformation,
+        )?;
+        db.add(
+            url,
+            Entry {
+                timestamp: timestamp(1_700_000_100),
+                digest: Digest::compute(b"unchanged"),
+                image_type: imghdr::Type::Jpeg,
+            },
+        )?;
+
+        assert_eq!(db.lookup(url)?.len(), 2);
+        assert_eq!(
+            db.transformation(url, timestamp(1_700_000_000))?,
+            Some(transformation)
+        );
+        assert_eq!(db.transformation(url, timestamp(1_700_000_100))?, None);
+
+        assert_eq!(db.prune_before(timestamp(1_700_000_050), false, |_| {})?, 1);
+        assert_eq!(db.transformation(url, timestamp(1_700_000_000))?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wide_digests() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let url = "https://example.com/a.jpg";
+        let entry = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: DigestKind::Sha256.compute(b"a"),
+            image_type: imghdr::Type::Jpeg,
+        };
+        let transformation = Transformation {
+            original_digest: DigestKind::Blake3.compute(b"original"),
+            original_size: 8,
+            steps: vec!["strip-metadata".to_string()],
+        };
+
+        db.add_transformed(url, entry, &transformation)?;
+
+        assert_eq!(db.lookup(url)?, vec![Ok(entry)]);
+        assert_eq!(db.filename(entry.digest)?, Some("a.jpg".to_string()));
+        assert_eq!(db.filename(Digest::compute(b"a"))?, None);
+        assert_eq!(
+            db.transformation(url, entry.timestamp)?,
+            Some(transformation)
+        );
+
+        db.tombstone(entry.digest, timestamp(1_700_000_100))?;
+
+        assert_eq!(
+            db.lookup(url)?,
+            vec![
+                Err(Missing::Deleted {
+                    timestamp: timestamp(1_700_000_100),
+                    digest: entry.digest,
+                }),
+                Ok(entry)
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_first_entry() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let entry = |s: i64, data: &[u8], image_type| Entry {
+            timestamp: timestamp(s),
+            digest: Digest::compute(data),
+            image_type,
+        };
+
+        db.add(
+            "https://example.com/a.png",
+            entry(1_700_000_100, b"a", imghdr::Type::Png),
+        )?;
+        db.add(
+            "https://example.com/b.png",
+            entry(1_700_000_200, b"a", imghdr::Type::Png),
+        )?;
+        db.add(
+            "https://example.com/c.png",
+            entry(1_700_000_000, b"a", imghdr::Type::Png),
+        )?;
+
+        assert_eq!(
+            db.first_entry(Digest::compute(b"a"))?,
+            Some(entry(1_700_000_000, b"a", imghdr::Type::Png))
+        );
+        assert_eq!(db.first_entry(Digest::compute(b"b"))?, None);
+
+        // The earliest entry in a batch is kept, whatever order the entries come in.
+        db.add_all([
+            (
+                "https://example.com/d.gif",
+                entry(1_700_000_300, b"b", imghdr::Type::Gif),
+            ),
+            (
+                "https://example.com/e.gif",
+                entry(1_700_000_200, b"b", imghdr::Type::Gif),
+            ),
+            (
+                "https://example.com/f.gif",
+                entry(1_700_000_400, b"b", imghdr::Type::Gif),
+            ),
+        ])?;
+
+        assert_eq!(
+            db.first_entry(Digest::compute(b"b"))?,
+            Some(entry(1_700_000_200, b"b", imghdr::Type::Gif))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filename() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let entry = |s: i64, data: &[u8]| Entry {
+            timestamp: timestamp(s),
+            digest: Digest::compute(data),
+            image_type: imghdr::Type::Png,
+        };
+
+        db.add(
+            "https://example.com/images/cat%20photo.png?size=2",
+            entry(1_700_000_000, b"a"),
+        )?;
+        db.add(
+            "https://example.com/images/other.png",
+            entry(1_700_000_100, b"a"),
+        )?;
+        db.add("https://example.com/", entry(1_700_000_000, b"b"))?;
+
+        assert_eq!(
+            db.filename(Digest::compute(b"a"))?,
+            Some("cat%20photo.png".to_string())
+        );
+        assert_eq!(db.filename(Digest::compute(b"b"))?, None);
+        assert_eq!(db.filename(Digest::compute(b"c"))?, None);
+
+        // The first file name in a batch is kept.
+        assert_eq!(
+            db.add_all([
+                ("https://example.com/first.gif", entry(1_700_000_200, b"c")),
+                ("https://example.com/second.gif", entry(1_700_000_300, b"c")),
+            ])?,
+            2
+        );
+        assert_eq!(
+            db.filename(Digest::compute(b"c"))?,
+            Some("first.gif".to_string())
+        );
+        assert_eq!(db.lookup("https://example.com/second.gif")?.len(), 1);
+
+        assert_eq!(super::url_filename("https://example.com"), None);
+        assert_eq!(
+            super::url_filename("https://example.com/a/b.gif#top"),
+            Some("b.gif")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_last_download() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        use image_scraper::history::{DownloadHistory, LastDownload};
+
+        let directory = tempfile::tempdir()?;
+        let db = Database::open(directory.path())?;
+
+        let url = "https://example.com/a.png";
+        let entry = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        assert_eq!(db.last_download(url)?, None);
+
+        db.add(url, entry)?;
+
+        assert_eq!(
+            db.last_download(url)?,
+            Some(LastDownload::Downloaded {
+                digest: entry.digest,
+                image_type: image_scraper::image_type::ImageType::new(Some(imghdr::Type::Png)),
+                timestamp: entry.timestamp.into(),
+            })
+        );
+
+        db.add_failed(url, timestamp(1_700_000_100), Some(503), None)?;
+
+        assert_eq!(
+            db.last_download(url)?,
+            Some(LastDownload::Failed {
+                timestamp: timestamp(1_700_000_100).into(),
+                status: Some(503),
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+
+        let url_a = "https://example.com/a.png";
+        let url_b = "https://example.com/b.png";
+
+        {
+            let db = Database::open(directory.path())?;
+
+            assert!(db.enqueue(url_a, timestamp(1_700_000_000))?);
+            assert!(db.enqueue(url_b, timestamp(1_700_000_100))?);
+            assert!(!db.enqueue(url_a, timestamp(1_700_000_200))?);
+
+            db.add_failed(url_b, timestamp(1_700_000_300), Some(404), None)?;
+        }
+
+        // Queued URLs should survive reopening the database.
+        let db = Database::open(directory.path())?;
+
+        assert_eq!(
+            db.queued()?,
+            vec![(url_a.to_string(), timestamp(1_700_000_000))]
+        );
+
+        db.add(
+            url_a,
+            Entry {
+                timestamp: timestamp(1_700_000_400),
+                digest: Digest::compute(b"a"),
+                image_type: imghdr::Type::Png,
+            },
+        )?;
+
+        assert!(db.queued()?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validators() -> Result<(), Box<dyn std::error::Error>> {
+        use image_scraper::history::Validators;
+
+        let directory = tempfile::tempdir()?;
+        let url = "https://example.com/a.png";
+        let validators = Validators {
+            etag: Some("\"abc\"".to_string()),
+            last_modified: Some("Tue, 14 Nov 2023 22:13:20 GMT".to_string()),
+        };
+
+        {
+            let db = Database::open(directory.path())?;
+
+            assert_eq!(db.validators(url)?, None);
+
+            db.set_validators(url, &validators)?;
+        }
+
+        // Validators should survive reopening the database.
+        let db = Database::open(directory.path())?;
+
+        assert_eq!(db.validators(url)?, Some(validators));
+
+        db.set_validators(url, &Validators::default())?;
+
+        assert_eq!(db.validators(url)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalized_lookup() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let normalizer = image_scraper::url_norm::Normalizer::new().with_stripped_params(["utm_*"]);
+        let db = Database::open(directory.path())?.with_normalizer(normalizer);
+
+        let entry = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        db.add("HTTPS://Example.com:443/%61.png?utm_source=x", entry)?;
+
+        assert_eq!(db.lookup("https://example.com/a.png")?, vec![Ok(entry)]);
+        assert_eq!(
+            db.urls(entry.digest)?,
+            vec!["https://example.com/a.png".to_string()]
+        );
+
+        assert!(db.enqueue("https://example.com/b.png#top", timestamp(1_700_000_100))?);
+        assert!(!db.enqueue("https://example.com/b.png", timestamp(1_700_000_200))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let backup_directory = tempfile::tempdir()?;
+        let restore_directory = tempfile::tempdir()?;
+        let keep = std::num::NonZeroUsize::new(2).unwrap();
+
+        let db = Database::open(directory.path())?;
+
+        let entry_a = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        let entry_b = Entry {
+            timestamp: timestamp(1_700_000_100),
+            digest: Digest::compute(b"b"),
+            image_type: imghdr::Type::Png,
+        };
+
+        let first = db.backup(backup_directory.path(), keep)?;
+        db.add("https://example.com/a.png", entry_a)?;
+        let second = db.backup(backup_directory.path(), keep)?;
+        db.add("https://example.com/b.png", entry_b)?;
+        let third = db.backup(backup_directory.path(), keep)?;
+
+        assert!(first.id < second.id && second.id < third.id);
+
+        // Only the two most recent backups are kept.
+        assert_eq!(
+            Database::backups(backup_directory.path())?
+                .iter()
+                .map(|info| info.id)
+                .collect::<Vec<_>>(),
+            vec![second.id, third.id]
+        );
+
+        let restore_path = restore_directory.path().join("index");
+        Database::restore_latest(backup_directory.path(), &restore_path)?;
+
+        let restored = Database::open(&restore_path)?;
+
+        assert_eq!(
+            restored.lookup("https://example.com/a.png")?,
+            vec![Ok(entry_a)]
+        );
+        assert_eq!(
+            restored.lookup("https://example.com/b.png")?,
+            vec![Ok(entry_b)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reversed_host_keys() -> Result<(), Box<dyn std::error::Error>> {
+        let directory = tempfile::tempdir()?;
+        let copy_directory = tempfile::tempdir()?;
+        let path = directory.path().join("index");
+
+        let entry = Entry {
+            timestamp: timestamp(1_700_000_000),
+            digest: Digest::compute(b"a"),
+            image_type: imghdr::Type::Png,
+        };
+
+        let urls = [
+            "https://example.com/a.png",
+            "http://cdn.Example.com:8080/b.png",
+            "https://user@www.example.com/c.png",
+            "https://example.org/d.png",
+            "https://notexample.com/e.png",
+            "https://example.com.evil.org/f.png",
+            "http://192.0.2.1/g.png",
+            "https://[2001:db8::1]/h.png",
+        ];
+
+        {
+            let db = Database::open_with_key_scheme(&path, KeyScheme::ReversedHost)?;
+
+            for url in urls {
+                db.add(url, entry)?;
+            }
+
+            db.add_failed(
+                "https://example.com/a.png",
+                timestamp(1_700_000_100),
+                Some(404),
+                None,
+            )?;
+        }
+
+        // The key scheme is recorded, and can't be changed once there are records.
+        let db = Database::open(&path)?;
+
+        assert_eq!(db.key_scheme(), KeyScheme::ReversedHost);
+        assert!(matches!(
+            Database::open_with_key_scheme(&path, KeyScheme::Url),
+            Err(super::Error::KeySchemeMismatch { .. })
+        ));
+
+        assert_eq!(db.lookup("https://example.com/a.png")?.len(), 2);
+        assert_eq!(db.lookup("https://[2001:db8::1]/h.png")?, vec![Ok(entry)]);
+
+        let domain_urls = db
+            .iter_domain("EXAMPLE.com")
+            .map(|result| result.map(|(url, _)| url))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(
+            domain_urls,
+            vec![
+                "http://cdn.example.com:8080/b.png",
+                "https://user@www.example.com/c.png",
+                "https://example.com/a.png",
+                "https://example.com/a.png",
+            ]
+        );
+
+        let prefix_urls = db
+            .iter_prefix("https://example.com/")
+            .map(|result| result.map(|(url, _)| url))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        assert_eq!(
+            prefix_urls,
+            vec!["https://example.com/a.png", "https://example.com/a.png"]
+        );
+
+        // Prefixes that don't include the whole host still match every URL.
+        assert_eq!(db.iter_prefix("https://example.").count(), 4);
+
+        // Copying the records into a database with URL keys preserves them.
+        let copy = Database::open(copy_directory.path())?;
+
+        assert_eq!(db.copy_into(&copy)?, urls.len() + 1);
+        assert_eq!(copy.key_scheme(), KeyScheme::Url);
+        assert_eq!(
+            copy.lookup("https://example.com/a.png")?,
+            db.lookup("https://example.com/a.png")?
+        );
+        assert_eq!(copy.iter_domain("example.com").count(), 4);
+        assert_eq!(copy.recent(100)?.len(), urls.len());
+
+        assert_eq!(db.remove_domain("example.com")?, 4);
+        assert_eq!(db.lookup("https://example.com/a.png")?, vec![]);
+        assert_eq!(
+            db.lookup("https://example.com.evil.org/f.png")?,
+            vec![Ok(entry)]
+        );
+        assert_eq!(db.iter().count(), urls.len() - 3);
+        assert_eq!(db.recent(100)?.len(), urls.len() - 3);
+
+        Ok(())
     }
 }
@@ -2,14 +2,18 @@
 #![allow(clippy::missing_errors_doc)]
 #![forbid(unsafe_code)]
 use chrono::{DateTime, Utc};
+use image_scraper::digest::Digest;
+use image_scraper::history::FailureKind;
 
 pub mod db;
+pub mod report;
 pub mod timestamp;
 
-#[derive(Copy, Clone, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, serde::Deserialize, serde::Serialize)]
 pub struct Entry {
     pub timestamp: DateTime<Utc>,
-    pub digest: md5::Digest,
+    pub digest: Digest,
+    #[serde(with = "image_scraper::image_type::known")]
     pub image_type: imghdr::Type,
 }
 
@@ -18,7 +22,7 @@ impl Ord for Entry {
         self.timestamp
             .cmp(&other.timestamp)
             .reverse()
-            .then_with(|| self.digest.0.cmp(&other.digest.0))
+            .then_with(|| self.digest.cmp(&other.digest))
             .then_with(|| self.image_type.cmp(&other.image_type))
     }
 }
@@ -28,3 +32,29 @@ impl PartialOrd for Entry {
         Some(self.cmp(other))
     }
 }
+
+/// A record indicating that a URL does not currently resolve to a stored image.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Missing {
+    /// The image download failed (with the HTTP status code, if one was received, and the kind of
+    /// failure, if it was recorded).
+    Failed {
+        timestamp: DateTime<Utc>,
+        status: Option<u16>,
+        kind: Option<FailureKind>,
+    },
+    /// The image was removed from the store.
+    Deleted {
+        timestamp: DateTime<Utc>,
+        digest: Digest,
+    },
+}
+
+impl Missing {
+    #[must_use]
+    pub const fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            Self::Failed { timestamp, .. } | Self::Deleted { timestamp, .. } => *timestamp,
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::{Entry, Missing, db::url_host};
+use image_scraper::digest::Digest;
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+
+/// Second-level labels that are commonly registered under country-code top-level domains
+/// (e.g. `co.uk` or `com.au`).
+const SECOND_LEVEL_LABELS: [&str; 10] = [
+    "ac", "co", "com", "edu", "go", "gov", "ne", "net", "or", "org",
+];
+
+/// Return the registrable part of a host (e.g. `example.co.uk` for `images.example.co.uk`).
+///
+/// This is an approximation that doesn't use the public suffix list: the last two labels are kept,
+/// or the last three if the top-level domain is a country code and the second-level label is a
+/// common one like `co`. IP addresses are returned unchanged.
+#[must_use]
+pub fn registrable_domain(host: &str) -> String {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+    if host.starts_with('[') || host.parse::<Ipv4Addr>().is_ok() {
+        return host;
+    }
+
+    let labels = host.split('.').collect::<Vec<_>>();
+
+    let kept = match labels.as_slice() {
+        [.., second, top]
+            if top.len() == 2 && SECOND_LEVEL_LABELS.contains(second) && labels.len() > 2 =>
+        {
+            3
+        }
+        _ => 2,
+    };
+
+    labels[labels.len().saturating_sub(kept)..].join(".")
+}
+
+#[derive(Clone, Debug, Default)]
+struct DomainCounts {
+    entries: usize,
+    failures: usize,
+    digests: HashSet<Digest>,
+}
+
+/// Summary counts for a registrable domain.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DomainRow {
+    pub domain: String,
+    /// Number of successful download records
+    pub entries: usize,
+    /// Number of distinct images
+    pub digests: usize,
+    /// Total size of the distinct images (if any sizes are known)
+    pub bytes: Option<u64>,
+    /// Number of failed download records
+    pub failures: usize,
+}
+
+impl DomainRow {
+    /// The proportion of download records that are failures.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn failure_rate(&self) -> f64 {
+        let total = self.entries + self.failures;
+
+        if total == 0 {
+            0.0
+        } else {
+            self.failures as f64 / total as f64
+        }
+    }
+}
+
+/// Index record counts grouped by the registrable domain of the URL (see [`registrable_domain`]).
+///
+/// Deletions aren't counted, and URLs without a host are grouped under an empty domain.
+#[derive(Clone, Debug, Default)]
+pub struct DomainReport {
+    domains: HashMap<String, DomainCounts>,
+}
+
+impl DomainReport {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, url: &str, record: &Result<Entry, Missing>) {
+        let domain = url_host(url).map(registrable_domain).unwrap_or_default();
+        let counts = self.domains.entry(domain).or_default();
+
+        match record {
+            Ok(entry) => {
+                counts.entries += 1;
+                counts.digests.insert(entry.digest);
+            }
+            Err(Missing::Failed { .. }) => {
+                counts.failures += 1;
+            }
+            Err(Missing::Deleted { .. }) => {}
+        }
+    }
+
+    /// Return a row for each domain, ordered by descending number of entries (and then by name).
+    ///
+    /// The size function is called for each distinct image of each domain, and images without a
+    /// known size are not included in the total.
+    pub fn rows<F: FnMut(Digest) -> Option<u64>>(&self, mut size: F) -> Vec<DomainRow> {
+        let mut rows = self
+            .domains
+            .iter()
+            .map(|(domain, counts)| DomainRow {
+                domain: domain.clone(),
+                entries: counts.entries,
+                digests: counts.digests.len(),
+                bytes: counts
+                    .digests
+                    .iter()
+                    .filter_map(|digest| size(*digest))
+                    .fold(None, |total, size| Some(total.unwrap_or(0) + size)),
+                failures: counts.failures,
+            })
+            .collect::<Vec<_>>();
+
+        rows.sort_by(|a, b| {
+            b.entries
+                .cmp(&a.entries)
+                .then_with(|| a.domain.cmp(&b.domain))
+        });
+
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DomainReport, DomainRow, registrable_domain};
+    use crate::{Entry, Missing};
+    use chrono::DateTime;
+    use image_scraper::digest::Digest;
+
+    #[test]
+    fn test_registrable_domain() {
+        assert_eq!(registrable_domain("images.Example.com"), "example.com");
+        assert_eq!(registrable_domain("example.com."), "example.com");
+        assert_eq!(registrable_domain("a.b.example.co.uk"), "example.co.uk");
+        assert_eq!(registrable_domain("example.co"), "example.co");
+        assert_eq!(registrable_domain("localhost"), "localhost");
+        assert_eq!(registrable_domain("192.0.2.1"), "192.0.2.1");
+        assert_eq!(registrable_domain("[2001:db8::1]"), "[2001:db8::1]");
+    }
+
+    #[test]
+    fn test_domain_report() {
+        let timestamp = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let a = Digest::compute(b"a");
+        let b = Digest::compute(b"b");
+        let entry = |digest| {
+            Ok(Entry {
+                timestamp,
+                digest,
+                image_type: imghdr::Type::Png,
+            })
+        };
+        let failure = Err(Missing::Failed {
+            timestamp,
+            status: Some(404),
+            kind: None,
+        });
+
+        let mut report = DomainReport::new();
+        report.add("https://example.com/a.png", &entry(a));
+        report.add("https://cdn.example.com/a.png", &entry(a));
+        report.add("https://cdn.example.com/b.png", &entry(b));
+        report.add("https://cdn.example.com/c.png", &failure);
+        report.add("https://example.org/a.png", &failure);
+        report.add(
+            "https://example.org/b.png",
+            &Err(Missing::Deleted {
+                timestamp,
+                digest: b,
+            }),
+        );
+
+        let rows = report.rows(|digest| (digest == a).then_some(10));
+
+        assert_eq!(
+            rows,
+            vec![
+                DomainRow {
+                    domain: "example.com".to_string(),
+                    entries: 3,
+                    digests: 2,
+                    bytes: Some(10),
+                    failures: 1,
+                },
+                DomainRow {
+                    domain: "example.org".to_string(),
+                    entries: 0,
+                    digests: 0,
+                    bytes: None,
+                    failures: 1,
+                },
+            ]
+        );
+
+        assert!((rows[0].failure_rate() - 0.25).abs() < f64::EPSILON);
+        assert!((rows[1].failure_rate() - 1.0).abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,82 @@
+use axum::{body::Body, extract::ConnectInfo};
+use image_scraper::digest::Digest;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tracing::{Span, field::Empty};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::{Layer, Registry, layer::SubscriberExt, util::SubscriberInitExt};
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+/// Install the global subscriber with the given output format.
+///
+/// Spans and events are also passed to the extra layer (such as a trace exporter), if there is one.
+pub fn init(
+    format: LogFormat,
+    level: impl Into<LevelFilter>,
+    extra: Option<Box<dyn Layer<Registry> + Send + Sync>>,
+) {
+    let output = match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .boxed(),
+    };
+
+    let level: LevelFilter = level.into();
+
+    tracing_subscriber::registry()
+        .with(extra)
+        .with(output)
+        .with(level)
+        .init();
+}
+
+/// Create the span for a request, leaving the image fields to be recorded by the handler.
+pub fn make_span(request: &http::Request<Body>) -> Span {
+    let client_ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        client_ip = client_ip.map(tracing::field::display),
+        digest = Empty,
+        url = Empty,
+        user = Empty,
+    )
+}
+
+/// Log a completed request.
+pub fn on_response(response: &http::Response<Body>, latency: Duration, _span: &Span) {
+    tracing::info!(
+        status = response.status().as_u16(),
+        latency_ms = latency.as_secs_f64() * 1000.0,
+        "request completed"
+    );
+}
+
+/// Record the image URL for the current request.
+pub fn record_url(url: &str) {
+    Span::current().record("url", url);
+}
+
+/// Record the user a request's token was issued for.
+pub fn record_user(user: &str) {
+    Span::current().record("user", user);
+}
+
+/// Record the image digest for the current request.
+pub fn record_digest(digest: Digest) {
+    Span::current().record("digest", format!("{digest:x}"));
+}
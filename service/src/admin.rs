@@ -0,0 +1,414 @@
+use crate::manager::Manager;
+use crate::{access_log, check_admin, error, resume_downloads, scrub, thumbnail};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use image_scraper::digest::Digest;
+use std::sync::Arc;
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct DeleteImageResponse {
+    /// Whether a file was removed from the store
+    removed: bool,
+    /// URLs whose index entries were tombstoned
+    urls: Vec<String>,
+}
+
+#[utoipa::path(
+    delete,
+    tag = "admin",
+    path = "/admin/image/{digest}",
+    params(("digest" = String, Path, description = "MD5 digest of the image")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Image deleted", body = DeleteImageResponse),
+        (status = 400, description = "Invalid digest", body = error::ErrorResponse),
+        (status = 401, description = "Missing or invalid admin token", body = error::ErrorResponse),
+        (status = 500, description = "Internal error", body = error::ErrorResponse),
+        (status = 503, description = "Changes are paused for maintenance", body = error::ErrorResponse)
+    )
+)]
+pub async fn delete_image(
+    State(manager): State<Arc<Manager>>,
+    headers: http::HeaderMap,
+    Path(digest): Path<String>,
+) -> Result<Json<DeleteImageResponse>, error::AdminError> {
+    check_admin(&manager, &headers).await?;
+
+    if manager.maintenance() {
+        return Err(error::AdminError::Maintenance);
+    }
+
+    let digest = Digest::from_hex(manager.store().digest_kind(), &digest)
+        .map_err(|_| error::AdminError::InvalidDigest(digest.clone()))?;
+    access_log::record_digest(digest);
+
+    let (removed, urls) = manager.delete(digest)?;
+
+    log::info!("Deleted image {digest:x} ({} URLs tombstoned)", urls.len());
+
+    Ok(Json(DeleteImageResponse { removed, urls }))
+}
+
+#[utoipa::path(
+    get,
+    tag = "admin",
+    path = "/admin/scrub",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Background scrubbing progress", body = scrub::ScrubStatus),
+        (status = 401, description = "Missing or invalid admin token", body = error::ErrorResponse),
+        (status = 404, description = "Scrubbing is not enabled", body = error::ErrorResponse)
+    )
+)]
+pub async fn scrub_status(
+    State(manager): State<Arc<Manager>>,
+    headers: http::HeaderMap,
+) -> Result<Json<scrub::ScrubStatus>, error::AdminError> {
+    check_admin(&manager, &headers).await?;
+
+    manager
+        .scrub_status()
+        .map(Json)
+        .ok_or(error::AdminError::ScrubDisabled)
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct IndexStatsOptions {
+    /// Count the records exactly (which requires reading the whole index)
+    #[serde(default)]
+    exact: bool,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct IndexStatsResponse {
+    /// Approximate number of index records (which may include overwritten or deleted records)
+    estimated_records: u64,
+    /// Exact number of index records (if requested)
+    records: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    tag = "admin",
+    path = "/admin/index",
+    params(IndexStatsOptions),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Index record counts", body = IndexStatsResponse),
+        (status = 401, description = "Missing or invalid admin token", body = error::ErrorResponse),
+        (status = 500, description = "Internal error", body = error::ErrorResponse)
+    )
+)]
+pub async fn index_stats(
+    State(manager): State<Arc<Manager>>,
+    headers: http::HeaderMap,
+    Query(options): Query<IndexStatsOptions>,
+) -> Result<Json<IndexStatsResponse>, error::AdminError> {
+    check_admin(&manager, &headers).await?;
+
+    let (estimated_records, records) = manager.index_counts(options.exact)?;
+
+    Ok(Json(IndexStatsResponse {
+        estimated_records,
+        records,
+    }))
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct CacheUsage {
+    entries: usize,
+    /// Total size of the cached contents in bytes
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+    /// Number of entries removed to make space for others
+    evictions: u64,
+}
+
+impl From<image_scraper::read_cache::ReadCacheStats> for CacheUsage {
+    fn from(stats: image_scraper::read_cache::ReadCacheStats) -> Self {
+        Self {
+            entries: stats.entries,
+            bytes: stats.len,
+            hits: stats.hits,
+            misses: stats.misses,
+            evictions: stats.evictions,
+        }
+    }
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct CacheStatsResponse {
+    /// Usage of the cache of image contents (if enabled)
+    read: Option<CacheUsage>,
+    /// Usage of the cache of generated thumbnails (if enabled)
+    variants: Option<CacheUsage>,
+}
+
+#[utoipa::path(
+    get,
+    tag = "admin",
+    path = "/admin/cache",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "In-memory cache usage (shared by all collections)", body = CacheStatsResponse),
+        (status = 401, description = "Missing or invalid admin token", body = error::ErrorResponse)
+    )
+)]
+pub async fn cache_stats(
+    State(manager): State<Arc<Manager>>,
+    headers: http::HeaderMap,
+) -> Result<Json<CacheStatsResponse>, error::AdminError> {
+    check_admin(&manager, &headers).await?;
+
+    Ok(Json(CacheStatsResponse {
+        read: manager
+            .store()
+            .read_cache()
+            .map(|read_cache| read_cache.stats().into()),
+        variants: manager
+            .thumbnails()
+            .and_then(thumbnail::ThumbnailCache::memory)
+            .map(|memory| memory.stats().into()),
+    }))
+}
+
+#[derive(serde::Deserialize, serde::Serialize, utoipa::ToSchema)]
+pub struct Maintenance {
+    /// Whether downloads and other changes to the store and index are refused
+    enabled: bool,
+}
+
+#[utoipa::path(
+    get,
+    tag = "admin",
+    path = "/admin/maintenance",
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Whether maintenance mode is on", body = Maintenance),
+        (status = 401, description = "Missing or invalid admin token", body = error::ErrorResponse)
+    )
+)]
+pub async fn maintenance_status(
+    State(manager): State<Arc<Manager>>,
+    headers: http::HeaderMap,
+) -> Result<Json<Maintenance>, error::AdminError> {
+    check_admin(&manager, &headers).await?;
+
+    Ok(Json(Maintenance {
+        enabled: manager.maintenance(),
+    }))
+}
+
+#[utoipa::path(
+    put,
+    tag = "admin",
+    path = "/admin/maintenance",
+    request_body = Maintenance,
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Maintenance mode was turned on or off", body = Maintenance),
+        (status = 401, description = "Missing or invalid admin token", body = error::ErrorResponse),
+        (status = 500, description = "Index error when resuming queued downloads", body = error::ErrorResponse)
+    )
+)]
+pub async fn set_maintenance(
+    State(manager): State<Arc<Manager>>,
+    headers: http::HeaderMap,
+    Json(maintenance): Json<Maintenance>,
+) -> Result<Json<Maintenance>, error::AdminError> {
+    check_admin(&manager, &headers).await?;
+
+    let previous = manager.set_maintenance(maintenance.enabled);
+
+    if previous != maintenance.enabled {
+        if maintenance.enabled {
+            log::info!("Maintenance mode turned on");
+        } else {
+            log::info!("Maintenance mode turned off");
+
+            // Downloads that were queued before maintenance mode was turned on are started again.
+            resume_downloads(&manager)?;
+        }
+    }
+
+    Ok(Json(maintenance))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use axum::body::Body;
+    use http::{Request, StatusCode, header};
+
+    #[tokio::test]
+    async fn test_delete_image() {
+        let (_dir, manager) = testing::manager();
+        let digest = testing::add_image(&manager, "https://example.com/a.png", b"a");
+        // This URL has since changed to another image, so it isn't tombstoned.
+        testing::add_image(&manager, "https://example.com/b.png", b"a");
+        testing::add_image(&manager, "https://example.com/b.png", b"b");
+        let router = testing::router(manager);
+
+        let (status, _) =
+            testing::get(router.clone(), &format!("/static/{digest:x}.png"), false).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let request = Request::delete(format!("/admin/image/{digest:x}"))
+            .header(
+                header::AUTHORIZATION,
+                format!("Bearer {}", testing::ADMIN_TOKEN),
+            )
+            .body(Body::empty())
+            .unwrap();
+        let (status, body) = testing::send(router.clone(), request).await;
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["removed"], true);
+        assert_eq!(
+            body["urls"],
+            serde_json::json!(["https://example.com/a.png"])
+        );
+
+        // The store no longer has the image.
+        let (status, _) = testing::get(router, &format!("/static/{digest:x}.png"), false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_scrub_status() {
+        let (_dir, manager) = testing::manager();
+        let router = testing::router(manager.with_scrub_status(true));
+
+        let (status, _) = testing::get(router.clone(), "/admin/scrub", false).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = testing::get_json(router, "/admin/scrub", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["passes"], 0);
+        assert_eq!(body["checked"], 0);
+        assert!(body["position"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_scrub_status_disabled() {
+        let (_dir, manager) = testing::manager();
+        let router = testing::router(manager);
+
+        let (status, _) = testing::get(router, "/admin/scrub", true).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_index_stats() {
+        let (_dir, manager) = testing::manager();
+        testing::add_entry(&manager, "https://example.com/a.png", b"a", 1_700_000_000);
+        testing::add_entry(&manager, "https://example.com/a.png", b"b", 1_700_000_100);
+        let router = testing::router(manager);
+
+        let (status, _) = testing::get(router.clone(), "/admin/index", false).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = testing::get_json(router.clone(), "/admin/index", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body["estimated_records"].is_u64());
+        assert!(body["records"].is_null());
+
+        let (status, body) = testing::get_json(router, "/admin/index?exact=true", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["records"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_cache_stats() {
+        let (_dir, manager) = testing::manager_with_store(|store| {
+            store.with_read_cache(image_scraper::read_cache::ReadCache::new(1024, 1024))
+        });
+        let digest = testing::add_image(&manager, "https://example.com/a.png", b"a");
+        let router = testing::router(manager);
+
+        for _ in 0..2 {
+            let (status, _) =
+                testing::get(router.clone(), &format!("/static/{digest:x}.png"), false).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+
+        let (status, _) = testing::get(router.clone(), "/admin/cache", false).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+        let (status, body) = testing::get_json(router, "/admin/cache", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["read"]["entries"], 1);
+        assert_eq!(body["read"]["bytes"], 17);
+        assert_eq!(body["read"]["hits"], 1);
+        assert!(body["variants"].is_null());
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let (_dir, manager) = testing::manager();
+        let digest = testing::add_image(&manager, "https://example.com/a.png", b"a");
+        let router = testing::router(manager);
+
+        let (status, body) = testing::get_json(router.clone(), "/admin/maintenance", true).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["enabled"], false);
+
+        let (status, _) = testing::send(
+            router.clone(),
+            testing::admin_request(
+                http::Method::PUT,
+                "/admin/maintenance",
+                Some(&serde_json::json!({ "enabled": true })),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (_, body) = testing::get_json(router.clone(), "/admin/maintenance", true).await;
+        assert_eq!(body["enabled"], true);
+
+        // Changes are refused, but stored images are still served.
+        let (status, _) = testing::send(
+            router.clone(),
+            testing::admin_request(
+                http::Method::DELETE,
+                &format!("/admin/image/{digest:x}"),
+                None,
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let (status, _) = testing::get(
+            router.clone(),
+            &testing::request_path("https://example.com/b.png"),
+            false,
+        )
+        .await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        let (status, _) =
+            testing::get(router.clone(), &format!("/static/{digest:x}.png"), false).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (status, body) = testing::send(
+            router,
+            testing::admin_request(
+                http::Method::PUT,
+                "/admin/maintenance",
+                Some(&serde_json::json!({ "enabled": false })),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["enabled"],
+            false
+        );
+    }
+}
@@ -0,0 +1,78 @@
+use image_scraper::store::PrefixPartLengths;
+use std::path::PathBuf;
+
+/// Path segments used by the service's own routes, which can't be used as collection names.
+const RESERVED_NAMES: [&str; 10] = [
+    "admin",
+    "gallery",
+    "images",
+    "openapi.json",
+    "recent",
+    "request",
+    "search",
+    "static",
+    "thumb",
+    "urls",
+];
+
+/// An image store and index served under their own path prefix.
+///
+/// The command-line representation is a comma-separated list of key-value pairs:
+/// `name=NAME,store=DIR,prefix=LENGTHS,index=DIR`.
+#[derive(Clone, Debug)]
+pub struct Collection {
+    pub name: String,
+    pub store: PathBuf,
+    pub prefix: PrefixPartLengths,
+    pub index: PathBuf,
+}
+
+impl Collection {
+    fn is_valid_name(name: &str) -> bool {
+        !name.is_empty()
+            && !RESERVED_NAMES.contains(&name)
+            && name
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+    }
+}
+
+impl std::str::FromStr for Collection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut name = None;
+        let mut store = None;
+        let mut prefix = None;
+        let mut index = None;
+
+        for part in s.split(',') {
+            match part.split_once('=') {
+                Some(("name", value)) => name = Some(value.to_string()),
+                Some(("store", value)) => store = Some(PathBuf::from(value)),
+                Some(("prefix", value)) => {
+                    prefix = Some(
+                        value
+                            .parse::<PrefixPartLengths>()
+                            .map_err(|value| format!("Invalid prefix part lengths: {value}"))?,
+                    );
+                }
+                Some(("index", value)) => index = Some(PathBuf::from(value)),
+                _ => return Err(format!("Invalid collection field: {part}")),
+            }
+        }
+
+        let name = name.ok_or_else(|| "Missing collection name".to_string())?;
+
+        if Self::is_valid_name(&name) {
+            Ok(Self {
+                store: store.ok_or_else(|| format!("Missing store for collection: {name}"))?,
+                prefix: prefix.ok_or_else(|| format!("Missing prefix for collection: {name}"))?,
+                index: index.ok_or_else(|| format!("Missing index for collection: {name}"))?,
+                name,
+            })
+        } else {
+            Err(format!("Invalid collection name: {name}"))
+        }
+    }
+}
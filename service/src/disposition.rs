@@ -0,0 +1,91 @@
+use http::HeaderValue;
+use image_scraper::image_type::ImageType;
+use std::fmt::Write;
+
+/// Whether a file is shown in the browser or saved.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Disposition {
+    Inline,
+    Attachment,
+}
+
+impl Disposition {
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::Inline => "inline",
+            Self::Attachment => "attachment",
+        }
+    }
+}
+
+/// Build a `Content-Disposition` header value for an image with the given file name.
+///
+/// The file name is taken from a URL, so it is percent-decoded, and is then given both as an ASCII
+/// approximation and in its original form (as described in RFC 6266). The image type's extension
+/// is added if the name doesn't have one.
+pub fn header_value(
+    disposition: Disposition,
+    filename: &str,
+    image_type: ImageType,
+) -> HeaderValue {
+    let mut decoded = String::from_utf8_lossy(&percent_decode(filename)).into_owned();
+
+    if !decoded.contains('.') {
+        decoded.push('.');
+        decoded.push_str(image_type.as_str());
+    }
+
+    let fallback = decoded
+        .chars()
+        .map(|c| {
+            if c == ' ' || (c.is_ascii_graphic() && c != '"' && c != '\\') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+
+    let mut value = format!("{}; filename=\"{fallback}\"", disposition.as_str());
+
+    if fallback != decoded {
+        value.push_str("; filename*=UTF-8''");
+
+        for byte in decoded.bytes() {
+            if byte.is_ascii_alphanumeric() || b"!#$&+-.^_`|~".contains(&byte) {
+                value.push(char::from(byte));
+            } else {
+                // Writing to a string can't fail.
+                let _ = write!(value, "%{byte:02X}");
+            }
+        }
+    }
+
+    // The value only contains printable ASCII characters, so this won't fail.
+    HeaderValue::from_str(&value).unwrap_or_else(|_| HeaderValue::from_static(disposition.as_str()))
+}
+
+/// Decode percent-encoded bytes (invalid escapes are left unchanged).
+fn percent_decode(input: &str) -> Vec<u8> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| input.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.bytes().all(|byte| byte.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        if let Some(byte) = escaped {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    decoded
+}
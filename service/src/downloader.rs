@@ -0,0 +1,455 @@
+use futures::future::{BoxFuture, TryFutureExt};
+use image_scraper::client::{Client, HostLimiter};
+use image_scraper::store::StoreBackend;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::{
+    sync::{
+        Mutex,
+        mpsc::{
+            Receiver, Sender,
+            error::{SendError, TrySendError},
+        },
+        oneshot,
+    },
+    task::JoinHandle,
+};
+use tracing::{Instrument, Span};
+
+pub type ClientResult = Result<
+    Result<(bytes::Bytes, image_scraper::store::Action), http::StatusCode>,
+    image_scraper::client::Error,
+>;
+
+pub type StreamResult =
+    Result<Result<image_scraper::store::Action, http::StatusCode>, image_scraper::client::Error>;
+
+pub type Chunks = Receiver<Result<bytes::Bytes, std::io::Error>>;
+
+/// A client for a specific store, whose backend may differ from other stores using the worker.
+pub trait DownloadClient: Send + Sync {
+    fn download<'a>(&'a self, url: &'a str) -> BoxFuture<'a, ClientResult>;
+
+    fn download_with<'a>(
+        &'a self,
+        url: &'a str,
+        on_chunk: Box<dyn FnMut(&bytes::Bytes) + Send + 'a>,
+    ) -> BoxFuture<'a, StreamResult>;
+}
+
+impl<B: StoreBackend> DownloadClient for Client<B> {
+    fn download<'a>(&'a self, url: &'a str) -> BoxFuture<'a, ClientResult> {
+        Box::pin(Self::download(self, url))
+    }
+
+    fn download_with<'a>(
+        &'a self,
+        url: &'a str,
+        on_chunk: Box<dyn FnMut(&bytes::Bytes) + Send + 'a>,
+    ) -> BoxFuture<'a, StreamResult> {
+        Box::pin(Self::download_with(self, url, on_chunk))
+    }
+}
+
+/// A download request, together with the span it was made in (which is used as the parent of the
+/// download's span).
+///
+/// Each request also has a child span that is closed when the worker starts the download, so that
+/// the time spent waiting in the queue is visible in traces.
+pub enum Request {
+    /// Download the full image before responding.
+    Download {
+        client: Arc<dyn DownloadClient>,
+        url: String,
+        sender: oneshot::Sender<ClientResult>,
+        span: Span,
+        queued: Span,
+    },
+    /// Forward chunks of the image as they arrive.
+    Stream {
+        client: Arc<dyn DownloadClient>,
+        url: String,
+        chunk_sender: Sender<Result<bytes::Bytes, std::io::Error>>,
+        sender: oneshot::Sender<StreamResult>,
+        span: Span,
+        queued: Span,
+    },
+}
+
+/// Number of background requests that can be waiting before senders block.
+const BACKGROUND_BUFFER_SIZE: usize = 16;
+
+/// Time after which a download that is still in progress is assumed to be stuck, and the watchdog
+/// is no longer pinged (so that systemd restarts the service).
+const MAX_REQUEST_DURATION: Duration = Duration::from_mins(10);
+
+/// Number of chunks that can be waiting to be sent to a streaming client.
+///
+/// The worker doesn't wait for slow clients (since other downloads would be held up), so chunks
+/// stop being forwarded to a client that falls this far behind, and the rest of the image is read
+/// from the store at the client's pace once it has been saved.
+const CHUNK_BUFFER_SIZE: usize = 64;
+
+impl Request {
+    fn url(&self) -> &str {
+        match self {
+            Self::Download { url, .. } | Self::Stream { url, .. } => url,
+        }
+    }
+}
+
+/// A single download worker that may be shared by several stores.
+///
+/// Each request carries the client for the store that the image should be saved to. Requests to
+/// the same host are separated by the configured delay, but requests to other hosts may be handled
+/// in the meantime. Background requests are only handled when no other requests are ready.
+///
+/// Requests are rejected immediately (instead of waiting for space in the queue) once the number of
+/// waiting requests reaches the high-water mark.
+pub struct Downloader {
+    request_sender: Sender<Option<Request>>,
+    high_water_mark: usize,
+    delay: Duration,
+    // Number of requests that have been sent but not yet started.
+    depth: Arc<AtomicUsize>,
+    // This channel never carries the shutdown signal, but shares a type with the main channel.
+    background_sender: Sender<Option<Request>>,
+    request_receiver_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+impl Downloader {
+    #[must_use]
+    /// If a watchdog interval is provided, the worker will ping the systemd watchdog at least this
+    /// often while it is running (including during downloads, unless one takes longer than ten
+    /// minutes).
+    pub fn new(
+        request_buffer_size: usize,
+        high_water_mark: usize,
+        delay: Duration,
+        watchdog: Option<Duration>,
+    ) -> Self {
+        let (request_sender, request_receiver) = tokio::sync::mpsc::channel(request_buffer_size);
+        let (background_sender, background_receiver) =
+            tokio::sync::mpsc::channel(BACKGROUND_BUFFER_SIZE);
+        let depth = Arc::new(AtomicUsize::new(0));
+
+        Self {
+            request_sender,
+            high_water_mark,
+            delay,
+            depth: depth.clone(),
+            background_sender,
+            request_receiver_handle: Arc::new(Mutex::new(Some(Self::handle_requests(
+                delay,
+                request_buffer_size,
+                depth,
+                watchdog,
+                request_receiver,
+                background_receiver,
+            )))),
+        }
+    }
+
+    /// Add a request to the queue without waiting, failing if the queue is too full.
+    fn try_send(&self, request: Request) -> Result<(), super::error::ChannelError> {
+        // The place in the queue is reserved before sending, so that concurrent requests can't
+        // exceed the high-water mark.
+        let depth = self
+            .depth
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |depth| {
+                (depth < self.high_water_mark).then_some(depth + 1)
+            })
+            .map_err(|depth| self.full_error(depth))?;
+
+        self.request_sender
+            .try_send(Some(request))
+            .map_err(|error| {
+                self.depth.fetch_sub(1, Ordering::SeqCst);
+
+                match error {
+                    TrySendError::Full(_) => self.full_error(depth),
+                    TrySendError::Closed(request) => SendError(request).into(),
+                }
+            })
+    }
+
+    /// The suggested retry time assumes every waiting request is for the same host.
+    fn full_error(&self, depth: usize) -> super::error::ChannelError {
+        let wait_ms = self
+            .delay
+            .as_millis()
+            .saturating_mul(u128::try_from(depth).unwrap_or(u128::MAX));
+
+        super::error::ChannelError::Full {
+            depth,
+            retry_after_s: u64::try_from(wait_ms.div_ceil(1000))
+                .unwrap_or(u64::MAX)
+                .max(1),
+        }
+    }
+
+    pub async fn close(&self) -> Result<(), super::error::ShutdownError> {
+        self.request_sender.send(None).await?;
+        let handle = self.request_receiver_handle.lock().await.take();
+
+        if let Some(handle) = handle {
+            handle.await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn request(
+        &self,
+        client: Arc<dyn DownloadClient>,
+        image_url: &str,
+    ) -> impl Future<Output = Result<ClientResult, super::error::ChannelError>> {
+        let (sender, receiver) = oneshot::channel();
+
+        let sent = self.try_send(Request::Download {
+            client,
+            url: image_url.to_string(),
+            sender,
+            span: Span::current(),
+            queued: queued_span(),
+        });
+
+        futures::future::ready(sent)
+            .and_then(|()| receiver.map_err(super::error::ChannelError::from))
+    }
+
+    /// Request a download that will only be started when no other requests are waiting.
+    pub fn request_background(
+        &self,
+        client: Arc<dyn DownloadClient>,
+        image_url: &str,
+    ) -> impl Future<Output = Result<ClientResult, super::error::ChannelError>> {
+        let (sender, receiver) = oneshot::channel();
+
+        self.background_sender
+            .send(Some(Request::Download {
+                client,
+                url: image_url.to_string(),
+                sender,
+                span: Span::current(),
+                queued: queued_span(),
+            }))
+            .map_err(super::error::ChannelError::from)
+            .and_then(|()| receiver.map_err(super::error::ChannelError::from))
+    }
+
+    /// Request a download whose chunks will be forwarded as they arrive.
+    ///
+    /// The receiver for the final result will only resolve after the chunk receiver is closed.
+    pub fn request_stream(
+        &self,
+        client: Arc<dyn DownloadClient>,
+        image_url: &str,
+    ) -> Result<(Chunks, oneshot::Receiver<StreamResult>), super::error::ChannelError> {
+        let (chunk_sender, chunk_receiver) = tokio::sync::mpsc::channel(CHUNK_BUFFER_SIZE);
+        let (sender, receiver) = oneshot::channel();
+
+        self.try_send(Request::Stream {
+            client,
+            url: image_url.to_string(),
+            chunk_sender,
+            sender,
+            span: Span::current(),
+            queued: queued_span(),
+        })?;
+
+        Ok((chunk_receiver, receiver))
+    }
+
+    fn handle_requests(
+        delay: Duration,
+        max_pending: usize,
+        depth: Arc<AtomicUsize>,
+        watchdog: Option<Duration>,
+        mut receiver: Receiver<Option<Request>>,
+        mut background_receiver: Receiver<Option<Request>>,
+    ) -> JoinHandle<()> {
+        tokio::task::spawn(async move {
+            let mut limiter = HostLimiter::new(delay);
+
+            // Requests that have been received but are waiting for their host's delay to pass.
+            let mut pending = VecDeque::new();
+            let mut pending_background = VecDeque::new();
+            let mut last_ping = Instant::now();
+
+            loop {
+                let now = Instant::now();
+
+                if let Some(watchdog) = watchdog
+                    && now.duration_since(last_ping) >= watchdog
+                {
+                    super::systemd::ping_watchdog();
+                    last_ping = now;
+                }
+
+                let next = Self::take_ready(&mut pending, &limiter, now)
+                    .inspect(|_| {
+                        depth.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .or_else(|| Self::take_ready(&mut pending_background, &limiter, now));
+
+                if let Some(request) = next {
+                    limiter.record(request.url(), Instant::now());
+
+                    match watchdog {
+                        Some(watchdog) => {
+                            last_ping =
+                                Self::handle_request_pinging(request, watchdog, last_ping).await;
+                        }
+                        None => Self::handle_request(request).await,
+                    }
+
+                    continue;
+                }
+
+                let wait = pending
+                    .iter()
+                    .chain(pending_background.iter())
+                    .map(|request: &Request| limiter.wait_time(request.url(), now))
+                    .chain(
+                        watchdog
+                            .map(|watchdog| (last_ping + watchdog).saturating_duration_since(now)),
+                    )
+                    .min();
+
+                tokio::select! {
+                    biased;
+                    request = receiver.recv(), if pending.len() < max_pending => {
+                        if let Some(request) = request.flatten() {
+                            pending.push_back(request);
+                        } else {
+                            receiver.close();
+                            background_receiver.close();
+                            break;
+                        }
+                    }
+                    Some(request) = background_receiver.recv(), if pending_background.is_empty() => {
+                        pending_background.extend(request);
+                    }
+                    () = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {}
+                }
+            }
+        })
+    }
+
+    /// Remove and return the first request whose host is ready for another request.
+    fn take_ready(
+        requests: &mut VecDeque<Request>,
+        limiter: &HostLimiter,
+        now: Instant,
+    ) -> Option<Request> {
+        requests
+            .iter()
+            .position(|request| limiter.wait_time(request.url(), now).is_zero())
+            .and_then(|index| requests.remove(index))
+    }
+
+    /// Handle a request, pinging the watchdog while it is in progress (for up to
+    /// [`MAX_REQUEST_DURATION`]), and return the time of the last ping.
+    async fn handle_request_pinging(
+        request: Request,
+        watchdog: Duration,
+        mut last_ping: Instant,
+    ) -> Instant {
+        let deadline = Instant::now() + MAX_REQUEST_DURATION;
+        let mut handled = std::pin::pin!(Self::handle_request(request));
+
+        loop {
+            let next_ping = last_ping + watchdog;
+
+            tokio::select! {
+                () = &mut handled => return last_ping,
+                () = tokio::time::sleep_until(next_ping.into()), if next_ping < deadline => {
+                    super::systemd::ping_watchdog();
+                    last_ping = Instant::now();
+                }
+            }
+        }
+    }
+
+    async fn handle_request(request: Request) {
+        match request {
+            Request::Download {
+                client,
+                url,
+                sender,
+                span,
+                queued,
+            } => {
+                drop(queued);
+                log::info!("Downloading image: {url}");
+                let result = client.download(&url).instrument(span).await;
+
+                match sender.send(result) {
+                    Ok(()) => {}
+                    Err(_result) => {
+                        log::warn!(
+                            "Image already downloaded (may need to re-index image store): {url})"
+                        );
+                    }
+                }
+            }
+            Request::Stream {
+                client,
+                url,
+                chunk_sender,
+                sender,
+                span,
+                queued,
+            } => {
+                drop(queued);
+                log::info!("Downloading image (streaming): {url}");
+
+                // If the client goes away (or falls behind) we still want to finish saving the
+                // image. The last place in the channel is kept for an error that ends the response.
+                let mut forwarding = true;
+
+                let result = client
+                    .download_with(
+                        &url,
+                        Box::new(|chunk: &bytes::Bytes| {
+                            if forwarding && chunk_sender.capacity() > 1 {
+                                let _ = chunk_sender.try_send(Ok(chunk.clone()));
+                            } else if forwarding {
+                                forwarding = false;
+                                log::info!(
+                                    "Streaming client fell behind (finishing from store): {url}"
+                                );
+                            }
+                        }),
+                    )
+                    .instrument(span)
+                    .await;
+
+                if let Err(error) = &result {
+                    let _ = chunk_sender.try_send(Err(std::io::Error::other(format!(
+                        "Download failed: {error}"
+                    ))));
+                }
+
+                drop(chunk_sender);
+
+                match sender.send(result) {
+                    Ok(()) => {}
+                    Err(_result) => {
+                        log::warn!(
+                            "Image already downloaded (may need to re-index image store): {url})"
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Create the span for a request's time in the queue (as a child of the current span).
+fn queued_span() -> Span {
+    tracing::info_span!("queued")
+}
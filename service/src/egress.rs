@@ -0,0 +1,98 @@
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use std::num::NonZeroU64;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Amount of unused capacity that can be saved up for a burst.
+const BURST: Duration = Duration::from_secs(1);
+
+/// A bandwidth limit (implemented as a generic cell rate algorithm).
+#[derive(Debug)]
+struct Rate {
+    bytes_per_second: NonZeroU64,
+    /// Time at which all bytes sent so far would have been sent at exactly the limit
+    theoretical_arrival: Option<Instant>,
+}
+
+impl Rate {
+    const fn new(bytes_per_second: NonZeroU64) -> Self {
+        Self {
+            bytes_per_second,
+            theoretical_arrival: None,
+        }
+    }
+
+    /// Reserve capacity for the given number of bytes, returning how long to wait before sending.
+    fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        let cost_ns =
+            (bytes as u128).saturating_mul(1_000_000_000) / u128::from(self.bytes_per_second.get());
+        let cost = Duration::from_nanos(u64::try_from(cost_ns).unwrap_or(u64::MAX));
+
+        let theoretical_arrival = self
+            .theoretical_arrival
+            .map_or(now, |theoretical_arrival| theoretical_arrival.max(now))
+            + cost;
+
+        self.theoretical_arrival = Some(theoretical_arrival);
+
+        theoretical_arrival
+            .saturating_duration_since(now)
+            .saturating_sub(BURST)
+    }
+}
+
+/// Bandwidth limits for responses, shared by every collection.
+///
+/// The global limit applies to the total of all limited responses, and the per-connection limit
+/// applies to each response individually.
+#[derive(Clone, Debug, Default)]
+pub struct EgressLimiter {
+    global: Option<Arc<Mutex<Rate>>>,
+    per_connection: Option<NonZeroU64>,
+}
+
+impl EgressLimiter {
+    pub fn new(global: Option<NonZeroU64>, per_connection: Option<NonZeroU64>) -> Self {
+        Self {
+            global: global.map(|global| Arc::new(Mutex::new(Rate::new(global)))),
+            per_connection,
+        }
+    }
+
+    /// Delay the chunks of a response body as necessary to stay within the limits.
+    pub fn limit<S, E>(&self, stream: S) -> impl Stream<Item = Result<Bytes, E>> + use<S, E>
+    where
+        S: Stream<Item = Result<Bytes, E>>,
+    {
+        let global = self.global.clone();
+        let mut per_connection = self.per_connection.map(Rate::new);
+
+        stream.then(move |result| {
+            let wait = result.as_ref().map_or(Duration::ZERO, |chunk| {
+                let now = Instant::now();
+
+                let global_wait = global.as_ref().map_or(Duration::ZERO, |global| {
+                    global
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .reserve(chunk.len(), now)
+                });
+
+                let per_connection_wait = per_connection
+                    .as_mut()
+                    .map_or(Duration::ZERO, |rate| rate.reserve(chunk.len(), now));
+
+                global_wait.max(per_connection_wait)
+            });
+
+            async move {
+                if !wait.is_zero() {
+                    tokio::time::sleep(wait).await;
+                }
+
+                result
+            }
+        })
+    }
+}
@@ -1,14 +1,52 @@
-use axum::response::IntoResponse;
+use axum::{
+    Json,
+    response::{IntoResponse, Response},
+};
 use chrono::{DateTime, Utc};
 use http::StatusCode;
-use tokio::sync::{mpsc::error::SendError, oneshot};
+use image_scraper::digest::Digest;
+use std::fmt::Display;
+use tokio::sync::mpsc::error::SendError;
+
+/// Header reporting the number of waiting downloads when the queue is full.
+const QUEUE_DEPTH_HEADER: &str = "x-queue-depth";
+
+/// Suggested number of seconds to wait before requesting an image that is already being downloaded.
+const IN_PROGRESS_RETRY_AFTER_S: u64 = 1;
+
+/// JSON body used for all error responses.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+impl ErrorResponse {
+    fn response<E: Display>(status_code: StatusCode, error: &E) -> Response {
+        (
+            status_code,
+            Json(Self {
+                error: error.to_string(),
+            }),
+        )
+            .into_response()
+    }
+}
 
 #[derive(thiserror::Error, Debug)]
 pub enum ChannelError {
     #[error("Send error")]
-    Send(#[from] SendError<Option<(String, oneshot::Sender<super::manager::ClientResult>)>>),
+    // The unsent request is boxed, since it is much larger than the other variants.
+    Send(Box<SendError<Option<super::downloader::Request>>>),
     #[error("Receive error")]
     Receive(#[from] tokio::sync::oneshot::error::RecvError),
+    #[error("Download queue is full ({depth} requests waiting)")]
+    Full { depth: usize, retry_after_s: u64 },
+}
+
+impl From<SendError<Option<super::downloader::Request>>> for ChannelError {
+    fn from(error: SendError<Option<super::downloader::Request>>) -> Self {
+        Self::Send(Box::new(error))
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -20,24 +58,45 @@ pub enum StaticImageError {
     #[error("Must be a recognized image extension: {0}")]
     InvalidExtension(String),
     #[error("Image not found for digest: {0:x}")]
-    ImageNotFound(md5::Digest),
+    ImageNotFound(Digest),
     #[error("Error reading image for digest: {0:x}")]
-    ImageIo(md5::Digest, std::io::Error),
+    ImageIo(Digest, std::io::Error),
+    #[error("Unsupported thumbnail size: {0}")]
+    InvalidThumbnailSize(u32),
+    #[error("Error generating thumbnail for digest: {0:x}")]
+    Thumbnail(Digest, image::ImageError),
+    #[error("Image task join error")]
+    Task(#[from] tokio::task::JoinError),
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
 }
 
 impl IntoResponse for StaticImageError {
-    fn into_response(self) -> axum::response::Response {
+    fn into_response(self) -> Response {
         match self {
             error @ (Self::InvalidFormat(_)
             | Self::InvalidDigest(_)
             | Self::InvalidExtension(_)
-            | Self::ImageNotFound(_)) => {
+            | Self::ImageNotFound(_)
+            | Self::InvalidThumbnailSize(_)) => {
                 log::error!("{error}");
-                (StatusCode::BAD_REQUEST, format!("{error}")).into_response()
+                ErrorResponse::response(StatusCode::BAD_REQUEST, &error)
             }
             ref error @ Self::ImageIo(_, ref io_error) => {
                 log::error!("{error}: {io_error}");
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("{error}")).into_response()
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            ref error @ Self::Thumbnail(_, ref image_error) => {
+                log::error!("{error}: {image_error}");
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            ref error @ Self::Task(ref join_error) => {
+                log::error!("{error}: {join_error}");
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            ref error @ Self::Index(ref index_db_error) => {
+                log::error!("{error}: {index_db_error}");
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
             }
         }
     }
@@ -49,10 +108,26 @@ pub enum RequestImageError {
     InvalidFormat(String),
     #[error("Must be valid UTF-8: {0:?}")]
     InvalidUtf8(Vec<u8>),
+    #[error("Missing or invalid admin token")]
+    Unauthorized,
     #[error("Index database error")]
     Index(#[from] image_scraper_index::db::Error),
-    #[error("Image download previously failed ({1}): {0}")]
-    DownloadFailed(String, DateTime<Utc>),
+    #[error(
+        "Image download previously failed ({timestamp}{}{}): {url}",
+        .status.map(|status| format!(", status {status}")).unwrap_or_default(),
+        .kind
+            .filter(|kind| *kind != image_scraper::history::FailureKind::Status)
+            .map(|kind| format!(", {kind}"))
+            .unwrap_or_default()
+    )]
+    DownloadFailed {
+        url: String,
+        timestamp: DateTime<Utc>,
+        status: Option<u16>,
+        kind: Option<image_scraper::history::FailureKind>,
+    },
+    #[error("Image was deleted ({1}): {0}")]
+    Deleted(String, DateTime<Utc>),
     #[error("Invalid image type: {0}")]
     InvalidImageType(image_scraper::image_type::ImageType),
     #[error("Unexpected client status code: {0}")]
@@ -61,39 +136,94 @@ pub enum RequestImageError {
     DownloadQueue(#[from] ChannelError),
     #[error("HTP client error")]
     Http(#[from] image_scraper::client::Error),
+    #[error("Download task join error")]
+    DownloadTask(#[from] tokio::task::JoinError),
+    #[error("URL not allowed: {0}")]
+    Blocked(#[from] image_scraper::url_policy::Error),
+    #[error("Downloads are paused for maintenance")]
+    Maintenance,
+    #[error("Image is already being downloaded: {0}")]
+    InProgress(String),
 }
 
 impl IntoResponse for RequestImageError {
-    fn into_response(self) -> axum::response::Response {
+    fn into_response(self) -> Response {
         match self {
             error @ (Self::InvalidFormat(_)
             | Self::InvalidUtf8(_)
-            | Self::DownloadFailed(_, _)
+            | Self::DownloadFailed { .. }
             | Self::InvalidImageType(_)) => {
                 log::error!("{error}");
-                (StatusCode::BAD_REQUEST, format!("{error}")).into_response()
+                ErrorResponse::response(StatusCode::BAD_REQUEST, &error)
+            }
+            error @ Self::Unauthorized => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::UNAUTHORIZED, &error)
             }
             ref error @ Self::Index(ref index_db_error) => {
                 log::error!("{error}: {index_db_error}");
 
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("{error}")).into_response()
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            error @ Self::Deleted(_, _) => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::GONE, &error)
             }
             error @ Self::UnexpectedStatus(status_code) => {
                 log::error!("{error}");
-                (status_code, format!("{error}")).into_response()
+                ErrorResponse::response(status_code, &error)
             }
             ref error @ Self::DownloadQueue(ChannelError::Receive(ref receive_error)) => {
                 log::error!("{error} (receive): {receive_error}");
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("{error}")).into_response()
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
             }
             ref error @ Self::DownloadQueue(ChannelError::Send(ref send_error)) => {
                 log::error!("{error} (send): {send_error}");
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("{error}")).into_response()
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            Self::DownloadQueue(
+                ref queue_error @ ChannelError::Full {
+                    depth,
+                    retry_after_s,
+                },
+            ) => {
+                log::warn!("{queue_error}");
+
+                let mut response =
+                    ErrorResponse::response(StatusCode::SERVICE_UNAVAILABLE, queue_error);
+                let headers = response.headers_mut();
+                headers.insert(http::header::RETRY_AFTER, retry_after_s.into());
+                headers.insert(QUEUE_DEPTH_HEADER, depth.into());
+
+                response
             }
             ref error @ Self::Http(ref client_error) => {
                 log::error!("{error}: {client_error}");
 
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("{error}")).into_response()
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            ref error @ Self::DownloadTask(ref join_error) => {
+                log::error!("{error}: {join_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            error @ Self::Blocked(_) => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::FORBIDDEN, &error)
+            }
+            error @ Self::Maintenance => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::SERVICE_UNAVAILABLE, &error)
+            }
+            error @ Self::InProgress(_) => {
+                log::info!("{error}");
+
+                let mut response = ErrorResponse::response(StatusCode::SERVICE_UNAVAILABLE, &error);
+                response
+                    .headers_mut()
+                    .insert(http::header::RETRY_AFTER, IN_PROGRESS_RETRY_AFTER_S.into());
+
+                response
             }
         }
     }
@@ -103,24 +233,298 @@ impl IntoResponse for RequestImageError {
 pub enum MapUrlsError {
     #[error("Index database error")]
     Index(#[from] image_scraper_index::db::Error),
+    #[error("Invalid request body: {0}")]
+    InvalidBody(#[from] axum::extract::rejection::JsonRejection),
+    #[error("Too many URLs ({count}, maximum is {max})")]
+    TooManyUrls { count: usize, max: usize },
+    #[error("Missing or invalid token")]
+    Unauthorized,
 }
 
 impl IntoResponse for MapUrlsError {
-    fn into_response(self) -> axum::response::Response {
+    fn into_response(self) -> Response {
+        match self {
+            ref error @ Self::Index(ref index_db_error) => {
+                log::error!("{error}: {index_db_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            ref error @ Self::InvalidBody(ref rejection) => {
+                log::warn!("{error}");
+                ErrorResponse::response(rejection.status(), &error)
+            }
+            error @ Self::TooManyUrls { .. } => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::PAYLOAD_TOO_LARGE, &error)
+            }
+            error @ Self::Unauthorized => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::UNAUTHORIZED, &error)
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ListImagesError {
+    #[error("Must be a MD5 digest: {0}")]
+    InvalidDigest(String),
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
+    #[error("Store iteration error")]
+    StoreIteration(#[from] image_scraper::store::IterationError),
+    #[error("I/O error")]
+    Io(#[from] std::io::Error),
+    #[error("Listing task join error")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl IntoResponse for ListImagesError {
+    fn into_response(self) -> Response {
+        match self {
+            error @ Self::InvalidDigest(_) => {
+                log::error!("{error}");
+                ErrorResponse::response(StatusCode::BAD_REQUEST, &error)
+            }
+            ref error @ Self::Index(ref index_db_error) => {
+                log::error!("{error}: {index_db_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            ref error @ Self::StoreIteration(ref iteration_error) => {
+                log::error!("{error}: {iteration_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            ref error @ Self::Io(ref io_error) => {
+                log::error!("{error}: {io_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            ref error @ Self::Task(ref join_error) => {
+                log::error!("{error}: {join_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum RecentImagesError {
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
+}
+
+impl IntoResponse for RecentImagesError {
+    fn into_response(self) -> Response {
+        match self {
+            ref error @ Self::Index(ref index_db_error) => {
+                log::error!("{error}: {index_db_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum IndexRecordsError {
+    #[error("Invalid cursor: {0}")]
+    InvalidCursor(String),
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
+}
+
+impl IntoResponse for IndexRecordsError {
+    fn into_response(self) -> Response {
+        match self {
+            error @ Self::InvalidCursor(_) => {
+                log::error!("{error}");
+                ErrorResponse::response(StatusCode::BAD_REQUEST, &error)
+            }
+            ref error @ Self::Index(ref index_db_error) => {
+                log::error!("{error}: {index_db_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ImagesByDateError {
+    #[error("Expected a date in YYYY-MM-DD format: {0}")]
+    InvalidDate(String),
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
+}
+
+impl IntoResponse for ImagesByDateError {
+    fn into_response(self) -> Response {
         match self {
+            error @ Self::InvalidDate(_) => {
+                log::error!("{error}");
+                ErrorResponse::response(StatusCode::BAD_REQUEST, &error)
+            }
             ref error @ Self::Index(ref index_db_error) => {
                 log::error!("{error}: {index_db_error}");
 
-                (StatusCode::INTERNAL_SERVER_ERROR, format!("{error}")).into_response()
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
             }
         }
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum SearchError {
+    #[error("A domain or query string is required")]
+    MissingQuery,
+    #[error("Invalid domain: {0}")]
+    InvalidDomain(String),
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
+}
+
+impl IntoResponse for SearchError {
+    fn into_response(self) -> Response {
+        match self {
+            error @ (Self::MissingQuery | Self::InvalidDomain(_)) => {
+                log::error!("{error}");
+                ErrorResponse::response(StatusCode::BAD_REQUEST, &error)
+            }
+            ref error @ Self::Index(ref index_db_error) => {
+                log::error!("{error}: {index_db_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum GalleryError {
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
+}
+
+impl IntoResponse for GalleryError {
+    fn into_response(self) -> Response {
+        match self {
+            ref error @ Self::Index(ref index_db_error) => {
+                log::error!("{error}: {index_db_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+        }
+    }
+}
+
+/// A URL in a mapping request that the service will not download.
+#[derive(thiserror::Error, Debug)]
+pub enum InvalidUrlError {
+    #[error("Invalid URL: {0}")]
+    Parse(String),
+    #[error("Unsupported URL scheme: {0}")]
+    UnsupportedScheme(String),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum AdminError {
+    #[error("Missing or invalid admin token")]
+    Unauthorized,
+    #[error("Must be a MD5 digest: {0}")]
+    InvalidDigest(String),
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
+    #[error("Store error")]
+    Store(#[from] image_scraper::store::Error),
+    #[error("Scrubbing is not enabled")]
+    ScrubDisabled,
+    #[error("Changes are paused for maintenance")]
+    Maintenance,
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        match self {
+            error @ Self::Unauthorized => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::UNAUTHORIZED, &error)
+            }
+            error @ Self::InvalidDigest(_) => {
+                log::error!("{error}");
+                ErrorResponse::response(StatusCode::BAD_REQUEST, &error)
+            }
+            error @ Self::ScrubDisabled => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::NOT_FOUND, &error)
+            }
+            error @ Self::Maintenance => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::SERVICE_UNAVAILABLE, &error)
+            }
+            ref error @ Self::Index(ref index_db_error) => {
+                log::error!("{error}: {index_db_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+            ref error @ Self::Store(ref store_error) => {
+                log::error!("{error}: {store_error}");
+
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ScrubError {
+    #[error("Index database error")]
+    Index(#[from] image_scraper_index::db::Error),
+    #[error("Store error")]
+    Store(#[from] image_scraper::store::Error),
+    #[error("Download error: {0}")]
+    Download(#[from] RequestImageError),
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ShutdownError {
     #[error("Request task join error")]
     RequestTaskJoin(#[from] tokio::task::JoinError),
     #[error("Send error")]
-    Send(#[from] SendError<Option<(String, oneshot::Sender<super::manager::ClientResult>)>>),
+    Send(#[from] SendError<Option<super::downloader::Request>>),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TimeoutError {
+    #[error("Request timed out")]
+    Elapsed,
+    #[error("Middleware error")]
+    Other(tower::BoxError),
+}
+
+impl From<tower::BoxError> for TimeoutError {
+    fn from(error: tower::BoxError) -> Self {
+        if error.is::<tower::timeout::error::Elapsed>() {
+            Self::Elapsed
+        } else {
+            Self::Other(error)
+        }
+    }
+}
+
+impl IntoResponse for TimeoutError {
+    fn into_response(self) -> Response {
+        match self {
+            error @ Self::Elapsed => {
+                log::warn!("{error}");
+                ErrorResponse::response(StatusCode::GATEWAY_TIMEOUT, &error)
+            }
+            ref error @ Self::Other(ref other_error) => {
+                log::error!("{error}: {other_error}");
+                ErrorResponse::response(StatusCode::INTERNAL_SERVER_ERROR, &error)
+            }
+        }
+    }
 }
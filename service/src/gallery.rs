@@ -0,0 +1,213 @@
+use crate::manager::{Manager, UrlStyle};
+use chrono::{NaiveDate, NaiveTime, TimeDelta};
+use image_scraper::digest::Digest;
+use image_scraper::image_type::ImageType;
+use image_scraper_index::{Entry, Missing};
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// Number of images shown on each gallery page.
+const PAGE_SIZE: usize = 60;
+
+/// Size of the thumbnails shown (if thumbnails are enabled).
+const THUMBNAIL_SIZE: u32 = 256;
+
+#[derive(Clone, Debug, serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GalleryOptions {
+    /// Zero-based page number (most recent images first)
+    #[serde(default)]
+    page: usize,
+    /// Only show images indexed on this day (UTC)
+    #[param(value_type = Option<String>, format = Date)]
+    date: Option<NaiveDate>,
+    /// Only show images with this extension
+    #[serde(rename = "type")]
+    #[param(value_type = Option<String>)]
+    image_type: Option<ImageType>,
+}
+
+impl GalleryOptions {
+    fn matches(&self, entry: &Entry) -> bool {
+        self.date
+            .is_none_or(|date| entry.timestamp.date_naive() == date)
+            && self
+                .image_type
+                .is_none_or(|image_type| image_type.value() == Some(entry.image_type))
+    }
+
+    fn query(&self, page: usize) -> String {
+        let mut query = format!("?page={page}");
+
+        if let Some(date) = self.date {
+            let _ = write!(query, "&date={date}");
+        }
+
+        if let Some(image_type) = self.image_type {
+            let _ = write!(query, "&type={image_type}");
+        }
+
+        query
+    }
+}
+
+/// Find the most recently indexed images that match the given filters.
+///
+/// Each image is listed once (with the most recent URL it was indexed for), and deleted images are
+/// skipped. This requires a full scan of the index, unless a date is given.
+fn recent_images(
+    manager: &Manager,
+    options: &GalleryOptions,
+) -> Result<(Vec<(String, Entry)>, bool), image_scraper_index::db::Error> {
+    let mut entries = vec![];
+    let mut deleted = BTreeSet::new();
+
+    if let Some(date) = options.date {
+        let start = date.and_time(NaiveTime::MIN).and_utc();
+
+        // The time-ordered index doesn't include entries for deleted images.
+        for result in manager
+            .index
+            .entries_between(start, start + TimeDelta::days(1))?
+        {
+            let (url, entry) = result?;
+
+            if options.matches(&entry) {
+                entries.push((url, entry));
+            }
+        }
+
+        return Ok(page_of_images(entries, &deleted, options.page));
+    }
+
+    for result in manager.index.iter() {
+        match result? {
+            (url, Ok(entry)) => {
+                if options.matches(&entry) {
+                    entries.push((url, entry));
+                }
+            }
+            (_, Err(Missing::Deleted { digest, .. })) => {
+                deleted.insert(digest);
+            }
+            (_, Err(Missing::Failed { .. })) => {}
+        }
+    }
+
+    Ok(page_of_images(entries, &deleted, options.page))
+}
+
+/// Select a page of distinct, undeleted images from entries, most recent first.
+fn page_of_images(
+    mut entries: Vec<(String, Entry)>,
+    deleted: &BTreeSet<Digest>,
+    page: usize,
+) -> (Vec<(String, Entry)>, bool) {
+    entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.timestamp));
+
+    let mut seen = BTreeSet::new();
+    let mut images = entries
+        .into_iter()
+        .filter(|(_, entry)| !deleted.contains(&entry.digest) && seen.insert(entry.digest))
+        .skip(page.saturating_mul(PAGE_SIZE))
+        .take(PAGE_SIZE + 1)
+        .collect::<Vec<_>>();
+
+    let has_more = images.len() > PAGE_SIZE;
+    images.truncate(PAGE_SIZE);
+
+    (images, has_more)
+}
+
+/// Render a page of the gallery.
+pub fn render(
+    manager: &Manager,
+    options: &GalleryOptions,
+) -> Result<String, image_scraper_index::db::Error> {
+    let (images, has_more) = recent_images(manager, options)?;
+
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Image gallery</title>\n\
+        <style>\n\
+        body { font-family: sans-serif; margin: 1em; }\n\
+        .grid { display: grid; grid-template-columns: repeat(auto-fill, minmax(160px, 1fr)); gap: 8px; }\n\
+        .grid a { display: block; aspect-ratio: 1; background: #eee; }\n\
+        .grid img { width: 100%; height: 100%; object-fit: cover; }\n\
+        nav { margin: 1em 0; }\n\
+        </style>\n</head>\n<body>\n",
+    );
+
+    let _ = write!(
+        html,
+        "<h1>Images (page {})</h1>\n<div class=\"grid\">\n",
+        options.page + 1
+    );
+
+    for (url, entry) in &images {
+        let static_url = manager.static_url(
+            entry.digest,
+            entry.image_type.into(),
+            UrlStyle::Relative,
+            None,
+        );
+
+        let image_url = if manager.thumbnails().is_some() {
+            manager.thumbnail_url(
+                entry.digest,
+                entry.image_type.into(),
+                THUMBNAIL_SIZE,
+                UrlStyle::Relative,
+                None,
+            )
+        } else {
+            static_url.clone()
+        };
+
+        let _ = writeln!(
+            html,
+            "<a href=\"{static_url}\" title=\"{} ({})\"><img src=\"{image_url}\" loading=\"lazy\" alt=\"\"></a>",
+            escape(url),
+            entry.timestamp.format("%Y-%m-%d %H:%M:%S")
+        );
+    }
+
+    html.push_str("</div>\n<nav>\n");
+
+    if options.page > 0 {
+        let _ = writeln!(
+            html,
+            "<a href=\"{}\">Newer</a>",
+            options.query(options.page - 1)
+        );
+    }
+
+    if has_more {
+        let _ = writeln!(
+            html,
+            "<a href=\"{}\">Older</a>",
+            options.query(options.page + 1)
+        );
+    }
+
+    html.push_str("</nav>\n</body>\n</html>\n");
+
+    Ok(html)
+}
+
+/// Escape text for use in HTML content or a quoted attribute value.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            other => escaped.push(other),
+        }
+    }
+
+    escaped
+}
@@ -0,0 +1,157 @@
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+
+/// Minimum time between requests for the key set.
+///
+/// The key set is requested again when a token is signed with a key that isn't known (since the
+/// provider may have rotated its keys), but not more often than this.
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_mins(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("HTTP client error")]
+    Http(#[from] reqwest::Error),
+    #[error("Invalid key set")]
+    KeySet(#[from] serde_json::Error),
+    #[error("Invalid token")]
+    Token(#[from] jsonwebtoken::errors::Error),
+    #[error("Unsupported algorithm: {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+    #[error("Unknown key: {0:?}")]
+    UnknownKey(Option<String>),
+}
+
+/// Settings for accepting JWTs issued by an OIDC provider as bearer tokens.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct JwtConfig {
+    pub issuer: String,
+    pub audience: String,
+    /// URL of the provider's JSON Web Key Set
+    pub jwks_url: String,
+    /// Scope that grants access to admin endpoints
+    pub admin_scope: String,
+    /// Scope required for mapping requests (which don't need a token if this isn't set)
+    pub mapping_scope: Option<String>,
+}
+
+/// Scopes, given either as a space-separated string or as a list.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize)]
+#[serde(untagged)]
+enum Scopes {
+    Joined(String),
+    List(Vec<String>),
+}
+
+impl Scopes {
+    fn contains(&self, scope: &str) -> bool {
+        match self {
+            Self::Joined(scopes) => scopes.split(' ').any(|value| value == scope),
+            Self::List(scopes) => scopes.iter().any(|value| value == scope),
+        }
+    }
+}
+
+/// The claims of a verified token that are used for authorization.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Deserialize)]
+pub struct Claims {
+    /// The user the token was issued for
+    pub sub: Option<String>,
+    scope: Option<Scopes>,
+    /// Some providers use `scp` instead of `scope`
+    scp: Option<Scopes>,
+}
+
+impl Claims {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope
+            .iter()
+            .chain(self.scp.iter())
+            .any(|scopes| scopes.contains(scope))
+    }
+}
+
+/// Verifies JWTs against the keys published by the provider.
+pub struct Verifier {
+    config: JwtConfig,
+    client: reqwest::Client,
+    keys: RwLock<Option<(JwkSet, Instant)>>,
+}
+
+impl Verifier {
+    pub fn new(config: JwtConfig, client: reqwest::Client) -> Self {
+        Self {
+            config,
+            client,
+            keys: RwLock::new(None),
+        }
+    }
+
+    pub const fn config(&self) -> &JwtConfig {
+        &self.config
+    }
+
+    /// Check a token's signature, issuer, audience, and expiration time, returning its claims.
+    pub async fn verify(&self, token: &str) -> Result<Claims, Error> {
+        let header = jsonwebtoken::decode_header(token)?;
+
+        // Keys are always public keys, so symmetric algorithms are never valid.
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            return Err(Error::UnsupportedAlgorithm(header.alg));
+        }
+
+        let key = self.key(header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(&[&self.config.audience]);
+
+        Ok(jsonwebtoken::decode::<Claims>(token, &key, &validation)?.claims)
+    }
+
+    async fn key(&self, kid: Option<&str>) -> Result<DecodingKey, Error> {
+        if let Some((keys, _)) = self.keys.read().await.as_ref()
+            && let Some(key) = find_key(keys, kid)
+        {
+            return key;
+        }
+
+        let mut keys = self.keys.write().await;
+
+        // The keys may have been requested by another request while we were waiting.
+        if keys
+            .as_ref()
+            .is_none_or(|(_, fetched_at)| fetched_at.elapsed() >= MIN_REFRESH_INTERVAL)
+        {
+            let bytes = self
+                .client
+                .get(&self.config.jwks_url)
+                .send()
+                .await?
+                .error_for_status()?
+                .bytes()
+                .await?;
+
+            *keys = Some((serde_json::from_slice(&bytes)?, Instant::now()));
+        }
+
+        keys.as_ref()
+            .and_then(|(keys, _)| find_key(keys, kid))
+            .unwrap_or_else(|| Err(Error::UnknownKey(kid.map(str::to_string))))
+    }
+}
+
+/// Find the key with the given ID (tokens without an ID can only be used with a single key).
+fn find_key(keys: &JwkSet, kid: Option<&str>) -> Option<Result<DecodingKey, Error>> {
+    let jwk = match kid {
+        Some(kid) => keys.find(kid),
+        None if keys.keys.len() == 1 => keys.keys.first(),
+        None => None,
+    }?;
+
+    Some(DecodingKey::from_jwk(jwk).map_err(Error::from))
+}
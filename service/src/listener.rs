@@ -0,0 +1,64 @@
+/// A set of routes that can be served on a listener.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RouteGroup {
+    /// Stored images and thumbnails
+    Static,
+    /// Image requests, URL mapping, listing, search, the gallery, and the `OpenAPI` description
+    Api,
+    /// Admin endpoints
+    Admin,
+}
+
+impl RouteGroup {
+    pub const ALL: [Self; 3] = [Self::Static, Self::Api, Self::Admin];
+}
+
+impl std::str::FromStr for RouteGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "static" => Ok(Self::Static),
+            "api" => Ok(Self::Api),
+            "admin" => Ok(Self::Admin),
+            other => Err(format!("Invalid route group: {other}")),
+        }
+    }
+}
+
+/// An address to listen on, together with the route groups that are served there.
+///
+/// The command-line representation is the address, optionally followed by `=` and a
+/// comma-separated list of route groups (e.g. `127.0.0.1:3001=admin`). All routes are served if no
+/// groups are given.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Listener {
+    pub address: String,
+    pub groups: Vec<RouteGroup>,
+}
+
+impl Listener {
+    pub fn serves(&self, group: RouteGroup) -> bool {
+        self.groups.contains(&group)
+    }
+}
+
+impl std::str::FromStr for Listener {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((address, groups)) => Ok(Self {
+                address: address.to_string(),
+                groups: groups
+                    .split(',')
+                    .map(str::parse)
+                    .collect::<Result<Vec<_>, _>>()?,
+            }),
+            None => Ok(Self {
+                address: s.to_string(),
+                groups: RouteGroup::ALL.to_vec(),
+            }),
+        }
+    }
+}
@@ -0,0 +1,452 @@
+use crate::error;
+use crate::manager::{self, Manager};
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+};
+use chrono::NaiveDate;
+use image_scraper::digest::Digest;
+use std::sync::Arc;
+
+/// Number of images listed if no limit is specified.
+const DEFAULT_LIST_LIMIT: usize = 100;
+
+/// Maximum number of images that can be listed in a single request.
+const MAX_LIST_LIMIT: usize = 1000;
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ListImagesOptions {
+    /// Only list images with digests after this one
+    after: Option<String>,
+    /// Maximum number of images to list (at most 1000)
+    limit: Option<usize>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct ListImagesResponse {
+    images: Vec<manager::StoredImage>,
+    /// Cursor for the next page (null if this is the last page)
+    next: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    tag = "images",
+    path = "/images",
+    params(ListImagesOptions),
+    responses(
+        (status = 200, description = "Stored images in digest order", body = ListImagesResponse),
+        (status = 400, description = "Invalid cursor", body = error::ErrorResponse),
+        (status = 500, description = "Internal error", body = error::ErrorResponse)
+    )
+)]
+pub async fn list_images(
+    State(manager): State<Arc<Manager>>,
+    Query(options): Query<ListImagesOptions>,
+) -> Result<Json<ListImagesResponse>, error::ListImagesError> {
+    let after = options
+        .after
+        .map(|after| {
+            Digest::from_hex(manager.store().digest_kind(), &after)
+                .map_err(|_| error::ListImagesError::InvalidDigest(after.clone()))
+        })
+        .transpose()?;
+
+    let limit = options
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .min(MAX_LIST_LIMIT);
+
+    let (images, has_more) =
+        tokio::task::spawn_blocking(move || manager.list_images(after, limit)).await??;
+
+    let next = if has_more {
+        images.last().map(|image| image.digest.clone())
+    } else {
+        None
+    };
+
+    Ok(Json(ListImagesResponse { images, next }))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct RecentImagesOptions {
+    /// Maximum number of entries to list (at most 1000)
+    limit: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    tag = "images",
+    path = "/recent",
+    params(RecentImagesOptions),
+    responses(
+        (status = 200, description = "Most recently indexed images", body = Vec<manager::IndexedImage>),
+        (status = 500, description = "Internal error", body = error::ErrorResponse)
+    )
+)]
+pub async fn recent_images(
+    State(manager): State<Arc<Manager>>,
+    Query(options): Query<RecentImagesOptions>,
+) -> Result<Json<Vec<manager::IndexedImage>>, error::RecentImagesError> {
+    let limit = options
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .min(MAX_LIST_LIMIT);
+
+    Ok(Json(manager.recent_images(limit)?))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ImagesByDateOptions {
+    /// Zero-based page number
+    #[serde(default)]
+    page: usize,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct ImagesByDateResponse {
+    images: Vec<manager::IndexedImage>,
+    /// Number of the next page (null if this is the last page)
+    next_page: Option<usize>,
+}
+
+#[utoipa::path(
+    get,
+    tag = "images",
+    path = "/by-date/{date}",
+    params(
+        ("date" = String, Path, description = "Day (UTC) in YYYY-MM-DD format"),
+        ImagesByDateOptions
+    ),
+    responses(
+        (status = 200, description = "Images whose URLs were first indexed on the day, in the order they were indexed", body = ImagesByDateResponse),
+        (status = 400, description = "Invalid date", body = error::ErrorResponse),
+        (status = 500, description = "Internal error", body = error::ErrorResponse)
+    )
+)]
+pub async fn images_by_date(
+    State(manager): State<Arc<Manager>>,
+    Path(date): Path<String>,
+    Query(options): Query<ImagesByDateOptions>,
+) -> Result<Json<ImagesByDateResponse>, error::ImagesByDateError> {
+    let date = date
+        .parse::<NaiveDate>()
+        .map_err(|_| error::ImagesByDateError::InvalidDate(date.clone()))?;
+
+    let (images, has_more) = manager.first_seen_on(date, options.page, DEFAULT_LIST_LIMIT)?;
+
+    Ok(Json(ImagesByDateResponse {
+        images,
+        next_page: has_more.then(|| options.page + 1),
+    }))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct IndexRecordsOptions {
+    /// Cursor returned with the previous page
+    cursor: Option<String>,
+    /// Maximum number of records to list (at most 1000)
+    limit: Option<usize>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct IndexRecordsResponse {
+    records: Vec<manager::IndexRecord>,
+    /// Cursor for the next page (null if this is the last page)
+    next: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    tag = "images",
+    path = "/index",
+    params(IndexRecordsOptions),
+    responses(
+        (status = 200, description = "Index records in URL order", body = IndexRecordsResponse),
+        (status = 400, description = "Invalid cursor", body = error::ErrorResponse),
+        (status = 500, description = "Internal error", body = error::ErrorResponse)
+    )
+)]
+pub async fn index_records(
+    State(manager): State<Arc<Manager>>,
+    Query(options): Query<IndexRecordsOptions>,
+) -> Result<Json<IndexRecordsResponse>, error::IndexRecordsError> {
+    let cursor = options
+        .cursor
+        .map(|cursor| {
+            cursor
+                .parse::<image_scraper_index::db::Cursor>()
+                .map_err(|_| error::IndexRecordsError::InvalidCursor(cursor.clone()))
+        })
+        .transpose()?;
+
+    let limit = options
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .min(MAX_LIST_LIMIT);
+
+    let (records, next) = manager.index_records(cursor.as_ref(), limit)?;
+
+    Ok(Json(IndexRecordsResponse {
+        records,
+        next: next.map(|next| next.to_string()),
+    }))
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchOptions {
+    /// Only match URLs with this host (e.g. `example.com` or `example.com:8080`)
+    domain: Option<String>,
+    /// Only match URLs containing this string
+    q: Option<String>,
+    /// Maximum number of images to list (at most 1000)
+    limit: Option<usize>,
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+pub struct SearchResponse {
+    images: Vec<manager::IndexedImage>,
+    /// Whether the search stopped before the whole index (or domain) was scanned
+    truncated: bool,
+}
+
+#[utoipa::path(
+    get,
+    tag = "images",
+    path = "/search",
+    params(SearchOptions),
+    responses(
+        (status = 200, description = "Matching URLs with their latest entries", body = SearchResponse),
+        (status = 400, description = "Missing or invalid query", body = error::ErrorResponse),
+        (status = 500, description = "Internal error", body = error::ErrorResponse)
+    )
+)]
+pub async fn search(
+    State(manager): State<Arc<Manager>>,
+    Query(options): Query<SearchOptions>,
+) -> Result<Json<SearchResponse>, error::SearchError> {
+    let domain = options
+        .domain
+        .map(|domain| {
+            if !domain.is_empty()
+                && domain.bytes().all(|byte| {
+                    byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b':' | b'[' | b']')
+                })
+            {
+                Ok(domain.to_ascii_lowercase())
+            } else {
+                Err(error::SearchError::InvalidDomain(domain))
+            }
+        })
+        .transpose()?;
+
+    let substring = options.q.filter(|q| !q.is_empty());
+
+    if domain.is_none() && substring.is_none() {
+        return Err(error::SearchError::MissingQuery);
+    }
+
+    let limit = options
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .min(MAX_LIST_LIMIT);
+
+    let (images, truncated) = manager.search(&manager::SearchQuery { domain, substring }, limit)?;
+
+    Ok(Json(SearchResponse { images, truncated }))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing;
+    use http::StatusCode;
+
+    #[tokio::test]
+    async fn test_list_images() {
+        let (_dir, manager) = testing::manager();
+        let indexed = ["a", "b", "c"].map(|name| {
+            testing::add_image(
+                &manager,
+                &format!("https://example.com/{name}.png"),
+                name.as_bytes(),
+            )
+        });
+
+        // Images that are stored but not indexed are also listed.
+        let unindexed = manager
+            .store()
+            .save(b"not an image".as_slice())
+            .unwrap()
+            .entry
+            .digest;
+
+        let mut digests = indexed.to_vec();
+        digests.push(unindexed);
+        digests.sort();
+        let router = testing::router(manager);
+
+        let (status, first) = testing::get_json(router.clone(), "/images?limit=2", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["next"], format!("{:x}", digests[1]));
+
+        let (status, second) = testing::get_json(
+            router.clone(),
+            &format!("/images?limit=2&after={:x}", digests[1]),
+            false,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(second["next"].is_null());
+
+        let images = [first, second]
+            .iter()
+            .flat_map(|page| page["images"].as_array().unwrap().clone())
+            .collect::<Vec<_>>();
+
+        assert_eq!(images.len(), 4);
+
+        for (image, digest) in images.iter().zip(&digests) {
+            assert_eq!(image["digest"], format!("{digest:x}"));
+
+            if *digest == unindexed {
+                assert_eq!(image["image_type"], "");
+                assert!(image["first_seen"].is_null());
+            } else {
+                assert_eq!(image["image_type"], "png");
+                assert!(image["first_seen"].is_string());
+            }
+        }
+
+        let (status, _) = testing::get(router, "/images?after=xyz", false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_recent_images() {
+        let (_dir, manager) = testing::manager();
+        testing::add_entry(&manager, "https://example.com/a.png", b"a", 1_700_000_000);
+        testing::add_entry(&manager, "https://example.com/b.png", b"b", 1_700_000_200);
+        testing::add_entry(&manager, "https://example.com/c.png", b"c", 1_700_000_100);
+        let router = testing::router(manager);
+
+        let (status, body) = testing::get_json(router, "/recent?limit=2", false).await;
+        let urls = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|image| image["url"].as_str().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            urls,
+            ["https://example.com/b.png", "https://example.com/c.png"]
+        );
+        assert_eq!(body[0]["image_type"], "png");
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let (_dir, manager) = testing::manager();
+        testing::add_entry(&manager, "https://example.com/cat.png", b"a", 1_700_000_000);
+        testing::add_entry(&manager, "https://example.com/dog.png", b"b", 1_700_000_100);
+        testing::add_entry(&manager, "https://example.org/cat.png", b"c", 1_700_000_200);
+        let router = testing::router(manager);
+
+        let urls = |body: &serde_json::Value| {
+            let mut urls = body["images"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|image| image["url"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>();
+            urls.sort();
+            urls
+        };
+
+        let (status, body) =
+            testing::get_json(router.clone(), "/search?domain=example.com", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            urls(&body),
+            ["https://example.com/cat.png", "https://example.com/dog.png"]
+        );
+        assert_eq!(body["truncated"], false);
+
+        let (status, body) =
+            testing::get_json(router.clone(), "/search?domain=example.com&q=cat", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(urls(&body), ["https://example.com/cat.png"]);
+
+        let (status, body) = testing::get_json(router.clone(), "/search?q=cat", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            urls(&body),
+            ["https://example.com/cat.png", "https://example.org/cat.png"]
+        );
+
+        let (status, _) = testing::get(router.clone(), "/search", false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = testing::get(router, "/search?domain=example.com/cat", false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_index_records() {
+        let (_dir, manager) = testing::manager();
+        testing::add_entry(&manager, "https://example.com/a.png", b"a", 1_700_000_000);
+        testing::add_entry(&manager, "https://example.com/b.png", b"b", 1_700_000_100);
+        testing::add_entry(&manager, "https://example.com/c.png", b"c", 1_700_000_200);
+        let router = testing::router(manager);
+
+        let (status, first) = testing::get_json(router.clone(), "/index?limit=2", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(first["records"][0]["url"], "https://example.com/a.png");
+        assert_eq!(first["records"][1]["url"], "https://example.com/b.png");
+        assert_eq!(first["records"][0]["kind"], "downloaded");
+
+        let cursor = first["next"].as_str().unwrap();
+        let (status, second) =
+            testing::get_json(router.clone(), &format!("/index?cursor={cursor}"), false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(second["records"].as_array().unwrap().len(), 1);
+        assert_eq!(second["records"][0]["url"], "https://example.com/c.png");
+        assert!(second["next"].is_null());
+
+        let (status, _) = testing::get(router, "/index?cursor=xyz", false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_images_by_date() {
+        let (_dir, manager) = testing::manager();
+        // 2023-11-14
+        testing::add_entry(&manager, "https://example.com/a.png", b"a", 1_700_000_000);
+        testing::add_entry(&manager, "https://example.com/b.png", b"b", 1_700_000_100);
+        // 2023-11-15 (the first URL has been seen before)
+        testing::add_entry(&manager, "https://example.com/a.png", b"c", 1_700_086_400);
+        testing::add_entry(&manager, "https://example.com/d.png", b"d", 1_700_086_500);
+        let router = testing::router(manager);
+
+        let (status, body) = testing::get_json(router.clone(), "/by-date/2023-11-14", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["images"][0]["url"], "https://example.com/a.png");
+        assert_eq!(body["images"][1]["url"], "https://example.com/b.png");
+        assert!(body["next_page"].is_null());
+
+        let (status, body) = testing::get_json(router.clone(), "/by-date/2023-11-15", false).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["images"].as_array().unwrap().len(), 1);
+        assert_eq!(body["images"][0]["url"], "https://example.com/d.png");
+
+        let (status, _) = testing::get(router, "/by-date/yesterday", false).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+}
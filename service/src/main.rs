@@ -1,115 +1,693 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery, rust_2018_idioms)]
 #![allow(clippy::missing_errors_doc)]
 #![forbid(unsafe_code)]
+use crate::downloader::Downloader;
 use crate::manager::Manager;
 use axum::{
     Json, Router,
     body::Body,
-    extract::{Path, Query, State},
-    response::{IntoResponse, Redirect, Response},
-    routing::{get, post},
+    extract::{ConnectInfo, Path, Query, State, rejection::JsonRejection},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::get,
 };
 use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD};
 use chrono::Utc;
 use clap::Parser;
+use futures::StreamExt;
+use image_scraper::client::{ChunkedDownloads, ConnectionOptions, Revalidation};
+use image_scraper::digest::{Digest, DigestKind};
+use image_scraper::header_template::{HeaderTemplate, HeaderTemplates, HostPattern};
+use image_scraper::history::{FailureKind, Validators};
+use image_scraper::hook::Hooks;
 use image_scraper::image_type::ImageType;
-use image_scraper::store::{PrefixPartLengths, Store};
+use image_scraper::quota::{Eviction, Quota};
+use image_scraper::read_cache::ReadCache;
+use image_scraper::refresh::RefreshPolicy;
+use image_scraper::store::{Action, PrefixPartLengths, Store, StoreBackend as _};
+use image_scraper::transform::{Pipeline, RejectCorrupt, StripMetadata};
+use image_scraper::url_norm::Normalizer;
+use image_scraper::url_policy::{IpRange, UrlPattern, UrlPolicy};
 use image_scraper_index::Entry;
+use std::future::IntoFuture;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU64;
 use std::sync::Arc;
 use std::{path::PathBuf, time::Duration};
 use tokio_util::io::ReaderStream;
+use tokio_util::sync::CancellationToken;
+use tower_http::compression::CompressionLayer;
+use tower_http::services::ServeFile;
+use tracing::Instrument;
 
+mod access_log;
+mod admin;
+mod collection;
+mod disposition;
+mod downloader;
+mod egress;
 mod error;
+mod gallery;
+mod jwt;
+mod listener;
+mod listing;
 mod manager;
+mod openapi;
+mod reencode;
+mod retry;
+mod routes;
+mod scrub;
 mod shutdown;
+mod snapshot;
+mod systemd;
+#[cfg(feature = "otel")]
+mod telemetry;
+#[cfg(test)]
+mod testing;
+mod thumbnail;
+
+/// Number of bytes needed before a streamed image's type is determined.
+const IMAGE_TYPE_HEADER_LEN: usize = 32;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let opts: Opts = Opts::parse();
 
     match opts.command {
-        Command::Serve {
-            base,
-            server,
+        Command::Serve(serve_opts) => serve(&serve_opts, opts.verbosity).await,
+    }
+}
+
+/// Serve every collection until the service is shut down.
+async fn serve(opts: &ServeOpts, verbosity: clap_verbosity_flag::Verbosity) -> Result<(), Error> {
+    // Spans are exported until the exporter is dropped at the end of this function.
+    #[cfg(feature = "otel")]
+    let (otel_layer, _exporter) = match &opts.otlp_endpoint {
+        Some(otlp_endpoint) => {
+            let (layer, exporter) = telemetry::layer(otlp_endpoint)?;
+
+            (Some(layer), Some(exporter))
+        }
+        None => (None, None),
+    };
+    #[cfg(not(feature = "otel"))]
+    let otel_layer = None;
+
+    access_log::init(opts.log_format, verbosity, otel_layer);
+
+    let downloader = Arc::new(Downloader::new(
+        opts.buffer,
+        opts.queue_high_water_mark.unwrap_or(opts.buffer),
+        Duration::from_millis(opts.delay),
+        systemd::watchdog_interval(),
+    ));
+
+    let mounts = mounts(opts)?;
+
+    let openapi_path = format!("{}openapi.json", opts.base);
+    let openapi = Arc::new(openapi::document(
+        &mounts
+            .iter()
+            .map(|(path, _, _, _)| path.as_str())
+            .collect::<Vec<_>>(),
+    ));
+
+    let shared = Shared::new(opts, downloader.clone())?;
+    let mut managers = vec![];
+
+    for (path, store, prefix, index) in mounts {
+        let manager = Arc::new(shared.manager(opts, &path, store, prefix, index)?);
+
+        spawn_tasks(opts, &path, &manager)?;
+        managers.push((path, manager));
+    }
+
+    // Every listener stops accepting connections when the service is shut down.
+    let shutdown = CancellationToken::new();
+
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+
+        async move {
+            shutdown::signal(downloader).await;
+            shutdown.cancel();
+        }
+    });
+
+    let mut serving = vec![];
+
+    for (i, server) in opts.servers.iter().enumerate() {
+        let mut app = Router::new();
+
+        if server.serves(listener::RouteGroup::Api) {
+            let openapi = openapi.clone();
+
+            app = app.route(
+                &openapi_path,
+                get(move || std::future::ready(Json(openapi.as_ref().clone())))
+                    .layer(CompressionLayer::new()),
+            );
+        }
+
+        for (path, manager) in &managers {
+            app = app.merge(routes::router(path, manager.clone(), &server.groups));
+        }
+
+        let app = app.layer(
+            tower_http::trace::TraceLayer::new_for_http()
+                .make_span_with(access_log::make_span)
+                .on_response(access_log::on_response),
+        );
+
+        // Listeners passed by systemd are used in the order the addresses are given.
+        let tcp_listener = match systemd::listener(i)? {
+            Some(tcp_listener) => tokio::net::TcpListener::from_std(tcp_listener)?,
+            None => tokio::net::TcpListener::bind(&server.address).await?,
+        };
+
+        serving.push(
+            axum::serve(
+                tcp_listener,
+                app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+            )
+            .with_graceful_shutdown(shutdown.clone().cancelled_owned())
+            .into_future(),
+        );
+    }
+
+    systemd::notify_ready();
+
+    futures::future::try_join_all(serving).await?;
+
+    Ok(())
+}
+
+/// The base path, store, prefix part lengths, and index of each collection.
+///
+/// The default collection (if any) is mounted directly under the base path.
+fn mounts(opts: &ServeOpts) -> Result<Vec<(String, PathBuf, PrefixPartLengths, PathBuf)>, Error> {
+    let mut mounts = vec![];
+
+    if let Some(((store, prefix), index)) = opts
+        .store
+        .clone()
+        .zip(opts.prefix.clone())
+        .zip(opts.index.clone())
+    {
+        mounts.push((opts.base.clone(), store, prefix, index));
+    }
+
+    for collection in &opts.collections {
+        let path = format!("{}{}/", opts.base, collection.name);
+
+        if mounts.iter().any(|(other, _, _, _)| *other == path) {
+            return Err(Error::DuplicateCollection(collection.name.clone()));
+        }
+
+        mounts.push((
+            path,
+            collection.store.clone(),
+            collection.prefix.clone(),
+            collection.index.clone(),
+        ));
+    }
+
+    Ok(mounts)
+}
+
+/// Components that are shared by every collection.
+struct Shared {
+    downloader: Arc<Downloader>,
+    secure: bool,
+    external_server: String,
+    normalizer: Normalizer,
+    refresh_policy: RefreshPolicy,
+    url_policy: Arc<UrlPolicy>,
+    http_client: reqwest::Client,
+    jwt: Option<Arc<jwt::Verifier>>,
+    egress: egress::EgressLimiter,
+    hooks: Hooks,
+    pipeline: Pipeline,
+    // Images are identified by digest, so collections can share caches.
+    read_cache: Option<ReadCache>,
+    variant_cache: Option<ReadCache<thumbnail::VariantKey>>,
+}
+
+impl Shared {
+    fn new(opts: &ServeOpts, downloader: Arc<Downloader>) -> Result<Self, Error> {
+        let (secure, external_server) = external_server(opts)?;
+
+        let refresh_policy = opts.stale_after_domain.iter().cloned().fold(
+            opts.stale_after
+                .map_or_else(RefreshPolicy::new, |stale_after| {
+                    RefreshPolicy::new().with_max_age(Duration::from_secs(stale_after))
+                }),
+            RefreshPolicy::with_domain_max_age,
+        );
+
+        let connection_options = ConnectionOptions {
+            pool_max_idle_per_host: opts.pool_max_idle_per_host,
+            pool_idle_timeout: opts.pool_idle_timeout.map(Duration::from_secs),
+            http_version: opts.http_version,
+            ip_version: opts.ip_version,
+            connect_timeout: opts.connect_timeout.map(Duration::from_secs),
+            read_timeout: opts.read_timeout.map(Duration::from_secs),
+            user_agent: opts.user_agent.clone(),
+            proxy: opts.proxy.clone(),
+            max_redirects: opts.max_redirects,
+            url_policy: None,
+        };
+
+        let url_policy = url_policy(opts);
+
+        // Hosts would be resolved by the proxy, so their addresses couldn't be checked.
+        if opts.proxy.is_some() && url_policy.checks_addresses() {
+            return Err(Error::ProxyWithAddressChecks);
+        }
+
+        // Collections share a connection pool, since they may download from the same hosts.
+        let http_client = ConnectionOptions {
+            url_policy: Some(url_policy.clone()),
+            ..connection_options.clone()
+        }
+        .build()?;
+
+        // The identity provider may be an internal service, so the URL policy doesn't apply.
+        let jwt = jwt_verifier(opts, connection_options.build()?);
+
+        Ok(Self {
+            downloader,
+            secure,
+            external_server,
+            normalizer: Normalizer::new().with_stripped_params(opts.strip_params.clone()),
+            refresh_policy,
+            url_policy,
+            http_client,
+            jwt,
+            egress: egress::EgressLimiter::new(opts.egress_limit, opts.egress_connection_limit),
+            hooks: opts
+                .hook_commands
+                .iter()
+                .fold(Hooks::new(), |hooks, program| {
+                    hooks.with(image_scraper::hook::Command::new(program))
+                }),
+            pipeline: pipeline(opts),
+            read_cache: opts.read_cache_size.map(|read_cache_size| {
+                ReadCache::new(read_cache_size, opts.read_cache_max_image_size)
+            }),
+            variant_cache: opts.variant_cache_size.map(|variant_cache_size| {
+                ReadCache::new(variant_cache_size, opts.variant_cache_max_image_size)
+            }),
+        })
+    }
+
+    /// Open a collection's store and index.
+    fn manager(
+        &self,
+        opts: &ServeOpts,
+        path: &str,
+        store: PathBuf,
+        prefix: PrefixPartLengths,
+        index: PathBuf,
+    ) -> Result<Manager, Error> {
+        let store = self.store(opts, store, prefix)?;
+
+        let thumbnail_cache = opts.thumbnails.as_ref().map(|thumbnails| {
+            thumbnail::ThumbnailCache::new(thumbnails).with_memory(self.variant_cache.clone())
+        });
+
+        // Thumbnails are removed along with their images, however the images are removed
+        // (including by quota eviction and scrubbing).
+        let store = match &thumbnail_cache {
+            Some(thumbnail_cache) => {
+                let thumbnail_cache = thumbnail_cache.clone();
+
+                store.on_deleted(move |digest| {
+                    if let Err(error) = thumbnail_cache.remove(digest) {
+                        log::warn!("Failed to remove thumbnails for {digest:x}: {error}");
+                    }
+                })
+            }
+            None => store,
+        };
+
+        Ok(Manager::new(
+            manager::UrlConfig::new(self.secure, self.external_server.clone(), path.to_string())
+                .with_trusted_proxies(opts.trusted_proxies.clone()),
             store,
-            prefix,
             index,
-            buffer,
-            delay,
-        } => {
-            tracing_subscriber::fmt()
-                .with_max_level(opts.verbosity)
-                .init();
-
-            let store = Store::new(store).with_prefix_part_lengths(prefix.0)?;
-            let manager = Arc::new(Manager::new(
-                manager::UrlConfig::new(false, server.clone(), base.clone()),
-                store,
-                index,
-                buffer,
-                Duration::from_millis(delay),
-            )?);
-
-            let static_path = format!("{base}static/{{digest_with_image_type}}");
-            let request_path = format!("{base}request/{{url}}");
-            let urls_path = format!("{base}urls");
-
-            let app = Router::new()
-                .route(
-                    &static_path,
-                    get(|manager, digest_with_image_type| {
-                        static_image(manager, digest_with_image_type)
-                    }),
-                )
-                .with_state(manager.clone())
-                .route(&request_path, get(request_image))
-                .with_state(manager.clone())
-                .route(&urls_path, post(map_urls))
-                .with_state(manager.clone())
-                .layer(tower_http::trace::TraceLayer::new_for_http());
+            opts.index_key_scheme,
+            self.downloader.clone(),
+        )?
+        .with_http_client(self.http_client.clone())
+        .with_url_policy(self.url_policy.clone())
+        .with_chunked_downloads(opts.chunk_size.map(|chunk_size| ChunkedDownloads {
+            chunk_size,
+            concurrency: opts.chunk_concurrency,
+        }))
+        .with_hooks(self.hooks.clone())
+        .with_admin_token(opts.admin_token.clone())
+        .with_scrub_status(opts.scrub_interval.is_some())
+        .with_jwt(self.jwt.clone())
+        .with_streaming(opts.stream)
+        .with_maintenance(opts.maintenance)
+        .with_urls_limits(manager::UrlsLimits {
+            max_urls: opts.max_urls,
+            max_body_bytes: opts.max_body_bytes,
+        })
+        .with_timeouts(manager::Timeouts {
+            request: opts.request_timeout.map(Duration::from_secs),
+            default: opts.timeout.map(Duration::from_secs),
+        })
+        .with_egress(self.egress.clone())
+        .with_normalizer(self.normalizer.clone())
+        .with_refresh_policy(self.refresh_policy.clone())
+        .with_stale_mode(opts.stale_mode)
+        .with_header_templates(HeaderTemplates::new(opts.header_templates.clone()))
+        .with_gallery(opts.gallery)
+        .with_thumbnails(thumbnail_cache))
+    }
 
-            let listener = tokio::net::TcpListener::bind(server).await.unwrap();
+    /// Open a collection's store with the configured layout, transforms, and limits.
+    fn store(
+        &self,
+        opts: &ServeOpts,
+        store: PathBuf,
+        prefix: PrefixPartLengths,
+    ) -> Result<Store, Error> {
+        let store = Store::load(store)?
+            .with_prefix_part_lengths(prefix.0)?
+            .with_pipeline(self.pipeline.clone())
+            .with_fsync(opts.fsync)
+            .with_compression(opts.compress)
+            .with_metadata(opts.metadata);
+        let store = match opts.digest_kind {
+            Some(digest_kind) => store.with_digest_kind(digest_kind)?,
+            None => store,
+        };
+        store.record_layout()?;
 
-            axum::serve(listener, app)
-                .with_graceful_shutdown(shutdown::signal(manager))
-                .await
-                .unwrap();
+        let store = match opts.pack_threshold {
+            Some(pack_threshold) => store.with_packs(pack_threshold)?,
+            None => store,
+        };
+        let store = if opts.max_store_bytes.is_some() || opts.max_store_files.is_some() {
+            let quota = Quota::new(opts.eviction.clone());
+            let quota = match opts.max_store_bytes {
+                Some(max_store_bytes) => quota.with_max_bytes(max_store_bytes),
+                None => quota,
+            };
+            let quota = match opts.max_store_files {
+                Some(max_store_files) => quota.with_max_files(max_store_files),
+                None => quota,
+            };
+
+            store.with_quota(quota)
+        } else {
+            store
+        };
+
+        Ok(match &self.read_cache {
+            Some(read_cache) => store.with_read_cache(read_cache.clone()),
+            None => store,
+        })
+    }
+}
+
+/// The scheme and server used for full URLs.
+///
+/// Full URLs use the bind address unless an external URL is provided.
+fn external_server(opts: &ServeOpts) -> Result<(bool, String), Error> {
+    match &opts.external_url {
+        Some(external_url) => {
+            let host = external_url
+                .host_str()
+                .ok_or_else(|| Error::InvalidExternalUrl(external_url.to_string()))?;
+
+            let secure = match external_url.scheme() {
+                "http" => false,
+                "https" => true,
+                _ => return Err(Error::InvalidExternalUrl(external_url.to_string())),
+            };
+
+            let server = external_url
+                .port()
+                .map_or_else(|| host.to_string(), |port| format!("{host}:{port}"));
+
+            Ok((secure, server))
         }
+        // Full URLs point to static images, so we use the first address that serves them.
+        None => Ok((
+            false,
+            opts.servers
+                .iter()
+                .find(|server| server.serves(listener::RouteGroup::Static))
+                .or_else(|| opts.servers.first())
+                .map(|server| server.address.clone())
+                .unwrap_or_default(),
+        )),
+    }
+}
+
+fn url_policy(opts: &ServeOpts) -> Arc<UrlPolicy> {
+    let url_policy = UrlPolicy::new()
+        .with_allowed_hosts(opts.allowed_hosts.clone())
+        .with_denied_hosts(opts.denied_hosts.clone())
+        .with_denied_ranges(opts.denied_ranges.clone())
+        .with_allowed_patterns(opts.allowed_url_patterns.clone())
+        .with_denied_patterns(opts.denied_url_patterns.clone());
+    let url_policy = if opts.allowed_schemes.is_empty() {
+        url_policy
+    } else {
+        url_policy.with_schemes(opts.allowed_schemes.clone())
+    };
+
+    Arc::new(if opts.allow_private_addresses {
+        url_policy
+    } else {
+        url_policy.with_private_ranges_denied()
+    })
+}
+
+/// The key set is shared by every collection.
+fn jwt_verifier(opts: &ServeOpts, jwks_client: reqwest::Client) -> Option<Arc<jwt::Verifier>> {
+    opts.jwt_issuer
+        .clone()
+        .zip(opts.jwt_audience.clone())
+        .zip(opts.jwks_url.clone())
+        .map(|((issuer, audience), jwks_url)| {
+            Arc::new(jwt::Verifier::new(
+                jwt::JwtConfig {
+                    issuer,
+                    audience,
+                    jwks_url,
+                    admin_scope: opts.jwt_admin_scope.clone(),
+                    mapping_scope: opts.jwt_mapping_scope.clone(),
+                },
+                jwks_client,
+            ))
+        })
+}
+
+/// Corrupt images are rejected before any other transforms are applied.
+fn pipeline(opts: &ServeOpts) -> Pipeline {
+    let mut pipeline = Pipeline::new();
+
+    if opts.reject_corrupt {
+        pipeline = pipeline.with(RejectCorrupt);
+    }
+
+    if opts.strip_metadata {
+        pipeline = pipeline.with(StripMetadata);
+    }
+
+    if let Some(reencode_above) = opts.reencode_above {
+        pipeline = pipeline.with(reencode::Reencode::new(
+            reencode_above,
+            opts.reencode_quality,
+        ));
+    }
+
+    pipeline
+}
+
+/// Start the background tasks for a collection.
+fn spawn_tasks(opts: &ServeOpts, path: &str, manager: &Arc<Manager>) -> Result<(), Error> {
+    // Queued downloads are resumed when maintenance mode is turned off.
+    if !opts.maintenance {
+        resume_downloads(manager)?;
+    }
+
+    if let Some(scrub_interval) = opts.scrub_interval {
+        scrub::spawn(
+            manager.clone(),
+            Duration::from_millis(scrub_interval),
+            opts.scrub_redownload,
+        );
+    }
+
+    if let Some(retry_interval) = opts.retry_interval {
+        retry::spawn(
+            manager.clone(),
+            retry::RetryPolicy {
+                interval: Duration::from_secs(retry_interval),
+            },
+        );
+    }
+
+    if let Some(snapshot_dir) = &opts.snapshot_dir {
+        // Each collection's index is backed up to its own directory.
+        let directory = match path
+            .strip_prefix(&opts.base)
+            .and_then(|name| name.strip_suffix('/'))
+        {
+            Some(name) if !name.is_empty() => snapshot_dir.join("collections").join(name),
+            _ => snapshot_dir.join("default"),
+        };
+
+        snapshot::spawn(
+            manager.clone(),
+            directory,
+            Duration::from_secs(opts.snapshot_interval),
+            opts.snapshot_keep,
+        );
     }
 
     Ok(())
 }
 
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct StaticImageOptions {
+    /// Serve the image as an attachment to be saved (`1` or `true`)
+    download: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    tag = "images",
+    path = "/static/{digest_with_image_type}",
+    params(
+        ("digest_with_image_type" = String, Path, description = "MD5 digest and image extension"),
+        StaticImageOptions
+    ),
+    responses(
+        (status = 200, description = "Image file", content_type = "image/*"),
+        (status = 206, description = "Requested range of the image file", content_type = "image/*"),
+        (status = 304, description = "Image file has not been modified"),
+        (status = 400, description = "Invalid or unknown image", body = error::ErrorResponse),
+        (status = 416, description = "Requested range is not satisfiable"),
+        (status = 500, description = "Error reading image", body = error::ErrorResponse)
+    )
+)]
 async fn static_image(
     State(manager): State<Arc<Manager>>,
     Path(digest_with_image_type): Path<String>,
+    Query(options): Query<StaticImageOptions>,
+    request: axum::extract::Request,
 ) -> Result<Response, error::StaticImageError> {
+    let (digest, image_type, image_mime_type) =
+        parse_digest_with_image_type(digest_with_image_type, manager.store().digest_kind())?;
+
+    let entry = manager
+        .entry_for_digest(digest)
+        .ok_or(error::StaticImageError::ImageNotFound(digest))?;
+
+    let disposition = if options
+        .download
+        .is_some_and(|download| download == "1" || download == "true")
+    {
+        disposition::Disposition::Attachment
+    } else {
+        disposition::Disposition::Inline
+    };
+
+    // Images without a known file name are named by their digest when they are downloaded.
+    let content_disposition = match manager.index.filename(digest)? {
+        Some(filename) => Some(disposition::header_value(
+            disposition,
+            &filename,
+            image_type,
+        )),
+        None if disposition == disposition::Disposition::Attachment => Some(
+            disposition::header_value(disposition, &format!("{digest:x}"), image_type),
+        ),
+        None => None,
+    };
+
+    let read_cache = manager.store().read_cache();
+    let cached = read_cache.and_then(|read_cache| read_cache.get(&digest));
+
+    // Packed images and images that fit in the read cache are small, so they are read into memory,
+    // and compressed images have to be decompressed.
+    let in_memory = cached.is_some()
+        || entry.packed.is_some()
+        || entry.compressed
+        || read_cache
+            .is_some_and(|read_cache| entry.size().is_ok_and(|size| read_cache.accepts(size)));
+
+    let mut response = if in_memory {
+        let bytes = if let Some(bytes) = cached {
+            bytes
+        } else {
+            let store = manager.store().clone();
+
+            tokio::task::spawn_blocking(move || store.read_entry(&entry))
+                .await?
+                .map_err(|error| error::StaticImageError::ImageIo(digest, error))?
+        };
+
+        (
+            [(http::header::CONTENT_TYPE, image_mime_type.essence_str())],
+            [(http::header::CONTENT_LENGTH, bytes.len())],
+            Body::from_stream(manager.egress().limit(futures::stream::once(async move {
+                Ok::<_, std::io::Error>(bytes::Bytes::from_owner(bytes))
+            }))),
+        )
+            .into_response()
+    } else {
+        // This handles conditional and range requests, and sets the content length.
+        ServeFile::new_with_mime(&entry.path, &image_mime_type)
+            .try_call(request)
+            .await
+            .map_err(|error| error::StaticImageError::ImageIo(digest, error))?
+            .map(|body| {
+                Body::from_stream(manager.egress().limit(Body::new(body).into_data_stream()))
+            })
+    };
+
+    if let Some(content_disposition) = content_disposition {
+        response
+            .headers_mut()
+            .insert(http::header::CONTENT_DISPOSITION, content_disposition);
+    }
+
+    Ok(response)
+}
+
+/// Parse a path segment made up of an image digest and extension.
+///
+/// The extension must be for an image type with a known MIME type.
+fn parse_digest_with_image_type(
+    digest_with_image_type: String,
+    digest_kind: DigestKind,
+) -> Result<(Digest, ImageType, mime::Mime), error::StaticImageError> {
     let parts = digest_with_image_type.split('.').collect::<Vec<_>>();
 
     if parts.len() == 2 {
-        let digest_bytes: [u8; 16] = hex::FromHex::from_hex(parts[0])
+        let digest = Digest::from_hex(digest_kind, parts[0])
             .map_err(|_| error::StaticImageError::InvalidDigest(parts[0].to_string()))?;
 
-        let digest = md5::Digest(digest_bytes);
+        access_log::record_digest(digest);
 
-        let image_mime_type = parts[1]
+        let image_type = parts[1]
             .parse::<ImageType>()
-            .ok()
-            .and_then(image_scraper::image_type::ImageType::mime_type)
-            .ok_or_else(|| error::StaticImageError::InvalidExtension(parts[1].to_string()))?;
+            .map_err(|_| error::StaticImageError::InvalidExtension(parts[1].to_string()))?;
 
-        let path = manager
-            .path_for_digest(md5::Digest(digest_bytes))
-            .ok_or(error::StaticImageError::ImageNotFound(digest))?;
-
-        let headers = [(http::header::CONTENT_TYPE, image_mime_type.essence_str())];
-
-        let body = tokio::fs::File::open(path)
-            .await
-            .map(|file| Body::from_stream(ReaderStream::new(file)))
-            .map_err(|error| error::StaticImageError::ImageIo(digest, error))?;
+        let image_mime_type = image_type
+            .mime_type()
+            .ok_or_else(|| error::StaticImageError::InvalidExtension(parts[1].to_string()))?;
 
-        Ok((headers, body).into_response())
+        Ok((digest, image_type, image_mime_type))
     } else {
         Err(error::StaticImageError::InvalidFormat(
             digest_with_image_type,
@@ -117,9 +695,130 @@ async fn static_image(
     }
 }
 
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ThumbnailOptions {
+    /// Maximum width and height in pixels (64, 128, 256, or 512; defaults to 256)
+    size: Option<u32>,
+}
+
+#[utoipa::path(
+    get,
+    tag = "images",
+    path = "/thumb/{digest_with_image_type}",
+    params(
+        ("digest_with_image_type" = String, Path, description = "MD5 digest and image extension"),
+        ThumbnailOptions
+    ),
+    responses(
+        (status = 200, description = "Thumbnail (JPEG for JPEG images, PNG otherwise)", content_type = "image/*"),
+        (status = 304, description = "Thumbnail has not changed"),
+        (status = 400, description = "Invalid or unknown image or size", body = error::ErrorResponse),
+        (status = 500, description = "Error generating thumbnail", body = error::ErrorResponse)
+    )
+)]
+async fn thumbnail(
+    State(manager): State<Arc<Manager>>,
+    Path(digest_with_image_type): Path<String>,
+    Query(options): Query<ThumbnailOptions>,
+    headers: http::HeaderMap,
+) -> Result<Response, error::StaticImageError> {
+    let (digest, image_type, _) =
+        parse_digest_with_image_type(digest_with_image_type, manager.store().digest_kind())?;
+    let size = options.size.unwrap_or(thumbnail::DEFAULT_SIZE);
+
+    if !thumbnail::SIZES.contains(&size) {
+        return Err(error::StaticImageError::InvalidThumbnailSize(size));
+    }
+
+    // Thumbnails never change, since images are identified by digest.
+    let etag = format!("\"{digest:x}-{size}\"");
+    let cache_headers = [
+        (http::header::ETAG, etag.clone()),
+        (
+            http::header::CACHE_CONTROL,
+            "public, max-age=31536000, immutable".to_string(),
+        ),
+    ];
+
+    if headers
+        .get(http::header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    {
+        return Ok((http::StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    let source = manager
+        .entry_for_digest(digest)
+        .ok_or(error::StaticImageError::ImageNotFound(digest))?;
+
+    let cache = manager
+        .thumbnails()
+        .cloned()
+        .ok_or(error::StaticImageError::ImageNotFound(digest))?;
+
+    let format = thumbnail::ThumbnailCache::format(image_type);
+
+    let body = if cache.memory().is_some() {
+        let contents =
+            tokio::task::spawn_blocking(move || cache.read(&source, digest, size, format))
+                .await?
+                .map_err(|error| error::StaticImageError::Thumbnail(digest, error))?;
+
+        Body::from(bytes::Bytes::from_owner(contents))
+    } else {
+        let path = tokio::task::spawn_blocking(move || cache.get(&source, digest, size, format))
+            .await?
+            .map_err(|error| error::StaticImageError::Thumbnail(digest, error))?;
+
+        tokio::fs::File::open(path)
+            .await
+            .map(|file| Body::from_stream(ReaderStream::new(file)))
+            .map_err(|error| error::StaticImageError::ImageIo(digest, error))?
+    };
+
+    let content_type = [(http::header::CONTENT_TYPE, format.to_mime_type())];
+
+    Ok((cache_headers, content_type, body).into_response())
+}
+
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
+struct RequestImageOptions {
+    /// Download the image again even if it has already been downloaded or has failed (requires the
+    /// admin token)
+    #[serde(default)]
+    force: bool,
+}
+
+#[utoipa::path(
+    get,
+    tag = "images",
+    path = "/request/{url}",
+    params(
+        ("url" = String, Path, description = "URL-safe Base64-encoded image URL"),
+        RequestImageOptions
+    ),
+    security((), ("admin_token" = [])),
+    responses(
+        (status = 200, description = "Newly downloaded image", content_type = "image/*"),
+        (status = 307, description = "Redirect to the static URL for a stale image that is being downloaded again"),
+        (status = 308, description = "Redirect to the static URL for a stored image"),
+        (status = 400, description = "Invalid request or failed download", body = error::ErrorResponse),
+        (status = 401, description = "Missing or invalid admin token for a forced download", body = error::ErrorResponse),
+        (status = 403, description = "URL is not allowed by the URL policy", body = error::ErrorResponse),
+        (status = 410, description = "Image was deleted", body = error::ErrorResponse),
+        (status = 500, description = "Internal error", body = error::ErrorResponse),
+        (status = 503, description = "Download queue is full, the image is already being downloaded, or downloads are paused for maintenance", body = error::ErrorResponse,
+            headers(("Retry-After" = u64, description = "Suggested number of seconds to wait"))),
+        (status = 504, description = "Download did not complete in time", body = error::ErrorResponse)
+    )
+)]
 async fn request_image(
     State(manager): State<Arc<Manager>>,
+    headers: http::HeaderMap,
     Path(url): Path<String>,
+    Query(options): Query<RequestImageOptions>,
 ) -> Result<Response, error::RequestImageError> {
     let url_bytes = URL_SAFE_NO_PAD
         .decode(&url)
@@ -127,91 +826,671 @@ async fn request_image(
 
     let url = std::str::from_utf8(&url_bytes)
         .map_err(|_| error::RequestImageError::InvalidUtf8(url_bytes.clone()))?;
+    let url = manager.normalize_url(url);
+    let url = url.as_ref();
+
+    access_log::record_url(url);
+    manager.check_url(url)?;
 
-    match manager
+    let status = match manager
         .lookup_status(url)
         .map_err(error::RequestImageError::from)?
     {
-        manager::ImageStatus::Downloaded { entry } => Ok(Redirect::permanent(
-            &manager.static_url(
+        // Forced downloads replace previous results (but not deletions).
+        manager::ImageStatus::Downloaded { .. } | manager::ImageStatus::Failed { .. }
+            if options.force =>
+        {
+            check_admin(&manager, &headers)
+                .await
+                .map_err(|_| error::RequestImageError::Unauthorized)?;
+
+            log::info!("Forcing download: {url}");
+
+            manager::ImageStatus::Downloading
+        }
+        manager::ImageStatus::Downloaded { stale: true, .. }
+            if manager.stale_mode() == manager::StaleMode::Wait =>
+        {
+            manager::ImageStatus::Downloading
+        }
+        status => status,
+    };
+
+    match status {
+        manager::ImageStatus::Downloaded { entry, stale } => {
+            access_log::record_digest(entry.digest);
+
+            let static_url = manager.static_url(
                 entry.digest,
                 entry.image_type.into(),
                 manager::UrlStyle::Absolute,
-            ),
-        )
-        .into_response()),
+                None,
+            );
+
+            // Stale images are still served while they are downloaded again (or while downloads
+            // are paused), but the redirect isn't permanent, since the URL will point to the new
+            // image.
+            if stale {
+                if !manager.maintenance() {
+                    let manager = manager.clone();
+                    let url = url.to_string();
+
+                    tokio::spawn(
+                        async move { retry::redownload(&manager, &url).await }.in_current_span(),
+                    );
+                }
+
+                Ok(Redirect::temporary(&static_url).into_response())
+            } else {
+                Ok(Redirect::permanent(&static_url).into_response())
+            }
+        }
         manager::ImageStatus::Downloading => {
-            let (bytes, action) = manager
-                .request(url)
+            if manager.maintenance() {
+                return Err(error::RequestImageError::Maintenance);
+            }
+
+            // The URL stays in the persistent queue until the result is recorded in the index, so
+            // if it's already queued, another request is downloading it.
+            if !manager.index.enqueue(url, Utc::now())? {
+                return Err(error::RequestImageError::InProgress(url.to_string()));
+            }
+
+            if manager.streaming() {
+                stream_image(manager, url).await
+            } else {
+                // The download is indexed in a separate task, so that it is recorded even if this
+                // request times out or the client disconnects.
+                let url = url.to_string();
+                let task = tokio::spawn(
+                    async move {
+                        let result = manager
+                            .request(&url)
+                            .await
+                            .map_err(error::RequestImageError::from)?;
+
+                        let (bytes, action) = check_download(&manager, &url, result)?;
+                        let mime_type = index_download(&manager, &url, &action)?;
+
+                        Ok::<_, error::RequestImageError>((bytes, mime_type))
+                    }
+                    .in_current_span(),
+                );
+
+                let (bytes, mime_type) = task.await??;
+                let headers = [(http::header::CONTENT_TYPE, mime_type.essence_str())];
+
+                Ok((headers, bytes).into_response())
+            }
+        }
+        manager::ImageStatus::Failed {
+            timestamp,
+            status,
+            kind,
+        } => Err(error::RequestImageError::DownloadFailed {
+            url: url.to_string(),
+            timestamp,
+            status,
+            kind,
+        }),
+        manager::ImageStatus::Deleted { timestamp } => Err(error::RequestImageError::Deleted(
+            url.to_string(),
+            timestamp,
+        )),
+    }
+}
+
+#[derive(serde::Serialize, utoipa::ToSchema)]
+struct RefreshResponse {
+    /// Whether the image differs from the previously indexed one
+    changed: bool,
+    /// Whether the origin confirmed that the image wasn't modified (without sending it again)
+    not_modified: bool,
+    /// MD5 digest of the current image
+    digest: String,
+    /// Static URL for the current image
+    url: String,
+}
+
+#[utoipa::path(
+    post,
+    tag = "admin",
+    path = "/refresh/{url}",
+    params(("url" = String, Path, description = "URL-safe Base64-encoded image URL")),
+    security(("admin_token" = [])),
+    responses(
+        (status = 200, description = "Result of revalidating the image", body = RefreshResponse),
+        (status = 400, description = "Invalid request or failed download", body = error::ErrorResponse),
+        (status = 401, description = "Missing or invalid admin token", body = error::ErrorResponse),
+        (status = 410, description = "Image was deleted", body = error::ErrorResponse),
+        (status = 500, description = "Internal error", body = error::ErrorResponse),
+        (status = 503, description = "Downloads are paused for maintenance", body = error::ErrorResponse),
+        (status = 504, description = "Revalidation did not complete in time", body = error::ErrorResponse)
+    )
+)]
+async fn refresh_image(
+    State(manager): State<Arc<Manager>>,
+    headers: http::HeaderMap,
+    Path(url): Path<String>,
+) -> Result<Json<RefreshResponse>, error::RequestImageError> {
+    check_admin(&manager, &headers)
+        .await
+        .map_err(|_| error::RequestImageError::Unauthorized)?;
+
+    if manager.maintenance() {
+        return Err(error::RequestImageError::Maintenance);
+    }
+
+    let url_bytes = URL_SAFE_NO_PAD
+        .decode(&url)
+        .map_err(|_| error::RequestImageError::InvalidFormat(url))?;
+
+    let url = std::str::from_utf8(&url_bytes)
+        .map_err(|_| error::RequestImageError::InvalidUtf8(url_bytes.clone()))?;
+    let url = manager.normalize_url(url);
+    let url = url.as_ref();
+
+    access_log::record_url(url);
+
+    let previous = match manager.lookup_status(url)? {
+        manager::ImageStatus::Downloaded { entry, .. } => Some(entry),
+        manager::ImageStatus::Deleted { timestamp } => {
+            return Err(error::RequestImageError::Deleted(
+                url.to_string(),
+                timestamp,
+            ));
+        }
+        _ => None,
+    };
+
+    // The request is only conditional if we still have the image that the validators describe.
+    let validators = match previous {
+        Some(entry) if manager.entry_for_digest(entry.digest).is_some() => {
+            manager.index.validators(url)?.unwrap_or_default()
+        }
+        _ => Validators::default(),
+    };
+
+    let result = manager.revalidate(url, &validators).await;
+
+    let (digest, image_type, validators, not_modified) =
+        match check_download(&manager, url, result)? {
+            Revalidation::NotModified { validators } => {
+                // Validators are only sent when there is a previous entry.
+                let Some(previous) = previous else {
+                    return Err(error::RequestImageError::UnexpectedStatus(
+                        http::StatusCode::NOT_MODIFIED,
+                    ));
+                };
+
+                manager.index.add(
+                    url,
+                    Entry {
+                        timestamp: Utc::now(),
+                        ..previous
+                    },
+                )?;
+                access_log::record_digest(previous.digest);
+
+                (
+                    previous.digest,
+                    previous.image_type.into(),
+                    validators,
+                    true,
+                )
+            }
+            Revalidation::Downloaded { action, validators } => {
+                index_download(&manager, url, &action)?;
+
+                (action.entry.digest, action.image_type, validators, false)
+            }
+        };
+
+    manager.index.set_validators(url, &validators)?;
+
+    let changed = previous.is_none_or(|previous| previous.digest != digest);
+
+    log::info!(
+        "Refreshed {url} ({})",
+        if changed { "changed" } else { "unchanged" }
+    );
+
+    Ok(Json(RefreshResponse {
+        changed,
+        not_modified,
+        digest: format!("{digest:x}"),
+        url: manager.static_url(digest, image_type, manager::UrlStyle::Absolute, None),
+    }))
+}
+
+/// Record a failed download in the index, so that it isn't retried on every request.
+fn check_download<T>(
+    manager: &Manager,
+    url: &str,
+    result: Result<Result<T, http::StatusCode>, image_scraper::client::Error>,
+) -> Result<T, error::RequestImageError> {
+    match result {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(status_code)) => {
+            manager.index.add_failed(
+                url,
+                Utc::now(),
+                Some(status_code.as_u16()),
+                Some(FailureKind::Status),
+            )?;
+
+            Err(error::RequestImageError::UnexpectedStatus(status_code))
+        }
+        Err(error) => {
+            manager
+                .index
+                .add_failed(url, Utc::now(), None, Some(error.failure_kind()))?;
+
+            Err(error::RequestImageError::Http(error))
+        }
+    }
+}
+
+/// Add a downloaded image to the index, returning its MIME type.
+///
+/// Downloads that aren't recognized images are removed from the download queue without being
+/// indexed.
+fn index_download(
+    manager: &Manager,
+    url: &str,
+    action: &Action,
+) -> Result<mime::Mime, error::RequestImageError> {
+    if let Some((mime_type, image_type)) =
+        action.image_type.mime_type().zip(action.image_type.value())
+    {
+        access_log::record_digest(action.entry.digest);
+
+        let entry = Entry {
+            timestamp: Utc::now(),
+            digest: action.entry.digest,
+            image_type,
+        };
+
+        match &action.transformation {
+            Some(transformation) => {
+                manager.index.add_transformed(url, entry, transformation)?;
+            }
+            None => manager.index.add(url, entry)?,
+        }
+
+        Ok(mime_type)
+    } else {
+        manager.index.dequeue(url)?;
+
+        Err(error::RequestImageError::InvalidImageType(
+            action.image_type,
+        ))
+    }
+}
+
+/// Re-submit downloads that were queued but not completed when the service last stopped.
+fn resume_downloads(manager: &Arc<Manager>) -> Result<(), image_scraper_index::db::Error> {
+    let queued = manager.index.queued()?;
+
+    if !queued.is_empty() {
+        log::info!("Resuming {} queued downloads", queued.len());
+    }
+
+    for (url, _) in queued {
+        let manager = manager.clone();
+
+        tokio::spawn(async move {
+            let result = manager
+                .request_background(&url)
                 .await
-                .map_err(error::RequestImageError::from)?
-                .map_err(error::RequestImageError::from)?
-                .map_err(error::RequestImageError::UnexpectedStatus)?;
-
-            match action.image_type.mime_type().zip(action.image_type.value()) {
-                Some((mime_type, image_type)) => {
-                    let headers = [(http::header::CONTENT_TYPE, mime_type.essence_str())];
-
-                    manager
-                        .index
-                        .add(
-                            url,
-                            Entry {
-                                timestamp: Utc::now(),
-                                digest: action.entry.digest,
-                                image_type,
-                            },
-                        )
-                        .map_err(error::RequestImageError::from)?;
+                .map_err(error::RequestImageError::from)
+                .and_then(|result| check_download(&manager, &url, result))
+                .and_then(|(_, action)| index_download(&manager, &url, &action));
 
-                    Ok((headers, bytes).into_response())
-                }
-                None => Err(error::RequestImageError::InvalidImageType(
-                    action.image_type,
-                )),
+            if let Err(error) = result {
+                log::error!("Resumed download failed ({url}): {error}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+/// Respond with an image as it is downloaded.
+///
+/// The response is started as soon as enough bytes have arrived to determine the image type, and
+/// the image is added to the index once the download completes. The downloaded bytes are the bytes
+/// that are saved, since streaming can't be combined with transformations that change images, so
+/// if the client falls behind the download, the rest of the response is read from the store.
+async fn stream_image(
+    manager: Arc<Manager>,
+    url: &str,
+) -> Result<Response, error::RequestImageError> {
+    let (mut chunks, result) = manager
+        .request_stream(url)
+        .map_err(error::RequestImageError::from)?;
+
+    // The download is indexed in a separate task, so that it is recorded even if this request
+    // times out or the client disconnects.
+    let task = tokio::spawn({
+        let manager = manager.clone();
+        let url = url.to_string();
+
+        async move {
+            let result = result.await.map_err(error::ChannelError::from)?;
+            let action = check_download(&manager, &url, result)?;
+
+            index_download(&manager, &url, &action)
+                .map(|mime_type| (mime_type, action.entry.digest))
+        }
+        .in_current_span()
+    });
+
+    let mut initial_chunks = vec![];
+    let mut header = Vec::with_capacity(IMAGE_TYPE_HEADER_LEN);
+
+    while header.len() < IMAGE_TYPE_HEADER_LEN {
+        match chunks.recv().await {
+            Some(Ok(chunk)) => {
+                let remaining = IMAGE_TYPE_HEADER_LEN - header.len();
+                header.extend_from_slice(&chunk[..chunk.len().min(remaining)]);
+                initial_chunks.push(chunk);
             }
+            // The final result will tell us whether the download succeeded.
+            Some(Err(_)) | None => break,
         }
-        manager::ImageStatus::Failed { timestamp } => Err(
-            error::RequestImageError::DownloadFailed(url.to_string(), timestamp),
-        ),
     }
+
+    if header.len() < IMAGE_TYPE_HEADER_LEN {
+        let (mime_type, _) = task.await??;
+        let headers = [(http::header::CONTENT_TYPE, mime_type.essence_str())];
+
+        Ok((headers, initial_chunks.concat()).into_response())
+    } else {
+        let image_type = ImageType::new(imghdr::from_bytes(&header));
+        let mime_type = image_type
+            .mime_type()
+            .ok_or(error::RequestImageError::InvalidImageType(image_type))?;
+
+        let url = url.to_string();
+        let (saved_sender, saved) = tokio::sync::oneshot::channel();
+
+        tokio::spawn(async move {
+            match task.await {
+                Ok(Ok((_, digest))) => {
+                    let _ = saved_sender.send(digest);
+                }
+                Ok(Err(error)) => log::error!("Streamed image download failed ({url}): {error}"),
+                Err(error) => log::error!("Streamed image task join error ({url}): {error}"),
+            }
+        });
+
+        let received = initial_chunks.iter().map(bytes::Bytes::len).sum();
+        let rest = futures::stream::unfold(Some((chunks, received, saved)), move |state| {
+            let manager = manager.clone();
+
+            async move {
+                let (mut chunks, received, saved) = state?;
+
+                match chunks.recv().await {
+                    Some(Ok(chunk)) => {
+                        let received = received + chunk.len();
+
+                        Some((Ok(chunk), Some((chunks, received, saved))))
+                    }
+                    Some(Err(error)) => Some((Err(error), None)),
+                    // Any chunks that weren't forwarded are read from the store.
+                    None => remaining_bytes(&manager, saved, received)
+                        .await
+                        .transpose()
+                        .map(|result| (result, None)),
+                }
+            }
+        });
+
+        let body = futures::stream::iter(initial_chunks.into_iter().map(Ok)).chain(rest);
+
+        let headers = [(http::header::CONTENT_TYPE, mime_type.essence_str())];
+
+        Ok((headers, Body::from_stream(body)).into_response())
+    }
+}
+
+/// Read the bytes of a streamed image that weren't forwarded to the client, once it has been saved.
+async fn remaining_bytes(
+    manager: &Manager,
+    saved: tokio::sync::oneshot::Receiver<Digest>,
+    received: usize,
+) -> Result<Option<bytes::Bytes>, std::io::Error> {
+    let digest = saved
+        .await
+        .map_err(|_| std::io::Error::other("Download failed"))?;
+
+    let bytes = manager
+        .store()
+        .read_async(digest)
+        .await
+        .map_err(std::io::Error::other)?
+        .ok_or_else(|| std::io::Error::other("Saved image not found"))?;
+
+    Ok((received < bytes.len()).then(|| bytes::Bytes::from(bytes).slice(received..)))
 }
 
-#[derive(serde::Deserialize)]
+#[derive(serde::Deserialize, utoipa::IntoParams)]
+#[into_params(parameter_in = Query)]
 struct MapUrlsOptions {
     style: Option<manager::UrlStyle>,
 }
 
+/// The result of mapping a single image URL.
+#[derive(serde::Serialize, utoipa::ToSchema)]
+#[serde(untagged)]
+enum MappedUrl {
+    /// Local URL for the image
+    Local(String),
+    /// The image URL was rejected
+    Invalid { error: String },
+    /// The image URL isn't allowed by the URL policy
+    Blocked { blocked: String },
+}
+
+#[utoipa::path(
+    post,
+    tag = "images",
+    path = "/urls",
+    params(MapUrlsOptions),
+    request_body = Vec<String>,
+    security((), ("admin_token" = [])),
+    responses(
+        (status = 200, description = "Local URL, validation error, or policy error for each image URL (null if the download failed)", body = Vec<Option<MappedUrl>>),
+        (status = 400, description = "Invalid request body", body = error::ErrorResponse),
+        (status = 401, description = "Missing or invalid token (if mapping requires a JWT scope)", body = error::ErrorResponse),
+        (status = 413, description = "Request body or number of URLs exceeds the configured limit", body = error::ErrorResponse),
+        (status = 422, description = "Request body is not a list of strings", body = error::ErrorResponse),
+        (status = 500, description = "Index error", body = error::ErrorResponse)
+    )
+)]
 async fn map_urls(
     State(manager): State<Arc<Manager>>,
     Query(options): Query<MapUrlsOptions>,
-    Json(urls): Json<Vec<String>>,
-) -> Result<Json<Vec<Option<String>>>, error::MapUrlsError> {
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: http::HeaderMap,
+    urls: Result<Json<Vec<String>>, JsonRejection>,
+) -> Result<Json<Vec<Option<MappedUrl>>>, error::MapUrlsError> {
+    check_mapping(&manager, &headers).await?;
+
+    let Json(urls) = urls?;
+    let origin = manager.forwarded_origin(&headers, peer.ip());
+    let max_urls = manager.urls_limits().max_urls;
+
+    if urls.len() > max_urls {
+        return Err(error::MapUrlsError::TooManyUrls {
+            count: urls.len(),
+            max: max_urls,
+        });
+    }
+
     urls.into_iter()
-        .map(|url| match manager.lookup_status(&url)? {
-            manager::ImageStatus::Downloaded { entry } => Ok(Some(manager.static_url(
-                entry.digest,
-                entry.image_type.into(),
-                options.style.unwrap_or_default(),
-            ))),
-            manager::ImageStatus::Downloading => Ok(Some(manager.request_url(
-                &URL_SAFE_NO_PAD.encode(&url),
-                options.style.unwrap_or_default(),
-            ))),
-            manager::ImageStatus::Failed { timestamp: _ } => Ok(None),
+        .map(|url| {
+            if let Err(error) = validate_url(&url) {
+                return Ok(Some(MappedUrl::Invalid {
+                    error: error.to_string(),
+                }));
+            }
+
+            let url = manager.normalize_url(&url);
+
+            if let Err(error) = manager.check_url(&url) {
+                return Ok(Some(MappedUrl::Blocked {
+                    blocked: error.to_string(),
+                }));
+            }
+
+            match manager.lookup_status(&url)? {
+                manager::ImageStatus::Downloaded { entry, .. } => {
+                    Ok(Some(MappedUrl::Local(manager.static_url(
+                        entry.digest,
+                        entry.image_type.into(),
+                        options.style.unwrap_or_default(),
+                        origin.as_ref(),
+                    ))))
+                }
+                manager::ImageStatus::Downloading => {
+                    Ok(Some(MappedUrl::Local(manager.request_url(
+                        &URL_SAFE_NO_PAD.encode(url.as_bytes()),
+                        options.style.unwrap_or_default(),
+                        origin.as_ref(),
+                    ))))
+                }
+                manager::ImageStatus::Failed { .. }
+                | manager::ImageStatus::Deleted { timestamp: _ } => Ok(None),
+            }
         })
         .collect::<Result<Vec<_>, error::MapUrlsError>>()
         .map(Json)
 }
 
+/// Check that an image URL is one that the service is able to download.
+fn validate_url(url: &str) -> Result<(), error::InvalidUrlError> {
+    let parsed = reqwest::Url::parse(url)
+        .map_err(|error| error::InvalidUrlError::Parse(error.to_string()))?;
+
+    match parsed.scheme() {
+        "http" | "https" => Ok(()),
+        other => Err(error::InvalidUrlError::UnsupportedScheme(other.to_string())),
+    }
+}
+
+#[utoipa::path(
+    get,
+    tag = "images",
+    path = "/gallery",
+    params(gallery::GalleryOptions),
+    responses(
+        (status = 200, description = "HTML page of recently indexed images", content_type = "text/html"),
+        (status = 500, description = "Index error", body = error::ErrorResponse)
+    )
+)]
+async fn gallery(
+    State(manager): State<Arc<Manager>>,
+    Query(options): Query<gallery::GalleryOptions>,
+) -> Result<Html<String>, error::GalleryError> {
+    Ok(Html(gallery::render(&manager, &options)?))
+}
+
+/// Return the bearer token from a request's `Authorization` header.
+fn bearer_token(headers: &http::HeaderMap) -> Option<&str> {
+    headers
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Check the bearer token for an admin request.
+///
+/// The token must be the admin token, or a JWT with the admin scope (if JWTs are accepted).
+async fn check_admin(
+    manager: &Manager,
+    headers: &http::HeaderMap,
+) -> Result<(), error::AdminError> {
+    let token = bearer_token(headers).ok_or(error::AdminError::Unauthorized)?;
+
+    if manager.is_admin(token) {
+        return Ok(());
+    }
+
+    match manager.jwt() {
+        Some(jwt) if has_jwt_scope(jwt, token, &jwt.config().admin_scope).await => Ok(()),
+        _ => Err(error::AdminError::Unauthorized),
+    }
+}
+
+/// Check the bearer token for a mapping request, if mapping requires a scope.
+///
+/// The admin token is also accepted.
+async fn check_mapping(
+    manager: &Manager,
+    headers: &http::HeaderMap,
+) -> Result<(), error::MapUrlsError> {
+    let Some((jwt, scope)) = manager.jwt().and_then(|jwt| {
+        jwt.config()
+            .mapping_scope
+            .as_deref()
+            .map(|scope| (jwt, scope))
+    }) else {
+        return Ok(());
+    };
+
+    let token = bearer_token(headers).ok_or(error::MapUrlsError::Unauthorized)?;
+
+    if manager.is_admin(token) || has_jwt_scope(jwt, token, scope).await {
+        Ok(())
+    } else {
+        Err(error::MapUrlsError::Unauthorized)
+    }
+}
+
+/// Check whether a token is a valid JWT with the given scope (recording its user in the access log).
+async fn has_jwt_scope(jwt: &jwt::Verifier, token: &str, scope: &str) -> bool {
+    match jwt.verify(token).await {
+        Ok(claims) => {
+            if let Some(user) = &claims.sub {
+                access_log::record_user(user);
+            }
+
+            claims.has_scope(scope)
+        }
+        Err(error) => {
+            log::warn!("Rejected JWT: {error}");
+
+            false
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("I/O error")]
     Io(#[from] std::io::Error),
     #[error("Store initialization error")]
     StoreInitialization(#[from] image_scraper::store::InitializationError),
+    #[error("Store error")]
+    Store(#[from] image_scraper::store::Error),
     #[error("Index error")]
     IndexI(#[from] image_scraper_index::db::Error),
+    #[error("Duplicate collection name: {0}")]
+    DuplicateCollection(String),
+    #[error("External URL must be an HTTP or HTTPS URL with a host: {0}")]
+    InvalidExternalUrl(String),
+    #[error("HTTP client error")]
+    HttpClient(#[from] reqwest::Error),
+    #[error(
+        "A proxy can't be used when addresses are checked (use --allow-private-addresses without --deny-address)"
+    )]
+    ProxyWithAddressChecks,
+    #[cfg(feature = "otel")]
+    #[error("Telemetry error")]
+    Telemetry(#[from] telemetry::Error),
 }
 
 #[derive(Debug, Parser)]
@@ -225,21 +1504,391 @@ struct Opts {
 
 #[derive(Debug, Parser)]
 enum Command {
-    Serve {
-        #[clap(long, default_value = "/")]
-        base: String,
-        #[clap(long, default_value = "0.0.0.0:3000")]
-        server: String,
-        #[clap(long)]
-        store: PathBuf,
-        #[clap(long)]
-        prefix: PrefixPartLengths,
-        #[clap(long)]
-        index: PathBuf,
-        #[clap(long, default_value = "8192")]
-        buffer: usize,
-        /// Time to wait between image requests in milliseconds
-        #[clap(long, default_value = "500")]
-        delay: u64,
-    },
+    Serve(ServeOpts),
+}
+
+#[derive(Debug, Parser)]
+#[clap(group(
+    clap::ArgGroup::new("sources")
+        .required(true)
+        .multiple(true)
+        .args(["store", "collections"])
+))]
+#[allow(clippy::struct_excessive_bools)]
+struct ServeOpts {
+    #[clap(long, default_value = "/")]
+    base: String,
+    /// Address to listen on, optionally followed by the route groups served there (e.g.
+    /// 127.0.0.1:3001=admin, with groups static, api, and admin)
+    #[clap(long = "server", default_value = "0.0.0.0:3000")]
+    servers: Vec<listener::Listener>,
+    /// Store for the default collection (served directly under the base path)
+    #[clap(long, requires_all = ["prefix", "index"])]
+    store: Option<PathBuf>,
+    #[clap(long, requires = "store")]
+    prefix: Option<PrefixPartLengths>,
+    #[clap(long, requires = "store")]
+    index: Option<PathBuf>,
+    /// Additional collection served under its name (name=NAME,store=DIR,prefix=LENGTHS,index=DIR)
+    #[clap(long = "collection")]
+    collections: Vec<collection::Collection>,
+    /// Key scheme for newly created indexes (url or reversed-host)
+    #[clap(long)]
+    index_key_scheme: Option<image_scraper_index::db::KeyScheme>,
+    #[clap(long, default_value = "8192")]
+    buffer: usize,
+    /// Time to wait between image requests to the same host in milliseconds
+    #[clap(long, default_value = "500")]
+    delay: u64,
+    /// Bearer token for admin endpoints (which reject all requests if neither this nor JWTs are accepted)
+    #[clap(long, env = "IMAGE_SCRAPER_ADMIN_TOKEN")]
+    admin_token: Option<String>,
+    /// Issuer of JWTs that are accepted as bearer tokens (requires --jwt-audience and --jwks-url)
+    #[clap(long, requires_all = ["jwt_audience", "jwks_url"])]
+    jwt_issuer: Option<String>,
+    /// Audience that accepted JWTs must be issued for
+    #[clap(long, requires = "jwt_issuer")]
+    jwt_audience: Option<String>,
+    /// URL of the JSON Web Key Set used to verify JWTs
+    #[clap(long, requires = "jwt_issuer")]
+    jwks_url: Option<String>,
+    /// Scope that allows JWTs to be used for admin endpoints
+    #[clap(long, default_value = "admin")]
+    jwt_admin_scope: String,
+    /// Scope that JWTs must have for mapping requests (which don't need a token if not set)
+    #[clap(long, requires = "jwt_issuer")]
+    jwt_mapping_scope: Option<String>,
+    /// Forward newly downloaded images to the client as they arrive (not available when images are transformed before they are saved)
+    #[clap(long, conflicts_with_all = ["strip_metadata", "reencode_above"])]
+    stream: bool,
+    /// Start in maintenance mode, in which downloads and other changes are refused until it is turned off with the admin endpoint
+    #[clap(long)]
+    maintenance: bool,
+    /// Log output format (access logs are emitted at the info level)
+    #[clap(long, value_enum, default_value_t)]
+    log_format: access_log::LogFormat,
+    /// Maximum number of URLs accepted in a single mapping request
+    #[clap(long, default_value = "100000")]
+    max_urls: usize,
+    /// Maximum size in bytes of a mapping request body
+    #[clap(long, default_value = "16777216")]
+    max_body_bytes: usize,
+    /// Serve an HTML gallery of recently indexed images at /gallery
+    #[clap(long)]
+    gallery: bool,
+    /// Directory for generated thumbnails (thumbnails are served at /thumb if provided)
+    #[clap(long)]
+    thumbnails: Option<PathBuf>,
+    /// Interval in seconds between background retries of failed downloads
+    #[clap(long)]
+    retry_interval: Option<u64>,
+    /// Age in seconds after which images are downloaded again
+    #[clap(long)]
+    stale_after: Option<u64>,
+    /// Age in seconds after which images from a domain are downloaded again (DOMAIN=SECONDS)
+    #[clap(long)]
+    stale_after_domain: Vec<image_scraper::refresh::DomainMaxAge>,
+    /// Whether stale images are served while they are downloaded again, or after
+    #[clap(long, value_enum, default_value_t)]
+    stale_mode: manager::StaleMode,
+    /// Validate stored images in the background, waiting this many milliseconds between files
+    #[clap(long)]
+    scrub_interval: Option<u64>,
+    /// Remove corrupt images found while scrubbing and download them again
+    #[clap(long, requires = "scrub_interval")]
+    scrub_redownload: bool,
+    /// Directory to write periodic backups of the index to
+    #[clap(long)]
+    snapshot_dir: Option<PathBuf>,
+    /// Interval in seconds between index backups
+    #[clap(long, default_value = "3600", requires = "snapshot_dir")]
+    snapshot_interval: u64,
+    /// Number of index backups to keep
+    #[clap(long, default_value = "24", requires = "snapshot_dir")]
+    snapshot_keep: std::num::NonZeroUsize,
+    /// Header sent with downloads from matching hosts (e.g. `*.example.com=Referer: https://example.com/`)
+    #[clap(long = "header-template")]
+    header_templates: Vec<HeaderTemplate>,
+    /// Command to run after each image is saved (with the file path and image URL as arguments)
+    #[clap(long = "hook-command")]
+    hook_commands: Vec<PathBuf>,
+    /// Scheme, host, and port used for full URLs (e.g. <https://images.example.com>)
+    #[clap(long)]
+    external_url: Option<reqwest::Url>,
+    /// Proxy address whose X-Forwarded-Proto and X-Forwarded-Host headers are used for full URLs
+    #[clap(long = "trusted-proxy")]
+    trusted_proxies: Vec<IpAddr>,
+    /// Number of waiting downloads at which new requests are rejected (defaults to the buffer size)
+    #[clap(long)]
+    queue_high_water_mark: Option<usize>,
+    /// Time limit in seconds for image requests (the download continues if it is exceeded)
+    #[clap(long)]
+    request_timeout: Option<u64>,
+    /// Time limit in seconds for all other requests
+    #[clap(long)]
+    timeout: Option<u64>,
+    /// Maximum total bandwidth in bytes per second for serving static images
+    #[clap(long)]
+    egress_limit: Option<NonZeroU64>,
+    /// Maximum bandwidth in bytes per second for serving each static image response
+    #[clap(long)]
+    egress_connection_limit: Option<NonZeroU64>,
+    /// Query parameter removed from image URLs before they are indexed (a trailing * matches any suffix)
+    #[clap(long = "strip-param")]
+    strip_params: Vec<String>,
+    /// Store images smaller than this many bytes in pack files instead of individual files
+    #[clap(long)]
+    pack_threshold: Option<u64>,
+    /// Flush each saved image to disk before the download completes
+    #[clap(long)]
+    fsync: bool,
+    /// Store images in uncompressed formats (BMP, PBM, PGM, PPM, and TIFF) zstd-compressed
+    #[clap(long)]
+    compress: bool,
+    /// Record the URL, time, content type, and content length of each new image in its store
+    #[clap(long)]
+    metadata: bool,
+    /// Digest kind for new stores (existing stores must already use this kind)
+    #[clap(long)]
+    digest_kind: Option<DigestKind>,
+    /// Evict images to keep each store's total size under this many bytes
+    #[clap(long)]
+    max_store_bytes: Option<u64>,
+    /// Evict images to keep the number of images in each store under this limit
+    #[clap(long)]
+    max_store_files: Option<u64>,
+    /// Which images are evicted first when a store is over its quota (lru or oldest)
+    #[clap(long, default_value = "lru")]
+    eviction: Eviction,
+    /// Keep up to this many bytes of recently served images in memory
+    #[clap(long)]
+    read_cache_size: Option<usize>,
+    /// Only keep images up to this many bytes in the read cache
+    #[clap(long, default_value = "65536", requires = "read_cache_size")]
+    read_cache_max_image_size: usize,
+    /// Keep up to this many bytes of recently served thumbnails in memory
+    #[clap(long, requires = "thumbnails")]
+    variant_cache_size: Option<usize>,
+    /// Only keep thumbnails up to this many bytes in the thumbnail memory cache
+    #[clap(long, default_value = "262144", requires = "variant_cache_size")]
+    variant_cache_max_image_size: usize,
+    /// Reject downloaded images that are truncated or structurally invalid
+    #[clap(long)]
+    reject_corrupt: bool,
+    /// Remove metadata (EXIF, XMP, comments, and PNG text chunks) from JPEG and PNG images before saving them
+    #[clap(long)]
+    strip_metadata: bool,
+    /// Re-encode JPEG and PNG images larger than this many bytes before saving them (if that makes them smaller)
+    #[clap(long)]
+    reencode_above: Option<u64>,
+    /// JPEG quality (1-100) used when re-encoding images
+    #[clap(long, default_value = "85", requires = "reencode_above")]
+    reencode_quality: u8,
+    /// Maximum number of idle connections kept open for each host
+    #[clap(long)]
+    pool_max_idle_per_host: Option<usize>,
+    /// Time in seconds after which idle connections are closed
+    #[clap(long)]
+    pool_idle_timeout: Option<u64>,
+    /// HTTP versions used for downloads (negotiate, http1, or http2)
+    #[clap(long, default_value = "negotiate")]
+    http_version: image_scraper::client::HttpVersion,
+    /// IP versions used for downloads (any, ipv4, ipv6, prefer-ipv4, or prefer-ipv6)
+    #[clap(long, default_value = "any")]
+    ip_version: image_scraper::client::IpVersion,
+    /// Time in seconds allowed for connecting to a host (divided between its addresses)
+    #[clap(long)]
+    connect_timeout: Option<u64>,
+    /// Time in seconds allowed for each read from a connection (so that stalled downloads fail)
+    #[clap(long)]
+    read_timeout: Option<u64>,
+    /// User-Agent header sent with each outgoing request
+    #[clap(long)]
+    user_agent: Option<String>,
+    /// Proxy URL used for outgoing requests (by default proxies are taken from environment
+    /// variables such as `HTTPS_PROXY`), which requires --allow-private-addresses, since the
+    /// addresses that hosts resolve to can't be checked
+    #[clap(long)]
+    proxy: Option<String>,
+    /// Maximum number of redirects followed for each outgoing request (10 by default)
+    #[clap(long)]
+    max_redirects: Option<usize>,
+    /// URL scheme that images may be downloaded with (http and https if none are given)
+    #[clap(long = "allow-scheme")]
+    allowed_schemes: Vec<String>,
+    /// Only download images from hosts that match a pattern (e.g. "*.example.com")
+    #[clap(long = "allow-host")]
+    allowed_hosts: Vec<HostPattern>,
+    /// Never download images from hosts that match a pattern
+    #[clap(long = "deny-host")]
+    denied_hosts: Vec<HostPattern>,
+    /// Never connect to addresses in a range (e.g. "203.0.113.0/24")
+    #[clap(long = "deny-address")]
+    denied_ranges: Vec<IpRange>,
+    /// Allow downloads from loopback, private, link-local, and other non-public addresses
+    #[clap(long)]
+    allow_private_addresses: bool,
+    /// Only download images whose host and path match a glob pattern (e.g. "*.fbcdn.net/v/**")
+    #[clap(long = "allow-url")]
+    allowed_url_patterns: Vec<UrlPattern>,
+    /// Never download images whose host and path match a glob pattern
+    #[clap(long = "deny-url")]
+    denied_url_patterns: Vec<UrlPattern>,
+    /// Download large images in ranges of this many bytes (from servers that support ranges)
+    #[clap(long)]
+    chunk_size: Option<std::num::NonZeroU64>,
+    /// Maximum number of concurrent range requests for each chunked download
+    #[clap(long, default_value = "4", requires = "chunk_size")]
+    chunk_concurrency: std::num::NonZeroUsize,
+    /// OTLP/HTTP endpoint that spans are exported to (e.g. <http://localhost:4318/v1/traces>)
+    #[cfg(feature = "otel")]
+    #[clap(long)]
+    otlp_endpoint: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Error, Opts, Shared};
+    use crate::downloader::Downloader;
+    use crate::listener::RouteGroup;
+    use crate::{routes, testing};
+    use axum::{Router, body::Body, routing::get};
+    use clap::{CommandFactory, Parser};
+    use futures::StreamExt;
+    use http::StatusCode;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    #[test]
+    fn test_opts() {
+        Opts::command().debug_assert();
+    }
+
+    #[tokio::test]
+    async fn test_proxy_requires_private_addresses() {
+        let shared = |args: &[&str]| {
+            let opts = Opts::try_parse_from(
+                [
+                    "image-scraper-service",
+                    "serve",
+                    "--store",
+                    "store",
+                    "--prefix",
+                    "2",
+                    "--index",
+                    "index",
+                    "--proxy",
+                    "http://127.0.0.1:3128",
+                ]
+                .iter()
+                .chain(args),
+            )
+            .unwrap();
+            let Command::Serve(opts) = opts.command;
+
+            Shared::new(&opts, Arc::new(Downloader::new(8, 8, Duration::ZERO, None)))
+        };
+
+        assert!(matches!(shared(&[]), Err(Error::ProxyWithAddressChecks)));
+        assert!(shared(&["--allow-private-addresses"]).is_ok());
+        assert!(matches!(
+            shared(&[
+                "--allow-private-addresses",
+                "--deny-address",
+                "203.0.113.0/24"
+            ]),
+            Err(Error::ProxyWithAddressChecks)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stream_image_slow_client() {
+        // Many more chunks than the streaming buffer holds, sent slowly enough not to be combined.
+        let chunks = (0..256_u16)
+            .map(|i| bytes::Bytes::from(i.to_be_bytes().repeat(512)))
+            .collect::<Vec<_>>();
+        let image = [
+            bytes::Bytes::from_static(&testing::PNG_HEADER),
+            chunks.concat().into(),
+        ]
+        .concat();
+
+        let origin = Router::new().route(
+            "/image.png",
+            get(move || {
+                let chunks = chunks.clone();
+
+                async move {
+                    Body::from_stream(
+                        futures::stream::iter(
+                            std::iter::once(bytes::Bytes::from_static(&testing::PNG_HEADER))
+                                .chain(chunks),
+                        )
+                        .then(|chunk| async move {
+                            tokio::time::sleep(Duration::from_millis(1)).await;
+
+                            Ok::<_, std::io::Error>(chunk)
+                        }),
+                    )
+                }
+            }),
+        );
+        let address = testing::serve(origin).await;
+
+        let (_dir, manager) = testing::manager();
+        let manager = Arc::new(manager.with_streaming(true));
+        let router = routes::router("/", manager.clone(), &RouteGroup::ALL);
+
+        let url = format!("http://{address}/image.png");
+        let response = router
+            .oneshot(
+                http::Request::get(testing::request_path(&url))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[http::header::CONTENT_TYPE], "image/png");
+
+        // The client doesn't read anything until the download has been saved and indexed.
+        while manager.index.lookup(&url).unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+
+        assert_eq!(body, image);
+    }
+
+    #[tokio::test]
+    async fn test_request_image() {
+        let image = [testing::PNG_HEADER.as_slice(), b"a"].concat();
+        let origin = Router::new().route("/a.png", get(move || std::future::ready(image.clone())));
+        let address = testing::serve(origin).await;
+
+        let (_dir, manager) = testing::manager();
+        let manager = Arc::new(manager);
+        let router = routes::router("/", manager.clone(), &RouteGroup::ALL);
+
+        let url = format!("http://{address}/a.png");
+        let (status, body) =
+            testing::get(router.clone(), &testing::request_path(&url), false).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, [testing::PNG_HEADER.as_slice(), b"a"].concat());
+        assert_eq!(manager.index.lookup(&url).unwrap().len(), 1);
+        assert!(manager.index.queued().unwrap().is_empty());
+
+        // A URL that is already queued is being downloaded by another request.
+        let queued = format!("http://{address}/b.png");
+        manager.index.enqueue(&queued, chrono::Utc::now()).unwrap();
+
+        let (status, _) = testing::get(router, &testing::request_path(&queued), false).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
 }
@@ -1,31 +1,40 @@
-use chrono::{DateTime, Utc};
-use futures::future::TryFutureExt;
-use image_scraper::{client::Client, image_type::ImageType, store::Store};
-use image_scraper_index::{Entry, db::Database};
-use std::sync::Arc;
-use std::{
-    path::{Path, PathBuf},
-    time::Duration,
+use crate::downloader::{Chunks, ClientResult, Downloader, StreamResult};
+use crate::egress::EgressLimiter;
+use crate::jwt::Verifier;
+use crate::scrub::ScrubStatus;
+use crate::thumbnail::ThumbnailCache;
+use chrono::{DateTime, NaiveDate, NaiveTime, TimeDelta, Utc};
+use image_scraper::{
+    client::{ChunkedDownloads, Client, Revalidation},
+    digest::Digest,
+    header_template::HeaderTemplates,
+    history::{FailureKind, Validators},
+    hook::Hooks,
+    image_type::ImageType,
+    refresh::RefreshPolicy,
+    store::{self, Store, StoreBackend},
+    url_norm::Normalizer,
+    url_policy::UrlPolicy,
 };
-use tokio::{
-    sync::{
-        Mutex,
-        mpsc::{Receiver, Sender},
-        oneshot,
-    },
-    task::JoinHandle,
+use image_scraper_index::{
+    Entry, Missing,
+    db::{Cursor, Database, KeyScheme},
 };
-
-pub type ClientResult = Result<
-    Result<(bytes::Bytes, image_scraper::store::Action), http::StatusCode>,
-    image_scraper::client::Error,
->;
+use std::borrow::Cow;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
 
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct UrlConfig {
     pub secure: bool,
     pub server: String,
     pub base_path: String,
+    /// Proxies whose forwarded headers are used for full URLs
+    pub trusted_proxies: Vec<IpAddr>,
 }
 
 impl UrlConfig {
@@ -35,11 +44,58 @@ impl UrlConfig {
             secure,
             server,
             base_path,
+            trusted_proxies: vec![],
         }
     }
+
+    #[must_use]
+    pub fn with_trusted_proxies(self, trusted_proxies: Vec<IpAddr>) -> Self {
+        Self {
+            trusted_proxies,
+            ..self
+        }
+    }
+}
+
+/// The scheme and host that a client used to reach the service (possibly through a proxy).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Origin {
+    pub secure: bool,
+    pub server: String,
+}
+
+impl Origin {
+    /// Read the origin from `X-Forwarded-Proto` and `X-Forwarded-Host` headers.
+    ///
+    /// Only the first value of each header is used, and the host is ignored if it contains any
+    /// characters that aren't valid in a host and port.
+    fn from_forwarded_headers(headers: &http::HeaderMap, default_secure: bool) -> Option<Self> {
+        let first_value = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(',').next())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+        };
+
+        let server = first_value("x-forwarded-host").filter(|server| {
+            server.bytes().all(|byte| {
+                byte.is_ascii_alphanumeric() || matches!(byte, b'.' | b'-' | b':' | b'[' | b']')
+            })
+        })?;
+
+        let secure = first_value("x-forwarded-proto")
+            .map_or(default_secure, |proto| proto.eq_ignore_ascii_case("https"));
+
+        Some(Self {
+            secure,
+            server: server.to_string(),
+        })
+    }
 }
 
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, serde::Deserialize, utoipa::ToSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum UrlStyle {
     #[default]
@@ -48,67 +104,550 @@ pub enum UrlStyle {
     Relative,
 }
 
-pub struct Manager {
+/// Limits on the size of requests to map URLs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct UrlsLimits {
+    pub max_urls: usize,
+    pub max_body_bytes: usize,
+}
+
+impl Default for UrlsLimits {
+    fn default() -> Self {
+        Self {
+            max_urls: 100_000,
+            max_body_bytes: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// Time limits for handling requests (no limit is applied if a value is not set).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Timeouts {
+    /// Limit for image requests (which may wait for a download)
+    pub request: Option<Duration>,
+    /// Limit for all other endpoints
+    pub default: Option<Duration>,
+}
+
+/// How image requests are handled for images that are stale (according to the refresh policy).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, clap::ValueEnum)]
+pub enum StaleMode {
+    /// Serve the stored image immediately, and download it again in the background
+    #[default]
+    Revalidate,
+    /// Download the image again before responding
+    Wait,
+}
+
+/// Maximum number of index records examined by a single search.
+const SEARCH_SCAN_LIMIT: usize = 100_000;
+
+type IndexRecords<'a> = Box<
+    dyn Iterator<Item = Result<(String, Result<Entry, Missing>), image_scraper_index::db::Error>>
+        + 'a,
+>;
+
+/// Filters for searching the index (at least one should be provided).
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SearchQuery {
+    /// Only match URLs with exactly this host (and optional port)
+    pub domain: Option<String>,
+    /// Only match URLs containing this string
+    pub substring: Option<String>,
+}
+
+impl SearchQuery {
+    /// The URL prefixes to iterate over (every URL if no domain is provided).
+    fn prefixes(&self) -> Vec<String> {
+        self.domain.as_ref().map_or_else(
+            || vec![String::new()],
+            |domain| vec![format!("http://{domain}"), format!("https://{domain}")],
+        )
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        // The domain must not continue past the host (e.g. `example.com.evil.org`).
+        let domain_matches = self.domain.as_ref().is_none_or(|domain| {
+            ["http://", "https://"].iter().any(|scheme| {
+                url.strip_prefix(scheme)
+                    .and_then(|rest| rest.strip_prefix(domain.as_str()))
+                    .is_some_and(|rest| {
+                        rest.chars()
+                            .next()
+                            .is_none_or(|next| matches!(next, '/' | ':' | '?' | '#'))
+                    })
+            })
+        });
+
+        domain_matches
+            && self
+                .substring
+                .as_ref()
+                .is_none_or(|substring| url.contains(substring.as_str()))
+    }
+}
+
+/// A collection of images in a store (by default on the file system), together with its index.
+pub struct Manager<B = Store> {
     url_config: UrlConfig,
     pub index: Database,
-    store: Store,
-    request_sender: Sender<Option<(String, oneshot::Sender<ClientResult>)>>,
-    request_receiver_handle: Arc<Mutex<Option<JoinHandle<()>>>>,
+    store: B,
+    client: Arc<Client<B>>,
+    downloader: Arc<Downloader>,
+    admin_token: Option<String>,
+    jwt: Option<Arc<Verifier>>,
+    streaming: bool,
+    urls_limits: UrlsLimits,
+    timeouts: Timeouts,
+    gallery: bool,
+    thumbnails: Option<ThumbnailCache>,
+    scrub_status: Option<Mutex<ScrubStatus>>,
+    egress: EgressLimiter,
+    stale_mode: StaleMode,
+    maintenance: AtomicBool,
+}
+
+/// A stored image, together with details from the index.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct StoredImage {
+    /// MD5 digest of the image
+    pub digest: String,
+    /// Image extension (empty if the image has not been indexed)
+    #[schema(value_type = String)]
+    pub image_type: ImageType,
+    /// File size in bytes
+    pub size: u64,
+    /// Timestamp of the earliest index entry for the image
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub first_seen: Option<DateTime<Utc>>,
+}
+
+/// An index entry, together with the URL it was added for.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct IndexedImage {
+    /// Image URL
+    pub url: String,
+    /// MD5 digest of the image
+    pub digest: String,
+    /// Image extension
+    #[schema(value_type = String)]
+    pub image_type: ImageType,
+    /// Time the entry was added
+    #[schema(value_type = String, format = DateTime)]
+    pub timestamp: DateTime<Utc>,
+}
+
+/// The kind of an index record.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, serde::Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordKind {
+    Downloaded,
+    Failed,
+    Deleted,
+}
+
+/// A single index record (of any kind), together with the URL it was added for.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct IndexRecord {
+    /// Image URL
+    pub url: String,
+    pub kind: RecordKind,
+    /// MD5 digest of the image (for downloaded and deleted records)
+    pub digest: Option<String>,
+    /// Image extension (for downloaded records)
+    #[schema(value_type = Option<String>)]
+    pub image_type: Option<ImageType>,
+    /// HTTP status code (for failed records, if known)
+    pub status: Option<u16>,
+    /// Kind of failure (for failed records, if known)
+    #[schema(value_type = Option<String>)]
+    pub failure: Option<FailureKind>,
+    /// Time the record was added
+    #[schema(value_type = String, format = DateTime)]
+    pub timestamp: DateTime<Utc>,
+}
+
+impl IndexRecord {
+    fn new(url: String, record: Result<Entry, Missing>) -> Self {
+        match record {
+            Ok(entry) => Self {
+                url,
+                kind: RecordKind::Downloaded,
+                digest: Some(format!("{:x}", entry.digest)),
+                image_type: Some(entry.image_type.into()),
+                status: None,
+                failure: None,
+                timestamp: entry.timestamp,
+            },
+            Err(Missing::Failed {
+                timestamp,
+                status,
+                kind,
+            }) => Self {
+                url,
+                kind: RecordKind::Failed,
+                digest: None,
+                image_type: None,
+                status,
+                failure: kind,
+                timestamp,
+            },
+            Err(Missing::Deleted { timestamp, digest }) => Self {
+                url,
+                kind: RecordKind::Deleted,
+                digest: Some(format!("{digest:x}")),
+                image_type: None,
+                status: None,
+                failure: None,
+                timestamp,
+            },
+        }
+    }
 }
 
 pub enum ImageStatus {
-    Downloaded { entry: Entry },
+    Downloaded {
+        entry: Entry,
+        /// Whether the image should be downloaded again (according to the refresh policy)
+        stale: bool,
+    },
     Downloading,
-    Failed { timestamp: DateTime<Utc> },
+    Failed {
+        timestamp: DateTime<Utc>,
+        status: Option<u16>,
+        kind: Option<FailureKind>,
+    },
+    Deleted {
+        timestamp: DateTime<Utc>,
+    },
+}
+
+impl ImageStatus {
+    /// Determine the status of a URL from its index records (sorted by descending timestamp).
+    pub fn from_records(records: &[Result<Entry, Missing>]) -> Self {
+        // A tombstone hides any earlier entries.
+        let status = records.iter().find_map(|result| match result {
+            Ok(entry) => Some(Self::Downloaded {
+                entry: *entry,
+                stale: false,
+            }),
+            Err(Missing::Deleted { timestamp, .. }) => Some(Self::Deleted {
+                timestamp: *timestamp,
+            }),
+            Err(Missing::Failed { .. }) => None,
+        });
+
+        status.unwrap_or_else(|| match records.first() {
+            Some(Err(Missing::Failed {
+                timestamp,
+                status,
+                kind,
+            })) => Self::Failed {
+                timestamp: *timestamp,
+                status: *status,
+                kind: *kind,
+            },
+            // We've already handled all other cases above.
+            _ => Self::Downloading,
+        })
+    }
 }
 
-impl Manager {
+impl<B: StoreBackend> Manager<B> {
     pub fn new<I: AsRef<Path>>(
         url_config: UrlConfig,
-        store: Store,
+        store: B,
         index: I,
-        request_buffer_size: usize,
-        delay: Duration,
+        key_scheme: Option<KeyScheme>,
+        downloader: Arc<Downloader>,
     ) -> Result<Self, image_scraper_index::db::Error> {
         let client = Arc::new(Client::new(store.clone()));
-        let index = Database::open(index)?;
-
-        let (request_sender, request_receiver) = tokio::sync::mpsc::channel(request_buffer_size);
+        let index = match key_scheme {
+            Some(key_scheme) => Database::open_with_key_scheme(index, key_scheme)?,
+            None => Database::open(index)?,
+        };
 
         Ok(Self {
             url_config,
             store,
             index,
-            request_sender,
-            request_receiver_handle: Arc::new(Mutex::new(Some(Self::handle_requests(
-                client,
-                delay,
-                request_receiver,
-            )))),
+            client,
+            downloader,
+            admin_token: None,
+            jwt: None,
+            streaming: false,
+            urls_limits: UrlsLimits::default(),
+            timeouts: Timeouts::default(),
+            gallery: false,
+            thumbnails: None,
+            scrub_status: None,
+            egress: EgressLimiter::default(),
+            stale_mode: StaleMode::default(),
+            maintenance: AtomicBool::new(false),
+        })
+    }
+
+    /// Set the HTTP client used for downloads (which may be shared with other collections).
+    #[must_use]
+    pub fn with_http_client(self, http_client: reqwest::Client) -> Self {
+        Self {
+            client: Arc::new((*self.client).clone().with_http_client(http_client)),
+            ..self
+        }
+    }
+
+    /// Download large images from servers that support range requests in concurrent chunks.
+    #[must_use]
+    pub fn with_chunked_downloads(self, chunked_downloads: Option<ChunkedDownloads>) -> Self {
+        match chunked_downloads {
+            Some(chunked_downloads) => Self {
+                client: Arc::new(
+                    (*self.client)
+                        .clone()
+                        .with_chunked_downloads(chunked_downloads),
+                ),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    /// Set the hooks that are run after each image is saved.
+    #[must_use]
+    pub fn with_hooks(self, hooks: Hooks) -> Self {
+        Self {
+            client: Arc::new((*self.client).clone().with_hooks(hooks)),
+            ..self
+        }
+    }
+
+    /// Set the headers that are added to download requests for specific hosts.
+    #[must_use]
+    pub fn with_header_templates(self, header_templates: HeaderTemplates) -> Self {
+        Self {
+            client: Arc::new(
+                (*self.client)
+                    .clone()
+                    .with_header_templates(header_templates),
+            ),
+            ..self
+        }
+    }
+
+    /// Set the policy that determines when downloaded images are stale.
+    ///
+    /// How stale images are handled depends on the [`StaleMode`].
+    #[must_use]
+    pub fn with_refresh_policy(self, refresh_policy: RefreshPolicy) -> Self {
+        Self {
+            client: Arc::new((*self.client).clone().with_refresh_policy(refresh_policy)),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn with_stale_mode(self, stale_mode: StaleMode) -> Self {
+        Self { stale_mode, ..self }
+    }
+
+    pub const fn stale_mode(&self) -> StaleMode {
+        self.stale_mode
+    }
+
+    /// Set the policy that image URLs (and the addresses their hosts resolve to) are checked against.
+    ///
+    /// The HTTP client should be built with the same policy.
+    #[must_use]
+    pub fn with_url_policy(self, url_policy: Arc<UrlPolicy>) -> Self {
+        Self {
+            client: Arc::new((*self.client).clone().with_url_policy(url_policy)),
+            ..self
+        }
+    }
+
+    /// Check whether an image URL may be downloaded.
+    pub fn check_url(&self, image_url: &str) -> Result<(), image_scraper::url_policy::Error> {
+        self.client
+            .url_policy()
+            .map_or(Ok(()), |url_policy| url_policy.check(image_url))
+    }
+
+    /// Set the normalizer that is applied to image URLs before they are indexed or downloaded.
+    #[must_use]
+    pub fn with_normalizer(self, normalizer: Normalizer) -> Self {
+        Self {
+            client: Arc::new((*self.client).clone().with_normalizer(normalizer.clone())),
+            index: self.index.with_normalizer(normalizer),
+            ..self
+        }
+    }
+
+    #[must_use]
+    pub fn with_admin_token(self, admin_token: Option<String>) -> Self {
+        Self {
+            admin_token,
+            ..self
+        }
+    }
+
+    /// Accept JWTs as bearer tokens (in addition to the admin token).
+    #[must_use]
+    pub fn with_jwt(self, jwt: Option<Arc<Verifier>>) -> Self {
+        Self { jwt, ..self }
+    }
+
+    pub fn jwt(&self) -> Option<&Verifier> {
+        self.jwt.as_deref()
+    }
+
+    #[must_use]
+    pub fn with_streaming(self, streaming: bool) -> Self {
+        Self { streaming, ..self }
+    }
+
+    pub const fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    #[must_use]
+    pub fn with_urls_limits(self, urls_limits: UrlsLimits) -> Self {
+        Self {
+            urls_limits,
+            ..self
+        }
+    }
+
+    pub const fn urls_limits(&self) -> UrlsLimits {
+        self.urls_limits
+    }
+
+    #[must_use]
+    pub fn with_timeouts(self, timeouts: Timeouts) -> Self {
+        Self { timeouts, ..self }
+    }
+
+    pub const fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    #[must_use]
+    pub fn with_gallery(self, gallery: bool) -> Self {
+        Self { gallery, ..self }
+    }
+
+    pub const fn gallery(&self) -> bool {
+        self.gallery
+    }
+
+    /// Set the bandwidth limits for static images.
+    #[must_use]
+    pub fn with_egress(self, egress: EgressLimiter) -> Self {
+        Self { egress, ..self }
+    }
+
+    pub const fn egress(&self) -> &EgressLimiter {
+        &self.egress
+    }
+
+    #[must_use]
+    pub fn with_thumbnails(self, thumbnails: Option<ThumbnailCache>) -> Self {
+        Self { thumbnails, ..self }
+    }
+
+    pub const fn thumbnails(&self) -> Option<&ThumbnailCache> {
+        self.thumbnails.as_ref()
+    }
+
+    /// Enable tracking of background scrubbing progress.
+    #[must_use]
+    pub fn with_scrub_status(self, enabled: bool) -> Self {
+        Self {
+            scrub_status: enabled.then(|| Mutex::new(ScrubStatus::default())),
+            ..self
+        }
+    }
+
+    /// Return the current scrubbing progress, if scrubbing is enabled.
+    pub fn scrub_status(&self) -> Option<ScrubStatus> {
+        self.scrub_status.as_ref().map(|status| {
+            status
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .clone()
         })
     }
 
-    pub async fn close(&self) -> Result<(), super::error::ShutdownError> {
-        self.request_sender.send(None).await?;
-        let mut handle = self.request_receiver_handle.lock().await;
+    pub fn update_scrub_status<F: FnOnce(&mut ScrubStatus)>(&self, f: F) {
+        if let Some(status) = &self.scrub_status {
+            f(&mut status
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner));
+        }
+    }
 
-        if let Some(handle) = handle.take() {
-            handle.await?;
+    /// Start in maintenance mode, in which no downloads are started and nothing is written to the
+    /// store or the index.
+    #[must_use]
+    pub fn with_maintenance(self, maintenance: bool) -> Self {
+        Self {
+            maintenance: AtomicBool::new(maintenance),
+            ..self
         }
+    }
+
+    pub fn maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::SeqCst)
+    }
+
+    /// Turn maintenance mode on or off, returning whether it was previously on.
+    pub fn set_maintenance(&self, maintenance: bool) -> bool {
+        self.maintenance.swap(maintenance, Ordering::SeqCst)
+    }
 
-        Ok(())
+    pub const fn store(&self) -> &B {
+        &self.store
     }
 
     pub fn request(
         &self,
         image_url: &str,
     ) -> impl Future<Output = Result<ClientResult, super::error::ChannelError>> {
-        let (sender, receiver) = oneshot::channel();
+        self.downloader.request(self.client.clone(), image_url)
+    }
+
+    /// Request a download that will only be started when no other requests are waiting.
+    pub fn request_background(
+        &self,
+        image_url: &str,
+    ) -> impl Future<Output = Result<ClientResult, super::error::ChannelError>> {
+        self.downloader
+            .request_background(self.client.clone(), image_url)
+    }
+
+    /// Request a download whose chunks will be forwarded as they arrive.
+    ///
+    /// The receiver for the final result will only resolve after the chunk receiver is closed.
+    pub fn request_stream(
+        &self,
+        image_url: &str,
+    ) -> Result<(Chunks, oneshot::Receiver<StreamResult>), super::error::ChannelError> {
+        self.downloader
+            .request_stream(self.client.clone(), image_url)
+    }
 
-        self.request_sender
-            .send(Some((image_url.to_string(), sender)))
-            .map_err(super::error::ChannelError::from)
-            .and_then(|()| receiver.map_err(super::error::ChannelError::from))
+    /// Request an image again using validators from a previous response, bypassing the download
+    /// queue.
+    pub async fn revalidate(
+        &self,
+        image_url: &str,
+        validators: &Validators,
+    ) -> Result<Result<Revalidation, http::StatusCode>, image_scraper::client::Error> {
+        self.client.revalidate(image_url, validators).await
+    }
+
+    /// Normalize an image URL, returning it unchanged if it can't be normalized.
+    pub fn normalize_url<'a>(&self, image_url: &'a str) -> Cow<'a, str> {
+        self.index.normalizer().normalize_or_keep(image_url)
     }
 
     pub fn lookup_status(
@@ -117,114 +656,340 @@ impl Manager {
     ) -> Result<ImageStatus, image_scraper_index::db::Error> {
         let results = self.index.lookup(image_url)?;
 
-        if results.is_empty() {
-            Ok(ImageStatus::Downloading)
-        } else {
-            let entry = results.iter().find_map(|result| result.ok());
-
-            entry.map_or_else(
-                || {
-                    // We should always find a value because of the empty check above.
-                    let timestamp = results
-                        .iter()
-                        .find_map(|result| result.err())
-                        .unwrap_or_default();
-
-                    Ok(ImageStatus::Failed { timestamp })
-                },
-                |entry| Ok(ImageStatus::Downloaded { entry }),
+        Ok(self.status(image_url, &results))
+    }
+
+    /// Determine the status of a URL from its index records (sorted by descending timestamp),
+    /// checking downloaded images against the refresh policy.
+    pub fn status(&self, image_url: &str, records: &[Result<Entry, Missing>]) -> ImageStatus {
+        match ImageStatus::from_records(records) {
+            ImageStatus::Downloaded { entry, .. } => ImageStatus::Downloaded {
+                entry,
+                stale: self.client.is_stale(image_url, entry.timestamp.into()),
+            },
+            status => status,
+        }
+    }
+
+    /// Remove an image from the store and tombstone all index entries for it.
+    ///
+    /// Returns whether the file was removed, together with the tombstoned URLs.
+    pub fn delete(&self, digest: Digest) -> Result<(bool, Vec<String>), super::error::AdminError> {
+        let urls = self.index.tombstone(digest, Utc::now())?;
+        let removed = self.store.delete(digest)?;
+
+        Ok((removed, urls))
+    }
+
+    /// Check a bearer token against the configured admin token.
+    ///
+    /// Always fails if no admin token has been configured.
+    pub fn is_admin(&self, token: &str) -> bool {
+        self.admin_token.as_ref().is_some_and(|admin_token| {
+            admin_token.len() == token.len()
+                && admin_token
+                    .bytes()
+                    .zip(token.bytes())
+                    .fold(0, |acc, (a, b)| acc | (a ^ b))
+                    == 0
+        })
+    }
+
+    /// List stored images in digest order, starting after the given digest.
+    ///
+    /// Returns at most `limit` images, together with a flag indicating whether there are more.
+    /// This reads the store and the index, so it should be called from a blocking task.
+    pub fn list_images(
+        &self,
+        after: Option<Digest>,
+        limit: usize,
+    ) -> Result<(Vec<StoredImage>, bool), super::error::ListImagesError> {
+        let mut entries = after
+            .map_or_else(
+                || self.store.entries(),
+                |after| self.store.entries_after(store::Cursor::after(after)),
             )
+            .take(limit + 1)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let has_more = entries.len() > limit;
+        entries.truncate(limit);
+
+        let images = entries
+            .into_iter()
+            .map(|entry| {
+                let first = self.index.first_entry(entry.digest)?;
+
+                Ok(StoredImage {
+                    digest: format!("{:x}", entry.digest),
+                    image_type: first
+                        .map_or_else(ImageType::empty, |first| first.image_type.into()),
+                    size: entry.size()?,
+                    first_seen: first.map(|first| first.timestamp),
+                })
+            })
+            .collect::<Result<Vec<_>, super::error::ListImagesError>>()?;
+
+        Ok((images, has_more))
+    }
+
+    /// List the most recently indexed images (at most `limit`), most recent first.
+    pub fn recent_images(
+        &self,
+        limit: usize,
+    ) -> Result<Vec<IndexedImage>, image_scraper_index::db::Error> {
+        Ok(self
+            .index
+            .recent(limit)?
+            .into_iter()
+            .map(|(url, entry)| IndexedImage {
+                url,
+                digest: format!("{:x}", entry.digest),
+                image_type: entry.image_type.into(),
+                timestamp: entry.timestamp,
+            })
+            .collect())
+    }
+
+    /// List the images whose URLs were first indexed on the given day (UTC), in the order they were
+    /// indexed.
+    ///
+    /// Returns the given page (of `page_size` images), together with whether there are more pages.
+    /// Later entries for the same URLs (e.g. from refreshes) are not included.
+    pub fn first_seen_on(
+        &self,
+        date: NaiveDate,
+        page: usize,
+        page_size: usize,
+    ) -> Result<(Vec<IndexedImage>, bool), image_scraper_index::db::Error> {
+        let start = date.and_time(NaiveTime::MIN).and_utc();
+        let end = start + TimeDelta::days(1);
+
+        let mut images = vec![];
+
+        for result in self.index.entries_between(start, end)? {
+            let (url, entry) = result?;
+
+            if self.index.first_seen(&url)? == Some(entry.timestamp) {
+                images.push(IndexedImage {
+                    url,
+                    digest: format!("{:x}", entry.digest),
+                    image_type: entry.image_type.into(),
+                    timestamp: entry.timestamp,
+                });
+
+                if images.len() > page.saturating_add(1).saturating_mul(page_size) {
+                    break;
+                }
+            }
         }
+
+        let mut images = images
+            .into_iter()
+            .skip(page.saturating_mul(page_size))
+            .collect::<Vec<_>>();
+
+        let has_more = images.len() > page_size;
+        images.truncate(page_size);
+
+        Ok((images, has_more))
     }
 
-    pub fn path_for_digest(&self, digest: md5::Digest) -> Option<PathBuf> {
-        let path = self.store.path(digest);
+    /// List index records in URL order, starting at the given cursor.
+    ///
+    /// Returns at most `limit` records, together with the cursor for the next page (if there are
+    /// more records).
+    pub fn index_records(
+        &self,
+        cursor: Option<&Cursor>,
+        limit: usize,
+    ) -> Result<(Vec<IndexRecord>, Option<Cursor>), image_scraper_index::db::Error> {
+        let page = self.index.iter_from(cursor, limit)?;
+
+        Ok((
+            page.records
+                .into_iter()
+                .map(|(url, record)| IndexRecord::new(url, record))
+                .collect(),
+            page.next,
+        ))
+    }
 
-        if path.exists() && path.is_file() {
-            Some(path)
+    /// Return the estimated number of index records, and optionally the exact number.
+    pub fn index_counts(
+        &self,
+        exact: bool,
+    ) -> Result<(u64, Option<usize>), image_scraper_index::db::Error> {
+        let estimated = self.index.estimate_count()?;
+        let exact = if exact {
+            Some(self.index.count(|_, _| true)?)
         } else {
             None
-        }
+        };
+
+        Ok((estimated, exact))
     }
 
-    pub fn static_url(
+    /// Find indexed URLs that match the query, together with their latest entries.
+    ///
+    /// Only URLs that currently resolve to an image are included. At most `limit` images are
+    /// returned, and the returned flag indicates whether the search stopped early (because either
+    /// the limit or the maximum number of index records to scan was reached).
+    pub fn search(
         &self,
-        digest: md5::Digest,
-        image_type: ImageType,
-        style: UrlStyle,
-    ) -> String {
-        let image_type_str = image_type.as_str();
+        query: &SearchQuery,
+        limit: usize,
+    ) -> Result<(Vec<IndexedImage>, bool), image_scraper_index::db::Error> {
+        let mut images = vec![];
+        let mut scanned = 0;
+        let prefixes = query.prefixes();
+
+        let sources: Vec<IndexRecords<'_>> = match query.domain.as_deref() {
+            // With reversed host keys the domain's records are contiguous (for any scheme or port).
+            Some(domain) if self.index.key_scheme() == KeyScheme::ReversedHost => {
+                let host = domain.split(':').next().unwrap_or(domain);
+
+                vec![Box::new(self.index.iter_domain(host))]
+            }
+            _ => prefixes
+                .iter()
+                .map(|prefix| Box::new(self.index.iter_prefix(prefix)) as IndexRecords<'_>)
+                .collect(),
+        };
+
+        for source in sources {
+            let mut current_url: Option<String> = None;
+            let mut records = vec![];
+
+            // Index records for a URL are contiguous and sorted by ascending timestamp.
+            for result in source {
+                if scanned == SEARCH_SCAN_LIMIT {
+                    return Ok((images, true));
+                }
 
-        let mut prefix = String::new();
+                scanned += 1;
 
-        if style == UrlStyle::Full {
-            prefix.push_str(if self.url_config.secure {
-                "https://"
-            } else {
-                "http://"
-            });
+                let (url, record) = result?;
+
+                if current_url.as_ref() != Some(&url) {
+                    if let Some(previous_url) = current_url.replace(url) {
+                        Self::push_latest(&mut images, previous_url, &mut records);
+
+                        if images.len() >= limit {
+                            return Ok((images, true));
+                        }
+                    }
+
+                    records.clear();
+                }
 
-            prefix.push_str(&self.url_config.server);
+                if current_url.as_ref().is_some_and(|url| query.matches(url)) {
+                    records.push(record);
+                }
+            }
+
+            if let Some(previous_url) = current_url {
+                Self::push_latest(&mut images, previous_url, &mut records);
+
+                if images.len() >= limit {
+                    return Ok((images, true));
+                }
+            }
         }
 
-        if style != UrlStyle::Relative {
-            prefix.push_str(&self.url_config.base_path);
+        Ok((images, false))
+    }
+
+    /// Add the current entry for a URL (if there is one), given its records in ascending order.
+    fn push_latest(
+        images: &mut Vec<IndexedImage>,
+        url: String,
+        records: &mut [Result<Entry, Missing>],
+    ) {
+        records.reverse();
+
+        if let ImageStatus::Downloaded { entry, .. } = ImageStatus::from_records(records) {
+            images.push(IndexedImage {
+                url,
+                digest: format!("{:x}", entry.digest),
+                image_type: entry.image_type.into(),
+                timestamp: entry.timestamp,
+            });
         }
+    }
+
+    /// Return the store entry for a digest, if the image is in the store (individually or packed).
+    pub fn entry_for_digest(&self, digest: Digest) -> Option<image_scraper::store::Entry> {
+        self.store.lookup(digest)
+    }
 
-        if image_type_str.is_empty() {
+    /// Determine the origin for a request, if it was forwarded by a trusted proxy.
+    pub fn forwarded_origin(&self, headers: &http::HeaderMap, peer: IpAddr) -> Option<Origin> {
+        if self.url_config.trusted_proxies.contains(&peer) {
+            Origin::from_forwarded_headers(headers, self.url_config.secure)
+        } else {
+            None
+        }
+    }
+
+    pub fn static_url(
+        &self,
+        digest: Digest,
+        image_type: ImageType,
+        style: UrlStyle,
+        origin: Option<&Origin>,
+    ) -> String {
+        let prefix = self.url_prefix(style, origin);
+
+        if image_type.as_str().is_empty() {
             format!("{prefix}static/{digest:x}")
         } else {
             format!("{prefix}static/{digest:x}.{image_type}")
         }
     }
 
-    pub fn request_url(&self, encoded_url: &str, style: UrlStyle) -> String {
+    pub fn thumbnail_url(
+        &self,
+        digest: Digest,
+        image_type: ImageType,
+        size: u32,
+        style: UrlStyle,
+        origin: Option<&Origin>,
+    ) -> String {
+        let prefix = self.url_prefix(style, origin);
+
+        format!("{prefix}thumb/{digest:x}.{image_type}?size={size}")
+    }
+
+    pub fn request_url(
+        &self,
+        encoded_url: &str,
+        style: UrlStyle,
+        origin: Option<&Origin>,
+    ) -> String {
+        let prefix = self.url_prefix(style, origin);
+
+        format!("{prefix}request/{encoded_url}")
+    }
+
+    /// The origin is only used for full URLs, and defaults to the configured scheme and server.
+    fn url_prefix(&self, style: UrlStyle, origin: Option<&Origin>) -> String {
         let mut prefix = String::new();
 
         if style == UrlStyle::Full {
-            prefix.push_str(if self.url_config.secure {
-                "https://"
-            } else {
-                "http://"
-            });
+            let (secure, server) = origin.map_or(
+                (self.url_config.secure, self.url_config.server.as_str()),
+                |origin| (origin.secure, origin.server.as_str()),
+            );
 
-            prefix.push_str(&self.url_config.server);
+            prefix.push_str(if secure { "https://" } else { "http://" });
+            prefix.push_str(server);
         }
 
         if style != UrlStyle::Relative {
             prefix.push_str(&self.url_config.base_path);
         }
 
-        format!("{prefix}request/{encoded_url}")
-    }
-
-    fn handle_requests(
-        client: Arc<Client>,
-        delay: Duration,
-        mut receiver: Receiver<Option<(String, oneshot::Sender<ClientResult>)>>,
-    ) -> JoinHandle<()> {
-        tokio::task::spawn(async move {
-            while let Some(request) = receiver.recv().await {
-                if let Some((url, sender)) = request {
-                    log::info!("Downloading image: {url}");
-                    let result = client.download(&url).await;
-
-                    match sender.send(result) {
-                        Ok(()) => {}
-                        Err(_result) => {
-                            log::warn!(
-                                "Image already downloaded (may need to re-index image store): {url})"
-                            );
-                        }
-                    }
-
-                    log::info!("Waiting until next download: {delay:?}");
-                    tokio::time::sleep(delay).await;
-                } else {
-                    receiver.close();
-                    break;
-                }
-            }
-        })
+        prefix
     }
 }
@@ -0,0 +1,78 @@
+use utoipa::{
+    Modify, OpenApi,
+    openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme},
+};
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "image-scraper-service"),
+    paths(
+        super::static_image,
+        super::thumbnail,
+        super::request_image,
+        super::map_urls,
+        super::gallery,
+        super::listing::list_images,
+        super::listing::recent_images,
+        super::listing::images_by_date,
+        super::listing::index_records,
+        super::listing::search,
+        super::admin::delete_image,
+        super::admin::scrub_status,
+        super::admin::index_stats,
+        super::admin::cache_stats,
+        super::admin::maintenance_status,
+        super::admin::set_maintenance,
+        super::refresh_image
+    ),
+    components(schemas(super::error::ErrorResponse, super::manager::UrlStyle)),
+    modifiers(&AdminToken)
+)]
+pub struct ApiDoc;
+
+struct AdminToken;
+
+impl Modify for AdminToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "admin_token",
+                SecurityScheme::Http(HttpBuilder::new().scheme(HttpAuthScheme::Bearer).build()),
+            );
+        }
+    }
+}
+
+/// Build the `OpenAPI` document for a service with collections mounted at the given base paths.
+pub fn document(bases: &[&str]) -> utoipa::openapi::OpenApi {
+    let mut document = ApiDoc::openapi();
+    document.servers = Some(
+        bases
+            .iter()
+            .map(|base| utoipa::openapi::Server::new(*base))
+            .collect(),
+    );
+
+    document
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_document() {
+        let document = super::document(&["/", "/other/"]);
+
+        for path in [
+            "/static/{digest_with_image_type}",
+            "/urls",
+            "/gallery",
+            "/images",
+            "/admin/maintenance",
+            "/refresh/{url}",
+        ] {
+            assert!(document.paths.paths.contains_key(path), "missing {path}");
+        }
+
+        assert_eq!(document.servers.map(|servers| servers.len()), Some(2));
+    }
+}
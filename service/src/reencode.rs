@@ -0,0 +1,74 @@
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{DynamicImage, ImageError, ImageFormat};
+use image_scraper::transform::{Error, Transform};
+use imghdr::Type;
+
+/// Re-encode JPEG and PNG images that are larger than a given size.
+///
+/// JPEG images are encoded with the given quality, and PNG images with the best compression. The
+/// re-encoded image is only kept if it is smaller than the original.
+#[derive(Clone, Copy, Debug)]
+pub struct Reencode {
+    min_size: u64,
+    quality: u8,
+}
+
+impl Reencode {
+    /// The quality is clamped to the range from 1 to 100.
+    pub fn new(min_size: u64, quality: u8) -> Self {
+        Self {
+            min_size,
+            quality: quality.clamp(1, 100),
+        }
+    }
+
+    fn encode(&self, image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ImageError> {
+        let mut encoded = vec![];
+
+        if format == ImageFormat::Jpeg {
+            // JPEG doesn't support transparency.
+            DynamicImage::ImageRgb8(image.to_rgb8())
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, self.quality))?;
+        } else {
+            image.write_with_encoder(PngEncoder::new_with_quality(
+                &mut encoded,
+                CompressionType::Best,
+                FilterType::Adaptive,
+            ))?;
+        }
+
+        Ok(encoded)
+    }
+}
+
+impl Transform for Reencode {
+    fn name(&self) -> &'static str {
+        "reencode"
+    }
+
+    fn apply(&self, image_type: Type, bytes: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let format = match image_type {
+            Type::Jpeg => ImageFormat::Jpeg,
+            Type::Png => ImageFormat::Png,
+            _ => return Ok(None),
+        };
+
+        if (bytes.len() as u64) <= self.min_size {
+            return Ok(None);
+        }
+
+        let to_error = |error: ImageError| match error {
+            ImageError::Decoding(_) => Error::Corrupt("image couldn't be decoded"),
+            other => Error::Failed {
+                name: self.name().to_string(),
+                source: Box::new(other),
+            },
+        };
+
+        let image = image::load_from_memory_with_format(bytes, format).map_err(to_error)?;
+        let encoded = self.encode(&image, format).map_err(to_error)?;
+
+        Ok((encoded.len() < bytes.len()).then_some(encoded))
+    }
+}
@@ -0,0 +1,156 @@
+use crate::manager::{ImageStatus, Manager};
+use chrono::{DateTime, Utc};
+use image_scraper_index::{Entry, Missing};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Determines which index entries are periodically downloaded again.
+///
+/// Successfully downloaded images are downloaded again when the manager's refresh policy says that
+/// they are stale.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Time between scans of the index (also the minimum age of a failure before it is retried)
+    pub interval: Duration,
+}
+
+impl RetryPolicy {
+    /// Failures without a status code (network errors, etc.) and server errors may be transient.
+    const fn is_retryable(status: Option<u16>) -> bool {
+        match status {
+            None => true,
+            Some(status) => status == 408 || status == 429 || status >= 500,
+        }
+    }
+
+    /// Check whether a URL should be downloaded again, given its records (sorted by descending
+    /// timestamp).
+    fn is_due(
+        &self,
+        manager: &Manager,
+        url: &str,
+        records: &[Result<Entry, Missing>],
+        now: DateTime<Utc>,
+    ) -> bool {
+        let Some(latest) = records.first().map(|record| match record {
+            Ok(entry) => entry.timestamp,
+            Err(missing) => missing.timestamp(),
+        }) else {
+            return false;
+        };
+
+        let age = (now - latest).to_std().unwrap_or_default();
+
+        match manager.status(url, records) {
+            ImageStatus::Failed { status, .. } => {
+                Self::is_retryable(status) && age >= self.interval
+            }
+            ImageStatus::Downloaded { stale, .. } => stale,
+            ImageStatus::Downloading | ImageStatus::Deleted { .. } => false,
+        }
+    }
+
+    /// Find the URLs in the index that should be downloaded again.
+    ///
+    /// This requires a full scan of the index.
+    fn due_urls(
+        &self,
+        manager: &Manager,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<String>, image_scraper_index::db::Error> {
+        let mut urls = vec![];
+        let mut current_url = None;
+        let mut records = vec![];
+
+        // Index records for a URL are contiguous and sorted by ascending timestamp.
+        for result in manager.index.iter() {
+            let (url, record) = result?;
+
+            if current_url.as_ref() != Some(&url) {
+                if let Some(previous_url) = current_url.replace(url) {
+                    records.reverse();
+
+                    if self.is_due(manager, &previous_url, &records, now) {
+                        urls.push(previous_url);
+                    }
+                }
+
+                records.clear();
+            }
+
+            records.push(record);
+        }
+
+        if let Some(previous_url) = current_url {
+            records.reverse();
+
+            if self.is_due(manager, &previous_url, &records, now) {
+                urls.push(previous_url);
+            }
+        }
+
+        Ok(urls)
+    }
+}
+
+/// Start a background task that periodically downloads retryable failures and stale images again.
+///
+/// These downloads have a lower priority than requests from clients.
+pub fn spawn(manager: Arc<Manager>, policy: RetryPolicy) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(policy.interval).await;
+
+            if manager.maintenance() {
+                continue;
+            }
+
+            let urls = match policy.due_urls(&manager, Utc::now()) {
+                Ok(urls) => urls,
+                Err(error) => {
+                    log::error!("Retry scan failed: {error}");
+                    continue;
+                }
+            };
+
+            if !urls.is_empty() {
+                log::info!("Retrying {} downloads", urls.len());
+            }
+
+            for url in urls {
+                redownload(&manager, &url).await;
+            }
+        }
+    })
+}
+
+/// Download a URL again with a low priority, recording the result in the index.
+///
+/// URLs that are already waiting to be downloaded are skipped, as are all URLs in maintenance
+/// mode.
+pub async fn redownload(manager: &Manager, url: &str) {
+    if manager.maintenance() {
+        return;
+    }
+
+    match manager.index.enqueue(url, Utc::now()) {
+        Ok(false) => return,
+        Ok(true) => {}
+        Err(error) => {
+            log::error!("Failed to queue retry ({url}): {error}");
+            return;
+        }
+    }
+
+    let result = manager
+        .request_background(url)
+        .await
+        .map_err(super::error::RequestImageError::from)
+        .and_then(|result| super::check_download(manager, url, result))
+        .and_then(|(_, action)| super::index_download(manager, url, &action));
+
+    if let Err(error) = result {
+        log::warn!("Retried download failed ({url}): {error}");
+    }
+}
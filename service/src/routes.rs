@@ -0,0 +1,166 @@
+use crate::listener::RouteGroup;
+use crate::manager::{Manager, Timeouts};
+use crate::{admin, error, listing};
+use axum::{
+    Router,
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
+    routing::{MethodRouter, delete, get, post},
+};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+use tower::ServiceBuilder;
+use tower_http::compression::CompressionLayer;
+
+/// Routes in the given groups for a single store and index.
+///
+/// Only JSON responses are compressed, since image formats are already compressed.
+pub fn router(base: &str, manager: Arc<Manager>, groups: &[RouteGroup]) -> Router {
+    let mut router = Router::new();
+
+    if groups.contains(&RouteGroup::Static) {
+        router = static_routes(router, base, &manager);
+    }
+
+    if groups.contains(&RouteGroup::Api) {
+        router = api_routes(router, base, &manager);
+    }
+
+    if groups.contains(&RouteGroup::Admin) {
+        router = admin_routes(router, base, manager.timeouts());
+    }
+
+    router.with_state(manager)
+}
+
+fn static_routes(
+    router: Router<Arc<Manager>>,
+    base: &str,
+    manager: &Manager,
+) -> Router<Arc<Manager>> {
+    let timeouts = manager.timeouts();
+
+    let router = router.route(
+        &format!("{base}static/{{digest_with_image_type}}"),
+        with_timeout(get(super::static_image), timeouts.default),
+    );
+
+    if manager.thumbnails().is_some() {
+        router.route(
+            &format!("{base}thumb/{{digest_with_image_type}}"),
+            with_timeout(get(super::thumbnail), timeouts.default),
+        )
+    } else {
+        router
+    }
+}
+
+fn api_routes(router: Router<Arc<Manager>>, base: &str, manager: &Manager) -> Router<Arc<Manager>> {
+    let timeouts = manager.timeouts();
+    let max_body_bytes = manager.urls_limits().max_body_bytes;
+
+    let router = router
+        .route(
+            &format!("{base}request/{{url}}"),
+            with_timeout(get(super::request_image), timeouts.request),
+        )
+        .route(
+            &format!("{base}urls"),
+            with_timeout(
+                post(super::map_urls)
+                    .layer::<_, Infallible>(DefaultBodyLimit::max(max_body_bytes))
+                    .layer(CompressionLayer::new()),
+                timeouts.default,
+            ),
+        )
+        .route(
+            &format!("{base}images"),
+            compressed(get(listing::list_images), timeouts),
+        )
+        .route(
+            &format!("{base}recent"),
+            compressed(get(listing::recent_images), timeouts),
+        )
+        .route(
+            &format!("{base}by-date/{{date}}"),
+            compressed(get(listing::images_by_date), timeouts),
+        )
+        .route(
+            &format!("{base}index"),
+            compressed(get(listing::index_records), timeouts),
+        )
+        .route(
+            &format!("{base}search"),
+            compressed(get(listing::search), timeouts),
+        );
+
+    if manager.gallery() {
+        router.route(
+            &format!("{base}gallery"),
+            compressed(get(super::gallery), timeouts),
+        )
+    } else {
+        router
+    }
+}
+
+fn admin_routes(
+    router: Router<Arc<Manager>>,
+    base: &str,
+    timeouts: Timeouts,
+) -> Router<Arc<Manager>> {
+    router
+        .route(
+            &format!("{base}admin/image/{{digest}}"),
+            compressed(delete(admin::delete_image), timeouts),
+        )
+        .route(
+            &format!("{base}admin/scrub"),
+            with_timeout(get(admin::scrub_status), timeouts.default),
+        )
+        .route(
+            &format!("{base}admin/index"),
+            with_timeout(get(admin::index_stats), timeouts.default),
+        )
+        .route(
+            &format!("{base}admin/cache"),
+            with_timeout(get(admin::cache_stats), timeouts.default),
+        )
+        .route(
+            &format!("{base}admin/maintenance"),
+            with_timeout(
+                get(admin::maintenance_status).put(admin::set_maintenance),
+                timeouts.default,
+            ),
+        )
+        .route(
+            &format!("{base}refresh/{{url}}"),
+            with_timeout(post(super::refresh_image), timeouts.request),
+        )
+}
+
+/// A compressed route with the default timeout.
+fn compressed(
+    router: MethodRouter<Arc<Manager>>,
+    timeouts: Timeouts,
+) -> MethodRouter<Arc<Manager>> {
+    with_timeout(router.layer(CompressionLayer::new()), timeouts.default)
+}
+
+/// Respond with a 504 if the handler doesn't complete within the time limit (if any).
+fn with_timeout(
+    router: MethodRouter<Arc<Manager>>,
+    timeout: Option<Duration>,
+) -> MethodRouter<Arc<Manager>> {
+    match timeout {
+        Some(timeout) => router.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|error: tower::BoxError| async move {
+                    error::TimeoutError::from(error)
+                }))
+                .timeout(timeout),
+        ),
+        None => router,
+    }
+}
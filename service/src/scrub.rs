@@ -0,0 +1,158 @@
+use crate::manager::Manager;
+use chrono::{DateTime, Utc};
+use image_scraper::digest::Digest;
+use image_scraper::store::Entry;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Number of corrupt digests retained in the status.
+const RECENT_CORRUPT_LEN: usize = 100;
+
+/// Time between checks of whether maintenance mode has been turned off.
+const MAINTENANCE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Progress of the background integrity scrubber.
+#[derive(Clone, Debug, Default, Eq, PartialEq, serde::Serialize, utoipa::ToSchema)]
+pub struct ScrubStatus {
+    /// Number of complete passes over the store
+    pub passes: u64,
+    /// Number of files checked in the current pass
+    pub checked: u64,
+    /// Total number of corrupt files found
+    pub corrupt: u64,
+    /// Total number of corrupt files that were downloaded again
+    pub redownloaded: u64,
+    /// Digest of the most recently checked file
+    pub position: Option<String>,
+    /// Start of the current pass
+    #[schema(value_type = Option<String>, format = DateTime)]
+    pub pass_started: Option<DateTime<Utc>>,
+    /// Digests of the most recently found corrupt files
+    pub recent_corrupt: Vec<String>,
+}
+
+impl ScrubStatus {
+    fn record_corrupt(&mut self, digest: Digest) {
+        self.corrupt += 1;
+
+        if self.recent_corrupt.len() == RECENT_CORRUPT_LEN {
+            self.recent_corrupt.remove(0);
+        }
+
+        self.recent_corrupt.push(format!("{digest:x}"));
+    }
+}
+
+/// Start a background task that repeatedly validates the digest of every file in the store.
+///
+/// The task waits for the given interval after checking each file. If `redownload` is set, corrupt
+/// files are removed and downloaded again from a URL they are indexed for (with a low priority).
+pub fn spawn(manager: Arc<Manager>, interval: Duration, redownload: bool) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            manager.update_scrub_status(|status| {
+                status.checked = 0;
+                status.pass_started = Some(Utc::now());
+            });
+
+            for entry in manager.store().entries() {
+                // Files may be moved or rewritten during maintenance.
+                while manager.maintenance() {
+                    tokio::time::sleep(MAINTENANCE_POLL_INTERVAL).await;
+                }
+
+                match entry {
+                    Ok(entry) => {
+                        let digest = entry.digest;
+
+                        if !check(entry).await {
+                            manager.update_scrub_status(|status| status.record_corrupt(digest));
+
+                            if redownload {
+                                match redownload_image(&manager, digest).await {
+                                    Ok(true) => manager
+                                        .update_scrub_status(|status| status.redownloaded += 1),
+                                    Ok(false) => {
+                                        log::warn!("No URL to download corrupt image: {digest:x}");
+                                    }
+                                    Err(error) => log::error!(
+                                        "Failed to download corrupt image ({digest:x}): {error}"
+                                    ),
+                                }
+                            }
+                        }
+
+                        manager.update_scrub_status(|status| {
+                            status.checked += 1;
+                            status.position = Some(format!("{digest:x}"));
+                        });
+                    }
+                    Err(error) => log::error!("Scrub iteration error: {error}"),
+                }
+
+                tokio::time::sleep(interval).await;
+            }
+
+            manager.update_scrub_status(|status| status.passes += 1);
+
+            // Avoid spinning on an empty store.
+            tokio::time::sleep(interval).await;
+        }
+    })
+}
+
+/// Validate a single file, returning whether it is intact.
+///
+/// Files that can't be read (which may have been removed since iteration started) are skipped.
+async fn check(entry: Entry) -> bool {
+    let path = entry.path.clone();
+
+    match tokio::task::spawn_blocking(move || entry.validate()).await {
+        Ok(Ok(Ok(()))) => true,
+        Ok(Ok(Err(actual))) => {
+            log::error!("Corrupt image ({}): digest is {actual:x}", path.display());
+
+            false
+        }
+        Ok(Err(error)) => {
+            log::warn!(
+                "Unable to read image for scrub ({}): {error}",
+                path.display()
+            );
+
+            true
+        }
+        Err(error) => {
+            log::error!("Scrub task join error: {error}");
+
+            true
+        }
+    }
+}
+
+/// Remove a corrupt file and download it again, returning whether an indexed URL was found.
+async fn redownload_image(
+    manager: &Manager,
+    digest: Digest,
+) -> Result<bool, super::error::ScrubError> {
+    let urls = manager.index.urls(digest)?;
+
+    match urls.first() {
+        Some(url) => {
+            manager.store().delete(digest)?;
+            manager.index.enqueue(url, Utc::now())?;
+
+            let result = manager
+                .request_background(url)
+                .await
+                .map_err(super::error::RequestImageError::from)?;
+
+            let (_, action) = super::check_download(manager, url, result)?;
+            super::index_download(manager, url, &action)?;
+
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
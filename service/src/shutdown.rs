@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-pub async fn signal(manager: Arc<super::manager::Manager>) {
+pub async fn signal(downloader: Arc<super::downloader::Downloader>) {
     let ctrl_c = async {
         tokio::signal::ctrl_c()
             .await
@@ -20,10 +20,12 @@ pub async fn signal(manager: Arc<super::manager::Manager>) {
 
     tokio::select! {
         () = ctrl_c => {
+            super::systemd::notify_stopping();
             log::info!("Shutting down (user-requested)");
-            manager.close().await.expect("failed to install signal handler");
+            downloader.close().await.expect("failed to install signal handler");
         },
         () = terminate => {
+            super::systemd::notify_stopping();
             log::info!("Shutting down (terminated)");
         },
     }
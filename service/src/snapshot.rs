@@ -0,0 +1,42 @@
+use crate::manager::Manager;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Start a background task that periodically writes a backup of the index to a directory.
+///
+/// Only the most recent `keep` backups are retained. The directory must not be shared with other
+/// indexes.
+pub fn spawn(
+    manager: Arc<Manager>,
+    directory: PathBuf,
+    interval: Duration,
+    keep: NonZeroUsize,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+
+            let result = tokio::task::spawn_blocking({
+                let manager = manager.clone();
+                let directory = directory.clone();
+
+                move || manager.index.backup(directory, keep)
+            })
+            .await;
+
+            match result {
+                Ok(Ok(info)) => log::info!(
+                    "Wrote index snapshot {} to {} ({} bytes)",
+                    info.id,
+                    directory.display(),
+                    info.size
+                ),
+                Ok(Err(error)) => log::error!("Index snapshot failed: {error}"),
+                Err(error) => log::error!("Index snapshot task failed: {error}"),
+            }
+        }
+    })
+}
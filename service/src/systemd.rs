@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+/// Take the listener with the given index passed by systemd socket activation, if there is one.
+pub fn listener(index: usize) -> Result<Option<std::net::TcpListener>, std::io::Error> {
+    let listener = listenfd::ListenFd::from_env().take_tcp_listener(index)?;
+
+    // Tokio requires the listener to be in non-blocking mode.
+    if let Some(listener) = &listener {
+        listener.set_nonblocking(true)?;
+    }
+
+    Ok(listener)
+}
+
+/// Tell systemd that the service has started (this does nothing if not run by systemd).
+pub fn notify_ready() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Ready]);
+}
+
+/// Tell systemd that the service is shutting down.
+pub fn notify_stopping() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+/// Tell systemd that the service is still alive.
+pub fn ping_watchdog() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Watchdog]);
+}
+
+/// Return how often the watchdog should be pinged, if systemd has enabled it.
+///
+/// This is half of the configured timeout, as recommended by systemd.
+#[cfg(unix)]
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut timeout_us = 0;
+
+    if sd_notify::watchdog_enabled(false, &mut timeout_us) {
+        Some(Duration::from_micros(timeout_us) / 2)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub const fn watchdog_interval() -> Option<Duration> {
+    None
+}
+
+#[cfg(unix)]
+fn notify(states: &[sd_notify::NotifyState<'_>]) {
+    if let Err(error) = sd_notify::notify(false, states) {
+        log::warn!("Failed to notify systemd: {error}");
+    }
+}
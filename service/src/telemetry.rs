@@ -0,0 +1,50 @@
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use tracing_subscriber::{Layer, Registry};
+
+const SERVICE_NAME: &str = "image-scraper-service";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("OTLP exporter error")]
+    Exporter(#[from] opentelemetry_otlp::ExporterBuildError),
+}
+
+/// Exports spans until it is dropped, when any spans that haven't been sent yet are flushed.
+pub struct Exporter {
+    provider: SdkTracerProvider,
+}
+
+impl Drop for Exporter {
+    fn drop(&mut self) {
+        if let Err(error) = self.provider.shutdown() {
+            tracing::warn!("Failed to flush spans: {error}");
+        }
+    }
+}
+
+/// Build a layer that exports spans to an OTLP/HTTP endpoint (such as a Jaeger or Tempo collector).
+///
+/// Spans are sent in batches from a background thread.
+pub fn layer(endpoint: &str) -> Result<(Box<dyn Layer<Registry> + Send + Sync>, Exporter), Error> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()?;
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            opentelemetry_sdk::Resource::builder()
+                .with_service_name(SERVICE_NAME)
+                .build(),
+        )
+        .build();
+
+    let layer = tracing_opentelemetry::layer()
+        .with_tracer(provider.tracer(SERVICE_NAME))
+        .boxed();
+
+    Ok((layer, Exporter { provider }))
+}
@@ -0,0 +1,148 @@
+//! Helpers for handler tests.
+
+use crate::downloader::Downloader;
+use crate::listener::RouteGroup;
+use crate::manager::{Manager, UrlConfig};
+use crate::routes;
+use axum::{Router, body::Body};
+use bytes::Bytes;
+use http::{Request, StatusCode, header};
+use image_scraper::digest::Digest;
+use image_scraper::store::Store;
+use image_scraper_index::Entry;
+use std::sync::Arc;
+use std::time::Duration;
+use tempfile::TempDir;
+use tower::ServiceExt;
+
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+/// The PNG file signature and the start of a header chunk, which is enough for an image to be
+/// recognized.
+pub const PNG_HEADER: [u8; 16] = [
+    0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0, 0, 0, 0x0D, b'I', b'H', b'D', b'R',
+];
+
+/// A manager with the admin token set for a store and index in a new temporary directory.
+///
+/// The directory must be kept alive for as long as the manager is used.
+pub fn manager() -> (TempDir, Manager) {
+    manager_with_store(|store| store)
+}
+
+/// A manager like [`manager`], with the store configured by the given function.
+pub fn manager_with_store<F: FnOnce(Store) -> Store>(configure: F) -> (TempDir, Manager) {
+    let dir = tempfile::tempdir().unwrap();
+    let store = configure(Store::new(dir.path().join("store")));
+    let downloader = Arc::new(Downloader::new(8, 8, Duration::ZERO, None));
+
+    let manager = Manager::new(
+        UrlConfig::new(false, "localhost".to_string(), "/".to_string()),
+        store,
+        dir.path().join("index"),
+        None,
+        downloader,
+    )
+    .unwrap()
+    .with_admin_token(Some(ADMIN_TOKEN.to_string()));
+
+    (dir, manager)
+}
+
+/// Save a PNG image with the given contents (after the header) and index it for the URL.
+pub fn add_image(manager: &Manager, url: &str, contents: &[u8]) -> Digest {
+    let action = manager
+        .store()
+        .save([&PNG_HEADER, contents].concat().as_slice())
+        .unwrap();
+    crate::index_download(manager, url, &action).unwrap();
+
+    action.entry.digest
+}
+
+/// Index an entry for a PNG image with the given contents (which isn't saved) at the given time.
+pub fn add_entry(manager: &Manager, url: &str, contents: &[u8], timestamp_s: i64) -> Entry {
+    let entry = Entry {
+        timestamp: chrono::DateTime::from_timestamp(timestamp_s, 0).unwrap(),
+        digest: Digest::compute(contents),
+        image_type: imghdr::Type::Png,
+    };
+    manager.index.add(url, entry).unwrap();
+
+    entry
+}
+
+/// Serve a router on a local port, returning its address.
+pub async fn serve(router: Router) -> std::net::SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(std::future::IntoFuture::into_future(axum::serve(
+        listener, router,
+    )));
+
+    address
+}
+
+/// The request path for an image URL.
+pub fn request_path(url: &str) -> String {
+    use base64::Engine as _;
+
+    format!(
+        "/request/{}",
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(url)
+    )
+}
+
+/// Every route for the manager, mounted at the root.
+pub fn router(manager: Manager) -> Router {
+    routes::router("/", Arc::new(manager), &RouteGroup::ALL)
+}
+
+/// Send a request to the router, returning the response status and body.
+pub async fn send(router: Router, request: Request<Body>) -> (StatusCode, Bytes) {
+    let response = router.oneshot(request).await.unwrap();
+    let status = response.status();
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap();
+
+    (status, body)
+}
+
+/// A request with the admin token and an optional JSON body.
+pub fn admin_request(
+    method: http::Method,
+    uri: &str,
+    json: Option<&serde_json::Value>,
+) -> Request<Body> {
+    let request = Request::builder()
+        .method(method)
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"));
+
+    match json {
+        Some(json) => request
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(json.to_string())),
+        None => request.body(Body::empty()),
+    }
+    .unwrap()
+}
+
+/// Send a `GET` request (with the admin token, if requested).
+pub async fn get(router: Router, uri: &str, admin: bool) -> (StatusCode, Bytes) {
+    let mut request = Request::get(uri);
+
+    if admin {
+        request = request.header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"));
+    }
+
+    send(router, request.body(Body::empty()).unwrap()).await
+}
+
+/// Send a `GET` request and parse the response body as JSON.
+pub async fn get_json(router: Router, uri: &str, admin: bool) -> (StatusCode, serde_json::Value) {
+    let (status, body) = get(router, uri, admin).await;
+
+    (status, serde_json::from_slice(&body).unwrap())
+}
@@ -0,0 +1,159 @@
+use image::{DynamicImage, ImageFormat};
+use image_scraper::digest::Digest;
+use image_scraper::image_type::ImageType;
+use image_scraper::read_cache::ReadCache;
+use image_scraper::store::Entry;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Thumbnail sizes (maximum width and height in pixels) that may be requested.
+pub const SIZES: [u32; 4] = [64, 128, 256, 512];
+
+/// Thumbnail size used if none is requested.
+pub const DEFAULT_SIZE: u32 = 256;
+
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Identifies a generated thumbnail (by source image digest, size, and format).
+pub type VariantKey = (Digest, u32, ImageFormat);
+
+/// A directory of generated thumbnails, keyed by size and image digest.
+///
+/// Thumbnails are encoded as JPEG for JPEG source images, and as PNG for everything else. Recently
+/// used thumbnails can also be kept in memory.
+#[derive(Clone, Debug)]
+pub struct ThumbnailCache {
+    base: PathBuf,
+    memory: Option<ReadCache<VariantKey>>,
+}
+
+impl ThumbnailCache {
+    pub fn new<P: AsRef<Path>>(base: P) -> Self {
+        Self {
+            base: base.as_ref().to_path_buf(),
+            memory: None,
+        }
+    }
+
+    /// Keep recently used thumbnails in memory (the cache may be shared with other collections).
+    #[must_use]
+    pub fn with_memory(self, memory: Option<ReadCache<VariantKey>>) -> Self {
+        Self { memory, ..self }
+    }
+
+    #[must_use]
+    pub const fn memory(&self) -> Option<&ReadCache<VariantKey>> {
+        self.memory.as_ref()
+    }
+
+    #[must_use]
+    pub fn format(image_type: ImageType) -> ImageFormat {
+        if image_type.value() == Some(imghdr::Type::Jpeg) {
+            ImageFormat::Jpeg
+        } else {
+            ImageFormat::Png
+        }
+    }
+
+    fn path(&self, digest: Digest, size: u32, format: ImageFormat) -> PathBuf {
+        let digest_string = format!("{digest:x}");
+        let extension = format.extensions_str().first().copied().unwrap_or_default();
+
+        self.base
+            .join(size.to_string())
+            .join(&digest_string[0..2])
+            .join(format!("{digest_string}.{extension}"))
+    }
+
+    /// Return the path for a thumbnail of the given source image, generating it if necessary.
+    pub fn get(
+        &self,
+        source: &Entry,
+        digest: Digest,
+        size: u32,
+        format: ImageFormat,
+    ) -> Result<PathBuf, image::ImageError> {
+        let path = self.path(digest, size, format);
+
+        if !path.is_file() {
+            let image = match source.packed {
+                Some(_) => image::load_from_memory(&source.read()?)?,
+                None => image::open(&source.path)?,
+            }
+            .thumbnail(size, size);
+
+            // JPEG doesn't support transparency.
+            let image = if format == ImageFormat::Jpeg {
+                DynamicImage::ImageRgb8(image.to_rgb8())
+            } else {
+                image
+            };
+
+            // We construct the path, so we know there will always be a parent.
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+
+                let temp_path = parent.join(format!(
+                    ".tmp-{}-{}",
+                    std::process::id(),
+                    TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+                ));
+
+                let result = image.save_with_format(&temp_path, format).and_then(|()| {
+                    std::fs::rename(&temp_path, &path).map_err(image::ImageError::from)
+                });
+
+                if result.is_err() {
+                    let _ = std::fs::remove_file(&temp_path);
+                }
+
+                result?;
+            }
+        }
+
+        Ok(path)
+    }
+
+    /// Remove every thumbnail of a source image (e.g. after it has been deleted from the store).
+    pub fn remove(&self, digest: Digest) -> Result<(), std::io::Error> {
+        for size in SIZES {
+            for format in [ImageFormat::Jpeg, ImageFormat::Png] {
+                if let Some(memory) = &self.memory {
+                    memory.remove(&(digest, size, format));
+                }
+
+                if let Err(error) = std::fs::remove_file(self.path(digest, size, format))
+                    && error.kind() != std::io::ErrorKind::NotFound
+                {
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the contents of a thumbnail from memory, generating or reading it if necessary.
+    pub fn read(
+        &self,
+        source: &Entry,
+        digest: Digest,
+        size: u32,
+        format: ImageFormat,
+    ) -> Result<Arc<[u8]>, image::ImageError> {
+        let key = (digest, size, format);
+
+        if let Some(contents) = self.memory.as_ref().and_then(|memory| memory.get(&key)) {
+            return Ok(contents);
+        }
+
+        let contents = Arc::<[u8]>::from(std::fs::read(self.get(source, digest, size, format)?)?);
+
+        if let Some(memory) = &self.memory {
+            memory.insert(key, contents.clone());
+        }
+
+        Ok(contents)
+    }
+}